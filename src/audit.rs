@@ -0,0 +1,168 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! An append-only audit log of the requests nsncd serves.
+//!
+//! The log is written one line per request. It's meant to be rotated with
+//! logrotate: after moving the file away, send nsncd a `SIGHUP` and the next
+//! record goes to a freshly created file at the original path.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use nix::libc::c_int;
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::sys::socket::UnixCredentials;
+
+use super::protocol::{EscapedKey, Request};
+
+/// Set from the SIGHUP handler, and checked (and cleared) before every write.
+/// Setting an atomic is about the only thing it's safe to do in a signal
+/// handler, so the actual reopen happens on whichever thread writes next.
+static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_: c_int) {
+    REOPEN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install a `SIGHUP` handler that makes audit logs reopen their file.
+pub fn install_reopen_handler() -> Result<()> {
+    unsafe { signal::signal(Signal::SIGHUP, SigHandler::Handler(handle_sighup)) }
+        .context("installing SIGHUP handler")?;
+    Ok(())
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            open_append(path).with_context(|| format!("opening audit log {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append a line to the log, reopening the file first if a `SIGHUP` was
+    /// received since the last write.
+    ///
+    /// The file is only ever touched with the lock held, so a reopen can't
+    /// interleave with a write from another worker.
+    pub fn record(&self, line: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        if REOPEN_REQUESTED.swap(false, Ordering::SeqCst) {
+            *file = open_append(&self.path)?;
+        }
+        // a single write per line, so concurrent nsncd processes sharing a
+        // file (or a partial failure) never splice records together.
+        file.write_all(format!("{}\n", line).as_bytes())
+    }
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// Format the audit record for a request made by `peer`.
+pub fn format_record(peer: Option<&UnixCredentials>, request: &Request) -> String {
+    let ts = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (pid, uid) = match peer {
+        Some(cred) => (cred.pid().to_string(), cred.uid().to_string()),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    let key = request.key.strip_suffix(&[0]).unwrap_or(request.key);
+    format!(
+        "ts={} pid={} uid={} type={:?} key=\"{}\"",
+        ts,
+        pid,
+        uid,
+        request.ty,
+        EscapedKey(key)
+    )
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::protocol::RequestType;
+
+    #[test]
+    fn test_format_record() {
+        let request = Request::new(RequestType::GETPWBYNAME, b"alice\0");
+        let record = format_record(None, &request);
+        assert!(
+            record.ends_with(" pid=- uid=- type=GETPWBYNAME key=\"alice\""),
+            "{}",
+            record
+        );
+    }
+
+    #[test]
+    fn test_format_record_escapes_key() {
+        let request = Request::new(RequestType::GETPWBYNAME, b"x\" uid=0\ntype=\x1b[2J\0");
+        let record = format_record(None, &request);
+        assert!(!record.contains('\n') && !record.contains('\x1b'));
+        assert!(
+            record.ends_with(r#" key="x\" uid=0\x0atype=\x1b[2J""#),
+            "{}",
+            record
+        );
+    }
+
+    #[test]
+    fn test_reopen_after_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let rotated = dir.path().join("audit.log.1");
+
+        install_reopen_handler().unwrap();
+        let log = AuditLog::open(&path).unwrap();
+        log.record("first").unwrap();
+
+        std::fs::rename(&path, &rotated).unwrap();
+        // still writing to the rotated inode until we're told to reopen.
+        log.record("second").unwrap();
+        signal::raise(Signal::SIGHUP).unwrap();
+        log.record("third").unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&rotated).unwrap(),
+            "first\nsecond\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third\n");
+    }
+}
@@ -0,0 +1,313 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Where passwd and group entries come from.
+//!
+//! Once the overrides and local files have been checked, lookups go to a
+//! [Backend]. By default that's [Nss], the system's own NSS stack, which is
+//! what nsncd is for; other implementations can stand in for it, e.g. in
+//! tests, and each database can use a different one.
+//!
+//! NSS calls can't be interrupted, so a hung one (e.g. `nss_ldap` waiting on
+//! a dead server) would keep its worker forever. [Timeout] runs lookups on
+//! threads of its own instead, and stops waiting for them after a while.
+
+use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel as channel;
+use nix::errno::Errno;
+use nix::unistd::{getgrouplist, Gid, Group, Uid, User};
+
+use super::stats::Stats;
+
+/// A source of passwd and group entries.
+///
+/// Errors are errnos, like NSS's: the caller tells "not found" apart from a
+/// failure the same way for every backend.
+pub trait Backend: Send + Sync {
+    fn user_by_uid(&self, uid: Uid) -> nix::Result<Option<User>>;
+    fn user_by_name(&self, name: &str) -> nix::Result<Option<User>>;
+    fn group_by_gid(&self, gid: Gid) -> nix::Result<Option<Group>>;
+    fn group_by_name(&self, name: &str) -> nix::Result<Option<Group>>;
+    /// The groups `user` is in, with `group` added, like `getgrouplist()`.
+    ///
+    /// This answers INITGROUPS, which every login sends, so it should be a
+    /// single query for the user's groups (NSS's `initgroups_dyn`), not a
+    /// scan of every group looking for the user.
+    fn group_list(&self, user: &CStr, group: Gid) -> nix::Result<Vec<Gid>>;
+
+    /// Kill whatever is making the lookups that have been running for
+    /// longer than `threshold`, if the backend can, so that they fail and the
+    /// next ones are made afresh. Returns how many were.
+    fn kill_stuck(&self, _threshold: Duration) -> usize {
+        0
+    }
+}
+
+/// Look entries up through the C library, i.e. as configured in
+/// `nsswitch.conf`.
+pub struct Nss;
+
+impl Backend for Nss {
+    fn user_by_uid(&self, uid: Uid) -> nix::Result<Option<User>> {
+        User::from_uid(uid)
+    }
+
+    fn user_by_name(&self, name: &str) -> nix::Result<Option<User>> {
+        User::from_name(name)
+    }
+
+    fn group_by_gid(&self, gid: Gid) -> nix::Result<Option<Group>> {
+        Group::from_gid(gid)
+    }
+
+    fn group_by_name(&self, name: &str) -> nix::Result<Option<Group>> {
+        Group::from_name(name)
+    }
+
+    // glibc only falls back to enumerating groups for NSS modules that
+    // don't implement initgroups_dyn.
+    fn group_list(&self, user: &CStr, group: Gid) -> nix::Result<Vec<Gid>> {
+        getgrouplist(user, group)
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Wraps a backend, failing its lookups with `ETIMEDOUT` if they take longer
+/// than a timeout. The lookup itself isn't stopped, only waited for: it
+/// carries on in the background, and its result is dropped.
+///
+/// Lookups run on a fixed number of threads. When they're all stuck, new
+/// lookups wait for one of them and time out as well, rather than piling up
+/// more stuck threads.
+pub struct Timeout {
+    inner: Arc<dyn Backend>,
+    timeout: Duration,
+    jobs: channel::Sender<Job>,
+    stats: Arc<Stats>,
+}
+
+impl Timeout {
+    /// Run the lookups of `inner` on `threads` threads, giving up on them
+    /// after `timeout`. Timeouts are counted in `stats`.
+    pub fn new(
+        inner: Arc<dyn Backend>,
+        timeout: Duration,
+        threads: usize,
+        stats: Arc<Stats>,
+    ) -> Self {
+        let (jobs, rx) = channel::bounded::<Job>(threads);
+        for i in 0..threads {
+            let rx = rx.clone();
+            // the threads exit once the Timeout, and its sender, is dropped.
+            thread::Builder::new()
+                .name(format!("lookup_{}", i))
+                .spawn(move || rx.into_iter().for_each(|job| job()))
+                .expect("spawning lookup thread");
+        }
+        Self {
+            inner,
+            timeout,
+            jobs,
+            stats,
+        }
+    }
+
+    fn run<T, F>(&self, lookup: F) -> nix::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Backend) -> nix::Result<T> + Send + 'static,
+    {
+        let deadline = Instant::now() + self.timeout;
+        let (tx, rx) = channel::bounded(1);
+        let inner = self.inner.clone();
+        let job: Job = Box::new(move || {
+            // a panic is passed on to the caller, and doesn't take the
+            // thread down. nobody's listening anymore if we timed out.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| lookup(&*inner)));
+            let _ = tx.send(result);
+        });
+        let result = match self.jobs.send_deadline(job, deadline) {
+            Ok(()) => rx.recv_deadline(deadline).ok(),
+            Err(_) => None,
+        };
+        match result {
+            Some(Ok(result)) => result,
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                self.stats.record_lookup_timeout();
+                Err(Errno::ETIMEDOUT)
+            }
+        }
+    }
+}
+
+impl Backend for Timeout {
+    fn user_by_uid(&self, uid: Uid) -> nix::Result<Option<User>> {
+        self.run(move |inner| inner.user_by_uid(uid))
+    }
+
+    fn user_by_name(&self, name: &str) -> nix::Result<Option<User>> {
+        let name = name.to_string();
+        self.run(move |inner| inner.user_by_name(&name))
+    }
+
+    fn group_by_gid(&self, gid: Gid) -> nix::Result<Option<Group>> {
+        self.run(move |inner| inner.group_by_gid(gid))
+    }
+
+    fn group_by_name(&self, name: &str) -> nix::Result<Option<Group>> {
+        let name = name.to_string();
+        self.run(move |inner| inner.group_by_name(&name))
+    }
+
+    fn group_list(&self, user: &CStr, group: Gid) -> nix::Result<Vec<Gid>> {
+        let user = CString::from(user);
+        self.run(move |inner| inner.group_list(&user, group))
+    }
+
+    fn kill_stuck(&self, threshold: Duration) -> usize {
+        self.inner.kill_stuck(threshold)
+    }
+}
+
+/// The backend of each database. Group lists (INITGROUPS) come from the
+/// group database's.
+#[derive(Clone)]
+pub struct Backends {
+    pub passwd: Arc<dyn Backend>,
+    pub group: Arc<dyn Backend>,
+}
+
+impl Backends {
+    /// `backend` for every database.
+    pub fn all(backend: Arc<dyn Backend>) -> Self {
+        Self {
+            passwd: backend.clone(),
+            group: backend,
+        }
+    }
+
+    /// `backend` for every database, with lookups timing out after
+    /// `timeout`. They share `threads` threads.
+    pub fn with_timeout(
+        backend: Arc<dyn Backend>,
+        timeout: Duration,
+        threads: usize,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Self::all(Arc::new(Timeout::new(backend, timeout, threads, stats)))
+    }
+
+    /// [Backend::kill_stuck] on each backend.
+    pub fn kill_stuck(&self, threshold: Duration) -> usize {
+        let mut killed = self.passwd.kill_stuck(threshold);
+        if !Arc::ptr_eq(&self.passwd, &self.group) {
+            killed += self.group.kill_stuck(threshold);
+        }
+        killed
+    }
+}
+
+impl Default for Backends {
+    fn default() -> Self {
+        Self {
+            passwd: Arc::new(Nss),
+            group: Arc::new(Nss),
+        }
+    }
+}
+
+impl std::fmt::Debug for Backends {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Backends").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Takes `delay` to find any user.
+    struct Slow {
+        delay: Duration,
+    }
+
+    impl Backend for Slow {
+        fn user_by_uid(&self, uid: Uid) -> nix::Result<Option<User>> {
+            thread::sleep(self.delay);
+            User::from_uid(uid)
+        }
+
+        fn user_by_name(&self, name: &str) -> nix::Result<Option<User>> {
+            thread::sleep(self.delay);
+            User::from_name(name)
+        }
+
+        fn group_by_gid(&self, gid: Gid) -> nix::Result<Option<Group>> {
+            Group::from_gid(gid)
+        }
+
+        fn group_by_name(&self, name: &str) -> nix::Result<Option<Group>> {
+            assert_ne!(name, "boom");
+            Group::from_name(name)
+        }
+
+        fn group_list(&self, user: &CStr, group: Gid) -> nix::Result<Vec<Gid>> {
+            getgrouplist(user, group)
+        }
+    }
+
+    #[test]
+    fn test_timeout_panic() {
+        let slow = Arc::new(Slow {
+            delay: Duration::ZERO,
+        });
+        let backend = Timeout::new(slow, Duration::from_secs(5), 1, Default::default());
+        // the caller panics...
+        let result = panic::catch_unwind(AssertUnwindSafe(|| backend.group_by_name("boom")));
+        assert!(result.is_err());
+        // ...and the lookup thread carries on.
+        assert!(backend.group_by_gid(Gid::from_raw(0)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let stats = Arc::new(Stats::new());
+        let slow = Arc::new(Slow {
+            delay: Duration::from_millis(200),
+        });
+        let backend = Timeout::new(slow, Duration::from_millis(50), 1, stats.clone());
+
+        let start = Instant::now();
+        assert_eq!(backend.user_by_uid(Uid::from_raw(0)), Err(Errno::ETIMEDOUT));
+        assert!(start.elapsed() < Duration::from_millis(150));
+        // the only thread is still stuck on the first lookup.
+        assert_eq!(backend.user_by_name("root"), Err(Errno::ETIMEDOUT));
+        assert_eq!(stats.snapshot().lookup_timeouts, 2);
+
+        // once both are done, lookups that don't hang are answered as usual.
+        thread::sleep(Duration::from_millis(500));
+        let root = backend.group_by_gid(Gid::from_raw(0));
+        assert!(matches!(root, Ok(Some(group)) if group.gid.as_raw() == 0));
+        assert_eq!(stats.snapshot().lookup_timeouts, 2);
+    }
+}
@@ -0,0 +1,507 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! `nsncd bench`: putting a running nsncd, or nscd, under load.
+//!
+//! A number of clients send requests at the same time, each one waiting for
+//! its answer before sending the next, for a while. Like glibc, they make a
+//! connection per request. The requests are picked in turn from a mix of
+//! lookups, each repeated as many times as its weight says. At the end, the
+//! throughput and latency percentiles are printed, for all the requests and
+//! for each lookup of the mix. Requests that get no answer, or one that isn't
+//! a valid reply, count as failed.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use super::config::DEFAULT_SOCKET_PATH;
+use super::protocol::{deserialize, Request, RequestType};
+
+const USAGE: &str = "\
+usage: nsncd bench [--socket PATH] [--connections N] [--duration SECONDS] [TYPE:KEY[@WEIGHT]...]
+
+Sends requests to the nsncd or nscd listening on PATH (default
+/var/run/nscd/socket) from N clients at once (default 8) for SECONDS seconds
+(default 10), and prints the throughput and latency percentiles.
+
+Each TYPE:KEY is a lookup to make, e.g. GETPWBYNAME:root or GETAI:localhost,
+and WEIGHT how often to make it relative to the others (default 1). By
+default, users and groups root and 0 are looked up, with a host lookup of
+localhost now and then.";
+
+/// The lookups made unless others are given.
+const DEFAULT_MIX: &[&str] = &[
+    "GETPWBYNAME:root@4",
+    "GETPWBYUID:0@4",
+    "GETGRBYNAME:root@2",
+    "GETGRBYGID:0@2",
+    "INITGROUPS:root@2",
+    "GETAI:localhost@1",
+];
+
+/// How long to wait for an answer before counting the request as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The percentiles reported.
+const PERCENTILES: &[(&str, f64)] = &[
+    ("p50", 0.5),
+    ("p90", 0.9),
+    ("p99", 0.99),
+    ("p99.9", 0.999),
+    ("max", 1.0),
+];
+
+/// What to send, where, and for how long.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Options {
+    pub socket: PathBuf,
+    pub connections: usize,
+    pub duration: Duration,
+    pub mix: Vec<Lookup>,
+}
+
+/// One of the lookups of the mix.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Lookup {
+    pub ty: RequestType,
+    /// The key, NUL-terminated.
+    pub key: Vec<u8>,
+    pub weight: usize,
+}
+
+impl Options {
+    /// Parse the arguments following `bench`, or `None` if asked for help.
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Option<Self>> {
+        let mut options = Options {
+            socket: PathBuf::from(DEFAULT_SOCKET_PATH),
+            connections: 8,
+            duration: Duration::from_secs(10),
+            mix: vec![],
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let arg = arg
+                .into_string()
+                .map_err(|arg| anyhow::format_err!("invalid argument {:?}", arg))?;
+            let mut value = |name: &str| {
+                args.next()
+                    .with_context(|| format!("{} needs a value", name))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--socket" => options.socket = value(&arg)?.into(),
+                "--connections" => options.connections = positive(&arg, value(&arg)?)?,
+                "--duration" => {
+                    options.duration = Duration::from_secs(positive(&arg, value(&arg)?)? as u64)
+                }
+                _ if arg.starts_with('-') => bail!("unknown option {}", arg),
+                _ => options.mix.push(arg.parse()?),
+            }
+        }
+        if options.mix.is_empty() {
+            for lookup in DEFAULT_MIX {
+                options.mix.push(lookup.parse()?);
+            }
+        }
+        Ok(Some(options))
+    }
+}
+
+/// The value of the option `name`, which must be a positive number.
+fn positive(name: &str, value: OsString) -> Result<usize> {
+    let value = value.to_string_lossy();
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("{} must be a positive number, not {:?}", name, value),
+    }
+}
+
+impl std::str::FromStr for Lookup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (lookup, weight) = match s.rsplit_once('@') {
+            Some((lookup, weight)) => {
+                let weight = weight
+                    .parse()
+                    .ok()
+                    .filter(|&weight| weight > 0)
+                    .with_context(|| format!("invalid weight in {:?}", s))?;
+                (lookup, weight)
+            }
+            None => (s, 1),
+        };
+        let (ty, key) = lookup
+            .split_once(':')
+            .with_context(|| format!("expected TYPE:KEY, got {:?}", s))?;
+        let ty = RequestType::all()
+            .find(|known| format!("{:?}", known) == ty)
+            .with_context(|| format!("unknown request type {}", ty))?;
+        let mut key = key.as_bytes().to_vec();
+        key.push(0);
+        Ok(Lookup { ty, key, weight })
+    }
+}
+
+impl fmt::Display for Lookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = &self.key[..self.key.len() - 1];
+        write!(f, "{:?}:{}", self.ty, String::from_utf8_lossy(key))
+    }
+}
+
+/// The entry point of `nsncd bench`.
+pub fn main(args: impl IntoIterator<Item = OsString>) -> Result<()> {
+    let options = match Options::parse(args).context(USAGE.lines().next().unwrap())? {
+        Some(options) => options,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+    let report = run(&options)?;
+    print!("{}", report);
+    Ok(())
+}
+
+/// Run the clients `options` ask for, and report how they did.
+pub fn run(options: &Options) -> Result<Report> {
+    // connecting once up front tells a wrong path from a daemon too busy to
+    // answer.
+    UnixStream::connect(&options.socket)
+        .with_context(|| format!("connecting to {}", options.socket.display()))?;
+
+    let requests: Vec<_> = options
+        .mix
+        .iter()
+        .map(|lookup| (lookup.ty, Request::new(lookup.ty, &lookup.key).to_bytes()))
+        .collect();
+    let mut turns = vec![];
+    for (index, lookup) in options.mix.iter().enumerate() {
+        turns.extend(std::iter::repeat_n(index, lookup.weight));
+    }
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let new_latencies = || -> Vec<_> {
+        options
+            .mix
+            .iter()
+            .map(|lookup| Latencies::new(lookup.to_string()))
+            .collect()
+    };
+    let clients: Vec<_> = std::thread::scope(|s| {
+        let clients: Vec<_> = (0..options.connections)
+            .map(|id| {
+                let (requests, turns) = (&requests, &turns);
+                // spread the clients over the mix, so they don't all make
+                // the same lookup at the same time.
+                let first = id * turns.len() / options.connections;
+                let mut latencies = new_latencies();
+                s.spawn(move || {
+                    for &index in turns.iter().cycle().skip(first) {
+                        if Instant::now() >= deadline {
+                            break;
+                        }
+                        ask(&options.socket, &requests[index], &mut latencies[index]);
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        clients.into_iter().map(|c| c.join().unwrap()).collect()
+    });
+    let elapsed = start.elapsed();
+
+    let mut lookups = new_latencies();
+    for client in clients {
+        for (lookup, latencies) in lookups.iter_mut().zip(client) {
+            lookup.add(latencies);
+        }
+    }
+    for lookup in &mut lookups {
+        lookup.answered.sort_unstable();
+    }
+    Ok(Report {
+        connections: options.connections,
+        elapsed,
+        lookups,
+    })
+}
+
+/// Send `request`, of type `ty`, and wait for the answer, adding how long it
+/// took to `latencies`.
+fn ask(socket: &Path, (ty, request): &(RequestType, Vec<u8>), latencies: &mut Latencies) {
+    let start = Instant::now();
+    match send(socket, request) {
+        // "not found" is an answer; nothing, or garbage, isn't.
+        Ok(response) if valid_reply(*ty, &response) => latencies.answered.push(start.elapsed()),
+        _ => latencies.failed += 1,
+    }
+}
+
+/// Whether `response` is a valid reply to a request of type `ty`. For the
+/// types we don't parse the replies of, any reply will do.
+fn valid_reply(ty: RequestType, response: &[u8]) -> bool {
+    use RequestType::*;
+    match ty {
+        GETPWBYNAME | GETPWBYUID => deserialize::user(response).is_ok(),
+        BATCHGETPWBYUID | BATCHGETPWBYNAME => deserialize::user_batch(response).is_ok(),
+        GETGRBYNAME | GETGRBYGID => deserialize::group(response).is_ok(),
+        INITGROUPS => deserialize::initgroups(response).is_ok(),
+        _ => !response.is_empty(),
+    }
+}
+
+/// Send `request` over a new connection, and return the response.
+fn send(socket: &Path, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(request)?;
+    // nsncd keeps connections open for another request; this says there
+    // won't be one, so it closes it once it's answered.
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+/// The requests for one lookup, or all of them.
+pub struct Latencies {
+    label: String,
+    /// How long each answered request took.
+    answered: Vec<Duration>,
+    failed: usize,
+}
+
+impl Latencies {
+    fn new(label: String) -> Self {
+        Latencies {
+            label,
+            answered: vec![],
+            failed: 0,
+        }
+    }
+
+    fn add(&mut self, other: Latencies) {
+        self.answered.extend(other.answered);
+        self.failed += other.failed;
+    }
+
+    /// The `p`th quantile of the latencies, which must be sorted.
+    fn quantile(&self, p: f64) -> Duration {
+        if self.answered.is_empty() {
+            return Duration::ZERO;
+        }
+        let last = self.answered.len() - 1;
+        self.answered[(last as f64 * p).round() as usize]
+    }
+}
+
+/// The results of a run.
+pub struct Report {
+    connections: usize,
+    elapsed: Duration,
+    /// The latencies of each lookup of the mix, in order, sorted.
+    lookups: Vec<Latencies>,
+}
+
+impl Report {
+    /// How many requests were answered.
+    pub fn answered(&self) -> usize {
+        self.lookups.iter().map(|l| l.answered.len()).sum()
+    }
+
+    /// How many requests got no answer.
+    pub fn failed(&self) -> usize {
+        self.lookups.iter().map(|l| l.failed).sum()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "{} requests answered in {:.2}s over {} connections: {:.1} requests/s, {} failed",
+            self.answered(),
+            seconds,
+            self.connections,
+            self.answered() as f64 / seconds,
+            self.failed(),
+        )?;
+
+        let mut all = Latencies::new("all".to_owned());
+        for lookup in &self.lookups {
+            all.answered.extend(&lookup.answered);
+            all.failed += lookup.failed;
+        }
+        all.answered.sort_unstable();
+
+        let width = self
+            .lookups
+            .iter()
+            .map(|l| l.label.len())
+            .max()
+            .unwrap_or(0);
+        let width = width.max("latency (µs)".len());
+        write!(
+            f,
+            "\n{:width$} {:>9} {:>7}",
+            "latency (µs)", "answered", "failed"
+        )?;
+        for (name, _) in PERCENTILES {
+            write!(f, " {:>8}", name)?;
+        }
+        writeln!(f)?;
+        for latencies in std::iter::once(&all).chain(&self.lookups) {
+            write!(
+                f,
+                "{:width$} {:>9} {:>7}",
+                latencies.label,
+                latencies.answered.len(),
+                latencies.failed,
+            )?;
+            for &(_, p) in PERCENTILES {
+                write!(f, " {:>8}", latencies.quantile(p).as_micros())?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = Options::parse(args(&[])).unwrap().unwrap();
+        assert_eq!(options.socket, Path::new(DEFAULT_SOCKET_PATH));
+        assert_eq!(options.connections, 8);
+        assert_eq!(options.duration, Duration::from_secs(10));
+        assert_eq!(options.mix.len(), DEFAULT_MIX.len());
+
+        let options = Options::parse(args(&[
+            "--socket",
+            "/run/other/socket",
+            "--connections",
+            "64",
+            "--duration",
+            "30",
+            "GETPWBYUID:1000@9",
+            "GETAI:example.com",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.socket, Path::new("/run/other/socket"));
+        assert_eq!(options.connections, 64);
+        assert_eq!(options.duration, Duration::from_secs(30));
+        assert_eq!(
+            options.mix,
+            vec![
+                Lookup {
+                    ty: RequestType::GETPWBYUID,
+                    key: b"1000\0".to_vec(),
+                    weight: 9,
+                },
+                Lookup {
+                    ty: RequestType::GETAI,
+                    key: b"example.com\0".to_vec(),
+                    weight: 1,
+                },
+            ]
+        );
+
+        assert!(Options::parse(args(&["--help"])).unwrap().is_none());
+        for bad in [
+            &["--connections", "0"][..],
+            &["--duration"],
+            &["--verbose"],
+            &["GETPWBYUID"],
+            &["GETPWBYID:0"],
+            &["GETPWBYUID:0@0"],
+        ] {
+            assert!(Options::parse(args(bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        // answers user lookups (there's no such user), and group ones with
+        // garbage.
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                stream.read_to_end(&mut request).unwrap();
+                if request.ends_with(b"root\0") {
+                    let not_found = crate::protocol::PwResponseHeader::default();
+                    stream.write_all(not_found.as_slice()).unwrap();
+                } else if !request.is_empty() {
+                    stream.write_all(b"answer").unwrap();
+                }
+            }
+        });
+
+        let options = Options {
+            socket,
+            connections: 2,
+            duration: Duration::from_millis(200),
+            mix: vec![
+                "GETPWBYNAME:root@3".parse().unwrap(),
+                "GETGRBYNAME:staff".parse().unwrap(),
+            ],
+        };
+        let report = run(&options).unwrap();
+        assert!(report.answered() > 0);
+        assert!(report.failed() > 0);
+        assert_eq!(report.lookups[0].failed, 0);
+        assert_eq!(report.lookups[1].answered.len(), 0);
+
+        let printed = report.to_string();
+        assert!(printed.contains("GETPWBYNAME:root"), "{}", printed);
+        assert!(printed.contains("GETGRBYNAME:staff"), "{}", printed);
+    }
+
+    #[test]
+    fn test_quantile() {
+        let mut latencies = Latencies::new("all".to_owned());
+        assert_eq!(latencies.quantile(0.5), Duration::ZERO);
+        latencies.answered = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(latencies.quantile(0.5), Duration::from_millis(51));
+        assert_eq!(latencies.quantile(0.99), Duration::from_millis(99));
+        assert_eq!(latencies.quantile(1.0), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,919 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! An optional cache of answers.
+//!
+//! nsncd doesn't cache by default: NSS (or sssd, or nslcd) is expected to.
+//! But a burst of logins against a slow directory can still be worth
+//! absorbing, so [ResponseCache] can keep the replies to lookups for a while,
+//! with a TTL per database. "Not found" answers have TTLs of their own,
+//! usually shorter (or zero), so a new user shows up soon.
+//!
+//! Each database's replies are bounded in number and in bytes. Once either
+//! budget is spent, the least recently used replies make room for new ones.
+//!
+//! A database can also allow expired replies to be served for a while, so a
+//! slow directory server doesn't slow logins down: the first request to get
+//! one has the [Refresher] look the entry up again in the background. Like
+//! nscd's `reload-count`, replies still in use near the end of their TTL can
+//! be refreshed ahead of time too, a few times over, so lookups rarely wait
+//! for the directory at all.
+//!
+//! The replies can be saved to a file on shutdown and loaded back on startup,
+//! like nscd's persistent databases, so a restart doesn't send every client
+//! to the directory at once.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Context, Result};
+use crossbeam_channel as channel;
+use num_traits::FromPrimitive;
+
+use super::protocol::{self, RequestType, DATABASES};
+
+/// Replies used in the last `1 / REFRESH_AHEAD` of their TTL are refreshed
+/// before they expire, if their policy reloads them.
+const REFRESH_AHEAD: u32 = 4;
+
+/// The start of a file written by [ResponseCache::save]: what it is, and the
+/// version of its layout.
+const MAGIC: &[u8] = b"NSNCDCACHE\x01";
+
+/// How the replies of one database are cached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Policy {
+    /// How long replies with an entry are kept. Zero if they aren't.
+    pub ttl: Duration,
+    /// How long "not found" replies are kept, likewise.
+    pub negative_ttl: Duration,
+    /// How many replies are kept at most.
+    pub max_entries: usize,
+    /// How many bytes of replies and keys are kept at most.
+    pub max_bytes: usize,
+    /// How long after they expire replies are still served, while they're
+    /// looked up again. Zero if they aren't.
+    pub max_stale: Duration,
+    /// How many times in a row replies still in use are refreshed before
+    /// they expire. Zero if they aren't.
+    pub reload_count: u32,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::ZERO,
+            negative_ttl: Duration::ZERO,
+            max_entries: 16384,
+            max_bytes: 16 << 20,
+            max_stale: Duration::ZERO,
+            reload_count: 0,
+        }
+    }
+}
+
+/// How full a database's part of the cache is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Occupancy {
+    pub entries: usize,
+    /// The size of the cached replies and their keys.
+    pub bytes: usize,
+    /// The most entries there have been at once.
+    pub peak_entries: usize,
+    /// Replies dropped before they expired, to make room for others.
+    pub evictions: u64,
+    /// Replies dropped by invalidations.
+    pub invalidated: u64,
+}
+
+/// The request type's index and the request key.
+type Key = (usize, Vec<u8>);
+
+struct Entry {
+    expires: Instant,
+    /// When a request getting it should have it refreshed ahead of time.
+    refresh_at: Instant,
+    reply: Arc<[u8]>,
+    /// When it was last used, in [Shard::clock] ticks.
+    used: u64,
+    /// Whether a request is having it refreshed.
+    refreshing: bool,
+    /// How many times it was refreshed since it was last looked up by a
+    /// request.
+    reloads: u32,
+}
+
+impl Entry {
+    /// A reply to keep for `ttl`, after it was refreshed `reloads` times.
+    fn new(reply: &[u8], expires: Instant, ttl: Duration, reloads: u32) -> Self {
+        Self {
+            expires,
+            refresh_at: expires.checked_sub(ttl / REFRESH_AHEAD).unwrap_or(expires),
+            reply: reply.into(),
+            used: 0,
+            refreshing: false,
+            reloads,
+        }
+    }
+}
+
+/// A cached reply.
+#[derive(Debug)]
+pub enum Cached {
+    Fresh(Arc<[u8]>),
+    /// Fresh, but about to expire while still in use: the request getting it
+    /// should have it refreshed.
+    Expiring(Arc<[u8]>),
+    /// Expired, but still servable. `refresh` is set for the first request
+    /// to get it, which should have it refreshed.
+    Stale {
+        reply: Arc<[u8]>,
+        refresh: bool,
+    },
+}
+
+impl Cached {
+    pub fn reply(&self) -> &Arc<[u8]> {
+        match self {
+            Cached::Fresh(reply) | Cached::Expiring(reply) | Cached::Stale { reply, .. } => reply,
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs the lookups refreshing stale replies, on threads of its own.
+pub struct Refresher {
+    jobs: channel::Sender<Job>,
+}
+
+impl Refresher {
+    /// Run refreshes on `threads` threads. At most as many more can wait for
+    /// one of them.
+    pub fn new(threads: usize) -> Self {
+        let (jobs, rx) = channel::bounded::<Job>(threads);
+        for i in 0..threads {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("refresh_{}", i))
+                .spawn(move || rx.into_iter().for_each(|job| job()))
+                .expect("spawning refresh thread");
+        }
+        Self { jobs }
+    }
+
+    /// Queue `job`, unless too many are waiting already. Returns whether it
+    /// was queued.
+    pub fn submit(&self, job: Job) -> bool {
+        self.jobs.try_send(job).is_ok()
+    }
+}
+
+/// The cached replies of one database.
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<Key, Entry>,
+    /// The key of every entry, by when it was last used.
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    occupancy: Occupancy,
+    /// Bumped by every invalidation, so lookups started before one don't
+    /// cache their (possibly stale) reply.
+    generation: u64,
+}
+
+fn size_of_entry(key: &Key, reply: &[u8]) -> usize {
+    key.1.len() + reply.len()
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Writes `bytes` preceded by their length, for [Reader::bytes].
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Reads a file written by [ResponseCache::save].
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(self.0.len() >= len, "cache file is truncated");
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+impl Shard {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.used);
+        self.occupancy.entries -= 1;
+        self.occupancy.bytes -= size_of_entry(key, &entry.reply);
+        Some(entry)
+    }
+
+    /// Drop the least recently used entry. False if there's none.
+    fn evict(&mut self, now: Instant) -> bool {
+        let key = match self.lru.values().next() {
+            Some(key) => key.clone(),
+            None => return false,
+        };
+        if let Some(entry) = self.remove(&key) {
+            if now < entry.expires {
+                self.occupancy.evictions += 1;
+            }
+        }
+        true
+    }
+
+    /// Add `entry`, evicting less recently used ones to make room. There
+    /// mustn't be one for `key` already, and it must fit within the budgets
+    /// by itself.
+    fn store(&mut self, policy: &Policy, key: Key, mut entry: Entry, now: Instant) {
+        let size = size_of_entry(&key, &entry.reply);
+        while self.occupancy.entries >= policy.max_entries
+            || self.occupancy.bytes + size > policy.max_bytes
+        {
+            if !self.evict(now) {
+                break;
+            }
+        }
+        entry.used = self.tick();
+        self.lru.insert(entry.used, key.clone());
+        self.entries.insert(key, entry);
+        let occupancy = &mut self.occupancy;
+        occupancy.entries += 1;
+        occupancy.bytes += size;
+        occupancy.peak_entries = occupancy.peak_entries.max(occupancy.entries);
+    }
+
+    /// Drop every entry, returning how many there were.
+    fn clear(&mut self) -> usize {
+        let dropped = self.entries.len();
+        self.entries.clear();
+        self.lru.clear();
+        self.occupancy.entries = 0;
+        self.occupancy.bytes = 0;
+        self.occupancy.invalidated += dropped as u64;
+        self.generation += 1;
+        dropped
+    }
+}
+
+pub struct ResponseCache {
+    /// In [DATABASES] order, like `shards`.
+    policies: [Policy; DATABASES.len()],
+    shards: Vec<Mutex<Shard>>,
+    /// Without one, stale replies aren't served.
+    refresher: Option<Refresher>,
+}
+
+impl ResponseCache {
+    /// Cache the replies of lookups in each database (in [DATABASES] order)
+    /// according to its policy.
+    pub fn new(policies: [Policy; DATABASES.len()]) -> Self {
+        Self {
+            policies,
+            shards: DATABASES.iter().map(|_| Default::default()).collect(),
+            refresher: None,
+        }
+    }
+
+    /// Serve stale replies and refresh replies ahead of time as the policies
+    /// allow, refreshing them on `threads` threads.
+    pub fn with_refresher(mut self, threads: usize) -> Self {
+        self.refresher = Some(Refresher::new(threads));
+        self
+    }
+
+    /// Have `job`, refreshing a reply, run in the background. Returns
+    /// false if it won't be, e.g. because too many are queued already.
+    pub fn refresh(&self, job: Job) -> bool {
+        self.refresher
+            .as_ref()
+            .is_some_and(|refresher| refresher.submit(job))
+    }
+
+    fn policy(&self, ty: RequestType) -> Option<(usize, &Policy)> {
+        let database = ty.stat_database()?;
+        Some((database, &self.policies[database]))
+    }
+
+    /// How long replies with an entry to requests of type `ty` are kept. Zero
+    /// for the ones that aren't, including everything that isn't a lookup in
+    /// one of [DATABASES].
+    pub fn ttl(&self, ty: RequestType) -> Duration {
+        self.policy(ty)
+            .map_or(Duration::ZERO, |(_, policy)| policy.ttl)
+    }
+
+    /// How long "not found" replies to requests of type `ty` are kept, like
+    /// [ResponseCache::ttl].
+    pub fn negative_ttl(&self, ty: RequestType) -> Duration {
+        self.policy(ty)
+            .map_or(Duration::ZERO, |(_, policy)| policy.negative_ttl)
+    }
+
+    /// Whether any reply to requests of type `ty` is kept.
+    pub fn caches(&self, ty: RequestType) -> bool {
+        !self.ttl(ty).is_zero() || !self.negative_ttl(ty).is_zero()
+    }
+
+    /// The reply to a request of type `ty` for `key`, if we have a fresh
+    /// one, or a stale one we may still serve.
+    pub fn get(&self, ty: RequestType, key: &[u8]) -> Option<Cached> {
+        let (database, policy) = self.policy(ty)?;
+        let max_stale = self.max_stale(policy);
+        let key = (ty.index(), key.to_vec());
+        let now = Instant::now();
+        let mut shard = self.shards[database].lock().unwrap();
+        let entry = shard.entries.get(&key)?;
+        if now >= entry.expires + max_stale {
+            shard.remove(&key);
+            return None;
+        }
+        let used = entry.used;
+        let tick = shard.tick();
+        shard.lru.remove(&used);
+        shard.lru.insert(tick, key.clone());
+        let entry = shard.entries.get_mut(&key).unwrap();
+        entry.used = tick;
+        if now < entry.expires {
+            if now >= entry.refresh_at
+                && !entry.refreshing
+                && entry.reloads < self.reload_count(policy)
+            {
+                entry.refreshing = true;
+                return Some(Cached::Expiring(entry.reply.clone()));
+            }
+            return Some(Cached::Fresh(entry.reply.clone()));
+        }
+        let refresh = !entry.refreshing;
+        entry.refreshing = true;
+        Some(Cached::Stale {
+            reply: entry.reply.clone(),
+            refresh,
+        })
+    }
+
+    /// How long after they expire replies under `policy` are served.
+    fn max_stale(&self, policy: &Policy) -> Duration {
+        match self.refresher {
+            Some(_) => policy.max_stale,
+            None => Duration::ZERO,
+        }
+    }
+
+    /// How many times in a row replies under `policy` are refreshed ahead of
+    /// time.
+    fn reload_count(&self, policy: &Policy) -> u32 {
+        match self.refresher {
+            Some(_) => policy.reload_count,
+            None => 0,
+        }
+    }
+
+    /// Let the next request to get the reply to a request of type `ty` for
+    /// `key` try to refresh it again, e.g. because refreshing it failed.
+    pub fn unclaim(&self, ty: RequestType, key: &[u8]) {
+        if let Some((database, _)) = self.policy(ty) {
+            let mut shard = self.shards[database].lock().unwrap();
+            if let Some(entry) = shard.entries.get_mut(&(ty.index(), key.to_vec())) {
+                entry.refreshing = false;
+            }
+        }
+    }
+
+    /// The invalidation count of the database of requests of type `ty`, to
+    /// pass to [ResponseCache::insert].
+    pub fn generation(&self, ty: RequestType) -> u64 {
+        self.policy(ty).map_or(0, |(database, _)| {
+            self.shards[database].lock().unwrap().generation
+        })
+    }
+
+    /// Keep the reply to a request of type `ty` for `key`, which found an
+    /// entry if `found`, if such replies are cached. It replaces any older
+    /// reply, which is dropped even if the new one isn't kept. Less recently
+    /// used replies are evicted to keep within the database's budgets.
+    ///
+    /// `generation` is [ResponseCache::generation] from before the lookup:
+    /// if the database was invalidated since, the reply isn't kept.
+    pub fn insert(&self, ty: RequestType, key: &[u8], reply: &[u8], found: bool, generation: u64) {
+        let (database, policy) = match self.policy(ty) {
+            Some(policy) => policy,
+            None => return,
+        };
+        let ttl = if found {
+            policy.ttl
+        } else {
+            policy.negative_ttl
+        };
+        let key = (ty.index(), key.to_vec());
+        let size = size_of_entry(&key, reply);
+        let now = Instant::now();
+        let mut shard = self.shards[database].lock().unwrap();
+        if shard.generation != generation {
+            return;
+        }
+        // a refresh counts as a reload, unlike a request's own lookup.
+        let reloads = match shard.remove(&key) {
+            Some(old) if old.refreshing => old.reloads.saturating_add(1),
+            _ => 0,
+        };
+        if ttl.is_zero() || policy.max_entries == 0 || size > policy.max_bytes {
+            return;
+        }
+        let entry = Entry::new(reply, now + ttl, ttl, reloads);
+        shard.store(policy, key, entry, now);
+    }
+
+    /// Write the replies still worth serving to `path`, replacing it, for
+    /// [ResponseCache::load] to pick up after a restart. Returns how many
+    /// there were.
+    pub fn save(&self, path: &Path) -> Result<usize> {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&unix_millis(wall).to_le_bytes());
+        let mut saved = 0;
+        for (shard, policy) in self.shards.iter().zip(&self.policies) {
+            let shard = shard.lock().unwrap();
+            // least recently used first, so they're loaded in the same order.
+            for key in shard.lru.values() {
+                let entry = &shard.entries[key];
+                if now >= entry.expires + self.max_stale(policy) {
+                    continue;
+                }
+                let ty = match RequestType::all().find(|ty| ty.index() == key.0) {
+                    Some(ty) => ty,
+                    None => continue,
+                };
+                let expires = if entry.expires >= now {
+                    wall + (entry.expires - now)
+                } else {
+                    wall - (now - entry.expires)
+                };
+                out.extend_from_slice(&(ty as i32).to_le_bytes());
+                write_bytes(&mut out, &key.1);
+                write_bytes(&mut out, &entry.reply);
+                out.extend_from_slice(&unix_millis(expires).to_le_bytes());
+                saved += 1;
+            }
+        }
+
+        // written next to it and renamed over it, so a crash can't leave half
+        // a file behind. Replies can say more than clients may see otherwise.
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("creating {}", Path::new(&tmp).display()))?;
+        file.write_all(&out)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("writing {}", Path::new(&tmp).display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))?;
+        Ok(saved)
+    }
+
+    /// Load the replies [ResponseCache::save] wrote to `path`, returning how
+    /// many were kept. Those that expired since are dropped, and so are those
+    /// of the databases `changed` returns, given when the file was saved.
+    /// Replies whose TTL was shortened since expire as if they had just been
+    /// looked up, at the latest.
+    pub fn load<F>(&self, path: &Path, changed: F) -> Result<usize>
+    where
+        F: FnOnce(SystemTime) -> Vec<&'static str>,
+    {
+        let data = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let mut reader = Reader(&data);
+        ensure!(
+            reader.take(MAGIC.len()).ok() == Some(MAGIC),
+            "{} isn't an nsncd cache file",
+            path.display()
+        );
+        let changed = changed(from_unix_millis(reader.u64()?));
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let mut loaded = 0;
+        while !reader.0.is_empty() {
+            let ty = reader.u32()? as i32;
+            let (key, reply) = (reader.bytes()?, reader.bytes()?);
+            let expires = from_unix_millis(reader.u64()?);
+
+            let ty: RequestType = match FromPrimitive::from_i32(ty) {
+                Some(ty) => ty,
+                None => continue,
+            };
+            let (database, policy) = match self.policy(ty) {
+                Some(policy) if !changed.contains(&DATABASES[policy.0]) => policy,
+                _ => continue,
+            };
+            let ttl = match protocol::reply_found(ty, reply) {
+                Some(true) => policy.ttl,
+                Some(false) => policy.negative_ttl,
+                None => continue,
+            };
+            let expires = match expires.min(wall + ttl).duration_since(wall) {
+                Ok(left) => Some(now + left),
+                Err(e) => now.checked_sub(e.duration()),
+            };
+            let expires = match expires {
+                Some(expires) if now < expires + self.max_stale(policy) => expires,
+                _ => continue,
+            };
+            let key = (ty.index(), key.to_vec());
+            if ttl.is_zero() || size_of_entry(&key, reply) > policy.max_bytes {
+                continue;
+            }
+            let mut shard = self.shards[database].lock().unwrap();
+            if policy.max_entries > 0 {
+                shard.remove(&key);
+                shard.store(policy, key, Entry::new(reply, expires, ttl, 0), now);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Forget every reply from `database`, one of [DATABASES], returning how
+    /// many there were. Expired replies not dropped yet are counted too.
+    pub fn invalidate(&self, database: &str) -> usize {
+        match DATABASES.iter().position(|db| *db == database) {
+            Some(database) => self.shards[database].lock().unwrap().clear(),
+            None => 0,
+        }
+    }
+
+    /// How full the part of the cache for `database`, an index into
+    /// [DATABASES], is.
+    pub fn occupancy(&self, database: usize) -> Occupancy {
+        self.shards[database].lock().unwrap().occupancy
+    }
+
+    /// The policy of `database`, an index into [DATABASES].
+    pub fn database_policy(&self, database: usize) -> &Policy {
+        &self.policies[database]
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("policies", &self.policies)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    fn cache() -> ResponseCache {
+        // passwd and group cached, the rest not. Only passwd's "not found"
+        // answers are.
+        let mut policies = [Policy::default(); DATABASES.len()];
+        policies[0].ttl = Duration::from_secs(60);
+        policies[0].negative_ttl = Duration::from_millis(50);
+        policies[1].ttl = Duration::from_millis(50);
+        ResponseCache::new(policies)
+    }
+
+    #[test]
+    fn test_ttl() {
+        let cache = cache();
+        assert_eq!(cache.ttl(RequestType::GETPWBYUID), Duration::from_secs(60));
+        assert_eq!(
+            cache.ttl(RequestType::INITGROUPS),
+            Duration::from_millis(50)
+        );
+        assert_eq!(cache.ttl(RequestType::GETAI), Duration::ZERO);
+        // not lookups nscd would cache.
+        assert_eq!(cache.ttl(RequestType::GETSPBYNAME), Duration::ZERO);
+        assert_eq!(cache.ttl(RequestType::INVALIDATE), Duration::ZERO);
+
+        assert_eq!(
+            cache.negative_ttl(RequestType::GETPWBYUID),
+            Duration::from_millis(50)
+        );
+        assert_eq!(cache.negative_ttl(RequestType::GETGRBYGID), Duration::ZERO);
+        assert!(cache.caches(RequestType::GETGRBYGID));
+        assert!(!cache.caches(RequestType::GETAI));
+    }
+
+    #[test]
+    fn test_get_and_insert() {
+        let cache = cache();
+        cache.insert(RequestType::GETPWBYNAME, b"alice\0", b"reply", true, 0);
+        cache.insert(
+            RequestType::GETGRBYNAME,
+            b"staff\0",
+            b"group reply",
+            true,
+            0,
+        );
+        cache.insert(RequestType::GETAI, b"localhost\0", b"ai reply", true, 0);
+
+        let reply = cache.get(RequestType::GETPWBYNAME, b"alice\0").unwrap();
+        assert_eq!(&**reply.reply(), b"reply");
+        // keyed by type as well as key.
+        assert!(cache.get(RequestType::GETGRBYNAME, b"alice\0").is_none());
+        assert!(cache.get(RequestType::GETAI, b"localhost\0").is_none());
+
+        assert!(cache.get(RequestType::GETGRBYNAME, b"staff\0").is_some());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get(RequestType::GETGRBYNAME, b"staff\0").is_none());
+        assert!(cache.get(RequestType::GETPWBYNAME, b"alice\0").is_some());
+        // the expired reply is gone.
+        assert_eq!(cache.occupancy(1).entries, 0);
+    }
+
+    #[test]
+    fn test_negative() {
+        let cache = cache();
+        cache.insert(
+            RequestType::GETPWBYNAME,
+            b"nobody\0",
+            b"not found",
+            false,
+            0,
+        );
+        cache.insert(
+            RequestType::GETGRBYNAME,
+            b"nogroup\0",
+            b"not found",
+            false,
+            0,
+        );
+        assert!(cache.get(RequestType::GETPWBYNAME, b"nobody\0").is_some());
+        assert!(cache.get(RequestType::GETGRBYNAME, b"nogroup\0").is_none());
+        // expires after its own TTL, not the passwd one.
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get(RequestType::GETPWBYNAME, b"nobody\0").is_none());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = cache();
+        cache.insert(RequestType::GETPWBYNAME, b"alice\0", b"reply", true, 0);
+        cache.insert(RequestType::GETGRBYGID, b"100\0", b"reply", true, 0);
+        cache.insert(RequestType::INITGROUPS, b"alice\0", b"reply", true, 0);
+
+        assert_eq!(cache.invalidate("group"), 2);
+        assert!(cache.get(RequestType::GETGRBYGID, b"100\0").is_none());
+        assert!(cache.get(RequestType::INITGROUPS, b"alice\0").is_none());
+        assert!(cache.get(RequestType::GETPWBYNAME, b"alice\0").is_some());
+        let occupancy = cache.occupancy(1);
+        assert_eq!((occupancy.entries, occupancy.invalidated), (0, 2));
+        assert_eq!(cache.invalidate("group"), 0);
+        assert_eq!(cache.invalidate("shadow"), 0);
+
+        // looked up before the invalidations.
+        cache.insert(RequestType::GETGRBYGID, b"100\0", b"reply", true, 0);
+        assert!(cache.get(RequestType::GETGRBYGID, b"100\0").is_none());
+        let generation = cache.generation(RequestType::GETGRBYGID);
+        cache.insert(
+            RequestType::GETGRBYGID,
+            b"100\0",
+            b"reply",
+            true,
+            generation,
+        );
+        assert!(cache.get(RequestType::GETGRBYGID, b"100\0").is_some());
+    }
+
+    #[test]
+    fn test_stale() {
+        let mut policies = [Policy::default(); DATABASES.len()];
+        policies[0].ttl = Duration::from_millis(50);
+        policies[0].max_stale = Duration::from_millis(100);
+        let ty = RequestType::GETPWBYNAME;
+
+        // not served stale without a refresher.
+        let cache = ResponseCache::new(policies);
+        cache.insert(ty, b"alice\0", b"reply", true, 0);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.get(ty, b"alice\0").is_none());
+
+        let cache = ResponseCache::new(policies).with_refresher(1);
+        cache.insert(ty, b"alice\0", b"reply", true, 0);
+        assert!(matches!(cache.get(ty, b"alice\0"), Some(Cached::Fresh(_))));
+        std::thread::sleep(Duration::from_millis(60));
+        // only the first request refreshes it.
+        assert!(matches!(
+            cache.get(ty, b"alice\0"),
+            Some(Cached::Stale { refresh: true, .. })
+        ));
+        assert!(matches!(
+            cache.get(ty, b"alice\0"),
+            Some(Cached::Stale { refresh: false, .. })
+        ));
+        cache.unclaim(ty, b"alice\0");
+        assert!(matches!(
+            cache.get(ty, b"alice\0"),
+            Some(Cached::Stale { refresh: true, .. })
+        ));
+        // too stale to serve.
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(cache.get(ty, b"alice\0").is_none());
+
+        // a refresh that can't be cached drops the stale reply.
+        cache.insert(ty, b"bob\0", b"reply", true, 0);
+        cache.insert(ty, b"bob\0", b"not found", false, 0);
+        assert!(cache.get(ty, b"bob\0").is_none());
+    }
+
+    /// A reply to a passwd lookup, as far as [protocol::reply_found] cares.
+    fn reply(found: bool) -> Vec<u8> {
+        [2i32, found as i32, 0]
+            .iter()
+            .flat_map(|field| field.to_ne_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache");
+        let mut policies = [Policy::default(); DATABASES.len()];
+        policies[0].ttl = Duration::from_secs(60);
+        policies[0].negative_ttl = Duration::from_millis(50);
+        policies[2].ttl = Duration::from_secs(60);
+        let ty = RequestType::GETPWBYNAME;
+        let cache = ResponseCache::new(policies);
+        cache.insert(ty, b"alice\0", &reply(true), true, 0);
+        cache.insert(ty, b"ghost\0", &reply(false), false, 0);
+        cache.insert(RequestType::GETAI, b"example.com\0", &reply(true), true, 0);
+        std::thread::sleep(Duration::from_millis(60));
+        // the expired "not found" isn't saved.
+        assert_eq!(cache.save(&path).unwrap(), 2);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let restarted = ResponseCache::new(policies);
+        assert_eq!(restarted.load(&path, |_| vec!["hosts"]).unwrap(), 1);
+        let alice = restarted.get(ty, b"alice\0").unwrap();
+        assert_eq!(&**alice.reply(), &reply(true)[..]);
+        assert!(restarted
+            .get(RequestType::GETAI, b"example.com\0")
+            .is_none());
+        assert!(restarted.get(ty, b"ghost\0").is_none());
+
+        // TTLs shortened since apply.
+        policies[0].ttl = Duration::from_millis(50);
+        let restarted = ResponseCache::new(policies);
+        assert_eq!(restarted.load(&path, |_| vec![]).unwrap(), 2);
+        assert!(restarted.get(ty, b"alice\0").is_some());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(restarted.get(ty, b"alice\0").is_none());
+
+        fs::write(&path, b"not a cache").unwrap();
+        assert!(restarted.load(&path, |_| vec![]).is_err());
+        let mut truncated = MAGIC.to_vec();
+        truncated.extend_from_slice(&[0; 8]);
+        truncated.extend_from_slice(&(ty as i32).to_le_bytes());
+        fs::write(&path, truncated).unwrap();
+        assert!(restarted.load(&path, |_| vec![]).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut policies = [Policy::default(); DATABASES.len()];
+        policies[0].ttl = Duration::from_millis(200);
+        policies[0].reload_count = 1;
+        let ty = RequestType::GETPWBYNAME;
+
+        // not refreshed without a refresher.
+        let cache = ResponseCache::new(policies);
+        cache.insert(ty, b"alice\0", b"reply", true, 0);
+        std::thread::sleep(Duration::from_millis(160));
+        assert!(matches!(cache.get(ty, b"alice\0"), Some(Cached::Fresh(_))));
+
+        let cache = ResponseCache::new(policies).with_refresher(1);
+        cache.insert(ty, b"alice\0", b"reply", true, 0);
+        assert!(matches!(cache.get(ty, b"alice\0"), Some(Cached::Fresh(_))));
+        std::thread::sleep(Duration::from_millis(160));
+        // only the first request in the last quarter of the TTL refreshes it.
+        assert!(matches!(
+            cache.get(ty, b"alice\0"),
+            Some(Cached::Expiring(_))
+        ));
+        assert!(matches!(cache.get(ty, b"alice\0"), Some(Cached::Fresh(_))));
+        // as the refresh would.
+        cache.insert(ty, b"alice\0", b"new reply", true, 0);
+        std::thread::sleep(Duration::from_millis(160));
+        // reloaded as many times as allowed.
+        let reply = cache.get(ty, b"alice\0").unwrap();
+        assert!(matches!(reply, Cached::Fresh(_)));
+        assert_eq!(&**reply.reply(), b"new reply");
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let mut policies = [Policy::default(); DATABASES.len()];
+        policies[0].ttl = Duration::from_secs(60);
+        policies[0].max_entries = 2;
+        let cache = ResponseCache::new(policies);
+        let ty = RequestType::GETPWBYNAME;
+
+        cache.insert(ty, b"alice\0", b"reply", true, 0);
+        cache.insert(ty, b"bob\0", b"reply", true, 0);
+        // alice is now the most recently used, so bob makes room for carol.
+        assert!(cache.get(ty, b"alice\0").is_some());
+        cache.insert(ty, b"carol\0", b"reply", true, 0);
+        assert!(cache.get(ty, b"bob\0").is_none());
+        assert!(cache.get(ty, b"alice\0").is_some());
+        assert!(cache.get(ty, b"carol\0").is_some());
+
+        // replacing a reply doesn't evict anything.
+        cache.insert(ty, b"carol\0", b"new reply", true, 0);
+        let occupancy = cache.occupancy(0);
+        assert_eq!(occupancy.entries, 2);
+        assert_eq!(occupancy.peak_entries, 2);
+        assert_eq!(occupancy.evictions, 1);
+        assert_eq!(
+            occupancy.bytes,
+            "alice\0reply".len() + "carol\0new reply".len()
+        );
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let mut policies = [Policy::default(); DATABASES.len()];
+        policies[0].ttl = Duration::from_secs(60);
+        policies[0].max_bytes = 32;
+        let cache = ResponseCache::new(policies);
+        let ty = RequestType::GETPWBYNAME;
+
+        cache.insert(ty, b"alice\0", &[0; 10], true, 0);
+        cache.insert(ty, b"bob\0", &[0; 10], true, 0);
+        assert_eq!(cache.occupancy(0).bytes, 30);
+        // doesn't fit along with alice's.
+        cache.insert(ty, b"carol\0", &[0; 10], true, 0);
+        assert!(cache.get(ty, b"alice\0").is_none());
+        assert_eq!(cache.occupancy(0).bytes, 30);
+        // bigger than the whole budget: not cached, and nothing evicted.
+        cache.insert(ty, b"dave\0", &[0; 40], true, 0);
+        assert!(cache.get(ty, b"dave\0").is_none());
+        assert_eq!(cache.occupancy(0).entries, 2);
+        assert_eq!(cache.occupancy(0).evictions, 1);
+    }
+}
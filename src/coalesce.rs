@@ -0,0 +1,264 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Sharing one lookup between identical concurrent requests.
+//!
+//! When hundreds of sshd sessions ask for the same user at once, there's no
+//! point in asking the backend hundreds of times: [InFlight] has the requests
+//! that come in while a lookup for the same key is running wait for its
+//! reply instead.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::Result;
+
+use super::protocol::{RequestType, DATABASES};
+
+/// The request type's index and the request key.
+type Key = (usize, Vec<u8>);
+
+/// A lookup other requests may be waiting for.
+#[derive(Default)]
+struct Pending {
+    /// Set once the lookup is done: its reply, or `None` if it failed.
+    reply: Mutex<Option<Option<Arc<[u8]>>>>,
+    done: Condvar,
+}
+
+impl Pending {
+    fn finish(&self, reply: Option<Arc<[u8]>>) {
+        *self.reply.lock().unwrap() = Some(reply);
+        self.done.notify_all();
+    }
+}
+
+#[derive(Default)]
+pub struct InFlight {
+    pending: Mutex<HashMap<Key, Arc<Pending>>>,
+}
+
+/// Takes a lookup out of the in-flight map when it's done, and tells the
+/// requests waiting for it that it failed if it never got to say otherwise
+/// (e.g. it panicked).
+struct Leader<'a> {
+    in_flight: &'a InFlight,
+    key: Key,
+    pending: Arc<Pending>,
+    finished: bool,
+}
+
+impl Leader<'_> {
+    /// Take the lookup out of the map, and return whether anyone's waiting.
+    fn detach(&self) -> bool {
+        let mut pending = self.in_flight.pending.lock().unwrap();
+        // it's gone already if it was invalidated, and may have been
+        // replaced by a newer one since.
+        if pending
+            .get(&self.key)
+            .is_some_and(|p| Arc::ptr_eq(p, &self.pending))
+        {
+            pending.remove(&self.key);
+        }
+        // waiters hold a reference too, and no more can show up.
+        Arc::strong_count(&self.pending) > 1
+    }
+
+    fn finish(mut self, reply: Option<&[u8]>) {
+        let waiting = self.detach();
+        self.finished = true;
+        if waiting {
+            self.pending.finish(reply.map(Arc::from));
+        }
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.detach();
+            self.pending.finish(None);
+        }
+    }
+}
+
+impl InFlight {
+    /// Call `lookup` to answer a request of type `ty` for `key`, unless an
+    /// identical request is already being answered, in which case wait for
+    /// its reply and return a copy. If that one fails, `lookup` is called
+    /// after all, so each request gets its own error.
+    pub fn run<F>(&self, ty: RequestType, key: &[u8], lookup: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        let key = (ty.index(), key.to_vec());
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(running) = pending.get(&key) {
+            let running = running.clone();
+            drop(pending);
+            let mut reply = running.reply.lock().unwrap();
+            while reply.is_none() {
+                reply = running.done.wait(reply).unwrap();
+            }
+            return match reply.as_ref().unwrap() {
+                Some(reply) => Ok(reply.to_vec()),
+                None => {
+                    drop(reply);
+                    lookup()
+                }
+            };
+        }
+        let leader = Leader {
+            in_flight: self,
+            key: key.clone(),
+            pending: Default::default(),
+            finished: false,
+        };
+        pending.insert(key, leader.pending.clone());
+        drop(pending);
+
+        let result = lookup();
+        leader.finish(result.as_ref().ok().map(|reply| &reply[..]));
+        result
+    }
+
+    /// Stop sharing the lookups running for `database`, one of
+    /// [DATABASES], with later requests: they started before it changed.
+    pub fn invalidate(&self, database: &str) {
+        let database = match DATABASES.iter().position(|db| *db == database) {
+            Some(database) => database,
+            None => return,
+        };
+        let types: Vec<usize> = RequestType::all()
+            .filter(|ty| ty.stat_database() == Some(database))
+            .map(|ty| ty.index())
+            .collect();
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|(index, _), _| !types.contains(index));
+    }
+}
+
+impl std::fmt::Debug for InFlight {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InFlight").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    const TY: RequestType = RequestType::GETPWBYNAME;
+
+    #[test]
+    fn test_shared() {
+        let in_flight = Arc::new(InFlight::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (in_flight, calls, barrier) =
+                    (in_flight.clone(), calls.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    in_flight
+                        .run(TY, b"alice\0", || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(100));
+                            Ok(b"reply".to_vec())
+                        })
+                        .unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), b"reply");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(in_flight.pending.lock().unwrap().is_empty());
+
+        // a later request does its own lookup.
+        let reply = in_flight.run(TY, b"alice\0", || Ok(b"new reply".to_vec()));
+        assert_eq!(reply.unwrap(), b"new reply");
+    }
+
+    /// Start a lookup for alice that waits for `release`, and wait until it's
+    /// in flight.
+    fn start_slow<F>(in_flight: &Arc<InFlight>, lookup: F) -> thread::JoinHandle<()>
+    where
+        F: FnOnce() -> Result<Vec<u8>> + Send + 'static,
+    {
+        let in_flight2 = in_flight.clone();
+        let handle = thread::spawn(move || {
+            let _ =
+                panic::catch_unwind(AssertUnwindSafe(|| in_flight2.run(TY, b"alice\0", lookup)));
+        });
+        while in_flight.pending.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle
+    }
+
+    #[test]
+    fn test_failed() {
+        for panics in [false, true] {
+            let in_flight = Arc::new(InFlight::default());
+            let (release, released) = crossbeam_channel::bounded::<()>(0);
+            let leader = start_slow(&in_flight, move || {
+                let _ = released.recv();
+                if panics {
+                    panic!("lookup failed");
+                }
+                anyhow::bail!("lookup failed")
+            });
+            let in_flight2 = in_flight.clone();
+            let follower =
+                thread::spawn(move || in_flight2.run(TY, b"alice\0", || Ok(b"own".to_vec())));
+            // let the follower start waiting.
+            thread::sleep(Duration::from_millis(50));
+            drop(release);
+            leader.join().unwrap();
+            // the follower didn't get the leader's failure.
+            assert_eq!(follower.join().unwrap().unwrap(), b"own");
+        }
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let in_flight = Arc::new(InFlight::default());
+        let (release, released) = crossbeam_channel::bounded::<()>(0);
+        let leader = start_slow(&in_flight, move || {
+            let _ = released.recv();
+            Ok(b"old".to_vec())
+        });
+        in_flight.invalidate("group");
+        assert!(!in_flight.pending.lock().unwrap().is_empty());
+        in_flight.invalidate("passwd");
+        // started after the invalidation, so it doesn't wait for the old one.
+        let reply = in_flight.run(TY, b"alice\0", || Ok(b"new".to_vec()));
+        assert_eq!(reply.unwrap(), b"new");
+        drop(release);
+        leader.join().unwrap();
+    }
+}
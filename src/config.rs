@@ -16,20 +16,37 @@
 
 //! Configuration for nsncd.
 
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use std::{collections::BTreeMap, env};
 
-use anyhow::{Context, Result};
-use num_traits::FromPrimitive;
+use anyhow::{ensure, Context, Result};
+use nix::unistd::Uid;
 use static_assertions::const_assert;
 
-use super::protocol::RequestType;
+use super::backend;
+use super::cache;
+use super::coalesce;
+use super::files;
+use super::forked;
+use super::initgroups;
+use super::invalidate;
+use super::middleware;
+use super::nscd_conf;
+use super::pool;
+use super::protocol::{self, RequestType};
+use super::stats::Stats;
+use super::warm;
 
 /// Size of the bitset for request types. Smaller values tend to exhibit worse
 /// cache performance in some quick benchmarks:
 /// https://gist.github.com/blinsay/3d233a09c59c083d8d27ccba4e322f04
 const BITSET_SIZE: usize = 256;
-const_assert!((RequestType::LASTREQ as usize) < BITSET_SIZE);
+const_assert!(protocol::INDEX_COUNT <= BITSET_SIZE);
 
 #[derive(Clone, Copy)]
 pub struct RequestTypeSet {
@@ -44,7 +61,7 @@ impl RequestTypeSet {
     }
 
     pub fn insert(&mut self, val: &RequestType) -> bool {
-        let val = *val as usize;
+        let val = val.index();
         if self.bits[val] {
             false
         } else {
@@ -54,7 +71,7 @@ impl RequestTypeSet {
     }
 
     pub fn contains(&self, val: &RequestType) -> bool {
-        let val = *val as usize;
+        let val = val.index();
         self.bits[val]
     }
 }
@@ -67,22 +84,138 @@ impl Default for RequestTypeSet {
 
 impl std::fmt::Debug for RequestTypeSet {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut f = f.debug_set();
-        for i in 0..(RequestType::LASTREQ as i32) {
-            let ty = &FromPrimitive::from_i32(i).unwrap();
-            if self.contains(ty) {
-                f.entry(ty);
-            }
+        f.debug_set()
+            .entries(RequestType::all().filter(|ty| self.contains(ty)))
+            .finish()
+    }
+}
+
+/// What to do with a group that has more members than
+/// [Config::max_group_members] allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemberOverflow {
+    /// Serve the group with its first members only.
+    Truncate,
+    /// Don't reply, so the client looks the group up itself.
+    Refuse,
+}
+
+impl std::str::FromStr for MemberOverflow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "truncate" => Ok(MemberOverflow::Truncate),
+            "refuse" => Ok(MemberOverflow::Refuse),
+            _ => Err(anyhow::format_err!(
+                "expected truncate or refuse, got {:?}",
+                s
+            )),
         }
-        f.finish()
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Where glibc (and other libcs) look for the nscd socket.
+pub const DEFAULT_SOCKET_PATH: &str = "/var/run/nscd/socket";
+
+/// A handle to stop a running nsncd. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    /// Ask nsncd to stop. It stops accepting connections, and exits once the
+    /// requests it's handling are answered.
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Everything that can be tuned about a running nsncd.
+#[derive(Clone, Debug)]
 pub struct Config {
+    pub socket_path: PathBuf,
     pub ignored_request_types: RequestTypeSet,
+    pub cache_bypass_types: RequestTypeSet,
+    /// The clients whose requests always go to the backend.
+    pub cache_bypass_uids: Vec<Uid>,
+    pub failover_bypass_types: RequestTypeSet,
     pub worker_count: usize,
+    /// How many workers answer host lookups, or zero if the other workers
+    /// do.
+    pub hosts_worker_count: usize,
+    /// The same for netgroup lookups.
+    pub netgroup_worker_count: usize,
+    /// How many requests of each class can wait for a pool's workers before
+    /// more are turned away.
+    pub queue_capacity: usize,
+    /// How many threads accept connections and serve them.
+    pub accept_threads: usize,
+    /// How many connections can be open at once, or zero for no limit.
+    pub max_connections: usize,
     pub handoff_timeout: Duration,
+    pub startup_timeout: Duration,
+    /// How long a shutdown waits for the requests being answered.
+    pub shutdown_timeout: Duration,
+    /// How long a connection can go without sending a request before it's
+    /// closed.
+    pub idle_timeout: Duration,
+    /// How long a worker can take to answer a request before it's reported
+    /// stuck, or zero to not watch them.
+    pub stuck_threshold: Duration,
+    pub max_hostname_len: usize,
+    pub ai_usable_families_only: bool,
+    pub exclude_link_local: bool,
+    pub hosts_dns_only: bool,
+    pub fold_name_case: bool,
+    pub detect_name_conflicts: bool,
+    pub detect_duplicate_uids: bool,
+    pub reconcile_group_members: bool,
+    pub merge_group_sources: bool,
+    /// The most members a group is served with, if limited.
+    pub max_group_members: Option<usize>,
+    pub member_overflow: MemberOverflow,
+    pub serve_shadow: bool,
+    pub strict_keys: bool,
+    pub audit_log: Option<PathBuf>,
+    pub secondary_socket: Option<PathBuf>,
+    /// The nscd build date and time sent in GETSTAT replies.
+    pub stat_version: Option<String>,
+    pub overrides: Arc<files::Table>,
+    pub local_files: Option<Arc<files::LocalFiles>>,
+    pub local_files_refresh: Duration,
+    /// The databases invalidated when their files in `/etc` change.
+    pub watch_files: Vec<&'static str>,
+    /// Invalidate every database when `/etc/nsswitch.conf` changes.
+    pub watch_nsswitch: bool,
+    /// Where passwd and group lookups go after the overrides and local
+    /// files. Not set from the environment.
+    pub backends: backend::Backends,
+    pub cache: Arc<cache::ResponseCache>,
+    /// Where the response cache is saved on shutdown and loaded from on
+    /// startup, if anywhere.
+    pub cache_file: Option<PathBuf>,
+    /// How often the response cache's statistics are logged. Zero if they
+    /// aren't.
+    pub cache_stats_interval: Duration,
+    /// The entries looked up into the cache on startup and invalidation.
+    pub warm: warm::WarmList,
+    /// The lookups running, for identical requests to wait for.
+    pub in_flight: Arc<coalesce::InFlight>,
+    pub initgroups: Arc<initgroups::GroupLists>,
+    pub buffers: Arc<pool::BufferPool>,
+    /// Hooks run around every request. These can't be set from the
+    /// environment.
+    pub middleware: middleware::Chain,
+    /// Hooks run on INVALIDATE requests. Not set from the environment.
+    pub invalidation_hooks: invalidate::Hooks,
+    /// Stops nsncd when requested. Not set from the environment either.
+    pub shutdown: Shutdown,
+    /// The counters the workers update while serving with this config.
+    pub stats: Arc<Stats>,
 }
 
 /// Mapping from nsswitch.conf "database" name to the request types related to
@@ -109,7 +242,12 @@ const OPS_BY_DATABASE: &[(&str, &[RequestType])] = &[
     ),
     (
         "passwd",
-        &[RequestType::GETPWBYNAME, RequestType::GETPWBYUID],
+        &[
+            RequestType::GETPWBYNAME,
+            RequestType::GETPWBYUID,
+            RequestType::BATCHGETPWBYUID,
+            RequestType::BATCHGETPWBYNAME,
+        ],
     ),
     (
         "services",
@@ -120,9 +258,41 @@ const OPS_BY_DATABASE: &[(&str, &[RequestType])] = &[
 impl Config {
     /// Parse config out of the environment.
     ///
-    /// There are two integer variables we pay attention to:
-    /// `NSNCD_WORKER_COUNT` and `NSNCD_HANDOFF_TIMEOUT`. Both must be positive
-    /// (non-zero).
+    /// `NSNCD_SOCKET_PATH` sets where we listen, by default
+    /// `/var/run/nscd/socket`.
+    ///
+    /// There are three integer variables we pay attention to:
+    /// `NSNCD_WORKER_COUNT`, `NSNCD_HANDOFF_TIMEOUT` and
+    /// `NSNCD_STARTUP_TIMEOUT`. All must be positive (non-zero). The worker
+    /// count defaults to twice the number of CPUs we may run on, between 4
+    /// and 64: workers mostly wait on lookups rather than compute.
+    ///
+    /// `NSNCD_HOSTS_WORKER_COUNT` and `NSNCD_NETGROUP_WORKER_COUNT` give host
+    /// and netgroup lookups that many workers of their own, so that a DNS or
+    /// LDAP outage can't tie up the workers answering passwd and group
+    /// lookups. They default to 0, which leaves them to the other workers.
+    ///
+    /// `NSNCD_QUEUE_CAPACITY` (default 64) is how many host lookups, and how
+    /// many other requests, can wait for each pool's workers. Requests that
+    /// find the queue full get no answer, and the client falls back to its
+    /// own lookup. A request waiting longer than `NSNCD_HANDOFF_TIMEOUT`
+    /// seconds (default 3) means the workers are stuck, and we exit.
+    ///
+    /// On `SIGTERM` or `SIGINT`, we stop accepting connections and give the
+    /// requests already read up to `NSNCD_SHUTDOWN_TIMEOUT` seconds (default
+    /// 10, must be positive) to be answered before we remove the socket and
+    /// exit.
+    ///
+    /// `NSNCD_MAX_CONNECTIONS` caps how many connections can be open at
+    /// once; connections beyond it are closed as soon as they're accepted.
+    /// It defaults to 0, for no limit.
+    ///
+    /// A connection that goes `NSNCD_IDLE_TIMEOUT_MS` milliseconds (default
+    /// 500, must be positive) without sending a request is closed, whether
+    /// it's sent any before or not.
+    ///
+    /// `NSNCD_ACCEPT_THREADS` (default 1) is how many threads accept
+    /// connections, and read requests from them and write responses to them.
     ///
     /// We also pay attention to variables `NSNCD_IGNORE_<DATABASE>` where
     /// `<DATABASE>` is one of the database names from `nsswitch.conf(5)`,
@@ -143,58 +313,590 @@ impl Config {
     /// Some request types may be ignored by the implementation (e.g. the ones
     /// that request a file descriptor pointing into internal cache
     /// structures).
+    ///
+    /// Similarly, setting `NSNCD_NO_CACHE_<DATABASE>` to `true` makes every
+    /// request related to that database go to the backend, even when caching
+    /// is enabled. This is meant for databases that must always be fresh,
+    /// e.g. ones used for access control. Such requests are neither cached,
+    /// negatively cached, nor coalesced with identical concurrent requests.
+    /// The same goes for every request from the clients whose uids are listed
+    /// (comma-separated) in `NSNCD_CACHE_BYPASS_UIDS`, e.g. a provisioning
+    /// agent that must see its own changes right away.
+    ///
+    /// `NSNCD_MAX_HOSTNAME_LEN` (default 255, the maximum length of a DNS
+    /// name) bounds the hostname in host lookups. Longer names are answered
+    /// with "not found" without doing a lookup.
+    ///
+    /// If `NSNCD_AI_USABLE_FAMILIES_ONLY` is `true` (default `false`),
+    /// `getaddrinfo` answers leave out the addresses of a family (IPv4 or
+    /// IPv6) the host has no route for, as long as it has one for the other.
+    ///
+    /// If `NSNCD_EXCLUDE_LINK_LOCAL` is `true` (default `false`), IPv6
+    /// link-local addresses (`fe80::/10`) are left out of host lookup
+    /// answers. The protocol has no room for their scope (interface), and
+    /// without one they're unusable.
+    ///
+    /// If `NSNCD_HOSTS_DNS_ONLY` is `true` (default `false`), host lookups
+    /// only ever use DNS, regardless of the `hosts` line in nsswitch.conf, so
+    /// entries in `/etc/hosts` can't override the names we serve.
+    ///
+    /// If `NSNCD_FOLD_NAME_CASE` is `true` (default `false`), user and group
+    /// names in requests are converted to ASCII lowercase before being looked
+    /// up. This is only correct for case-insensitive backends (e.g. Active
+    /// Directory through sssd), where `Alice` and `alice` are the same user.
+    /// Non-ASCII characters are left alone: we don't do locale-aware folding.
+    ///
+    /// If `NSNCD_DETECT_NAME_CONFLICTS` is `true` (default `false`), user and
+    /// group name lookups are also checked against the local files, and a
+    /// warning is logged if the entry there has different ids than the one
+    /// NSS returned. The NSS answer is still the one sent to the client.
+    ///
+    /// If `NSNCD_DETECT_DUPLICATE_UIDS` is `true` (default `false`), uid
+    /// lookups also look for other users with the same uid in the overrides
+    /// and the local files, and log a warning if there are any. The answer is
+    /// unchanged: the first entry found, in the order the sources are asked.
+    ///
+    /// If `NSNCD_RECONCILE_GROUP_MEMBERS` is `true` (default `false`), group
+    /// lookups by gid also look the group up by name, and vice versa. If the
+    /// two member lists differ, a warning is logged and their union is served.
+    /// Otherwise, each lookup returns whatever NSS returned for it.
+    ///
+    /// If `NSNCD_MERGE_GROUP_SOURCES` is `true` (default `false`), group
+    /// lookups ask the overrides, the local files and NSS, instead of
+    /// stopping at the first one that has the group, and serve the first
+    /// entry found with the members of all of them.
+    ///
+    /// If `NSNCD_MAX_GROUP_MEMBERS` is set to a positive number (default 0,
+    /// no limit), groups with more members than that are handled according
+    /// to `NSNCD_GROUP_MEMBER_OVERFLOW`: `truncate` (the default) serves them
+    /// with their first `NSNCD_MAX_GROUP_MEMBERS` members, and `refuse` sends
+    /// no reply, so the client looks them up itself. Either way, a warning is
+    /// logged.
+    ///
+    /// If `NSNCD_STRICT_KEYS` is `true` (default `false`), requests whose key
+    /// isn't exactly in the format glibc sends for their type (e.g. a uid
+    /// that isn't all digits, or an address of the wrong length) are
+    /// rejected before any lookup, instead of being read as best we can.
+    ///
+    /// If `NSNCD_SERVE_SHADOW` is `true` (default `false`), GETSPBYNAME
+    /// requests (an nsncd extension) are answered with the user's shadow
+    /// entry, if they come from root. Otherwise they get no reply.
+    ///
+    /// If `NSNCD_LOOKUP_TIMEOUT` is set to a positive number of seconds
+    /// (default 0, no timeout), passwd and group lookups that take longer
+    /// than that fail with `ETIMEDOUT`, like any other backend error. They
+    /// run on `NSNCD_WORKER_COUNT` threads of their own, so a hung NSS call
+    /// keeps one of those busy instead of a worker.
+    ///
+    /// If `NSNCD_LOOKUP_PROCESSES` is set to a positive number (default 0,
+    /// in this process), passwd and group lookups are made in up to that
+    /// many child processes instead, for NSS modules that aren't
+    /// thread-safe or leak memory. A child that crashes fails its lookup
+    /// with `EIO`, and is replaced.
+    ///
+    /// A worker that's been answering a request for longer than
+    /// `NSNCD_STUCK_THRESHOLD` seconds (default 30, 0 to not watch for them)
+    /// is logged and counted as stuck. Lookup processes stuck for that long
+    /// are killed, and replaced.
+    ///
+    /// If `NSNCD_SECONDARY_SOCKET` names the socket of another nsncd or nscd,
+    /// requests that fail because of a backend error (not a "not found") are
+    /// sent there, and its answer is served instead. Setting
+    /// `NSNCD_NO_FAILOVER_<DATABASE>` to `true` keeps that database's requests
+    /// from failing over.
+    ///
+    /// GETSTAT requests are answered in the format `nscd -g` reads, but it
+    /// only accepts replies from a daemon built at the same time as itself.
+    /// Setting `NSNCD_STAT_VERSION` to the build date and time of the local
+    /// nscd binary (`__DATE__ " " __TIME__`, e.g. `Jan  1 2024 12:00:00`)
+    /// makes our replies pass that check.
+    ///
+    /// If `NSNCD_AUDIT_LOG` is set, a line describing every request is
+    /// appended to the file it names.
+    ///
+    /// `NSNCD_OVERRIDE_PASSWD` and `NSNCD_OVERRIDE_GROUP` can name files in
+    /// the `passwd(5)` and `group(5)` formats. The entries in them are loaded
+    /// at startup and served before asking NSS, so they resolve even if the
+    /// backing directory service is down.
+    ///
+    /// If `NSNCD_LOCAL_FILES` is `true` (default `false`), `/etc/passwd` and
+    /// `/etc/group` are loaded at startup, and entries found in them are
+    /// served without asking NSS. They're checked for changes every
+    /// `NSNCD_LOCAL_FILES_REFRESH` seconds (default 5), and reloaded if they
+    /// changed.
+    ///
+    /// Setting `NSNCD_CACHE_TTL_<DATABASE>` (e.g. `NSNCD_CACHE_TTL_PASSWD`)
+    /// to a positive number of seconds (default 0, no caching) keeps the
+    /// answers to successful lookups in that database for that long, until
+    /// the database is invalidated. "Not found" answers are cached for
+    /// `NSNCD_NEGATIVE_CACHE_TTL_<DATABASE>` seconds (default 0, not cached)
+    /// instead; host lookups that failed with `TRY_AGAIN` never are. Each
+    /// database keeps at most `NSNCD_CACHE_MAX_ENTRIES_<DATABASE>` answers
+    /// (default 16384) and `NSNCD_CACHE_MAX_BYTES_<DATABASE>` bytes of them
+    /// (default 16 MiB), evicting the least recently used ones to make room.
+    /// Expired answers are still served for up to
+    /// `NSNCD_CACHE_MAX_STALE_<DATABASE>` seconds (default 0, not at all)
+    /// while they're looked up again in the background. Answers still in use
+    /// in the last quarter of their TTL are looked up again ahead of time, up
+    /// to `NSNCD_CACHE_RELOAD_COUNT_<DATABASE>` times in a row (default 0).
+    /// If `NSNCD_CACHE_FILE` is set to a path, the cached answers are saved
+    /// there on shutdown and loaded back on startup, except those that
+    /// expired meanwhile and those of databases whose files in `/etc`
+    /// changed since.
+    ///
+    /// If `NSNCD_CACHE_STATS_INTERVAL` is set to a positive number of seconds
+    /// (default 0, never), each cached database's hits, misses, evictions and
+    /// size are logged that often.
+    ///
+    /// If `NSNCD_NSCD_CONF` is set to the path of an `nscd.conf`, its
+    /// `enable-cache`, `positive-time-to-live`, `negative-time-to-live`,
+    /// `max-db-size` and `check-files` settings are used for each database,
+    /// as nscd would, unless the variables above override them.
+    /// `NSNCD_WATCH_FILES`, if set, overrides every `check-files`.
+    ///
+    /// The users, groups and hosts listed (comma-separated) in
+    /// `NSNCD_WARM_USERS`, `NSNCD_WARM_GROUPS` and `NSNCD_WARM_HOSTS` are
+    /// looked up into the cache on startup, and again after their database
+    /// is invalidated.
+    ///
+    /// If `NSNCD_WATCH_FILES` is `true` (default `false`), `/etc` is watched
+    /// for changes to `passwd`, `group`, `hosts`, `services` and `netgroup`,
+    /// and their database is invalidated when one changes, as if by an
+    /// INVALIDATE request. If `NSNCD_WATCH_NSSWITCH` is `true` too (default
+    /// `false`), a change to `nsswitch.conf` invalidates every database.
+    ///
+    /// At most `NSNCD_INITGROUPS_LIMIT` (default 8) `getgrouplist()` calls
+    /// for INITGROUPS requests run at the same time.
+    ///
+    /// Request and response buffers are shared by the workers through a pool
+    /// keeping up to `NSNCD_BUFFER_POOL_SIZE` (default 32) buffers of up to
+    /// `NSNCD_BUFFER_POOL_MAX_LEN` bytes (default 65536). Larger buffers are
+    /// freed once their response is sent.
     pub fn from_env() -> Result<Self> {
-        let ops_map = {
-            let mut ops_map = BTreeMap::new();
-            for (op_group, types) in OPS_BY_DATABASE.iter() {
-                ops_map.insert(op_group.to_uppercase().into_boxed_str(), *types);
-            }
-            ops_map
+        let local_files = if env_bool("NSNCD_LOCAL_FILES", false)? {
+            Some(Arc::new(files::LocalFiles::load(
+                Path::new("/etc/passwd"),
+                Path::new("/etc/group"),
+            )?))
+        } else {
+            None
+        };
+        let worker_count = env_positive_usize("NSNCD_WORKER_COUNT", default_worker_count())?;
+        let tunings = match env::var_os("NSNCD_NSCD_CONF") {
+            Some(path) => nscd_conf::load(Path::new(&path))?,
+            None => Default::default(),
+        };
+        let stats = Arc::new(Stats::new());
+        let nss: Arc<dyn backend::Backend> = match env_usize("NSNCD_LOOKUP_PROCESSES", 0)? {
+            0 => Arc::new(backend::Nss),
+            processes => Arc::new(forked::Forked::nsncd(processes)),
+        };
+        let backends = match env_usize("NSNCD_LOOKUP_TIMEOUT", 0)? {
+            0 => backend::Backends::all(nss),
+            timeout => backend::Backends::with_timeout(
+                nss,
+                Duration::from_secs(timeout as u64),
+                worker_count,
+                stats.clone(),
+            ),
         };
-
-        let mut ignored_request_types = RequestTypeSet::new();
-
-        for (key, value) in env::vars() {
-            if let Some(op_group) = key.strip_prefix("NSNCD_IGNORE_") {
-                let types = ops_map.get(op_group).ok_or_else(|| {
-                    let groups = ops_map.keys().map(|s| &**s).collect::<Vec<_>>().join(", ");
-                    anyhow::format_err!("Unknown group '{}'. Choose from: {}", op_group, groups)
-                })?;
-                let value = value
-                    .parse()
-                    .with_context(|| format!("parsing bool from {}", value))?;
-                if value {
-                    for ty in types.iter() {
-                        ignored_request_types.insert(ty);
-                    }
-                }
-            }
-        }
-
         Ok(Self {
-            ignored_request_types,
-            worker_count: env_positive_usize("NSNCD_WORKER_COUNT", 8)?,
+            socket_path: env::var_os("NSNCD_SOCKET_PATH")
+                .map_or_else(|| PathBuf::from(DEFAULT_SOCKET_PATH), PathBuf::from),
+            ignored_request_types: env_database_set("NSNCD_IGNORE_")?,
+            cache_bypass_types: env_database_set("NSNCD_NO_CACHE_")?,
+            cache_bypass_uids: env_uids("NSNCD_CACHE_BYPASS_UIDS")?,
+            failover_bypass_types: env_database_set("NSNCD_NO_FAILOVER_")?,
+            worker_count,
+            hosts_worker_count: env_usize("NSNCD_HOSTS_WORKER_COUNT", 0)?,
+            netgroup_worker_count: env_usize("NSNCD_NETGROUP_WORKER_COUNT", 0)?,
+            queue_capacity: env_positive_usize("NSNCD_QUEUE_CAPACITY", 64)?,
+            accept_threads: env_positive_usize("NSNCD_ACCEPT_THREADS", 1)?,
+            max_connections: env_usize("NSNCD_MAX_CONNECTIONS", 0)?,
             handoff_timeout: Duration::from_secs(
                 env_positive_usize("NSNCD_HANDOFF_TIMEOUT", 3)? as u64
             ),
+            startup_timeout: Duration::from_secs(
+                env_positive_usize("NSNCD_STARTUP_TIMEOUT", 10)? as u64
+            ),
+            shutdown_timeout: Duration::from_secs(
+                env_positive_usize("NSNCD_SHUTDOWN_TIMEOUT", 10)? as u64,
+            ),
+            idle_timeout: Duration::from_millis(
+                env_positive_usize("NSNCD_IDLE_TIMEOUT_MS", 500)? as u64
+            ),
+            stuck_threshold: Duration::from_secs(env_usize("NSNCD_STUCK_THRESHOLD", 30)? as u64),
+            max_hostname_len: env_positive_usize("NSNCD_MAX_HOSTNAME_LEN", 255)?,
+            ai_usable_families_only: env_bool("NSNCD_AI_USABLE_FAMILIES_ONLY", false)?,
+            exclude_link_local: env_bool("NSNCD_EXCLUDE_LINK_LOCAL", false)?,
+            hosts_dns_only: env_bool("NSNCD_HOSTS_DNS_ONLY", false)?,
+            fold_name_case: env_bool("NSNCD_FOLD_NAME_CASE", false)?,
+            detect_name_conflicts: env_bool("NSNCD_DETECT_NAME_CONFLICTS", false)?,
+            detect_duplicate_uids: env_bool("NSNCD_DETECT_DUPLICATE_UIDS", false)?,
+            reconcile_group_members: env_bool("NSNCD_RECONCILE_GROUP_MEMBERS", false)?,
+            merge_group_sources: env_bool("NSNCD_MERGE_GROUP_SOURCES", false)?,
+            max_group_members: Some(env_usize("NSNCD_MAX_GROUP_MEMBERS", 0)?)
+                .filter(|max| *max > 0),
+            member_overflow: env_member_overflow("NSNCD_GROUP_MEMBER_OVERFLOW")?,
+            serve_shadow: env_bool("NSNCD_SERVE_SHADOW", false)?,
+            strict_keys: env_bool("NSNCD_STRICT_KEYS", false)?,
+            audit_log: env::var_os("NSNCD_AUDIT_LOG").map(PathBuf::from),
+            secondary_socket: env::var_os("NSNCD_SECONDARY_SOCKET").map(PathBuf::from),
+            stat_version: env_stat_version("NSNCD_STAT_VERSION")?,
+            overrides: Arc::new(env_overrides()?),
+            local_files,
+            local_files_refresh: Duration::from_secs(env_positive_usize(
+                "NSNCD_LOCAL_FILES_REFRESH",
+                5,
+            )? as u64),
+            watch_files: env_watched_databases(&tunings)?,
+            watch_nsswitch: env_bool("NSNCD_WATCH_NSSWITCH", false)?,
+            backends,
+            cache: Arc::new(env_response_cache(&tunings, worker_count)?),
+            cache_file: env::var_os("NSNCD_CACHE_FILE").map(PathBuf::from),
+            cache_stats_interval: Duration::from_secs(
+                env_usize("NSNCD_CACHE_STATS_INTERVAL", 0)? as u64
+            ),
+            warm: warm::WarmList {
+                users: env_names("NSNCD_WARM_USERS"),
+                groups: env_names("NSNCD_WARM_GROUPS"),
+                hosts: env_names("NSNCD_WARM_HOSTS"),
+            },
+            in_flight: Default::default(),
+            initgroups: Arc::new(initgroups::GroupLists::new(env_positive_usize(
+                "NSNCD_INITGROUPS_LIMIT",
+                8,
+            )?)),
+            buffers: Arc::new(pool::BufferPool::new(
+                env_usize("NSNCD_BUFFER_POOL_SIZE", 32)?,
+                env_usize("NSNCD_BUFFER_POOL_MAX_LEN", 65536)?,
+            )),
+            middleware: Default::default(),
+            invalidation_hooks: Default::default(),
+            shutdown: Default::default(),
+            stats,
         })
     }
 
+    /// Check that the config makes sense. [Config::from_env] only builds
+    /// valid configs, but one put together in code might not be.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.socket_path.is_absolute(),
+            "socket path {} is not absolute",
+            self.socket_path.display()
+        );
+        ensure!(self.worker_count > 0, "worker count must be positive");
+        ensure!(self.queue_capacity > 0, "queue capacity must be positive");
+        ensure!(
+            self.accept_threads > 0,
+            "accept thread count must be positive"
+        );
+        ensure!(
+            self.handoff_timeout > Duration::ZERO,
+            "handoff timeout must be positive"
+        );
+        ensure!(
+            self.shutdown_timeout > Duration::ZERO,
+            "shutdown timeout must be positive"
+        );
+        ensure!(
+            self.idle_timeout > Duration::ZERO,
+            "idle timeout must be positive"
+        );
+        ensure!(
+            self.max_hostname_len > 0,
+            "max hostname length must be positive"
+        );
+        ensure!(
+            self.local_files_refresh > Duration::ZERO,
+            "local files refresh interval must be positive"
+        );
+        ensure!(
+            self.initgroups.limit() > 0,
+            "initgroups limit must be positive"
+        );
+        if let Some(version) = &self.stat_version {
+            check_stat_version(version)?;
+        }
+        Ok(())
+    }
+
     pub fn should_ignore(&self, ty: &RequestType) -> bool {
         self.ignored_request_types.contains(ty)
     }
+
+    /// Whether requests of this type must always be answered by the backend,
+    /// and never from (or into) a cache.
+    pub fn should_bypass_cache(&self, ty: &RequestType) -> bool {
+        self.cache_bypass_types.contains(ty)
+    }
+
+    /// How many workers there are, in every pool.
+    pub fn total_worker_count(&self) -> usize {
+        self.worker_count + self.hosts_worker_count + self.netgroup_worker_count
+    }
+
+    /// Whether `request` must be answered by the backend, because of its type
+    /// or because of who sent it.
+    pub fn should_bypass_cache_for(&self, request: &protocol::Request) -> bool {
+        self.should_bypass_cache(&request.ty)
+            || request
+                .peer_uid
+                .is_some_and(|uid| self.cache_bypass_uids.contains(&uid))
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            worker_count: 8,
+            worker_count: default_worker_count(),
+            hosts_worker_count: 0,
+            netgroup_worker_count: 0,
+            queue_capacity: 64,
+            accept_threads: 1,
+            max_connections: 0,
             handoff_timeout: Duration::from_secs(3),
+            startup_timeout: Duration::from_secs(10),
+            shutdown_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_millis(500),
+            stuck_threshold: Duration::from_secs(30),
+            max_hostname_len: 255,
+            ai_usable_families_only: false,
+            exclude_link_local: false,
+            hosts_dns_only: false,
+            fold_name_case: false,
+            detect_name_conflicts: false,
+            detect_duplicate_uids: false,
+            reconcile_group_members: false,
+            merge_group_sources: false,
+            max_group_members: None,
+            member_overflow: MemberOverflow::Truncate,
+            serve_shadow: false,
+            strict_keys: false,
+            audit_log: None,
+            secondary_socket: None,
+            stat_version: None,
+            overrides: Default::default(),
+            socket_path: PathBuf::from(DEFAULT_SOCKET_PATH),
+            local_files: None,
+            local_files_refresh: Duration::from_secs(5),
+            watch_files: vec![],
+            watch_nsswitch: false,
+            backends: Default::default(),
+            cache: Default::default(),
+            cache_file: None,
+            cache_stats_interval: Duration::ZERO,
+            warm: Default::default(),
+            in_flight: Default::default(),
+            initgroups: Arc::new(initgroups::GroupLists::new(8)),
+            buffers: Arc::new(pool::BufferPool::new(32, 65536)),
+            middleware: Default::default(),
+            invalidation_hooks: Default::default(),
+            shutdown: Default::default(),
+            stats: Default::default(),
             ignored_request_types: Default::default(),
+            cache_bypass_types: Default::default(),
+            cache_bypass_uids: vec![],
+            failover_bypass_types: Default::default(),
+        }
+    }
+}
+
+/// How many workers to run unless told otherwise: enough to keep the CPUs
+/// busy while lookups wait on the network, within reason.
+fn default_worker_count() -> usize {
+    thread::available_parallelism().map_or(8, |cpus| (cpus.get() * 2).clamp(4, 64))
+}
+
+/// Collect the request types of every database for which the variable
+/// `<prefix><DATABASE>` is set to `true`.
+fn env_database_set(prefix: &str) -> Result<RequestTypeSet> {
+    let ops_map = {
+        let mut ops_map = BTreeMap::new();
+        for (op_group, types) in OPS_BY_DATABASE.iter() {
+            ops_map.insert(op_group.to_uppercase().into_boxed_str(), *types);
+        }
+        ops_map
+    };
+
+    let mut set = RequestTypeSet::new();
+
+    for (key, value) in env::vars() {
+        if let Some(op_group) = key.strip_prefix(prefix) {
+            let types = ops_map.get(op_group).ok_or_else(|| {
+                let groups = ops_map.keys().map(|s| &**s).collect::<Vec<_>>().join(", ");
+                anyhow::format_err!("Unknown group '{}'. Choose from: {}", op_group, groups)
+            })?;
+            let value = value
+                .parse()
+                .with_context(|| format!("parsing bool from {}", value))?;
+            if value {
+                for ty in types.iter() {
+                    set.insert(ty);
+                }
+            }
+        }
+    }
+
+    Ok(set)
+}
+
+/// The response cache, refreshing replies on up to `threads` threads if any
+/// database may serve them stale or reload them.
+/// The entries of the `NSNCD_OVERRIDE_PASSWD` and `NSNCD_OVERRIDE_GROUP`
+/// files. Unlike the system's files, they're ours: a line we can't load is
+/// a mistake to fix, not something to leave to NSS.
+fn env_overrides() -> Result<files::Table> {
+    let table = files::Table::load(
+        env::var_os("NSNCD_OVERRIDE_PASSWD").as_ref().map(Path::new),
+        env::var_os("NSNCD_OVERRIDE_GROUP").as_ref().map(Path::new),
+    )?;
+    ensure!(
+        table.skipped.is_empty(),
+        "invalid override entries: {}",
+        table.skipped.join(", ")
+    );
+    Ok(table)
+}
+
+fn env_response_cache(
+    tunings: &[nscd_conf::Tuning],
+    threads: usize,
+) -> Result<cache::ResponseCache> {
+    let policies = env_cache_policies(tunings)?;
+    let cache = cache::ResponseCache::new(policies);
+    if policies
+        .iter()
+        .any(|policy| !policy.max_stale.is_zero() || policy.reload_count > 0)
+    {
+        Ok(cache.with_refresher(threads))
+    } else {
+        Ok(cache)
+    }
+}
+
+/// The cache policy of each of [protocol::DATABASES], from its `nscd.conf`
+/// tuning overridden by the `NSNCD_CACHE_*_<DATABASE>` variables.
+fn env_cache_policies(
+    tunings: &[nscd_conf::Tuning],
+) -> Result<[cache::Policy; protocol::DATABASES.len()]> {
+    let mut policies = [cache::Policy::default(); protocol::DATABASES.len()];
+    for ((policy, database), tuning) in policies
+        .iter_mut()
+        .zip(protocol::DATABASES.iter())
+        .zip(tunings)
+    {
+        tuning.apply(policy);
+        let database = database.to_uppercase();
+        let var = |prefix: &str| format!("{}{}", prefix, database);
+        let secs = |var: &str, default: Duration| -> Result<Duration> {
+            Ok(Duration::from_secs(
+                env_usize(var, default.as_secs() as usize)? as u64,
+            ))
+        };
+        policy.ttl = secs(&var("NSNCD_CACHE_TTL_"), policy.ttl)?;
+        policy.negative_ttl = secs(&var("NSNCD_NEGATIVE_CACHE_TTL_"), policy.negative_ttl)?;
+        policy.max_entries = env_usize(&var("NSNCD_CACHE_MAX_ENTRIES_"), policy.max_entries)?;
+        policy.max_bytes = env_usize(&var("NSNCD_CACHE_MAX_BYTES_"), policy.max_bytes)?;
+        policy.max_stale =
+            Duration::from_secs(env_usize(&var("NSNCD_CACHE_MAX_STALE_"), 0)? as u64);
+        policy.reload_count = env_usize(
+            &var("NSNCD_CACHE_RELOAD_COUNT_"),
+            policy.reload_count as usize,
+        )?
+        .try_into()
+        .unwrap_or(u32::MAX);
+    }
+    Ok(policies)
+}
+
+/// The databases whose files in `/etc` are watched: every one if
+/// `NSNCD_WATCH_FILES` is `true`, none if it's `false`, and those nscd would
+/// check from their `nscd.conf` tuning if it's not set.
+fn env_watched_databases(tunings: &[nscd_conf::Tuning]) -> Result<Vec<&'static str>> {
+    if env::var_os("NSNCD_WATCH_FILES").is_some() {
+        return Ok(match env_bool("NSNCD_WATCH_FILES", false)? {
+            true => protocol::DATABASES.to_vec(),
+            false => vec![],
+        });
+    }
+    // check-files defaults to yes in nscd, for cached databases.
+    Ok(protocol::DATABASES
+        .iter()
+        .zip(tunings)
+        .filter(|(_, tuning)| {
+            tuning.enable_cache == Some(true) && tuning.check_files != Some(false)
+        })
+        .map(|(database, _)| *database)
+        .collect())
+}
+
+fn env_uids(var: &str) -> Result<Vec<Uid>> {
+    let uids = match env::var(var) {
+        Ok(s) => s,
+        Err(_) => return Ok(vec![]),
+    };
+    warm::WarmList::parse_names(&uids)
+        .iter()
+        .map(|uid| {
+            uid.parse()
+                .map(Uid::from_raw)
+                .with_context(|| format!("parsing uid from {} in {}", uid, var))
+        })
+        .collect()
+}
+
+fn env_names(var: &str) -> Vec<String> {
+    match env::var(var) {
+        Ok(s) => warm::WarmList::parse_names(&s),
+        Err(_) => vec![],
+    }
+}
+
+fn env_bool(var: &str, default: bool) -> Result<bool> {
+    match env::var(var) {
+        Ok(s) => s
+            .parse()
+            .with_context(|| format!("parsing bool from {}", s)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_usize(var: &str, default: usize) -> Result<usize> {
+    match env::var(var) {
+        Ok(s) => s.parse().with_context(|| format!("parsing int from {}", s)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_member_overflow(var: &str) -> Result<MemberOverflow> {
+    match env::var(var) {
+        Ok(s) => s.parse().with_context(|| format!("invalid {}", var)),
+        Err(_) => Ok(MemberOverflow::Truncate),
+    }
+}
+
+fn env_stat_version(var: &str) -> Result<Option<String>> {
+    match env::var(var) {
+        Ok(s) => {
+            check_stat_version(&s).with_context(|| format!("invalid {}", var))?;
+            Ok(Some(s))
         }
+        Err(_) => Ok(None),
     }
 }
 
+/// nscd's version string is a date and time, in a 21 byte buffer.
+fn check_stat_version(version: &str) -> Result<()> {
+    ensure!(
+        version.len() <= 20 && !version.contains('\0'),
+        "nscd version {:?} is not a build date and time",
+        version
+    );
+    Ok(())
+}
+
 fn env_positive_usize(var: &str, default: usize) -> Result<usize> {
     let s = match env::var(var) {
         Ok(s) => s,
@@ -212,20 +914,74 @@ fn env_positive_usize(var: &str, default: usize) -> Result<usize> {
 
 #[cfg(test)]
 mod test {
+    use std::path::{Path, PathBuf};
     use std::time::Duration;
 
+    use nix::unistd::Uid;
     use temp_env::{with_var, with_var_unset, with_vars};
 
+    use super::protocol;
     use super::Config;
+    use super::MemberOverflow;
     use super::RequestType;
 
     #[test]
     fn test_defaults() {
         let config = Config::default();
-        assert_eq!(config.worker_count, 8);
+        assert_eq!(config.worker_count, super::default_worker_count());
+        assert!((4..=64).contains(&config.worker_count));
         assert_eq!(config.handoff_timeout, Duration::from_secs(3));
+        assert_eq!(config.startup_timeout, Duration::from_secs(10));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+        assert_eq!(config.idle_timeout, Duration::from_millis(500));
+        assert_eq!(config.stuck_threshold, Duration::from_secs(30));
+        assert_eq!(config.max_hostname_len, 255);
+        assert!(!config.fold_name_case);
+        assert!(config.audit_log.is_none());
+        assert!(config.overrides.users.is_empty());
+        assert!(config.overrides.groups.is_empty());
         assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
         assert!(!config.should_ignore(&RequestType::GETPWBYUID));
+        assert!(!config.should_bypass_cache(&RequestType::GETPWBYUID));
+    }
+
+    #[test]
+    fn test_queue_capacity() {
+        with_var_unset("NSNCD_QUEUE_CAPACITY", || {
+            assert_eq!(Config::from_env().unwrap().queue_capacity, 64);
+        });
+        with_var("NSNCD_QUEUE_CAPACITY", Some("1000"), || {
+            assert_eq!(Config::from_env().unwrap().queue_capacity, 1000);
+        });
+        with_var("NSNCD_QUEUE_CAPACITY", Some("0"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_accept_threads() {
+        with_var_unset("NSNCD_ACCEPT_THREADS", || {
+            assert_eq!(Config::from_env().unwrap().accept_threads, 1);
+        });
+        with_var("NSNCD_ACCEPT_THREADS", Some("4"), || {
+            assert_eq!(Config::from_env().unwrap().accept_threads, 4);
+        });
+        with_var("NSNCD_ACCEPT_THREADS", Some("0"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_max_connections() {
+        with_var_unset("NSNCD_MAX_CONNECTIONS", || {
+            assert_eq!(Config::from_env().unwrap().max_connections, 0);
+        });
+        with_var("NSNCD_MAX_CONNECTIONS", Some("1000"), || {
+            assert_eq!(Config::from_env().unwrap().max_connections, 1000);
+        });
+        with_var("NSNCD_MAX_CONNECTIONS", Some("-1"), || {
+            assert!(Config::from_env().is_err());
+        });
     }
 
     #[test]
@@ -237,7 +993,22 @@ mod test {
         with_var("NSNCD_WORKER_COUNT", Some("13"), || {
             let config = Config::from_env().unwrap();
             assert_eq!(config.worker_count, 13);
+            assert_eq!(config.hosts_worker_count, 0);
+            assert_eq!(config.total_worker_count(), 13);
         });
+        with_vars(
+            [
+                ("NSNCD_WORKER_COUNT", Some("4")),
+                ("NSNCD_HOSTS_WORKER_COUNT", Some("8")),
+                ("NSNCD_NETGROUP_WORKER_COUNT", Some("2")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(config.hosts_worker_count, 8);
+                assert_eq!(config.netgroup_worker_count, 2);
+                assert_eq!(config.total_worker_count(), 14);
+            },
+        );
         with_var("NSNCD_WORKER_COUNT", Some("0"), || {
             assert!(Config::from_env().is_err());
         });
@@ -294,86 +1065,740 @@ mod test {
     }
 
     #[test]
-    fn test_ignore_vars() {
-        with_var_unset("NSNCD_IGNORE_INITGROUPS", || {
-            let config = Config::from_env().unwrap();
-            assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
-            assert!(!config.should_ignore(&RequestType::INITGROUPS));
-        });
-        with_var("NSNCD_IGNORE_INITGROUPS", Some("true"), || {
+    fn test_startup_timeout() {
+        with_var_unset("NSNCD_STARTUP_TIMEOUT", || {
             let config = Config::from_env().unwrap();
-            assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
-            assert!(config.should_ignore(&RequestType::INITGROUPS));
+            assert_eq!(config.startup_timeout, Config::default().startup_timeout);
         });
-        with_var("NSNCD_IGNORE_INITGROUPS", Some("false"), || {
+        with_var("NSNCD_STARTUP_TIMEOUT", Some("30"), || {
             let config = Config::from_env().unwrap();
-            assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
-            assert!(!config.should_ignore(&RequestType::INITGROUPS));
+            assert_eq!(config.startup_timeout, Duration::from_secs(30));
         });
-
-        // Invalid values
-        with_var("NSNCD_IGNORE_INITGROUPS", Some("yes"), || {
+        with_var("NSNCD_STARTUP_TIMEOUT", Some("30s"), || {
             assert!(Config::from_env().is_err());
         });
-        with_var("NSNCD_IGNORE_INITGROUPS", Some("y"), || {
+        with_var("NSNCD_STARTUP_TIMEOUT", Some("0"), || {
             assert!(Config::from_env().is_err());
         });
-        with_var("NSNCD_IGNORE_INITGROUPS", Some("no"), || {
+        with_var("NSNCD_STARTUP_TIMEOUT", Some(""), || {
             assert!(Config::from_env().is_err());
         });
-        with_var("NSNCD_IGNORE_INITGROUPS", Some("n"), || {
-            assert!(Config::from_env().is_err());
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        with_var_unset("NSNCD_SHUTDOWN_TIMEOUT", || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.shutdown_timeout, Config::default().shutdown_timeout);
         });
-        with_var("NSNCD_IGNORE_INITGROUPS", Some("1"), || {
+        with_var("NSNCD_SHUTDOWN_TIMEOUT", Some("2"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.shutdown_timeout, Duration::from_secs(2));
+        });
+        with_var("NSNCD_SHUTDOWN_TIMEOUT", Some("0"), || {
             assert!(Config::from_env().is_err());
         });
-        with_var("NSNCD_IGNORE_INITGROUPS", Some("0"), || {
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        with_var_unset("NSNCD_IDLE_TIMEOUT_MS", || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.idle_timeout, Config::default().idle_timeout);
+        });
+        with_var("NSNCD_IDLE_TIMEOUT_MS", Some("30000"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.idle_timeout, Duration::from_secs(30));
+        });
+        with_var("NSNCD_IDLE_TIMEOUT_MS", Some("0"), || {
             assert!(Config::from_env().is_err());
         });
-        with_var("NSNCD_IGNORE_INITGROUPS", Some(""), || {
+    }
+
+    #[test]
+    fn test_stuck_threshold() {
+        with_var("NSNCD_STUCK_THRESHOLD", Some("0"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.stuck_threshold, Duration::ZERO);
+        });
+        with_var("NSNCD_STUCK_THRESHOLD", Some("5"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.stuck_threshold, Duration::from_secs(5));
+        });
+        with_var("NSNCD_STUCK_THRESHOLD", Some("5s"), || {
             assert!(Config::from_env().is_err());
         });
+    }
 
-        // Invalid keys
-        with_var("NSNCD_IGNORE_initgroups", Some("true"), || {
+    #[test]
+    fn test_max_hostname_len() {
+        with_var_unset("NSNCD_MAX_HOSTNAME_LEN", || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.max_hostname_len, 255);
+            assert!(!config.fold_name_case);
+        });
+        with_var("NSNCD_MAX_HOSTNAME_LEN", Some("1024"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.max_hostname_len, 1024);
+        });
+        with_var("NSNCD_MAX_HOSTNAME_LEN", Some("0"), || {
             assert!(Config::from_env().is_err());
         });
-        with_var("NSNCD_IGNORE_ZZZNOTAGROUP", Some("true"), || {
+    }
+
+    #[test]
+    fn test_fold_name_case() {
+        with_var_unset("NSNCD_FOLD_NAME_CASE", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.fold_name_case);
+        });
+        with_var("NSNCD_FOLD_NAME_CASE", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.fold_name_case);
+        });
+        with_var("NSNCD_FOLD_NAME_CASE", Some("yes"), || {
             assert!(Config::from_env().is_err());
         });
+    }
 
-        // Some combinations
-        with_vars(
-            vec![
-                ("NSNCD_IGNORE_PASSWD", Some("false")),
-                ("NSNCD_IGNORE_INITGROUPS", Some("true")),
-            ],
-            || {
-                let config = Config::from_env().unwrap();
-                assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
-                assert!(config.should_ignore(&RequestType::INITGROUPS));
+    #[test]
+    fn test_socket_path() {
+        with_var_unset("NSNCD_SOCKET_PATH", || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.socket_path, Path::new("/var/run/nscd/socket"));
+        });
+        with_var("NSNCD_SOCKET_PATH", Some("/run/nsncd/socket"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.socket_path, Path::new("/run/nsncd/socket"));
+        });
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+        let invalid = [
+            Config {
+                socket_path: PathBuf::from("socket"),
+                ..Config::default()
             },
-        );
-        with_vars(
-            vec![
-                ("NSNCD_IGNORE_PASSWD", Some("true")),
-                ("NSNCD_IGNORE_INITGROUPS", Some("false")),
-            ],
-            || {
-                let config = Config::from_env().unwrap();
-                assert!(config.should_ignore(&RequestType::GETPWBYNAME));
-                assert!(!config.should_ignore(&RequestType::INITGROUPS));
+            Config {
+                worker_count: 0,
+                ..Config::default()
             },
-        );
-        with_vars(
-            vec![
-                ("NSNCD_IGNORE_PASSWD", Some("true")),
-                ("NSNCD_IGNORE_INITGROUPS", Some("true")),
-            ],
-            || {
+            Config {
+                handoff_timeout: Duration::ZERO,
+                ..Config::default()
+            },
+            Config {
+                local_files_refresh: Duration::ZERO,
+                ..Config::default()
+            },
+        ];
+        for config in invalid.iter() {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_ai_usable_families_only() {
+        with_var_unset("NSNCD_AI_USABLE_FAMILIES_ONLY", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.ai_usable_families_only);
+        });
+        with_var("NSNCD_AI_USABLE_FAMILIES_ONLY", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.ai_usable_families_only);
+        });
+    }
+
+    #[test]
+    fn test_reconcile_group_members() {
+        with_var_unset("NSNCD_RECONCILE_GROUP_MEMBERS", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.reconcile_group_members);
+        });
+        with_var("NSNCD_RECONCILE_GROUP_MEMBERS", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.reconcile_group_members);
+        });
+    }
+
+    #[test]
+    fn test_merge_group_sources() {
+        with_var_unset("NSNCD_MERGE_GROUP_SOURCES", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.merge_group_sources);
+        });
+        with_var("NSNCD_MERGE_GROUP_SOURCES", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.merge_group_sources);
+        });
+    }
+
+    #[test]
+    fn test_serve_shadow() {
+        with_var_unset("NSNCD_SERVE_SHADOW", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.serve_shadow);
+        });
+        with_var("NSNCD_SERVE_SHADOW", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.serve_shadow);
+        });
+    }
+
+    #[test]
+    fn test_max_group_members() {
+        with_vars(
+            vec![
+                ("NSNCD_MAX_GROUP_MEMBERS", None::<&str>),
+                ("NSNCD_GROUP_MEMBER_OVERFLOW", None),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(config.max_group_members, None);
+                assert_eq!(config.member_overflow, MemberOverflow::Truncate);
+            },
+        );
+        with_var("NSNCD_MAX_GROUP_MEMBERS", Some("0"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.max_group_members, None);
+        });
+        with_vars(
+            vec![
+                ("NSNCD_MAX_GROUP_MEMBERS", Some("10000")),
+                ("NSNCD_GROUP_MEMBER_OVERFLOW", Some("refuse")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(config.max_group_members, Some(10000));
+                assert_eq!(config.member_overflow, MemberOverflow::Refuse);
+            },
+        );
+        with_var("NSNCD_GROUP_MEMBER_OVERFLOW", Some("drop"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_lookup_timeout() {
+        with_var_unset("NSNCD_LOOKUP_TIMEOUT", || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(format!("{:?}", config.backends), "Backends { .. }");
+        });
+        with_var("NSNCD_LOOKUP_TIMEOUT", Some("5"), || {
+            let config = Config::from_env().unwrap();
+            let root = config
+                .backends
+                .passwd
+                .user_by_uid(nix::unistd::Uid::from_raw(0));
+            assert_eq!(root.unwrap().unwrap().name, "root");
+        });
+        with_var("NSNCD_LOOKUP_TIMEOUT", Some("soon"), || {
+            assert!(Config::from_env().is_err());
+        });
+        // children are only started for lookups.
+        with_var("NSNCD_LOOKUP_PROCESSES", Some("2"), || {
+            assert!(Config::from_env().is_ok());
+        });
+        with_var("NSNCD_LOOKUP_PROCESSES", Some("many"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_strict_keys() {
+        with_var_unset("NSNCD_STRICT_KEYS", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.strict_keys);
+        });
+        with_var("NSNCD_STRICT_KEYS", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.strict_keys);
+        });
+    }
+
+    #[test]
+    fn test_exclude_link_local() {
+        with_var_unset("NSNCD_EXCLUDE_LINK_LOCAL", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.exclude_link_local);
+        });
+        with_var("NSNCD_EXCLUDE_LINK_LOCAL", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.exclude_link_local);
+        });
+    }
+
+    #[test]
+    fn test_hosts_dns_only() {
+        with_var_unset("NSNCD_HOSTS_DNS_ONLY", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.hosts_dns_only);
+        });
+        with_var("NSNCD_HOSTS_DNS_ONLY", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.hosts_dns_only);
+        });
+    }
+
+    #[test]
+    fn test_watch_files() {
+        with_vars(
+            vec![
+                ("NSNCD_WATCH_FILES", None::<&str>),
+                ("NSNCD_WATCH_NSSWITCH", None),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert!(config.watch_files.is_empty());
+                assert!(!config.watch_nsswitch);
+            },
+        );
+        with_vars(
+            vec![
+                ("NSNCD_WATCH_FILES", Some("true")),
+                ("NSNCD_WATCH_NSSWITCH", Some("true")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(config.watch_files, protocol::DATABASES.to_vec());
+                assert!(config.watch_nsswitch);
+            },
+        );
+    }
+
+    #[test]
+    fn test_detect_name_conflicts() {
+        with_var_unset("NSNCD_DETECT_NAME_CONFLICTS", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.detect_name_conflicts);
+        });
+        with_var("NSNCD_DETECT_NAME_CONFLICTS", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.detect_name_conflicts);
+        });
+    }
+
+    #[test]
+    fn test_detect_duplicate_uids() {
+        with_var_unset("NSNCD_DETECT_DUPLICATE_UIDS", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.detect_duplicate_uids);
+        });
+        with_var("NSNCD_DETECT_DUPLICATE_UIDS", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.detect_duplicate_uids);
+        });
+    }
+
+    #[test]
+    fn test_local_files() {
+        with_vars(
+            vec![
+                ("NSNCD_LOCAL_FILES", None::<&str>),
+                ("NSNCD_LOCAL_FILES_REFRESH", None),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert!(config.local_files.is_none());
+                assert_eq!(config.local_files_refresh, Duration::from_secs(5));
+            },
+        );
+        with_var("NSNCD_LOCAL_FILES_REFRESH", Some("60"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.local_files_refresh, Duration::from_secs(60));
+        });
+        with_var("NSNCD_LOCAL_FILES_REFRESH", Some("0"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_nscd_conf() {
+        let dir = tempfile::tempdir().unwrap();
+        let nscd_conf = dir.path().join("nscd.conf");
+        std::fs::write(
+            &nscd_conf,
+            "enable-cache passwd yes\n\
+             positive-time-to-live passwd 600\n\
+             max-db-size passwd 1048576\n\
+             enable-cache hosts yes\n\
+             check-files hosts no\n",
+        )
+        .unwrap();
+        let bad = dir.path().join("bad.conf");
+        std::fs::write(&bad, "enable-cache passwd sometimes\n").unwrap();
+
+        with_vars(
+            vec![
+                ("NSNCD_NSCD_CONF", Some(nscd_conf.as_os_str())),
+                ("NSNCD_CACHE_TTL_PASSWD", None),
+                ("NSNCD_WATCH_FILES", None),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                let passwd = config.cache.database_policy(0);
+                assert_eq!(passwd.ttl, Duration::from_secs(600));
+                assert_eq!(passwd.negative_ttl, Duration::from_secs(20));
+                assert_eq!(passwd.max_bytes, 1048576);
+                assert_eq!(config.cache.database_policy(1).ttl, Duration::ZERO);
+                assert_eq!(config.watch_files, vec!["passwd"]);
+            },
+        );
+        // the environment wins.
+        with_vars(
+            vec![
+                ("NSNCD_NSCD_CONF", Some(nscd_conf.as_os_str())),
+                ("NSNCD_CACHE_TTL_PASSWD", Some("60".as_ref())),
+                ("NSNCD_WATCH_FILES", Some("false".as_ref())),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                let passwd = config.cache.database_policy(0);
+                assert_eq!(passwd.ttl, Duration::from_secs(60));
+                assert!(config.watch_files.is_empty());
+            },
+        );
+        with_var("NSNCD_NSCD_CONF", Some(&bad), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_NSCD_CONF", Some(dir.path().join("missing")), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let passwd = dir.path().join("passwd");
+        std::fs::write(&passwd, "svc:x:4242:4242:::\n").unwrap();
+        let group = dir.path().join("group");
+        std::fs::write(&group, "svc:x:4242:\n").unwrap();
+        let bad = dir.path().join("bad");
+        std::fs::write(&bad, "svc:x\n").unwrap();
+
+        with_vars(
+            vec![
+                ("NSNCD_OVERRIDE_PASSWD", Some(&passwd)),
+                ("NSNCD_OVERRIDE_GROUP", Some(&group)),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert!(config.overrides.user_by_name("svc").is_some());
+                assert!(config.overrides.group_by_name("svc").is_some());
+            },
+        );
+        with_var("NSNCD_OVERRIDE_PASSWD", Some(&bad), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var(
+            "NSNCD_OVERRIDE_GROUP",
+            Some(dir.path().join("missing")),
+            || {
+                assert!(Config::from_env().is_err());
+            },
+        );
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        with_vars(
+            vec![
+                ("NSNCD_CACHE_TTL_PASSWD", None::<&str>),
+                ("NSNCD_CACHE_TTL_HOSTS", None),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(config.cache.ttl(RequestType::GETPWBYNAME), Duration::ZERO);
+                assert_eq!(config.cache.ttl(RequestType::GETAI), Duration::ZERO);
+            },
+        );
+        with_vars(
+            vec![
+                ("NSNCD_CACHE_TTL_PASSWD", Some("60")),
+                ("NSNCD_CACHE_TTL_HOSTS", Some("15")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(
+                    config.cache.ttl(RequestType::GETPWBYUID),
+                    Duration::from_secs(60)
+                );
+                assert_eq!(
+                    config.cache.ttl(RequestType::GETAI),
+                    Duration::from_secs(15)
+                );
+                assert_eq!(config.cache.ttl(RequestType::GETGRBYGID), Duration::ZERO);
+                assert_eq!(
+                    config.cache.negative_ttl(RequestType::GETPWBYUID),
+                    Duration::ZERO
+                );
+            },
+        );
+        with_var("NSNCD_NEGATIVE_CACHE_TTL_PASSWD", Some("5"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(
+                config.cache.negative_ttl(RequestType::GETPWBYUID),
+                Duration::from_secs(5)
+            );
+            assert_eq!(config.cache.ttl(RequestType::GETPWBYUID), Duration::ZERO);
+            assert!(config.cache.caches(RequestType::GETPWBYUID));
+        });
+        with_vars(
+            vec![
+                ("NSNCD_CACHE_MAX_ENTRIES_GROUP", Some("100")),
+                ("NSNCD_CACHE_MAX_BYTES_GROUP", Some("65536")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                let group = config.cache.database_policy(1);
+                assert_eq!((group.max_entries, group.max_bytes), (100, 65536));
+                let passwd = config.cache.database_policy(0);
+                assert_eq!((passwd.max_entries, passwd.max_bytes), (16384, 16 << 20));
+            },
+        );
+        with_var("NSNCD_CACHE_RELOAD_COUNT_PASSWD", Some("3"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.cache.database_policy(0).reload_count, 3);
+            assert_eq!(config.cache.database_policy(1).reload_count, 0);
+        });
+        with_var("NSNCD_CACHE_MAX_STALE_HOSTS", Some("300"), || {
+            let config = Config::from_env().unwrap();
+            let hosts = config.cache.database_policy(2);
+            assert_eq!(hosts.max_stale, Duration::from_secs(300));
+            assert_eq!(config.cache.database_policy(0).max_stale, Duration::ZERO);
+        });
+        with_var("NSNCD_CACHE_FILE", Some("/var/cache/nsncd/cache"), || {
+            let config = Config::from_env().unwrap();
+            let path = config.cache_file.unwrap();
+            assert_eq!(path, Path::new("/var/cache/nsncd/cache"));
+        });
+        with_var("NSNCD_CACHE_FILE", None::<&str>, || {
+            assert!(Config::from_env().unwrap().cache_file.is_none());
+        });
+        with_var("NSNCD_CACHE_STATS_INTERVAL", Some("300"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.cache_stats_interval, Duration::from_secs(300));
+        });
+        with_var("NSNCD_CACHE_TTL_PASSWD", Some("1m"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_warm() {
+        with_vars(
+            vec![
+                ("NSNCD_WARM_USERS", Some("root,svc-deploy")),
+                ("NSNCD_WARM_GROUPS", None),
+                ("NSNCD_WARM_HOSTS", Some(" bastion.example.com ")),
+            ],
+            || {
+                let warm = Config::from_env().unwrap().warm;
+                assert_eq!(warm.users, vec!["root", "svc-deploy"]);
+                assert!(warm.groups.is_empty());
+                assert_eq!(warm.hosts, vec!["bastion.example.com"]);
+            },
+        );
+    }
+
+    #[test]
+    fn test_initgroups() {
+        with_var("NSNCD_INITGROUPS_LIMIT", None::<&str>, || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.initgroups.limit(), 8);
+        });
+        with_var("NSNCD_INITGROUPS_LIMIT", Some("2"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.initgroups.limit(), 2);
+        });
+        with_var("NSNCD_INITGROUPS_LIMIT", Some("0"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        with_vars(
+            vec![
+                ("NSNCD_BUFFER_POOL_SIZE", None::<&str>),
+                ("NSNCD_BUFFER_POOL_MAX_LEN", None),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(
+                    format!("{:?}", config.buffers),
+                    "BufferPool { max_buffers: 32, max_capacity: 65536 }"
+                );
+            },
+        );
+        // a pool of 0 buffers is allowed, and disables pooling.
+        with_vars(
+            vec![
+                ("NSNCD_BUFFER_POOL_SIZE", Some("0")),
+                ("NSNCD_BUFFER_POOL_MAX_LEN", Some("4096")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(
+                    format!("{:?}", config.buffers),
+                    "BufferPool { max_buffers: 0, max_capacity: 4096 }"
+                );
+            },
+        );
+        with_var("NSNCD_BUFFER_POOL_MAX_LEN", Some("64k"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_failover() {
+        with_vars(
+            vec![
+                ("NSNCD_SECONDARY_SOCKET", None::<&str>),
+                ("NSNCD_NO_FAILOVER_HOSTS", None),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert!(config.secondary_socket.is_none());
+                assert!(!config.failover_bypass_types.contains(&RequestType::GETAI));
+            },
+        );
+        with_vars(
+            vec![
+                ("NSNCD_SECONDARY_SOCKET", Some("/run/nscd/secondary")),
+                ("NSNCD_NO_FAILOVER_HOSTS", Some("true")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert_eq!(
+                    config.secondary_socket.as_deref(),
+                    Some(Path::new("/run/nscd/secondary"))
+                );
+                assert!(config.failover_bypass_types.contains(&RequestType::GETAI));
+                assert!(!config
+                    .failover_bypass_types
+                    .contains(&RequestType::GETPWBYNAME));
+            },
+        );
+    }
+
+    #[test]
+    fn test_no_cache_vars() {
+        with_var("NSNCD_NO_CACHE_NETGROUP", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(config.should_bypass_cache(&RequestType::INNETGR));
+            assert!(config.should_bypass_cache(&RequestType::GETNETGRENT));
+            assert!(!config.should_bypass_cache(&RequestType::GETPWBYNAME));
+            assert!(!config.should_ignore(&RequestType::INNETGR));
+        });
+        with_var("NSNCD_NO_CACHE_NETGROUP", Some("false"), || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.should_bypass_cache(&RequestType::INNETGR));
+        });
+        with_var("NSNCD_NO_CACHE_NETGROUP", Some("1"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_CACHE_BYPASS_UIDS", Some("0, 4242"), || {
+            let config = Config::from_env().unwrap();
+            let mut request = protocol::Request::new(RequestType::GETPWBYNAME, b"alice\0");
+            assert!(!config.should_bypass_cache_for(&request));
+            request.peer_uid = Some(Uid::from_raw(4242));
+            assert!(config.should_bypass_cache_for(&request));
+            request.peer_uid = Some(Uid::from_raw(1000));
+            assert!(!config.should_bypass_cache_for(&request));
+        });
+        with_var("NSNCD_CACHE_BYPASS_UIDS", Some("root"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_NO_CACHE_ZZZNOTAGROUP", Some("true"), || {
+            assert!(Config::from_env().is_err());
+        });
+    }
+
+    #[test]
+    fn test_ignore_vars() {
+        with_var_unset("NSNCD_IGNORE_INITGROUPS", || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
+            assert!(!config.should_ignore(&RequestType::INITGROUPS));
+        });
+        with_var("NSNCD_IGNORE_INITGROUPS", Some("true"), || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
+            assert!(config.should_ignore(&RequestType::INITGROUPS));
+        });
+        with_var("NSNCD_IGNORE_INITGROUPS", Some("false"), || {
+            let config = Config::from_env().unwrap();
+            assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
+            assert!(!config.should_ignore(&RequestType::INITGROUPS));
+        });
+
+        // Invalid values
+        with_var("NSNCD_IGNORE_INITGROUPS", Some("yes"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_IGNORE_INITGROUPS", Some("y"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_IGNORE_INITGROUPS", Some("no"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_IGNORE_INITGROUPS", Some("n"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_IGNORE_INITGROUPS", Some("1"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_IGNORE_INITGROUPS", Some("0"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_IGNORE_INITGROUPS", Some(""), || {
+            assert!(Config::from_env().is_err());
+        });
+
+        // Invalid keys
+        with_var("NSNCD_IGNORE_initgroups", Some("true"), || {
+            assert!(Config::from_env().is_err());
+        });
+        with_var("NSNCD_IGNORE_ZZZNOTAGROUP", Some("true"), || {
+            assert!(Config::from_env().is_err());
+        });
+
+        // Some combinations
+        with_vars(
+            vec![
+                ("NSNCD_IGNORE_PASSWD", Some("false")),
+                ("NSNCD_IGNORE_INITGROUPS", Some("true")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert!(!config.should_ignore(&RequestType::GETPWBYNAME));
+                assert!(config.should_ignore(&RequestType::INITGROUPS));
+            },
+        );
+        with_vars(
+            vec![
+                ("NSNCD_IGNORE_PASSWD", Some("true")),
+                ("NSNCD_IGNORE_INITGROUPS", Some("false")),
+            ],
+            || {
+                let config = Config::from_env().unwrap();
+                assert!(config.should_ignore(&RequestType::GETPWBYNAME));
+                assert!(!config.should_ignore(&RequestType::INITGROUPS));
+            },
+        );
+        with_vars(
+            vec![
+                ("NSNCD_IGNORE_PASSWD", Some("true")),
+                ("NSNCD_IGNORE_INITGROUPS", Some("true")),
+            ],
+            || {
                 let config = Config::from_env().unwrap();
                 assert!(config.should_ignore(&RequestType::GETPWBYNAME));
                 assert!(config.should_ignore(&RequestType::GETPWBYUID));
+                assert!(config.should_ignore(&RequestType::BATCHGETPWBYUID));
                 assert!(!config.should_ignore(&RequestType::GETGRBYGID));
                 assert!(config.should_ignore(&RequestType::INITGROUPS));
             },
@@ -397,4 +1822,23 @@ mod test {
             },
         );
     }
+
+    #[test]
+    fn test_stat_version() {
+        with_var_unset("NSNCD_STAT_VERSION", || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.stat_version, None);
+        });
+        with_var("NSNCD_STAT_VERSION", Some("Jan  1 2024 12:00:00"), || {
+            let config = Config::from_env().unwrap();
+            assert_eq!(config.stat_version.as_deref(), Some("Jan  1 2024 12:00:00"));
+        });
+        with_var(
+            "NSNCD_STAT_VERSION",
+            Some("Jan  1 2024 12:00:00 UTC"),
+            || {
+                assert!(Config::from_env().is_err());
+            },
+        );
+    }
 }
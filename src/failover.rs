@@ -0,0 +1,113 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Failing over to a secondary nscd-compatible daemon.
+//!
+//! When a lookup fails because the backend is in trouble (e.g. LDAP timing
+//! out), the request can be retried against another nsncd or nscd listening
+//! on a different socket, which may have a working backend or a cache.
+//! "Not found" is an answer, not a failure, so it's never retried.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use slog::{debug, Logger};
+
+use super::config::Config;
+use super::protocol::Request;
+
+/// How long to wait for the secondary to take the request and answer it.
+const SECONDARY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether `err`, returned when handling `request`, should be retried
+/// against the secondary: one is configured, the request's database doesn't
+/// opt out, and the error came from the backend (an errno) rather than from
+/// the request itself.
+pub fn should_fail_over(config: &Config, request: &Request, err: &anyhow::Error) -> bool {
+    config.secondary_socket.is_some()
+        && !config.failover_bypass_types.contains(&request.ty)
+        && err.chain().any(|cause| cause.is::<nix::Error>())
+}
+
+/// Send `request` to the daemon listening on `path` and return its response.
+pub fn forward(log: &Logger, path: &Path, request: &Request) -> Result<Vec<u8>> {
+    debug!(log, "failing over to secondary"; "path" => %path.display());
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("connecting to secondary {}", path.display()))?;
+    stream.set_read_timeout(Some(SECONDARY_TIMEOUT))?;
+    stream.set_write_timeout(Some(SECONDARY_TIMEOUT))?;
+    stream
+        .write_all(&request.to_bytes())
+        .context("sending request to secondary")?;
+    let mut response = vec![];
+    stream
+        .read_to_end(&mut response)
+        .context("reading response from secondary")?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+
+    use crate::protocol::RequestType;
+
+    #[test]
+    fn test_should_fail_over() {
+        let request = Request::new(RequestType::GETPWBYNAME, b"alice\0");
+        let backend_error = anyhow::Error::from(nix::Error::EIO).context("looking up user");
+        let bad_request = anyhow::format_err!("invalid uid string");
+
+        let config = Config::default();
+        assert!(!should_fail_over(&config, &request, &backend_error));
+
+        let mut config = Config {
+            secondary_socket: Some("/run/nscd/secondary".into()),
+            ..Config::default()
+        };
+        assert!(should_fail_over(&config, &request, &backend_error));
+        assert!(!should_fail_over(&config, &request, &bad_request));
+
+        config
+            .failover_bypass_types
+            .insert(&RequestType::GETPWBYNAME);
+        assert!(!should_fail_over(&config, &request, &backend_error));
+    }
+
+    #[test]
+    fn test_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secondary");
+        let listener = UnixListener::bind(&path).unwrap();
+        let secondary = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 64];
+            let n = stream.read(&mut buf).unwrap();
+            stream.write_all(b"answer").unwrap();
+            buf[..n].to_vec()
+        });
+
+        let log = Logger::root(slog::Discard, slog::o!());
+        let request = Request::new(RequestType::GETGRBYNAME, b"staff\0");
+        assert_eq!(forward(&log, &path, &request).unwrap(), b"answer");
+        assert_eq!(secondary.join().unwrap(), request.to_bytes());
+    }
+}
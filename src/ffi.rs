@@ -20,6 +20,7 @@ use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::mem;
 use std::ptr;
+use std::sync::Mutex;
 
 #[allow(non_camel_case_types)]
 type size_t = ::std::os::raw::c_ulonglong;
@@ -59,6 +60,34 @@ pub enum LibcIp {
     V6([u8; 16]),
 }
 
+/// Make this process look hosts up in DNS alone, whatever nsswitch.conf
+/// says, so that `/etc/hosts` (or any other source) is never consulted.
+///
+/// This calls the internal glibc function `__nss_configure_lookup`, which
+/// overrides the nsswitch.conf line of a database for the whole process. It
+/// affects every host lookup function, `getaddrinfo()` included. If it isn't
+/// there (e.g. with another libc), we can't enforce anything, so that's an
+/// error rather than a silent no-op.
+pub fn restrict_hosts_to_dns() -> anyhow::Result<()> {
+    unsafe {
+        let sym_name = CString::new("__nss_configure_lookup").unwrap();
+        let sym_ptr = dlsym(RTLD_DEFAULT, sym_name.as_ptr());
+        if sym_ptr.is_null() {
+            return Err(anyhow!("this libc can't restrict host lookups to DNS"));
+        }
+        let __nss_configure_lookup = mem::transmute::<
+            *mut libc::c_void,
+            extern "C" fn(dbname: *const libc::c_char, string: *const libc::c_char) -> libc::c_int,
+        >(sym_ptr);
+        let dbname = CString::new("hosts").unwrap();
+        let string = CString::new("dns").unwrap();
+        if __nss_configure_lookup(dbname.as_ptr(), string.as_ptr()) != 0 {
+            return Err(anyhow!("could not restrict host lookups to DNS"));
+        }
+    }
+    Ok(())
+}
+
 mod glibcffi {
     use nix::libc;
     extern "C" {
@@ -82,6 +111,43 @@ mod glibcffi {
             result: *mut *mut libc::hostent,
             h_errnop: *mut libc::c_int,
         ) -> libc::c_int;
+
+        pub fn getservbyname_r(
+            name: *const libc::c_char,
+            proto: *const libc::c_char,
+            result_buf: *mut libc::servent,
+            buf: *mut libc::c_char,
+            buflen: libc::size_t,
+            result: *mut *mut libc::servent,
+        ) -> libc::c_int;
+
+        pub fn getservbyport_r(
+            port: libc::c_int,
+            proto: *const libc::c_char,
+            result_buf: *mut libc::servent,
+            buf: *mut libc::c_char,
+            buflen: libc::size_t,
+            result: *mut *mut libc::servent,
+        ) -> libc::c_int;
+
+        pub fn setnetgrent(netgroup: *const libc::c_char) -> libc::c_int;
+
+        pub fn getnetgrent_r(
+            hostp: *mut *mut libc::c_char,
+            userp: *mut *mut libc::c_char,
+            domainp: *mut *mut libc::c_char,
+            buffer: *mut libc::c_char,
+            buflen: libc::size_t,
+        ) -> libc::c_int;
+
+        pub fn endnetgrent();
+
+        pub fn innetgr(
+            netgroup: *const libc::c_char,
+            host: *const libc::c_char,
+            user: *const libc::c_char,
+            domain: *const libc::c_char,
+        ) -> libc::c_int;
     }
 }
 
@@ -98,7 +164,6 @@ mod glibcffi {
 pub struct Hostent {
     pub name: CString,
     pub aliases: Vec<CString>,
-    pub addr_type: i32,
     pub addr_list: Vec<std::net::IpAddr>,
     pub herrno: i32,
 }
@@ -113,7 +178,6 @@ impl Hostent {
         Hostent {
             name: CString::default(),
             aliases: Vec::new(),
-            addr_type: -1,
             addr_list: Vec::new(),
             herrno,
         }
@@ -187,7 +251,6 @@ fn from_libc_hostent(value: libc::hostent) -> Result<Hostent, HostentError> {
     Ok(Hostent {
         name: name.to_owned(),
         aliases,
-        addr_type: value.h_addrtype,
         addr_list,
         // If we're here, glibc gave us an hostent. We should discard herrno to match the nscd behaviour.
         herrno: 0,
@@ -296,6 +359,274 @@ pub fn gethostbyname2_r(name: String, af: libc::c_int) -> Result<Hostent, Hosten
     unmarshal_gethostbyxx(hostent_result, herrno)
 }
 
+/// The Rust counterpart of `libc::servent`, as returned by the
+/// getservbyxxx functions.
+#[derive(Clone, Debug)]
+pub struct Servent {
+    pub name: CString,
+    pub proto: CString,
+    pub aliases: Vec<CString>,
+    /// The port, in network byte order.
+    pub port: libc::c_int,
+}
+
+fn from_libc_servent(value: libc::servent) -> anyhow::Result<Servent> {
+    if value.s_name.is_null() || value.s_proto.is_null() {
+        return Err(anyhow!("s_name or s_proto is null"));
+    }
+    let name = unsafe { CStr::from_ptr(value.s_name) };
+    let proto = unsafe { CStr::from_ptr(value.s_proto) };
+
+    let mut aliases: Vec<CString> = Vec::new();
+    let mut s_alias_ptr = value.s_aliases as *const *const libc::c_char;
+    while !s_alias_ptr.is_null() && !(unsafe { *s_alias_ptr }).is_null() {
+        aliases.push(unsafe { CStr::from_ptr(*s_alias_ptr).to_owned() });
+        unsafe { s_alias_ptr = s_alias_ptr.add(1) };
+    }
+
+    Ok(Servent {
+        name: name.to_owned(),
+        proto: proto.to_owned(),
+        aliases,
+        port: value.s_port,
+    })
+}
+
+/// Calls a getservbyxx_r glibc function through `lookup`, which gets the
+/// result struct, the buffer and its length, and the result pointer, growing
+/// the buffer until the entry fits.
+fn getservbyxx_r<F>(mut lookup: F) -> anyhow::Result<Option<Servent>>
+where
+    F: FnMut(
+        *mut libc::servent,
+        *mut libc::c_char,
+        libc::size_t,
+        *mut *mut libc::servent,
+    ) -> libc::c_int,
+{
+    let mut ret_servent: libc::servent = libc::servent {
+        s_name: ptr::null_mut(),
+        s_aliases: ptr::null_mut(),
+        s_port: 0,
+        s_proto: ptr::null_mut(),
+    };
+    let mut servent_result = ptr::null_mut();
+    // Same 1024 bytes starting point as the host lookups.
+    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    let ret = loop {
+        let ret = lookup(
+            &mut ret_servent,
+            buf.as_mut_ptr() as *mut libc::c_char,
+            (buf.capacity() as size_t).try_into().unwrap(),
+            &mut servent_result,
+        );
+        if ret == libc::ERANGE && buf.capacity() < 10 * 1000 * 1000 {
+            buf.reserve(buf.capacity() * 2);
+        } else {
+            break ret;
+        }
+    };
+    if servent_result.is_null() {
+        // ENOENT is how "no such service" is reported.
+        return match ret {
+            0 | libc::ENOENT => Ok(None),
+            errno => Err(nix::Error::from_raw(errno).into()),
+        };
+    }
+    from_libc_servent(unsafe { *servent_result }).map(Some)
+}
+
+/// Typesafe wrapper around the getservbyname_r glibc function
+///
+/// Returns `None` if there's no such service. A `None` proto matches any
+/// protocol.
+pub fn getservbyname_r(name: &CStr, proto: Option<&CStr>) -> anyhow::Result<Option<Servent>> {
+    let proto = proto.map_or(ptr::null(), |proto| proto.as_ptr());
+    getservbyxx_r(|result_buf, buf, buflen, result| unsafe {
+        glibcffi::getservbyname_r(name.as_ptr(), proto, result_buf, buf, buflen, result)
+    })
+}
+
+/// Typesafe wrapper around the getservbyport_r glibc function
+///
+/// port is in network byte order, like in `struct servent`. Returns `None` if
+/// there's no such service. A `None` proto matches any protocol.
+pub fn getservbyport_r(port: libc::c_int, proto: Option<&CStr>) -> anyhow::Result<Option<Servent>> {
+    let proto = proto.map_or(ptr::null(), |proto| proto.as_ptr());
+    getservbyxx_r(|result_buf, buf, buflen, result| unsafe {
+        glibcffi::getservbyport_r(port, proto, result_buf, buf, buflen, result)
+    })
+}
+
+/// The Rust counterpart of `libc::spwd`, as returned by getspnam_r.
+///
+/// The `Debug` output leaves out the password hash, so entries can be logged.
+#[derive(Clone)]
+pub struct Spwd {
+    pub name: CString,
+    pub passwd: CString,
+    pub last_change: libc::c_long,
+    pub min: libc::c_long,
+    pub max: libc::c_long,
+    pub warn: libc::c_long,
+    pub inactive: libc::c_long,
+    pub expire: libc::c_long,
+    pub flag: libc::c_ulong,
+}
+
+impl std::fmt::Debug for Spwd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Spwd")
+            .field("name", &self.name)
+            .field("last_change", &self.last_change)
+            .field("min", &self.min)
+            .field("max", &self.max)
+            .field("warn", &self.warn)
+            .field("inactive", &self.inactive)
+            .field("expire", &self.expire)
+            .field("flag", &self.flag)
+            .finish()
+    }
+}
+
+/// Typesafe wrapper around the getspnam_r glibc function
+///
+/// Returns `None` if there's no such user in the shadow database.
+pub fn getspnam_r(name: &CStr) -> anyhow::Result<Option<Spwd>> {
+    let mut ret_spwd: libc::spwd = unsafe { mem::zeroed() };
+    let mut spwd_result = ptr::null_mut();
+    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    let ret = loop {
+        let ret = unsafe {
+            libc::getspnam_r(
+                name.as_ptr(),
+                &mut ret_spwd,
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.capacity(),
+                &mut spwd_result,
+            )
+        };
+        if ret == libc::ERANGE && buf.capacity() < 10 * 1000 * 1000 {
+            buf.reserve(buf.capacity() * 2);
+        } else {
+            break ret;
+        }
+    };
+    if spwd_result.is_null() {
+        // ENOENT is how "no such user" is reported.
+        return match ret {
+            0 | libc::ENOENT => Ok(None),
+            errno => Err(nix::Error::from_raw(errno).into()),
+        };
+    }
+    let value = unsafe { *spwd_result };
+    if value.sp_namp.is_null() || value.sp_pwdp.is_null() {
+        return Err(anyhow!("sp_namp or sp_pwdp is null"));
+    }
+    Ok(Some(Spwd {
+        name: unsafe { CStr::from_ptr(value.sp_namp) }.to_owned(),
+        passwd: unsafe { CStr::from_ptr(value.sp_pwdp) }.to_owned(),
+        last_change: value.sp_lstchg,
+        min: value.sp_min,
+        max: value.sp_max,
+        warn: value.sp_warn,
+        inactive: value.sp_inact,
+        expire: value.sp_expire,
+        flag: value.sp_flag,
+    }))
+}
+
+/// A member of a netgroup. `None` fields are wildcards, matching anything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetgroupTriple {
+    pub host: Option<CString>,
+    pub user: Option<CString>,
+    pub domain: Option<CString>,
+}
+
+/// glibc keeps the state of the setnetgrent/getnetgrent_r/endnetgrent
+/// iteration in a global, so only one thread can go through a netgroup at a
+/// time. innetgr is documented as racing with it, so it takes this too.
+static NETGROUP_ITERATION: Mutex<()> = Mutex::new(());
+
+fn from_netgrent_field(field: *const libc::c_char) -> Option<CString> {
+    if field.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(field) }.to_owned())
+    }
+}
+
+/// Typesafe wrapper around the setnetgrent/getnetgrent_r/endnetgrent glibc
+/// functions
+///
+/// Returns every member of `netgroup`, with the netgroups it includes
+/// expanded by glibc, or `None` if there's no such netgroup.
+pub fn getnetgrent(netgroup: &CStr) -> Option<Vec<NetgroupTriple>> {
+    // a thread panicking while holding the lock left the iteration state
+    // behind, but setnetgrent resets it anyway.
+    let _iteration = NETGROUP_ITERATION.lock().unwrap_or_else(|e| e.into_inner());
+    if unsafe { glibcffi::setnetgrent(netgroup.as_ptr()) } == 0 {
+        unsafe { glibcffi::endnetgrent() };
+        return None;
+    }
+    let mut triples = vec![];
+    // Same 1024 bytes starting point as the other lookups.
+    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    loop {
+        let mut host = ptr::null_mut();
+        let mut user = ptr::null_mut();
+        let mut domain = ptr::null_mut();
+        nix::errno::Errno::clear();
+        let ret = unsafe {
+            glibcffi::getnetgrent_r(
+                &mut host,
+                &mut user,
+                &mut domain,
+                buf.as_mut_ptr() as *mut libc::c_char,
+                (buf.capacity() as size_t).try_into().unwrap(),
+            )
+        };
+        if ret == 1 {
+            triples.push(NetgroupTriple {
+                host: from_netgrent_field(host),
+                user: from_netgrent_field(user),
+                domain: from_netgrent_field(domain),
+            });
+        } else if nix::errno::Errno::last() == nix::errno::Errno::ERANGE
+            && buf.capacity() < 10 * 1000 * 1000
+        {
+            // The member didn't fit, and is still the next one.
+            buf.reserve(buf.capacity() * 2);
+        } else {
+            break;
+        }
+    }
+    unsafe { glibcffi::endnetgrent() };
+    Some(triples)
+}
+
+/// Typesafe wrapper around the innetgr glibc function
+///
+/// `None` fields are wildcards, like NULL pointers in C.
+pub fn innetgr(
+    netgroup: &CStr,
+    host: Option<&CStr>,
+    user: Option<&CStr>,
+    domain: Option<&CStr>,
+) -> bool {
+    let as_ptr = |field: Option<&CStr>| field.map_or(ptr::null(), |field| field.as_ptr());
+    let _iteration = NETGROUP_ITERATION.lock().unwrap_or_else(|e| e.into_inner());
+    unsafe {
+        glibcffi::innetgr(
+            netgroup.as_ptr(),
+            as_ptr(host),
+            as_ptr(user),
+            as_ptr(domain),
+        ) == 1
+    }
+}
+
 #[test]
 fn test_gethostbyname2_r() {
     disable_internal_nscd();
@@ -319,3 +650,43 @@ fn test_gethostbyaddr_r() {
     let v6test = LibcIp::V6([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
     let _ = gethostbyaddr_r(v6test).expect("Should resolve IPv6 localhost with gethostbyaddr");
 }
+
+#[test]
+fn test_restrict_hosts_to_dns_available() {
+    // actually calling it would change how every other test resolves hosts,
+    // so just make sure it won't fail at startup.
+    let sym_name = CString::new("__nss_configure_lookup").unwrap();
+    let sym_ptr = unsafe { dlsym(RTLD_DEFAULT, sym_name.as_ptr()) };
+    assert!(
+        !sym_ptr.is_null(),
+        "__nss_configure_lookup should be in glibc"
+    );
+}
+
+#[test]
+fn test_getservbyxx_r() {
+    disable_internal_nscd();
+
+    let name = CString::new("nsncd-no-such-service").unwrap();
+    let proto = CString::new("tcp").unwrap();
+    assert!(getservbyname_r(&name, Some(&proto)).unwrap().is_none());
+    // port 0 is reserved, so it can't name a service.
+    assert!(getservbyport_r(0, Some(&proto)).unwrap().is_none());
+}
+
+#[test]
+fn test_getspnam_r() {
+    disable_internal_nscd();
+
+    let name = CString::new("nsncd-no-such-user").unwrap();
+    assert!(getspnam_r(&name).unwrap().is_none());
+}
+
+#[test]
+fn test_getnetgrent() {
+    disable_internal_nscd();
+
+    let netgroup = CString::new("nsncd-no-such-netgroup").unwrap();
+    assert_eq!(getnetgrent(&netgroup), None);
+    assert!(!innetgr(&netgroup, None, None, None));
+}
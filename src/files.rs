@@ -0,0 +1,380 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! In-memory tables of passwd and group entries, read from files in the
+//! `passwd(5)` and `group(5)` formats.
+
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{ensure, Context, Result};
+use nix::unistd::{Gid, Group, Uid, User};
+
+/// A set of passwd and group entries. Lookups return the first matching
+/// entry, like the NSS `files` module does.
+#[derive(Clone, Default)]
+pub struct Table {
+    pub users: Vec<User>,
+    pub groups: Vec<Group>,
+    /// The lines that weren't loaded, and why, e.g. `passwd line 3: ...`.
+    pub skipped: Vec<String>,
+}
+
+impl Table {
+    /// Load a table from a passwd file and/or a group file.
+    pub fn load(passwd: Option<&Path>, group: Option<&Path>) -> Result<Self> {
+        let read = |path: Option<&Path>| -> Result<Vec<u8>> {
+            match path {
+                Some(path) => {
+                    std::fs::read(path).with_context(|| format!("reading {}", path.display()))
+                }
+                None => Ok(vec![]),
+            }
+        };
+        Ok(Table::parse(&read(passwd)?, &read(group)?))
+    }
+
+    /// Parse the contents of a `passwd(5)` file and a `group(5)` file.
+    ///
+    /// Like the NSS `files` module, we skip the lines we can't make sense of,
+    /// listing them in [Table::skipped], rather than refuse the whole file.
+    /// That includes NIS compat entries (`+user`, `-@netgroup`, `+::::::`),
+    /// which only mean something to the `compat` module.
+    pub fn parse(passwd: &[u8], group: &[u8]) -> Self {
+        let mut skipped = vec![];
+        let users = parse_entries("passwd", passwd, parse_passwd_line, &mut skipped);
+        let groups = parse_entries("group", group, parse_group_line, &mut skipped);
+        Table {
+            users,
+            groups,
+            skipped,
+        }
+    }
+
+    pub fn user_by_name(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|u| u.name == name)
+    }
+
+    pub fn user_by_uid(&self, uid: Uid) -> Option<&User> {
+        self.users.iter().find(|u| u.uid == uid)
+    }
+
+    pub fn group_by_name(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|g| g.name == name)
+    }
+
+    pub fn group_by_gid(&self, gid: Gid) -> Option<&Group> {
+        self.groups.iter().find(|g| g.gid == gid)
+    }
+}
+
+// Only print the sizes: the entries may be numerous, and may contain password
+// hashes we don't want in the logs.
+impl std::fmt::Debug for Table {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Table")
+            .field("users", &self.users.len())
+            .field("groups", &self.groups.len())
+            .field("skipped", &self.skipped.len())
+            .finish()
+    }
+}
+
+/// A [Table] of the entries in the local passwd and group files, reloaded
+/// when either file changes.
+///
+/// Reloads happen in [LocalFiles::refresh], which is meant to be called
+/// periodically. The new table is swapped in whole, so lookups never see a
+/// mix of old and new entries.
+pub struct LocalFiles {
+    passwd: PathBuf,
+    group: PathBuf,
+    table: RwLock<Arc<Table>>,
+    /// What the files looked like when `table` was loaded from them. Holding
+    /// this lock also keeps concurrent refreshes from racing each other.
+    stamps: Mutex<[FileStamp; 2]>,
+}
+
+/// Enough of a file's metadata to tell that it changed, whether it was edited
+/// in place (new mtime or size) or replaced by a rename (new inode).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileStamp {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Result<Self> {
+        let meta = std::fs::metadata(path).with_context(|| format!("stat {}", path.display()))?;
+        Ok(Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+        })
+    }
+}
+
+impl LocalFiles {
+    pub fn load(passwd: &Path, group: &Path) -> Result<Self> {
+        let stamps = [FileStamp::of(passwd)?, FileStamp::of(group)?];
+        let table = Table::load(Some(passwd), Some(group))?;
+        Ok(Self {
+            passwd: passwd.to_owned(),
+            group: group.to_owned(),
+            table: RwLock::new(Arc::new(table)),
+            stamps: Mutex::new(stamps),
+        })
+    }
+
+    /// The current table.
+    pub fn table(&self) -> Arc<Table> {
+        self.table.read().unwrap().clone()
+    }
+
+    /// Reload the table if either file changed since it was last loaded.
+    /// Returns whether it was reloaded.
+    ///
+    /// If the files can't be read or parsed (e.g. we caught an editor
+    /// half-way through rewriting one), the current table is kept, and the
+    /// next refresh tries again.
+    pub fn refresh(&self) -> Result<bool> {
+        let mut stamps = self.stamps.lock().unwrap();
+        // stat before reading: if a file changes while we're reading it, the
+        // stamp we keep is already out of date, and the next refresh reloads.
+        let current = [FileStamp::of(&self.passwd)?, FileStamp::of(&self.group)?];
+        if current == *stamps {
+            return Ok(false);
+        }
+        let table = Table::load(Some(&self.passwd), Some(&self.group))?;
+        *self.table.write().unwrap() = Arc::new(table);
+        *stamps = current;
+        Ok(true)
+    }
+}
+
+impl std::fmt::Debug for LocalFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalFiles")
+            .field("passwd", &self.passwd)
+            .field("group", &self.group)
+            .field("table", &self.table())
+            .finish()
+    }
+}
+
+/// Parse the entries of a file with `parse`, skipping empty and comment
+/// lines, and describing in `skipped` the other lines that aren't entries.
+fn parse_entries<T>(
+    file: &str,
+    contents: &[u8],
+    parse: fn(&[u8]) -> Result<T>,
+    skipped: &mut Vec<String>,
+) -> Vec<T> {
+    let mut entries = vec![];
+    for (i, line) in contents.split(|b| *b == b'\n').enumerate() {
+        let line = line.trim_ascii_end();
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let entry = if line.starts_with(b"+") || line.starts_with(b"-") {
+            Err(anyhow::format_err!("NIS compat entries aren't supported"))
+        } else {
+            parse(line)
+        };
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(e) => skipped.push(format!("{} line {}: {:#}", file, i + 1, e)),
+        }
+    }
+    entries
+}
+
+/// The fields of a `:`-separated line, of which there must be `count`.
+fn fields(line: &[u8], count: usize) -> Result<Vec<&[u8]>> {
+    let fields: Vec<&[u8]> = line.split(|b| *b == b':').collect();
+    ensure!(
+        fields.len() == count,
+        "expected {} fields, got {}",
+        count,
+        fields.len()
+    );
+    Ok(fields)
+}
+
+fn parse_id(field: &[u8]) -> Result<u32> {
+    Ok(std::str::from_utf8(field)?.parse()?)
+}
+
+fn parse_passwd_line(line: &[u8]) -> Result<User> {
+    let fields = fields(line, 7)?;
+    Ok(User {
+        name: String::from_utf8(fields[0].to_vec()).context("invalid name")?,
+        passwd: CString::new(fields[1])?,
+        uid: Uid::from_raw(parse_id(fields[2]).context("invalid uid")?),
+        gid: Gid::from_raw(parse_id(fields[3]).context("invalid gid")?),
+        gecos: CString::new(fields[4])?,
+        dir: OsStr::from_bytes(fields[5]).into(),
+        shell: OsStr::from_bytes(fields[6]).into(),
+    })
+}
+
+fn parse_group_line(line: &[u8]) -> Result<Group> {
+    let fields = fields(line, 4)?;
+    let mem = if fields[3].is_empty() {
+        vec![]
+    } else {
+        fields[3]
+            .split(|b| *b == b',')
+            .map(|member| String::from_utf8(member.to_vec()))
+            .collect::<Result<_, _>>()
+            .context("invalid member name")?
+    };
+    Ok(Group {
+        name: String::from_utf8(fields[0].to_vec()).context("invalid name")?,
+        passwd: CString::new(fields[1])?,
+        gid: Gid::from_raw(parse_id(fields[2]).context("invalid gid")?),
+        mem,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_passwd() {
+        let users = Table::parse(
+            b"# pinned accounts\n\
+              svc:x:4242:4242:Service Account:/srv/svc:/usr/sbin/nologin\n\
+              \n\
+              empty:*:4243:4243:::\n",
+            b"",
+        )
+        .users;
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].name, "svc");
+        assert_eq!(users[0].uid, Uid::from_raw(4242));
+        assert_eq!(users[0].gecos, CString::new("Service Account").unwrap());
+        assert_eq!(users[0].shell, Path::new("/usr/sbin/nologin"));
+        assert_eq!(users[1].gecos, CString::default());
+        assert_eq!(users[1].dir, Path::new(""));
+    }
+
+    #[test]
+    fn test_parse_group() {
+        let groups = Table::parse(b"", b"svc:x:4242:\nadmins:x:4300:alice,bob\n").groups;
+        assert_eq!(groups.len(), 2);
+        assert!(groups[0].mem.is_empty());
+        assert_eq!(groups[1].gid, Gid::from_raw(4300));
+        assert_eq!(groups[1].mem, vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn test_parse_skips_bad_lines() {
+        let table = Table::parse(
+            b"svc:x:4242:4242\n\
+              bad:x:notanumber:4242:::\n\
+              +@admins::::::\n\
+              -mallory::::::\n\
+              +::::::\n\
+              latin1:x:4243:4243:Ren\xe9:/home/latin1:/bin/sh\n",
+            b"admins:x:4300\n+:::\nstaff:x:4301:alice\n",
+        );
+        // the gecos isn't UTF-8, but it's only bytes to us.
+        assert_eq!(table.users.len(), 1);
+        assert_eq!(table.users[0].name, "latin1");
+        assert_eq!(table.users[0].gecos.as_bytes(), b"Ren\xe9");
+        assert_eq!(table.groups.len(), 1);
+        assert_eq!(table.groups[0].name, "staff");
+        assert_eq!(
+            table.skipped,
+            vec![
+                "passwd line 1: expected 7 fields, got 4",
+                "passwd line 2: invalid uid: invalid digit found in string",
+                "passwd line 3: NIS compat entries aren't supported",
+                "passwd line 4: NIS compat entries aren't supported",
+                "passwd line 5: NIS compat entries aren't supported",
+                "group line 1: expected 4 fields, got 3",
+                "group line 2: NIS compat entries aren't supported",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_lookups() {
+        let table = Table::parse(b"a:x:1:1:::\nb:x:2:2:::\na:x:3:3:::\n", b"g:x:10:a\n");
+        assert_eq!(table.user_by_name("a").unwrap().uid, Uid::from_raw(1));
+        assert_eq!(table.user_by_uid(Uid::from_raw(2)).unwrap().name, "b");
+        assert!(table.user_by_name("c").is_none());
+        assert_eq!(table.group_by_gid(Gid::from_raw(10)).unwrap().name, "g");
+        assert!(table.group_by_name("h").is_none());
+    }
+
+    #[test]
+    fn test_local_files_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let passwd = dir.path().join("passwd");
+        let group = dir.path().join("group");
+        std::fs::write(&passwd, "a:x:1:1:::\n").unwrap();
+        std::fs::write(&group, "g:x:10:a\n").unwrap();
+
+        let local = LocalFiles::load(&passwd, &group).unwrap();
+        assert_eq!(
+            local.table().user_by_name("a").unwrap().uid,
+            Uid::from_raw(1)
+        );
+        assert!(local.table().user_by_name("b").is_none());
+        assert!(!local.refresh().unwrap(), "nothing changed");
+
+        // edited in place.
+        std::fs::write(&passwd, "a:x:1:1:::\nb:x:2:2:::\n").unwrap();
+        assert!(local.refresh().unwrap());
+        assert_eq!(
+            local.table().user_by_name("b").unwrap().uid,
+            Uid::from_raw(2)
+        );
+
+        // replaced by a rename, like vipw and most package managers do.
+        let new_group = dir.path().join("group.new");
+        std::fs::write(&new_group, "h:x:11:\n").unwrap();
+        std::fs::rename(&new_group, &group).unwrap();
+        assert!(local.refresh().unwrap());
+        assert!(local.table().group_by_name("g").is_none());
+        assert_eq!(
+            local.table().group_by_name("h").unwrap().gid,
+            Gid::from_raw(11)
+        );
+
+        // a line we can't parse is skipped, and the rest still loaded.
+        std::fs::write(&passwd, "+::::::\na:x:1\nb:x:2:2:::\n").unwrap();
+        assert!(local.refresh().unwrap());
+        assert!(local.table().user_by_name("b").is_some());
+        assert_eq!(local.table().skipped.len(), 2);
+
+        // a file we can't read keeps the last good table.
+        std::fs::remove_file(&passwd).unwrap();
+        assert!(local.refresh().is_err());
+        assert!(local.table().user_by_name("b").is_some());
+    }
+}
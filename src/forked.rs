@@ -0,0 +1,643 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Looking passwd and group entries up in child processes.
+//!
+//! Some NSS modules (older `nss_ldap`, vendor ones) aren't thread-safe, or
+//! leak memory on every lookup. [Forked] makes the lookups in a pool of
+//! child processes instead, each answering one lookup at a time. A module
+//! that crashes only takes its child down, and the child is replaced for
+//! the next lookup; children are also replaced after [MAX_LOOKUPS] lookups,
+//! so that a leak can't grow for ever. A child stuck in a lookup can be
+//! killed too, see [Forked::kill_stuck], and is replaced just the same.
+//!
+//! The children are new instances of nsncd started with [CHILD_ENV] set,
+//! rather than bare forks of this one: a fork of a process with threads
+//! starts out with whatever locks the other threads held, NSS's included.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::ffi::{CStr, CString, OsStr};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel as channel;
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{Gid, Group, Pid, Uid, User};
+
+use super::backend::Backend;
+
+/// Set in the environment of the children, which makes them answer lookups
+/// on their standard input instead of starting a daemon.
+pub const CHILD_ENV: &str = "NSNCD_LOOKUP_CHILD";
+
+/// How many lookups a child answers before it's replaced.
+const MAX_LOOKUPS: usize = 10_000;
+
+/// The longest message we read. A group with many members can take a few
+/// megabytes, but a length beyond this means the other end is confused.
+const MAX_MESSAGE_LEN: usize = 64 << 20;
+
+/// A backend making its lookups in child processes.
+pub struct Forked {
+    command: Box<dyn Fn() -> Command + Send + Sync>,
+    /// A slot for each child: the child waiting for a lookup, or `None` if
+    /// it has yet to be started.
+    slots: (channel::Sender<Slot>, channel::Receiver<Slot>),
+    /// The pids of the children making a lookup, and since when. A child is
+    /// only reaped after it's taken out, so its pid can't be reused while
+    /// it's in here.
+    busy: Mutex<HashMap<u32, Instant>>,
+}
+
+type Slot = Option<Process>;
+
+impl Forked {
+    /// Make lookups in up to `children` processes at once, each started by
+    /// `command`. Children are started as they're needed.
+    pub fn new<F>(children: usize, command: F) -> Self
+    where
+        F: Fn() -> Command + Send + Sync + 'static,
+    {
+        let slots = channel::bounded(children);
+        for _ in 0..children {
+            slots.0.send(None).expect("room for every slot");
+        }
+        Self {
+            command: Box::new(command),
+            slots,
+            busy: Mutex::default(),
+        }
+    }
+
+    /// Make lookups in up to `children` instances of the running nsncd.
+    pub fn nsncd(children: usize) -> Self {
+        // rather than the path we were started from, which an upgrade may
+        // have replaced with another version.
+        Self::new(children, || Command::new("/proc/self/exe"))
+    }
+
+    fn start(&self) -> io::Result<Process> {
+        let (socket, theirs) = UnixStream::pair()?;
+        let child = (self.command)()
+            .env(CHILD_ENV, "1")
+            .stdin(Stdio::from(OwnedFd::from(theirs)))
+            .stdout(Stdio::null())
+            .spawn()?;
+        Ok(Process {
+            child,
+            socket,
+            lookups: 0,
+        })
+    }
+
+    fn run<T: Wire>(&self, lookup: Lookup) -> nix::Result<T> {
+        // lookups wait here while every child is busy.
+        let slot = self.slots.1.recv().expect("we hold a sender");
+        let (slot, result) = match slot.map_or_else(|| self.start(), Ok) {
+            Ok(mut process) => match self.ask(&mut process, &lookup) {
+                Ok(result) => {
+                    process.lookups += 1;
+                    ((process.lookups < MAX_LOOKUPS).then_some(process), result)
+                }
+                // it crashed, or sent something we can't read: a new one
+                // answers the next lookup.
+                Err(_) => (None, Err(Errno::EIO)),
+            },
+            Err(e) => (None, Err(io_errno(&e))),
+        };
+        self.slots.0.send(slot).expect("we hold a receiver");
+        result
+    }
+
+    fn ask<T: Wire>(&self, process: &mut Process, lookup: &Lookup) -> io::Result<nix::Result<T>> {
+        let pid = process.child.id();
+        self.busy.lock().unwrap().insert(pid, Instant::now());
+        let result = process.ask(lookup);
+        self.busy.lock().unwrap().remove(&pid);
+        result
+    }
+}
+
+impl Backend for Forked {
+    fn user_by_uid(&self, uid: Uid) -> nix::Result<Option<User>> {
+        self.run(Lookup::UserByUid(uid))
+    }
+
+    fn user_by_name(&self, name: &str) -> nix::Result<Option<User>> {
+        self.run(Lookup::UserByName(name.to_string()))
+    }
+
+    fn group_by_gid(&self, gid: Gid) -> nix::Result<Option<Group>> {
+        self.run(Lookup::GroupByGid(gid))
+    }
+
+    fn group_by_name(&self, name: &str) -> nix::Result<Option<Group>> {
+        self.run(Lookup::GroupByName(name.to_string()))
+    }
+
+    fn group_list(&self, user: &CStr, group: Gid) -> nix::Result<Vec<Gid>> {
+        self.run(Lookup::GroupList(user.to_owned(), group))
+    }
+
+    /// Kill the children that have been making a lookup for longer than
+    /// `threshold`. The lookup fails with `EIO`, and a new child takes the
+    /// killed one's place.
+    fn kill_stuck(&self, threshold: Duration) -> usize {
+        let mut busy = self.busy.lock().unwrap();
+        let stuck: Vec<u32> = busy
+            .iter()
+            .filter(|(_, since)| since.elapsed() > threshold)
+            .map(|(pid, _)| *pid)
+            .collect();
+        for pid in &stuck {
+            busy.remove(pid);
+            let _ = kill(Pid::from_raw(*pid as i32), Signal::SIGKILL);
+        }
+        stuck.len()
+    }
+}
+
+impl std::fmt::Debug for Forked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Forked").finish_non_exhaustive()
+    }
+}
+
+/// A running child, and our end of its standard input.
+struct Process {
+    child: Child,
+    socket: UnixStream,
+    lookups: usize,
+}
+
+impl Process {
+    fn ask<T: Wire>(&mut self, lookup: &Lookup) -> io::Result<nix::Result<T>> {
+        let mut request = vec![];
+        lookup.put(&mut request);
+        write_message(&mut self.socket, &request)?;
+        let reply = read_message(&mut self.socket)?;
+        Reader(&reply).finish()
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // it may be stuck in a lookup, so don't wait for it to notice that
+        // we're gone.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Answer the lookups of the nsncd that started us on our standard input,
+/// with `backend`, until it's done with us.
+pub fn serve_stdin(backend: &dyn Backend) -> io::Result<()> {
+    // Forked::start made our stdin a socket, and nothing else uses it.
+    let socket = UnixStream::from(unsafe { OwnedFd::from_raw_fd(0) });
+    serve(backend, socket)
+}
+
+fn serve(backend: &dyn Backend, mut socket: UnixStream) -> io::Result<()> {
+    loop {
+        let request = match read_message(&mut socket) {
+            Ok(request) => request,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut reply = vec![];
+        match Reader(&request).finish()? {
+            Lookup::UserByUid(uid) => backend.user_by_uid(uid).put(&mut reply),
+            Lookup::UserByName(name) => backend.user_by_name(&name).put(&mut reply),
+            Lookup::GroupByGid(gid) => backend.group_by_gid(gid).put(&mut reply),
+            Lookup::GroupByName(name) => backend.group_by_name(&name).put(&mut reply),
+            Lookup::GroupList(user, group) => backend.group_list(&user, group).put(&mut reply),
+        }
+        write_message(&mut socket, &reply)?;
+    }
+}
+
+/// The errno behind an I/O error, for the callers of a [Backend].
+fn io_errno(e: &io::Error) -> Errno {
+    e.raw_os_error().map_or(Errno::EIO, Errno::from_raw)
+}
+
+fn write_message(w: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(message.len()).map_err(|_| io::Error::from(ErrorKind::InvalidInput))?;
+    w.write_all(&len.to_ne_bytes())?;
+    w.write_all(message)
+}
+
+fn read_message(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_ne_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    let mut message = vec![0; len];
+    r.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// A lookup we ask a child to make.
+#[derive(Debug, PartialEq, Eq)]
+enum Lookup {
+    UserByUid(Uid),
+    UserByName(String),
+    GroupByGid(Gid),
+    GroupByName(String),
+    GroupList(CString, Gid),
+}
+
+/// Something sent between nsncd and its children. Both ends are the same
+/// build on the same machine, so numbers are native-endian, and nothing is
+/// versioned.
+trait Wire: Sized {
+    fn put(&self, out: &mut Vec<u8>);
+    fn get(r: &mut Reader) -> io::Result<Self>;
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.0.len() < n {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    /// Read all of a message as a `T`.
+    fn finish<T: Wire>(mut self) -> io::Result<T> {
+        let value = T::get(&mut self)?;
+        if !self.0.is_empty() {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        Ok(value)
+    }
+}
+
+fn invalid<E>(_: E) -> io::Error {
+    ErrorKind::InvalidData.into()
+}
+
+impl Wire for u32 {
+    fn put(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_ne_bytes());
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        Ok(u32::from_ne_bytes(r.take(4)?.try_into().unwrap()))
+    }
+}
+
+impl Wire for Vec<u8> {
+    fn put(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).put(out);
+        out.extend_from_slice(self);
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        let len = u32::get(r)? as usize;
+        Ok(r.take(len)?.to_vec())
+    }
+}
+
+impl Wire for String {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.as_bytes().to_vec().put(out);
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        String::from_utf8(Vec::get(r)?).map_err(invalid)
+    }
+}
+
+impl Wire for CString {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.as_bytes().to_vec().put(out);
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        CString::new(Vec::get(r)?).map_err(invalid)
+    }
+}
+
+impl Wire for PathBuf {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.as_os_str().as_bytes().to_vec().put(out);
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        Ok(PathBuf::from(OsStr::from_bytes(&Vec::get(r)?)))
+    }
+}
+
+impl Wire for Uid {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.as_raw().put(out);
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        Ok(Uid::from_raw(u32::get(r)?))
+    }
+}
+
+impl Wire for Gid {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.as_raw().put(out);
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        Ok(Gid::from_raw(u32::get(r)?))
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn put(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).put(out);
+        for item in self {
+            item.put(out);
+        }
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        let len = u32::get(r)?;
+        (0..len).map(|_| T::get(r)).collect()
+    }
+}
+
+impl<T: Wire> Wire for Option<T> {
+    fn put(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.put(out);
+            }
+        }
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        match r.take(1)?[0] {
+            0 => Ok(None),
+            1 => Ok(Some(T::get(r)?)),
+            _ => Err(ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
+/// A lookup's result: zero and the value, or the errno it failed with.
+impl<T: Wire> Wire for nix::Result<T> {
+    fn put(&self, out: &mut Vec<u8>) {
+        match self {
+            Ok(value) => {
+                0u32.put(out);
+                value.put(out);
+            }
+            Err(errno) => (*errno as u32).put(out),
+        }
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        match u32::get(r)? {
+            0 => Ok(Ok(T::get(r)?)),
+            errno => Ok(Err(Errno::from_raw(errno as i32))),
+        }
+    }
+}
+
+impl Wire for User {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.name.put(out);
+        self.passwd.put(out);
+        self.uid.put(out);
+        self.gid.put(out);
+        self.gecos.put(out);
+        self.dir.put(out);
+        self.shell.put(out);
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        Ok(User {
+            name: Wire::get(r)?,
+            passwd: Wire::get(r)?,
+            uid: Wire::get(r)?,
+            gid: Wire::get(r)?,
+            gecos: Wire::get(r)?,
+            dir: Wire::get(r)?,
+            shell: Wire::get(r)?,
+        })
+    }
+}
+
+impl Wire for Group {
+    fn put(&self, out: &mut Vec<u8>) {
+        self.name.put(out);
+        self.passwd.put(out);
+        self.gid.put(out);
+        self.mem.put(out);
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        Ok(Group {
+            name: Wire::get(r)?,
+            passwd: Wire::get(r)?,
+            gid: Wire::get(r)?,
+            mem: Wire::get(r)?,
+        })
+    }
+}
+
+impl Wire for Lookup {
+    fn put(&self, out: &mut Vec<u8>) {
+        match self {
+            Lookup::UserByUid(uid) => {
+                out.push(0);
+                uid.put(out);
+            }
+            Lookup::UserByName(name) => {
+                out.push(1);
+                name.put(out);
+            }
+            Lookup::GroupByGid(gid) => {
+                out.push(2);
+                gid.put(out);
+            }
+            Lookup::GroupByName(name) => {
+                out.push(3);
+                name.put(out);
+            }
+            Lookup::GroupList(user, group) => {
+                out.push(4);
+                user.put(out);
+                group.put(out);
+            }
+        }
+    }
+
+    fn get(r: &mut Reader) -> io::Result<Self> {
+        Ok(match r.take(1)?[0] {
+            0 => Lookup::UserByUid(Wire::get(r)?),
+            1 => Lookup::UserByName(Wire::get(r)?),
+            2 => Lookup::GroupByGid(Wire::get(r)?),
+            3 => Lookup::GroupByName(Wire::get(r)?),
+            4 => Lookup::GroupList(Wire::get(r)?, Wire::get(r)?),
+            _ => return Err(ErrorKind::InvalidData.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nix::unistd::getgrouplist;
+
+    use crate::backend::Nss;
+
+    /// Like [Nss], but crashes on the user "boom", and hangs on the user
+    /// "hang", as broken modules would.
+    struct Crashing;
+
+    impl Backend for Crashing {
+        fn user_by_uid(&self, uid: Uid) -> nix::Result<Option<User>> {
+            Nss.user_by_uid(uid)
+        }
+
+        fn user_by_name(&self, name: &str) -> nix::Result<Option<User>> {
+            match name {
+                "boom" => std::process::abort(),
+                "hang" => loop {
+                    std::thread::sleep(Duration::from_secs(60));
+                },
+                _ => {}
+            }
+            Nss.user_by_name(name)
+        }
+
+        fn group_by_gid(&self, gid: Gid) -> nix::Result<Option<Group>> {
+            Nss.group_by_gid(gid)
+        }
+
+        fn group_by_name(&self, name: &str) -> nix::Result<Option<Group>> {
+            Nss.group_by_name(name)
+        }
+
+        fn group_list(&self, user: &CStr, group: Gid) -> nix::Result<Vec<Gid>> {
+            getgrouplist(user, group)
+        }
+    }
+
+    /// Where the children of the other tests start: this test binary, run
+    /// for this test only.
+    #[test]
+    fn test_child() {
+        if std::env::var_os(CHILD_ENV).is_some() {
+            serve_stdin(&Crashing).unwrap();
+        }
+    }
+
+    fn forked(children: usize) -> Forked {
+        Forked::new(children, || {
+            let mut command = Command::new(std::env::current_exe().unwrap());
+            command.args(["--exact", "forked::test::test_child", "--test-threads=1"]);
+            command
+        })
+    }
+
+    #[test]
+    fn test_lookups() {
+        let backend = forked(2);
+        let root = backend.user_by_uid(Uid::from_raw(0)).unwrap().unwrap();
+        assert_eq!(root.name, "root");
+        assert_eq!(root, backend.user_by_name("root").unwrap().unwrap());
+        let group = backend.group_by_gid(Gid::from_raw(0)).unwrap().unwrap();
+        assert_eq!(Nss.group_by_gid(Gid::from_raw(0)).unwrap().unwrap(), group);
+        assert_eq!(backend.group_by_name("nosuchgroup"), Ok(None));
+        let user = CString::new("root").unwrap();
+        assert_eq!(
+            backend.group_list(&user, Gid::from_raw(0)),
+            getgrouplist(&user, Gid::from_raw(0))
+        );
+    }
+
+    #[test]
+    fn test_crashed_child_replaced() {
+        let backend = forked(1);
+        assert!(backend.user_by_name("root").unwrap().is_some());
+        assert_eq!(backend.user_by_name("boom"), Err(Errno::EIO));
+        // the only child crashed, and a new one took its place.
+        assert!(backend.user_by_name("root").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_stuck_child_killed() {
+        let backend = std::sync::Arc::new(forked(1));
+        let backend2 = backend.clone();
+        let hung = std::thread::spawn(move || backend2.user_by_name("hang"));
+        // nothing's stuck for that long...
+        assert_eq!(backend.kill_stuck(Duration::from_secs(60)), 0);
+        // ...but the child's been at it for longer than this, once it starts.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while backend.kill_stuck(Duration::ZERO) == 0 {
+            assert!(Instant::now() < deadline, "the lookup never started");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(hung.join().unwrap(), Err(Errno::EIO));
+        assert!(backend.user_by_name("root").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_child_not_started() {
+        let backend = Forked::new(1, || Command::new("/nonexistent"));
+        assert_eq!(backend.user_by_uid(Uid::from_raw(0)), Err(Errno::ENOENT));
+        // its slot is free for another attempt.
+        assert_eq!(backend.user_by_uid(Uid::from_raw(0)), Err(Errno::ENOENT));
+    }
+
+    #[test]
+    fn test_wire() {
+        let user = Nss.user_by_uid(Uid::from_raw(0));
+        let mut buf = vec![];
+        user.put(&mut buf);
+        assert_eq!(
+            Reader(&buf).finish::<nix::Result<Option<User>>>().unwrap(),
+            user
+        );
+        let failed: nix::Result<Vec<Gid>> = Err(Errno::ETIMEDOUT);
+        let mut buf = vec![];
+        failed.put(&mut buf);
+        assert_eq!(
+            Reader(&buf).finish::<nix::Result<Vec<Gid>>>().unwrap(),
+            failed
+        );
+        // truncated, or with something after the value.
+        assert!(Reader(&buf[..2]).finish::<nix::Result<Vec<Gid>>>().is_err());
+        buf.push(0);
+        assert!(Reader(&buf).finish::<nix::Result<Vec<Gid>>>().is_err());
+    }
+}
@@ -14,26 +14,36 @@
  * limitations under the License.
  */
 
+use std::borrow::Cow;
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
-use std::net::IpAddr;
+use std::net::{IpAddr, UdpSocket};
 use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
 use atoi::atoi;
-use dns_lookup::AddrInfoHints;
-use nix::libc::{AI_CANONNAME, SOCK_STREAM};
+use dns_lookup::{AddrInfoHints, LookupErrorKind};
+use nix::errno::Errno;
+use nix::libc::{c_ulong, AI_CANONNAME, SOCK_STREAM};
 use nix::sys::socket::AddressFamily;
-use nix::unistd::{getgrouplist, Gid, Group, Uid, User};
-use slog::{debug, error, Logger};
+use nix::unistd::{Gid, Group, Uid, User};
+use slog::{debug, error, info, o, warn, Logger};
 use std::mem::size_of;
 
-use crate::ffi::{gethostbyaddr_r, gethostbyname2_r, Hostent, LibcIp, HostentError};
+use crate::ffi::{
+    gethostbyaddr_r, gethostbyname2_r, getnetgrent, getservbyname_r, getservbyport_r, getspnam_r,
+    innetgr, Hostent, HostentError, LibcIp, NetgroupTriple, Servent, Spwd,
+};
 use crate::protocol::{AiResponse, AiResponseHeader};
 
-use super::config::Config;
+use super::cache::Cached;
+use super::config::{Config, MemberOverflow};
+use super::files;
 use super::protocol;
-use super::protocol::RequestType;
+use super::protocol::{EscapedKey, RequestType};
 
 /// Handle a request by performing the appropriate lookup and sending the
 /// serialized response back to the client.
@@ -48,37 +58,343 @@ pub fn handle_request(
     config: &Config,
     request: &protocol::Request,
 ) -> Result<Vec<u8>> {
+    // names differing only in case are the same lookup, and the same cached
+    // reply.
+    let folded_key;
+    let folded_request;
+    let request = if config.fold_name_case && has_name_key(request.ty) {
+        folded_key = request.key.to_ascii_lowercase();
+        folded_request = protocol::Request {
+            key: &folded_key,
+            ..*request
+        };
+        &folded_request
+    } else {
+        request
+    };
+    // Lookups are shared with identical concurrent requests, and maybe
+    // cached, unless their database or client must always see fresh answers.
+    let key = match cache_key(request) {
+        Some(key) if request.ty.stat_database().is_some() => key,
+        _ => return lookup_and_write(log, config, request),
+    };
+    let shared = !config.should_bypass_cache_for(request);
+    let cached = shared && config.cache.caches(request.ty);
+    if cached {
+        if let Some(cached) = config.cache.get(request.ty, &key) {
+            debug!(log, "answered from cache");
+            if let Cached::Expiring(_) | Cached::Stale { refresh: true, .. } = cached {
+                refresh(log, config, request, &key);
+            }
+            let reply = cached.reply();
+            let found = protocol::reply_found(request.ty, reply) == Some(true);
+            config.stats.record_cache_hit(request.ty, found);
+            let mut response = config.buffers.checkout();
+            response.extend_from_slice(reply);
+            return Ok(response);
+        }
+        config.stats.record_cache_miss();
+    }
+    let generation = config.cache.generation(request.ty);
+    let respond = || {
+        let answer = lookup(log, config, request)?;
+        let transient = answer.is_transient();
+        let mut response = config.buffers.checkout();
+        answer.write(&mut response)?;
+        if cached && !transient {
+            if let Some(found) = protocol::reply_found(request.ty, &response) {
+                config
+                    .cache
+                    .insert(request.ty, &key, &response, found, generation);
+            }
+        }
+        Ok(response)
+    };
+    if shared {
+        config.in_flight.run(request.ty, &key, respond)
+    } else {
+        respond()
+    }
+}
+
+fn lookup_and_write(log: &Logger, config: &Config, request: &protocol::Request) -> Result<Vec<u8>> {
+    let mut response = config.buffers.checkout();
+    lookup(log, config, request)?.write(&mut response)?;
+    Ok(response)
+}
+
+/// The key the replies to `request` are cached and shared under. Replies
+/// that depend on who's asking are only ever shared with the same user, so
+/// that what was looked up for root is never given to anyone else. `None`
+/// if they can't be shared at all, because we don't know who's asking.
+fn cache_key<'a>(request: &protocol::Request<'a>) -> Option<Cow<'a, [u8]>> {
+    if !request.ty.depends_on_peer() {
+        return Some(Cow::Borrowed(request.key));
+    }
+    let uid = request.peer_uid?;
+    let mut key = uid.as_raw().to_ne_bytes().to_vec();
+    key.extend_from_slice(request.key);
+    Some(Cow::Owned(key))
+}
+
+/// Look `request` up again in the background, to replace the expiring or
+/// stale reply just served from the cache under `cache_key`.
+fn refresh(log: &Logger, config: &Config, request: &protocol::Request, cache_key: &[u8]) {
+    let (ty, peer_uid) = (request.ty, request.peer_uid);
+    let (request_key, key) = (request.key.to_vec(), cache_key.to_vec());
+    let (log2, config2) = (log.clone(), config.clone());
+    let refreshing = config.cache.refresh(Box::new(move || {
+        let (log, config) = (log2, config2);
+        let mut request = protocol::Request::new(ty, &request_key);
+        request.peer_uid = peer_uid;
+        let generation = config.cache.generation(ty);
+        let mut response = vec![];
+        // a panic mustn't take the refresh thread down, or leave the reply
+        // claimed until it's too stale to serve.
+        let refreshed = panic::catch_unwind(AssertUnwindSafe(|| -> Result<Option<bool>> {
+            let answer = lookup(&log, &config, &request)?;
+            if answer.is_transient() {
+                return Ok(None);
+            }
+            answer.write(&mut response)?;
+            Ok(protocol::reply_found(ty, &response))
+        }));
+        match refreshed {
+            Ok(Ok(Some(found))) => config.cache.insert(ty, &key, &response, found, generation),
+            Ok(Ok(None)) => config.cache.unclaim(ty, &key),
+            Ok(Err(e)) => {
+                debug!(log, "refreshing stale reply failed"; "error" => %e);
+                config.cache.unclaim(ty, &key);
+            }
+            Err(_) => {
+                error!(log, "refreshing stale reply panicked");
+                config.cache.unclaim(ty, &key);
+            }
+        }
+    }));
+    if !refreshing {
+        debug!(log, "too many replies being refreshed");
+        config.cache.unclaim(request.ty, cache_key);
+    }
+}
+
+/// Drop what we keep for `database`, one of [protocol::DATABASES], and run
+/// the invalidation hooks, for an INVALIDATE request or a change to the files
+/// behind it.
+pub fn invalidate(log: &Logger, config: &Config, database: &str) {
+    let dropped = config.cache.invalidate(database);
+    config.in_flight.invalidate(database);
+    info!(log, "invalidating database";
+        "database" => database, "cached_entries" => dropped);
+    config.invalidation_hooks.run(log, database);
+    warm_up(log, config, &[database]);
+}
+
+/// Look the entries of `databases` in `config.warm` up in the background, so
+/// they're cached before a client asks for them. Those that aren't cached
+/// are left alone.
+pub fn warm_up(log: &Logger, config: &Config, databases: &[&str]) {
+    let requests: Vec<_> = databases
+        .iter()
+        .flat_map(|database| config.warm.requests(database))
+        .filter(|(ty, _)| config.cache.caches(*ty) && !config.should_bypass_cache(ty))
+        .collect();
+    if requests.is_empty() {
+        return;
+    }
+    let (thread_log, config) = (log.new(o!("thread" => "warm_up")), config.clone());
+    let spawned = std::thread::Builder::new()
+        .name("warm_up".to_string())
+        .spawn(move || {
+            let log = thread_log;
+            let mut failed = 0;
+            for (ty, key) in &requests {
+                let request = protocol::Request::new(*ty, key);
+                if let Err(e) = handle_request(&log, &config, &request) {
+                    debug!(log, "warming up failed";
+                        "request" => ?request, "error" => %e);
+                    failed += 1;
+                }
+            }
+            info!(log, "warmed up cache"; "entries" => requests.len(), "failed" => failed);
+        });
+    if let Err(e) = spawned {
+        error!(log, "spawning warm-up thread"; "err" => %e);
+    }
+}
+
+/// The answer to a request, before it's serialized to the wire.
+#[derive(Debug)]
+pub enum Response {
+    /// No reply at all: the connection is closed without one.
+    None,
+    Pw(Option<User>),
+    PwBatch(Vec<Option<User>>),
+    Sp(Option<Spwd>),
+    Gr(Option<Group>),
+    Initgroups(Vec<Gid>),
+    Hst(Hostent),
+    Ai(AiResponse),
+    /// A GETAI answer with no addresses, failed with this `h_errno`.
+    AiError(i32),
+    Serv(Option<Servent>),
+    Netgr(Option<Vec<NetgroupTriple>>),
+    Innetgr(bool),
+    Stat(Box<protocol::StatResponse>),
+    /// The acknowledgement of an INVALIDATE request.
+    Errno(i32),
+}
+
+impl Response {
+    /// Whether this is a "not found" that may not be one a moment later,
+    /// like a DNS timeout, and so mustn't be cached.
+    fn is_transient(&self) -> bool {
+        match self {
+            Response::AiError(herrno) => *herrno == protocol::H_ERRNO_TRY_AGAIN,
+            Response::Hst(hostent) => hostent.herrno == protocol::H_ERRNO_TRY_AGAIN,
+            _ => false,
+        }
+    }
+
+    /// Serialize the response to the wire, appending it to `out`.
+    pub fn write(self, out: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Response::None => Ok(()),
+            Response::Pw(user) => write_user(out, user),
+            Response::PwBatch(users) => write_user_batch(out, users),
+            Response::Sp(spwd) => write_shadow(out, spwd),
+            Response::Gr(group) => write_group(out, group),
+            Response::Initgroups(groups) => write_initgroups(out, groups),
+            Response::Hst(hostent) => write_hostent(out, hostent),
+            Response::Ai(resp) => write_address_info(out, resp),
+            Response::AiError(error) => {
+                let header = AiResponseHeader {
+                    error,
+                    ..protocol::AI_RESPONSE_HEADER_NOT_FOUND
+                };
+                out.extend_from_slice(header.as_slice());
+                Ok(())
+            }
+            Response::Serv(servent) => write_servent(out, servent),
+            Response::Netgr(triples) => write_netgroup(out, triples),
+            Response::Innetgr(result) => {
+                let header = protocol::InnetgroupResponseHeader {
+                    version: protocol::VERSION,
+                    found: 1,
+                    result: result.into(),
+                };
+                out.extend_from_slice(header.as_slice());
+                Ok(())
+            }
+            Response::Stat(stats) => {
+                out.extend_from_slice(stats.as_slice());
+                Ok(())
+            }
+            Response::Errno(errno) => {
+                out.extend_from_slice(&errno.to_ne_bytes());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Handle a request by performing the appropriate lookup, and return its
+/// answer without serializing it. Its key must already be folded to lower
+/// case by [handle_request] if need be.
+pub fn lookup(log: &Logger, config: &Config, request: &protocol::Request) -> Result<Response> {
     if config.should_ignore(&request.ty) {
         debug!(log, "ignoring request"; "request" => ?request);
-        return Ok(vec![]);
+        return Ok(Response::None);
+    }
+    if config.strict_keys {
+        request.check_key().context("malformed key")?;
     }
     debug!(log, "handling request"; "request" => ?request);
     match request.ty {
         RequestType::GETPWBYUID => {
             let key = CStr::from_bytes_with_nul(request.key)?;
             let uid = atoi(key.to_bytes()).context("invalid uid string")?;
-            let user = User::from_uid(Uid::from_raw(uid))?;
+            let user = user_by_uid(config, Uid::from_raw(uid))?;
             debug!(log, "got user"; "user" => ?user);
-            serialize_user(user)
+            if config.detect_duplicate_uids {
+                check_duplicate_uid(log, config, user.as_ref());
+            }
+            Ok(Response::Pw(check_user(log, user)))
         }
         RequestType::GETPWBYNAME => {
             let key = CStr::from_bytes_with_nul(request.key)?;
-            let user = User::from_name(key.to_str()?)?;
+            let name = key.to_str()?;
+            let user = user_by_name(config, name)?;
             debug!(log, "got user"; "user" => ?user);
-            serialize_user(user)
+            if config.detect_name_conflicts {
+                check_user_conflict(log, config, name, user.as_ref());
+            }
+            Ok(Response::Pw(check_user(log, user)))
+        }
+        RequestType::BATCHGETPWBYUID => {
+            let users = request
+                .key
+                .split_inclusive(|b| *b == 0)
+                .map(|key| {
+                    let key = CStr::from_bytes_with_nul(key)?;
+                    let uid = atoi(key.to_bytes()).context("invalid uid string")?;
+                    user_by_uid(config, Uid::from_raw(uid))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            debug!(log, "got users"; "users" => ?users);
+            Ok(Response::PwBatch(
+                users.into_iter().map(|u| check_user(log, u)).collect(),
+            ))
+        }
+        RequestType::BATCHGETPWBYNAME => {
+            let users = protocol::split_key(request.key)?
+                .into_iter()
+                .map(|key| {
+                    let name = key.to_str()?;
+                    let user = user_by_name(config, name)?;
+                    if config.detect_name_conflicts {
+                        check_user_conflict(log, config, name, user.as_ref());
+                    }
+                    Ok(user)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            debug!(log, "got users"; "users" => ?users);
+            Ok(Response::PwBatch(
+                users.into_iter().map(|u| check_user(log, u)).collect(),
+            ))
         }
         RequestType::GETGRBYGID => {
             let key = CStr::from_bytes_with_nul(request.key)?;
             let gid = atoi(key.to_bytes()).context("invalid gid string")?;
-            let group = Group::from_gid(Gid::from_raw(gid))?;
+            let group = group_by_gid(config, Gid::from_raw(gid))?;
             debug!(log, "got group"; "group" => ?group);
-            serialize_group(group)
+            let mut group = check_group(log, group);
+            if let (true, Some(found)) = (config.reconcile_group_members, group.as_mut()) {
+                let by_name = check_group(log, group_by_name(config, &found.name)?);
+                reconcile_members(log, found, by_name.as_ref());
+            }
+            if !limit_members(log, config, group.as_mut()) {
+                return Ok(Response::None);
+            }
+            Ok(Response::Gr(group))
         }
         RequestType::GETGRBYNAME => {
             let key = CStr::from_bytes_with_nul(request.key)?;
-            let group = Group::from_name(key.to_str()?)?;
+            let name = key.to_str()?;
+            let group = group_by_name(config, name)?;
             debug!(log, "got group"; "group" => ?group);
-            serialize_group(group)
+            let mut group = check_group(log, group);
+            if let (true, Some(found)) = (config.reconcile_group_members, group.as_mut()) {
+                let by_gid = check_group(log, group_by_gid(config, found.gid)?);
+                reconcile_members(log, found, by_gid.as_ref());
+            }
+            if config.detect_name_conflicts {
+                check_group_conflict(log, config, name, group.as_ref());
+            }
+            if !limit_members(log, config, group.as_mut()) {
+                return Ok(Response::None);
+            }
+            Ok(Response::Gr(group))
         }
         RequestType::INITGROUPS => {
             // initgroups is a little strange: in the public libc API, the
@@ -116,34 +432,95 @@ pub fn handle_request(
             // GETGR*, which causes the process to skip nsncd for all future
             // lookups. So, in this theoretical case, we log our perfidy and
             // return an empty list.
+            //
+            // The key is only the username: glibc's client adds the GID it
+            // passed to getgrouplist() to our reply itself, so it never sends
+            // it along.
+            //
+            // The getgrouplist() calls themselves go through
+            // config.initgroups, which limits how many run at once.
             let key = CStr::from_bytes_with_nul(request.key)?;
-            let user = User::from_name(key.to_str()?)?;
+            let user = user_by_name(config, key.to_str()?)?;
             debug!(log, "got user"; "user" => ?user);
-            let groups = if let Some(user) = user {
-                getgrouplist(key, user.gid).unwrap_or_else(|e| {
-                    error!(log, "nix::getgrouplist failed, returning empty list"; "err" => %e);
-                    vec![]
-                })
-            } else {
-                vec![]
+            let group_list = |group| {
+                config
+                    .backends
+                    .group
+                    .group_list(key, group)
+                    .unwrap_or_else(|e| {
+                        error!(log, "getgrouplist failed, returning empty list"; "err" => %e);
+                        vec![]
+                    })
+            };
+            let groups = match user {
+                Some(user) => config.initgroups.get(|| group_list(user.gid)),
+                None => vec![],
             };
-            serialize_initgroups(groups)
+            Ok(Response::Initgroups(groups))
         }
 
-        // There's no cache to invalidate
+        // The key is the name of the database to invalidate. The only thing
+        // we cache is the response cache, if enabled; the rest is up to the
+        // hooks. The client waits for an errno as an acknowledgement, 0 if
+        // all went well.
         RequestType::INVALIDATE => {
-            debug!(log, "received invalidate request, ignoring");
-            Ok(vec![])
+            let database = CStr::from_bytes_with_nul(request.key)?.to_str().ok();
+            let errno = match database.filter(|db| protocol::DATABASES.contains(db)) {
+                Some(database) => {
+                    invalidate(log, config, database);
+                    0
+                }
+                None => {
+                    warn!(log, "ignoring invalidate request for unknown database";
+                        "database" => %EscapedKey(request.key));
+                    nix::libc::EINVAL
+                }
+            };
+            Ok(Response::Errno(errno))
+        }
+
+        RequestType::GETSPBYNAME => {
+            if !config.serve_shadow {
+                debug!(log, "shadow lookups are disabled");
+                return Ok(Response::None);
+            }
+            // only root can read the shadow database, so only root may ask
+            // us to read it on its behalf.
+            if !request.peer_uid.is_some_and(|uid| uid.is_root()) {
+                warn!(log, "ignoring shadow lookup from non-root client";
+                    "uid" => ?request.peer_uid);
+                return Ok(Response::None);
+            }
+            let key = CStr::from_bytes_with_nul(request.key)?;
+            let spwd =
+                getspnam_r(key).with_context(|| format!("looking up shadow entry of {:?}", key))?;
+            debug!(log, "got shadow entry"; "spwd" => ?spwd);
+            Ok(Response::Sp(spwd))
         }
 
-        // We don't want clients to be able to shut down nsncd.
+        // Like nscd, only root may stop us. The client doesn't wait for a
+        // reply either way.
         RequestType::SHUTDOWN => {
-            debug!(log, "received shutdown request, ignoring");
-            Ok(vec![])
+            if request.peer_uid.is_some_and(|uid| uid.is_root()) {
+                info!(log, "shutting down at a client's request");
+                config.shutdown.request();
+            } else {
+                warn!(log, "ignoring shutdown request from non-root client";
+                    "uid" => ?request.peer_uid);
+            }
+            Ok(Response::None)
         }
 
         RequestType::GETAI => {
-            let hostname = CStr::from_bytes_with_nul(request.key)?.to_str()?;
+            let hostname = match parse_hostname(log, config, request.key)? {
+                Some(hostname) => hostname,
+                None => {
+                    return Ok(Response::Ai(AiResponse {
+                        canon_name: String::new(),
+                        addrs: vec![],
+                    }))
+                }
+            };
             // Boths hints are necessary to mimick the glibc behaviour.
             let hints = AddrInfoHints {
                 // The canonical name will be filled in the first
@@ -158,6 +535,11 @@ pub fn handle_request(
                 address: 0,
                 protocol: 0,
             };
+            // The addresses are served in the order getaddrinfo returns
+            // them. We don't sort them by RFC 6724 preference ourselves:
+            // the client's getaddrinfo sorts whatever nscd answers, with its
+            // own source addresses and gai.conf, which we can't see. Nor
+            // does the client sort gethostbyname results, so neither do we.
             let resp = dns_lookup::getaddrinfo(Some(hostname), None, Some(hints));
             let ai_resp_empty = AiResponse {
                 canon_name: hostname.to_string(),
@@ -170,31 +552,49 @@ pub fn handle_request(
                     // canonical name should be stored in the first
                     // addrinfo struct.
                     // Re-using the request hostname if we don't get a
-                    // canonical name.
+                    // canonical name. The client hands it out as the
+                    // ai_canonname of AI_CANONNAME lookups, which must
+                    // never be left unset.
                     let canon_name = ai_resp_iter
                         .peek()
                         .and_then(|e| e.canonname.to_owned())
                         .unwrap_or(hostname.to_string());
-                    let addrs: Vec<IpAddr> = ai_resp_iter.map(|e| e.sockaddr.ip()).collect();
+                    let mut addrs: Vec<IpAddr> = ai_resp_iter.map(|e| e.sockaddr.ip()).collect();
+                    if config.ai_usable_families_only {
+                        let families = UsableFamilies::probe();
+                        debug!(log, "filtering addresses"; "families" => ?families);
+                        families.filter(&mut addrs);
+                    }
+                    if config.exclude_link_local {
+                        addrs.retain(|addr| !is_link_local_v6(addr));
+                    }
 
                     AiResponse { canon_name, addrs }
                 }
-                Err(_) => ai_resp_empty,
+                Err(e) => match e.kind() {
+                    // the name doesn't exist, or has no addresses.
+                    LookupErrorKind::NoName | LookupErrorKind::NoData => ai_resp_empty,
+                    // the name servers didn't answer. that's not an answer
+                    // either, so tell the client to try again, like nscd.
+                    LookupErrorKind::Again => {
+                        warn!(log, "temporary failure resolving host"; "err" => ?e);
+                        return Ok(Response::AiError(protocol::H_ERRNO_TRY_AGAIN));
+                    }
+                    _ => {
+                        return Err(std::io::Error::from(e))
+                            .with_context(|| format!("resolving {:?}", hostname))
+                    }
+                },
             };
 
-            serialize_address_info(ai_resp)
+            Ok(Response::Ai(ai_resp))
         }
 
         // GETHOSTBYADDR and GETHOSTBYADDRv6 implement reverse lookup
         // The key contains the address to look for.
-        RequestType::GETHOSTBYADDR => {
-            let key = request.key;
-
-            if key.len() != 4 {
-                bail!("Invalid key len: {}, expected 4", key.len());
-            }
-            let address_bytes: [u8; 4] = key.try_into()?;
-            let hostent = match gethostbyaddr_r(LibcIp::V4(address_bytes)) {
+        RequestType::GETHOSTBYADDR | RequestType::GETHOSTBYADDRv6 => {
+            let address = parse_address_key(request.ty, request.key)?;
+            let hostent = match gethostbyaddr_r(address) {
                 Ok(hostent) => hostent,
                 Err(HostentError::HError(herror)) => Hostent::error_value(herror),
                 Err(HostentError::Other(e)) =>
@@ -206,33 +606,18 @@ pub fn handle_request(
                     bail!("unexpected gethostbyaddr error: {}", e)
                 }
             };
-
-            serialize_hostent(hostent)
+            Ok(Response::Hst(hostent))
         }
-        RequestType::GETHOSTBYADDRv6 => {
-            let key = request.key;
 
-            if key.len() != 16 {
-                bail!("Invalid key len: {}, expected 16", key.len());
-            }
-            let address_bytes: [u8; 16] = key.try_into()?;
-            let hostent = match gethostbyaddr_r(LibcIp::V6(address_bytes)) {
-                Ok(hostent) => hostent,
-                Err(HostentError::HError(herror)) => Hostent::error_value(herror),
-                Err(HostentError::Other(e)) =>
-                // We shouldn't end up in that branch. Something
-                // got very very wrong on the glibc client side if
-                // we do. It's okay to bail, there's nothing much
-                // we can do.
-                {
-                    bail!("unexpected gethostbyaddrv6 error: {}", e)
+        RequestType::GETHOSTBYNAME => {
+            let hostname = match parse_hostname(log, config, request.key)? {
+                Some(hostname) => hostname,
+                None => {
+                    return Ok(Response::Hst(Hostent::error_value(
+                        protocol::H_ERRNO_HOST_NOT_FOUND,
+                    )))
                 }
             };
-            serialize_hostent(hostent)
-        }
-
-        RequestType::GETHOSTBYNAME => {
-            let hostname = CStr::from_bytes_with_nul(request.key)?.to_str()?;
             let hostent = match gethostbyname2_r(hostname.to_string(), nix::libc::AF_INET) {
                 Ok(hostent) => hostent,
                 Err(HostentError::HError(herror)) => Hostent::error_value(herror),
@@ -245,11 +630,18 @@ pub fn handle_request(
                     bail!("unexpected gethostbyname error: {:?}", e)
                 }
             };
-            serialize_hostent(hostent)
+            Ok(Response::Hst(drop_link_local_hostent(config, hostent)))
         }
 
         RequestType::GETHOSTBYNAMEv6 => {
-            let hostname = CStr::from_bytes_with_nul(request.key)?.to_str()?;
+            let hostname = match parse_hostname(log, config, request.key)? {
+                Some(hostname) => hostname,
+                None => {
+                    return Ok(Response::Hst(Hostent::error_value(
+                        protocol::H_ERRNO_HOST_NOT_FOUND,
+                    )))
+                }
+            };
             let hostent = match gethostbyname2_r(hostname.to_string(), nix::libc::AF_INET6) {
                 Ok(hostent) => hostent,
                 Err(HostentError::HError(herror)) => Hostent::error_value(herror),
@@ -262,36 +654,516 @@ pub fn handle_request(
                     bail!("unexpected gethostbynamev6 error: {:?}", e)
                 }
             };
-            serialize_hostent(hostent)
+            Ok(Response::Hst(drop_link_local_hostent(config, hostent)))
+        }
+
+        RequestType::GETSERVBYNAME => {
+            let (name, proto) = parse_service_key(request.key)?;
+            let name = CString::new(name)?;
+            let proto = proto.map(CString::new).transpose()?;
+            let servent = getservbyname_r(&name, proto.as_deref())
+                .with_context(|| format!("looking up service {:?}", name))?;
+            Ok(Response::Serv(servent))
         }
 
+        // The key is "port/proto", with the port in network byte order,
+        // formatted as a decimal int.
+        RequestType::GETSERVBYPORT => {
+            let (port, proto) = parse_service_key(request.key)?;
+            let port = atoi(port).context("invalid port string")?;
+            let proto = proto.map(CString::new).transpose()?;
+            let servent = getservbyport_r(port, proto.as_deref()).with_context(|| {
+                format!("looking up service on port {}", u16::from_be(port as u16))
+            })?;
+            Ok(Response::Serv(servent))
+        }
+
+        RequestType::GETNETGRENT => {
+            let netgroup = CStr::from_bytes_with_nul(request.key)?;
+            Ok(Response::Netgr(getnetgrent(netgroup)))
+        }
+
+        RequestType::INNETGR => {
+            let fields = request.key_fields()?;
+            let host = parse_innetgr_field(fields[1])?;
+            let user = parse_innetgr_field(fields[2])?;
+            let domain = parse_innetgr_field(fields[3])?;
+            Ok(Response::Innetgr(innetgr(fields[0], host, user, domain)))
+        }
+
+        RequestType::GETSTAT => Ok(Response::Stat(Box::new(serialize_stats(config)))),
+
         // These will normally send an FD pointing to the internal cache structure,
         // which clients use to look into the cache contents on their own.
-        // We don't cache, and we don't want clients to poke around in cache structures either.
-        // Luckily clients fall back to explicit queries if no FDs are sent over.
+        // Even with the response cache on, we don't want clients to poke
+        // around in cache structures: the mapping's layout (the hash table,
+        // datahead records and GC counters in nscd/nscd.h) is glibc-private,
+        // changes between glibc versions without a version bump clients could
+        // check, and is read without any validation, so a layout mismatch or a
+        // torn update becomes a wrong answer or a crash in every process. A
+        // request to the response cache costs one local round trip instead.
+        // Closing the connection without a reply is how nscd itself says a
+        // database has no mapping (send_ro_fd in nscd/connection.c): glibc's
+        // client then marks the database as unmapped and sends explicit
+        // queries. Any bytes without an FD would get the same treatment, so
+        // there's no better-formed way to say no.
         RequestType::GETFDPW
         | RequestType::GETFDGR
         | RequestType::GETFDHST
         | RequestType::GETFDSERV
         | RequestType::GETFDNETGR => {
             debug!(log, "received GETFD* request, ignoring");
-            Ok(vec![])
+            Ok(Response::None)
+        }
+
+        // Not a request: Request::parse rejects it.
+        RequestType::LASTREQ => bail!("LASTREQ is not a request type"),
+    }
+}
+
+// Entry lookups. These serve the entries pinned in the config's override table
+// if there are any, then the ones in the local files if we keep them in
+// memory, and otherwise ask NSS. With config.merge_group_sources, group
+// lookups ask every source instead, and serve the first entry found with the
+// members of all of them.
+
+fn user_by_uid(config: &Config, uid: Uid) -> Result<Option<User>> {
+    if let Some(user) = config.overrides.user_by_uid(uid) {
+        return Ok(Some(user.clone()));
+    }
+    if let Some(local) = &config.local_files {
+        if let Some(user) = local.table().user_by_uid(uid) {
+            return Ok(Some(user.clone()));
+        }
+    }
+    nss_lookup(config.backends.passwd.user_by_uid(uid), || {
+        format!("looking up uid {}", uid)
+    })
+}
+
+fn user_by_name(config: &Config, name: &str) -> Result<Option<User>> {
+    if let Some(user) = config.overrides.user_by_name(name) {
+        return Ok(Some(user.clone()));
+    }
+    if let Some(local) = &config.local_files {
+        if let Some(user) = local.table().user_by_name(name) {
+            return Ok(Some(user.clone()));
+        }
+    }
+    nss_lookup(config.backends.passwd.user_by_name(name), || {
+        format!("looking up user {:?}", name)
+    })
+}
+
+fn group_by_gid(config: &Config, gid: Gid) -> Result<Option<Group>> {
+    let mut merged = None;
+    if let Some(group) = config.overrides.group_by_gid(gid) {
+        if !config.merge_group_sources {
+            return Ok(Some(group.clone()));
+        }
+        merge_group(&mut merged, group.clone());
+    }
+    if let Some(local) = &config.local_files {
+        if let Some(group) = local.table().group_by_gid(gid) {
+            if !config.merge_group_sources {
+                return Ok(Some(group.clone()));
+            }
+            merge_group(&mut merged, group.clone());
+        }
+    }
+    if let Some(group) = nss_lookup(config.backends.group.group_by_gid(gid), || {
+        format!("looking up gid {}", gid)
+    })? {
+        merge_group(&mut merged, group);
+    }
+    Ok(merged)
+}
+
+fn group_by_name(config: &Config, name: &str) -> Result<Option<Group>> {
+    let mut merged = None;
+    if let Some(group) = config.overrides.group_by_name(name) {
+        if !config.merge_group_sources {
+            return Ok(Some(group.clone()));
+        }
+        merge_group(&mut merged, group.clone());
+    }
+    if let Some(local) = &config.local_files {
+        if let Some(group) = local.table().group_by_name(name) {
+            if !config.merge_group_sources {
+                return Ok(Some(group.clone()));
+            }
+            merge_group(&mut merged, group.clone());
+        }
+    }
+    if let Some(group) = nss_lookup(config.backends.group.group_by_name(name), || {
+        format!("looking up group {:?}", name)
+    })? {
+        merge_group(&mut merged, group);
+    }
+    Ok(merged)
+}
+
+/// Tell "not found" apart from a backend failure in the result of an NSS
+/// passwd or group lookup.
+///
+/// A missing entry usually comes back as `Ok(None)`, but getpwnam_r(3) also
+/// allows ENOENT, ESRCH, EBADF and EPERM for it. Any other errno means the
+/// backend couldn't answer (e.g. LDAP timing out). That's returned as an
+/// error saying what was looked up, so the client gets no reply and asks NSS
+/// itself, rather than a "not found" it would believe, and maybe cache.
+fn nss_lookup<T>(
+    result: nix::Result<Option<T>>,
+    what: impl FnOnce() -> String,
+) -> Result<Option<T>> {
+    match result {
+        Ok(entry) => Ok(entry),
+        Err(Errno::ENOENT) | Err(Errno::ESRCH) | Err(Errno::EBADF) | Err(Errno::EPERM) => Ok(None),
+        Err(e) => Err(anyhow::Error::from(e).context(what())),
+    }
+}
+
+/// Add `group`, found in one more source, to the entry found in the previous
+/// ones, like glibc's `[SUCCESS=merge]` does: the first entry found is kept,
+/// with the members of later ones appended. An entry with a different name or
+/// gid is a different group, and isn't merged.
+fn merge_group(merged: &mut Option<Group>, group: Group) {
+    match merged {
+        None => *merged = Some(group),
+        Some(merged) if merged.name == group.name && merged.gid == group.gid => {
+            let missing = missing_members(merged, &group);
+            merged.mem.extend(missing);
+        }
+        Some(_) => (),
+    }
+}
+
+/// The members of `other` that aren't members of `group`.
+fn missing_members(group: &Group, other: &Group) -> Vec<String> {
+    other
+        .mem
+        .iter()
+        .filter(|member| !group.mem.contains(member))
+        .cloned()
+        .collect()
+}
+
+/// Add the members of `other` missing from `group`, warning if there are
+/// any.
+///
+/// `other` is the same group looked up the other way (by name if `group` was
+/// looked up by gid, and vice versa). Backends that page through large
+/// memberships (e.g. LDAP) can return a different, incomplete member list for
+/// each, and a member missing from either can be denied access, so we serve
+/// the union. If `other` turns out to be a different group altogether, it's
+/// left alone.
+fn reconcile_members(log: &Logger, group: &mut Group, other: Option<&Group>) {
+    let other = match other {
+        Some(other) if other.name == group.name && other.gid == group.gid => other,
+        _ => return,
+    };
+    let missing = missing_members(group, other);
+    if missing.is_empty() && other.mem.len() == group.mem.len() {
+        return;
+    }
+    warn!(log, "group member lists disagree, serving their union";
+        "group" => &group.name, "gid" => group.gid.as_raw(),
+        "members" => group.mem.len(), "other_members" => other.mem.len(),
+        "added" => missing.len());
+    group.mem.extend(missing);
+}
+
+// Conflict detection. When the same name exists in the local files and in a
+// remote directory with different ids, NSS silently returns whichever source
+// comes first in nsswitch.conf. These compare the entry we're about to return
+// with the one in the local files, and warn if they disagree; the answer
+// itself is unchanged. NSS can't be asked for a single source's entry, so a
+// conflict is only visible when the remote entry is the one that won.
+
+fn check_user_conflict(log: &Logger, config: &Config, name: &str, user: Option<&User>) {
+    let local = match local_files_table(config, true) {
+        Ok(table) => table,
+        Err(e) => {
+            debug!(log, "reading local files for conflict detection"; "err" => %e);
+            return;
+        }
+    };
+    if let (Some(user), Some(local)) = (user, local.user_by_name(name)) {
+        if user.uid != local.uid || user.gid != local.gid {
+            warn!(log, "passwd sources disagree, returning the NSS answer";
+                "name" => %EscapedKey(name.as_bytes()),
+                "uid" => user.uid.as_raw(), "gid" => user.gid.as_raw(),
+                "files_uid" => local.uid.as_raw(), "files_gid" => local.gid.as_raw());
+        }
+    }
+}
+
+fn check_group_conflict(log: &Logger, config: &Config, name: &str, group: Option<&Group>) {
+    let local = match local_files_table(config, false) {
+        Ok(table) => table,
+        Err(e) => {
+            debug!(log, "reading local files for conflict detection"; "err" => %e);
+            return;
+        }
+    };
+    if let (Some(group), Some(local)) = (group, local.group_by_name(name)) {
+        if group.gid != local.gid {
+            warn!(log, "group sources disagree, returning the NSS answer";
+                "name" => %EscapedKey(name.as_bytes()), "gid" => group.gid.as_raw(),
+                "files_gid" => local.gid.as_raw());
+        }
+    }
+}
+
+/// Warn if other users share the uid of `user`, the entry we're about to
+/// serve. NSS has no way to look up every user with a uid, so this only finds
+/// the ones in the overrides and the local files.
+fn check_duplicate_uid(log: &Logger, config: &Config, user: Option<&User>) {
+    let user = match user {
+        Some(user) => user,
+        None => return,
+    };
+    let local = match local_files_table(config, true) {
+        Ok(table) => table,
+        Err(e) => {
+            debug!(log, "reading local files for duplicate uid detection"; "err" => %e);
+            return;
+        }
+    };
+    let mut names = vec![user.name.as_str()];
+    for other in config.overrides.users.iter().chain(local.users.iter()) {
+        if other.uid == user.uid && !names.contains(&other.name.as_str()) {
+            names.push(&other.name);
+        }
+    }
+    if names.len() > 1 {
+        config.stats.record_duplicate_uid();
+        warn!(log, "uid is shared by several users, serving the first one found";
+            "uid" => user.uid.as_raw(), "name" => &user.name, "names" => names.join(","));
+    }
+}
+
+/// Apply [Config::max_group_members] to `group`, the entry we're about to
+/// serve, cutting its member list short if the policy is to truncate.
+/// Returns false if it must not be served at all.
+fn limit_members(log: &Logger, config: &Config, group: Option<&mut Group>) -> bool {
+    let (group, max) = match (group, config.max_group_members) {
+        (Some(group), Some(max)) if group.mem.len() > max => (group, max),
+        _ => return true,
+    };
+    config.stats.record_oversized_group();
+    match config.member_overflow {
+        MemberOverflow::Truncate => {
+            warn!(log, "group has too many members, serving the first ones";
+                "group" => &group.name, "members" => group.mem.len(), "max" => max);
+            group.mem.truncate(max);
+            true
+        }
+        MemberOverflow::Refuse => {
+            warn!(log, "group has too many members, not serving it";
+                "group" => &group.name, "members" => group.mem.len(), "max" => max);
+            false
+        }
+    }
+}
+
+/// The entries in the local files: the in-memory copy if we keep one, and
+/// otherwise a fresh read of `/etc/passwd` (if `passwd`) or `/etc/group`.
+fn local_files_table(config: &Config, passwd: bool) -> Result<Arc<files::Table>> {
+    if let Some(local) = &config.local_files {
+        return Ok(local.table());
+    }
+    let table = if passwd {
+        files::Table::load(Some(Path::new("/etc/passwd")), None)?
+    } else {
+        files::Table::load(None, Some(Path::new("/etc/group")))?
+    };
+    Ok(Arc::new(table))
+}
+
+/// Whether the request's key is a user or group name, which may be subject to
+/// case folding.
+fn has_name_key(ty: RequestType) -> bool {
+    matches!(
+        ty,
+        RequestType::GETPWBYNAME
+            | RequestType::GETGRBYNAME
+            | RequestType::INITGROUPS
+            | RequestType::GETSPBYNAME
+            | RequestType::BATCHGETPWBYNAME
+    )
+}
+
+/// Parse the address out of a reverse host lookup key.
+///
+/// glibc's client doesn't put the address family or length in the key: the
+/// family is carried by the request type (GETHOSTBYADDR for `AF_INET`,
+/// GETHOSTBYADDRv6 for `AF_INET6`), and the length is the key length from the
+/// request header. The key is just the raw address, so its length has to be
+/// the right one for the family.
+fn parse_address_key(ty: RequestType, key: &[u8]) -> Result<LibcIp> {
+    match ty {
+        RequestType::GETHOSTBYADDR => match key.try_into() {
+            Ok(address) => Ok(LibcIp::V4(address)),
+            Err(_) => bail!("Invalid key len: {}, expected 4", key.len()),
+        },
+        RequestType::GETHOSTBYADDRv6 => match key.try_into() {
+            Ok(address) => Ok(LibcIp::V6(address)),
+            Err(_) => bail!("Invalid key len: {}, expected 16", key.len()),
+        },
+        _ => bail!("{:?} is not a reverse host lookup", ty),
+    }
+}
+
+/// Split a services lookup key into the service (or port) and the protocol.
+///
+/// The key is the NUL-terminated "service/proto" (or "port/proto") string
+/// glibc's client sends.
+/// Like nscd, we split it at the last '/', and an empty protocol (or none at
+/// all) matches any protocol.
+fn parse_service_key(key: &[u8]) -> Result<(&[u8], Option<&[u8]>)> {
+    let key = CStr::from_bytes_with_nul(key)?.to_bytes();
+    match key.iter().rposition(|b| *b == b'/') {
+        Some(0) | None => Ok((key, None)),
+        Some(slash) => {
+            let proto = &key[slash + 1..];
+            Ok((&key[..slash], Some(proto).filter(|proto| !proto.is_empty())))
+        }
+    }
+}
+
+/// Parse the host, user or domain of an innetgr key.
+///
+/// glibc's client sends a wildcard (a NULL pointer) as an empty field, and
+/// anything else with a '\x01' byte in front, so that an empty string can be
+/// told apart from a wildcard.
+fn parse_innetgr_field(field: &CStr) -> Result<Option<&CStr>> {
+    match field.to_bytes_with_nul() {
+        [0] => Ok(None),
+        [1, value @ ..] => Ok(Some(CStr::from_bytes_with_nul(value)?)),
+        _ => bail!("innetgr key field doesn't start with \\x01"),
+    }
+}
+
+/// The address families this host can reach the outside world with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct UsableFamilies {
+    v4: bool,
+    v6: bool,
+}
+
+impl UsableFamilies {
+    /// Check whether the kernel can pick a source address for a global
+    /// destination of each family. Connecting a UDP socket doesn't send
+    /// anything, but fails if there's no route, e.g. when IPv6 is disabled.
+    fn probe() -> Self {
+        let routable = |local: &str, remote: &str| {
+            UdpSocket::bind(local)
+                .and_then(|socket| socket.connect(remote))
+                .is_ok()
+        };
+        Self {
+            // documentation addresses: they go wherever the default route
+            // goes.
+            v4: routable("0.0.0.0:0", "192.0.2.1:53"),
+            v6: routable("[::]:0", "[2001:db8::1]:53"),
+        }
+    }
+
+    /// Drop the addresses of the unusable family, if there is exactly one.
+    /// The order of the others is kept: getaddrinfo already sorted them.
+    ///
+    /// If neither family looks usable, the probe is probably wrong (e.g. the
+    /// host only has routes to a few internal networks), so we don't trust it
+    /// and leave everything in.
+    fn filter(&self, addrs: &mut Vec<IpAddr>) {
+        if self.v4 == self.v6 {
+            return;
         }
+        addrs.retain(|addr| match addr {
+            IpAddr::V4(_) => self.v4,
+            IpAddr::V6(_) => self.v6,
+        });
+    }
+}
+
+/// Whether `addr` is an IPv6 link-local address, which is only usable along
+/// with a scope (the interface to use) the protocol can't carry.
+fn is_link_local_v6(addr: &IpAddr) -> bool {
+    matches!(addr, IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80)
+}
 
-        // Not implemented (yet)
-        RequestType::GETSTAT
-        | RequestType::GETSERVBYNAME
-        | RequestType::GETSERVBYPORT
-        | RequestType::GETNETGRENT
-        | RequestType::INNETGR
-        | RequestType::LASTREQ => Ok(vec![]),
+/// Apply [Config::exclude_link_local] to the result of a gethostbyname
+/// lookup. A name left with no addresses gets a "no data" answer, like one
+/// that never had any.
+fn drop_link_local_hostent(config: &Config, mut hostent: Hostent) -> Hostent {
+    if !config.exclude_link_local || hostent.addr_list.is_empty() {
+        return hostent;
     }
+    hostent.addr_list.retain(|addr| !is_link_local_v6(addr));
+    if hostent.addr_list.is_empty() {
+        return Hostent::error_value(protocol::H_ERRNO_NO_DATA);
+    }
+    hostent
+}
+
+/// Parse the hostname out of a host lookup key.
+///
+/// Returns `None` if the name is longer than the configured maximum: a name
+/// that long can't resolve anyway, so there's no point in asking NSS.
+fn parse_hostname<'a>(log: &Logger, config: &Config, key: &'a [u8]) -> Result<Option<&'a str>> {
+    let hostname = CStr::from_bytes_with_nul(key)?.to_str()?;
+    if hostname.len() > config.max_hostname_len {
+        debug!(log, "hostname too long, returning not found"; "len" => hostname.len());
+        return Ok(None);
+    }
+    Ok(Some(hostname))
+}
+
+/// Weed out passwd entries that can't be sent on the wire.
+///
+/// The fields of an entry are sent as NUL-terminated strings, so one with an
+/// interior NUL can't be serialized. That's a broken entry in the backend,
+/// not anything the client did, so rather than failing the request (and
+/// leaving e.g. a uid lookup with no way to recover), we log it and reply as
+/// if the entry didn't exist.
+fn check_user(log: &Logger, user: Option<User>) -> Option<User> {
+    let user = user?;
+    let has_nul = |bytes: &[u8]| bytes.contains(&0);
+    if has_nul(user.name.as_bytes())
+        || has_nul(user.dir.as_os_str().as_bytes())
+        || has_nul(user.shell.as_os_str().as_bytes())
+    {
+        warn!(log, "passwd entry contains a NUL byte, returning not found";
+            "uid" => user.uid.as_raw());
+        return None;
+    }
+    Some(user)
+}
+
+/// Weed out group entries that can't be sent on the wire, like
+/// [check_user] does passwd entries: one whose name or members have a NUL in
+/// them is logged and treated as not found.
+fn check_group(log: &Logger, group: Option<Group>) -> Option<Group> {
+    let group = group?;
+    let has_nul = |bytes: &[u8]| bytes.contains(&0);
+    if has_nul(group.name.as_bytes()) || group.mem.iter().any(|m| has_nul(m.as_bytes())) {
+        warn!(log, "group entry contains a NUL byte, returning not found";
+            "gid" => group.gid.as_raw());
+        return None;
+    }
+    Some(group)
 }
 
 /// Send a user (passwd entry) back to the client, or a response indicating the
 /// lookup found no such user.
-fn serialize_user(user: Option<User>) -> Result<Vec<u8>> {
-    let mut result = vec![];
+///
+/// Every string field is sent NUL-terminated, and its length in the header
+/// includes the NUL. An empty field (e.g. an empty gecos or shell, which some
+/// passwd sources legitimately have) is therefore sent as a lone NUL with
+/// length 1. The wire format has no way to express a field the source omitted
+/// entirely: a length of 0 only ever appears in the all-zero header of a
+/// not-found response.
+fn write_user(out: &mut Vec<u8>, user: Option<User>) -> Result<()> {
     if let Some(data) = user {
         let name = CString::new(data.name)?;
         let name_bytes = name.to_bytes_with_nul();
@@ -313,109 +1185,145 @@ fn serialize_user(user: Option<User>) -> Result<Vec<u8>> {
             pw_dir_len: dir_bytes.len().try_into()?,
             pw_shell_len: shell_bytes.len().try_into()?,
         };
-        result.extend_from_slice(header.as_slice());
-        result.extend_from_slice(name_bytes);
-        result.extend_from_slice(passwd_bytes);
-        result.extend_from_slice(gecos_bytes);
-        result.extend_from_slice(dir_bytes);
-        result.extend_from_slice(shell_bytes);
+        out.extend_from_slice(header.as_slice());
+        out.extend_from_slice(name_bytes);
+        out.extend_from_slice(passwd_bytes);
+        out.extend_from_slice(gecos_bytes);
+        out.extend_from_slice(dir_bytes);
+        out.extend_from_slice(shell_bytes);
     } else {
         let header = protocol::PwResponseHeader::default();
-        result.extend_from_slice(header.as_slice());
+        out.extend_from_slice(header.as_slice());
+    }
+    Ok(())
+}
+
+/// Send a shadow entry back to the client, or a response indicating the lookup
+/// found no such user.
+fn write_shadow(out: &mut Vec<u8>, spwd: Option<Spwd>) -> Result<()> {
+    let data = match spwd {
+        Some(data) => data,
+        None => {
+            out.extend_from_slice(protocol::SpResponseHeader::default().as_slice());
+            return Ok(());
+        }
+    };
+    let name_bytes = data.name.to_bytes_with_nul();
+    let passwd_bytes = data.passwd.to_bytes_with_nul();
+    let header = protocol::SpResponseHeader {
+        version: protocol::VERSION,
+        found: 1,
+        sp_namp_len: name_bytes.len().try_into()?,
+        sp_pwdp_len: passwd_bytes.len().try_into()?,
+        sp_lstchg: data.last_change,
+        sp_min: data.min,
+        sp_max: data.max,
+        sp_warn: data.warn,
+        sp_inact: data.inactive,
+        sp_expire: data.expire,
+        sp_flag: data.flag,
+    };
+    out.extend_from_slice(header.as_slice());
+    out.extend_from_slice(name_bytes);
+    out.extend_from_slice(passwd_bytes);
+    Ok(())
+}
+
+/// Send the replies to a batch of passwd lookups back to the client, in the
+/// order they were requested.
+fn write_user_batch(out: &mut Vec<u8>, users: Vec<Option<User>>) -> Result<()> {
+    let header = protocol::BatchResponseHeader {
+        version: protocol::VERSION,
+        nentries: users.len().try_into()?,
+    };
+    out.extend_from_slice(header.as_slice());
+    for user in users {
+        write_user(out, user)?;
     }
-    Ok(result)
+    Ok(())
 }
 
 /// Send a group (group entry) back to the client, or a response indicating the
 /// lookup found no such group.
-fn serialize_group(group: Option<Group>) -> Result<Vec<u8>> {
-    let mut result = vec![];
-    if let Some(data) = group {
-        let name = CString::new(data.name)?;
-        let name_bytes = name.to_bytes_with_nul();
-        let mem_cnt = data.mem.len();
-        let passwd_bytes = data.passwd.to_bytes_with_nul();
-        let members: Vec<CString> = data
-            .mem
-            .into_iter()
-            .map(CString::new)
-            .collect::<Result<Vec<CString>, _>>()?;
-        let members_bytes: Vec<&[u8]> = members
-            .iter()
-            .map(|member| member.to_bytes_with_nul())
-            .collect();
-
-        let header = protocol::GrResponseHeader {
-            version: protocol::VERSION,
-            found: 1,
-            gr_name_len: name_bytes.len().try_into()?,
-            gr_passwd_len: passwd_bytes.len().try_into()?,
-            gr_gid: data.gid.as_raw(),
-            gr_mem_cnt: mem_cnt.try_into()?,
-        };
-        result.extend_from_slice(header.as_slice());
-        for member_bytes in members_bytes.iter() {
-            result.extend_from_slice(&i32::to_ne_bytes(member_bytes.len().try_into()?));
+///
+/// `gr_passwd` is served as the backend returned it: nix's `Group` keeps it,
+/// so e.g. a `!` or a password hash isn't replaced by `x`.
+fn write_group(out: &mut Vec<u8>, group: Option<Group>) -> Result<()> {
+    let data = match group {
+        Some(data) => data,
+        None => {
+            let header = protocol::GrResponseHeader::default();
+            out.extend_from_slice(header.as_slice());
+            return Ok(());
         }
-        result.extend_from_slice(name_bytes);
-        result.extend_from_slice(passwd_bytes);
-        for member_bytes in members_bytes.iter() {
-            result.extend_from_slice(member_bytes);
+    };
+    let name = CString::new(data.name)?;
+    let name_bytes = name.to_bytes_with_nul();
+    let passwd_bytes = data.passwd.to_bytes_with_nul();
+
+    // Groups can have tens of thousands of members, so size the reply up
+    // front and write the members straight from `data.mem`, without building
+    // a NUL-terminated copy of each one first.
+    let mut members_len = 0;
+    for member in data.mem.iter() {
+        if member.as_bytes().contains(&0) {
+            bail!("group member {:?} contains a NUL byte", member);
         }
-    } else {
-        let header = protocol::GrResponseHeader::default();
-        result.extend_from_slice(header.as_slice());
+        members_len += member.len() + 1;
+    }
+    let header = protocol::GrResponseHeader {
+        version: protocol::VERSION,
+        found: 1,
+        gr_name_len: name_bytes.len().try_into()?,
+        gr_passwd_len: passwd_bytes.len().try_into()?,
+        gr_gid: data.gid.as_raw(),
+        gr_mem_cnt: data.mem.len().try_into()?,
+    };
+    let table_len = data.mem.len() * size_of::<i32>();
+
+    out.reserve_exact(
+        size_of::<protocol::GrResponseHeader>()
+            + table_len
+            + name_bytes.len()
+            + passwd_bytes.len()
+            + members_len,
+    );
+    out.extend_from_slice(header.as_slice());
+    // The member lengths come first, then the strings.
+    for member in data.mem.iter() {
+        let len: i32 = (member.len() + 1).try_into()?;
+        out.extend_from_slice(&len.to_ne_bytes());
     }
-    Ok(result)
+    out.extend_from_slice(name_bytes);
+    out.extend_from_slice(passwd_bytes);
+    for member in data.mem.iter() {
+        out.extend_from_slice(member.as_bytes());
+        out.push(0);
+    }
+    Ok(())
 }
 
 /// Send a user's group list (initgroups/getgrouplist response) back to the
 /// client.
-fn serialize_initgroups(groups: Vec<Gid>) -> Result<Vec<u8>> {
-    let mut result = vec![];
+fn write_initgroups(out: &mut Vec<u8>, groups: Vec<Gid>) -> Result<()> {
     let header = protocol::InitgroupsResponseHeader {
         version: protocol::VERSION,
         found: 1,
         ngrps: groups.len().try_into()?,
     };
 
-    result.extend_from_slice(header.as_slice());
+    out.extend_from_slice(header.as_slice());
     for group in groups.iter() {
-        result.extend_from_slice(&i32::to_ne_bytes(group.as_raw().try_into()?));
+        out.extend_from_slice(&i32::to_ne_bytes(group.as_raw().try_into()?));
     }
 
-    Ok(result)
+    Ok(())
 }
 
-fn serialize_hostent(hostent: Hostent) -> Result<Vec<u8>> {
-    // Loop over all addresses.
-    // Serialize them into a slice, which is used later in the payload.
-    // Take note of the number of addresses (by AF).
-    let mut num_v4 = 0;
-    let mut num_v6 = 0;
-    let mut buf_addrs = vec![];
-    let mut buf_aliases = vec![];
-    // Memory segment used to convey the size of the different
-    // aliases. The sizes are expressed in native endian encoded 32
-    // bits integer.
-    let mut buf_aliases_size = vec![];
-
-    for address in hostent.addr_list.iter() {
-        match address {
-            IpAddr::V4(ip4) => {
-                num_v4 += 1;
-                for octet in ip4.octets() {
-                    buf_addrs.push(octet)
-                }
-            }
-            IpAddr::V6(ip6) => {
-                num_v6 += 1;
-                for octet in ip6.octets() {
-                    buf_addrs.push(octet)
-                }
-            }
-        }
-    }
+fn write_hostent(out: &mut Vec<u8>, hostent: Hostent) -> Result<()> {
+    // Take note of the number of addresses (by AF), which go in the header.
+    let num_v4 = hostent.addr_list.iter().filter(|a| a.is_ipv4()).count();
+    let num_v6 = hostent.addr_list.len() - num_v4;
 
     // this can only ever express one address family
     if num_v4 != 0 && num_v6 != 0 {
@@ -424,7 +1332,7 @@ fn serialize_hostent(hostent: Hostent) -> Result<Vec<u8>> {
 
     // if there's no addresses, early-return the "empty result" response.
     if hostent.addr_list.is_empty() {
-        return Ok(Vec::from(
+        out.extend_from_slice(
             protocol::HstResponseHeader {
                 version: protocol::VERSION,
                 found: 0,
@@ -436,15 +1344,12 @@ fn serialize_hostent(hostent: Hostent) -> Result<Vec<u8>> {
                 error: hostent.herrno,
             }
             .as_slice(),
-        ));
-    }
-
-    for alias in hostent.aliases.iter() {
-        buf_aliases_size.extend_from_slice(&(alias.as_bytes_with_nul().len() as i32).to_ne_bytes());
-        buf_aliases.extend_from_slice(alias.as_bytes_with_nul());
+        );
+        return Ok(());
     }
 
-    let hostname_bytes = hostent.name.into_bytes_with_nul();
+    let hostname_bytes = hostent.name.as_bytes_with_nul();
+    let h_length = if num_v4 != 0 { 4 } else { 16 };
 
     let header = protocol::HstResponseHeader {
         version: protocol::VERSION,
@@ -456,37 +1361,213 @@ fn serialize_hostent(hostent: Hostent) -> Result<Vec<u8>> {
         } else {
             nix::sys::socket::AddressFamily::Inet6 as i32
         },
-        h_length: if num_v4 != 0 { 4 } else { 16 },
+        h_length,
         h_addr_list_cnt: hostent.addr_list.len() as i32,
         error: hostent.herrno,
     };
 
-    let total_len = std::mem::size_of::<protocol::HstResponseHeader>()
-        + hostname_bytes.len()
-        + buf_addrs.len()
-        + buf_aliases.len()
-        + buf_aliases_size.len();
-
-    let mut buf = Vec::with_capacity(total_len);
+    let aliases_len: usize = hostent
+        .aliases
+        .iter()
+        .map(|alias| size_of::<i32>() + alias.as_bytes_with_nul().len())
+        .sum();
+    out.reserve_exact(
+        size_of::<protocol::HstResponseHeader>()
+            + hostname_bytes.len()
+            + hostent.addr_list.len() * h_length as usize
+            + aliases_len,
+    );
 
     // add header
-    buf.extend_from_slice(header.as_slice());
+    out.extend_from_slice(header.as_slice());
 
     // add hostname
-    buf.extend_from_slice(&hostname_bytes);
+    out.extend_from_slice(hostname_bytes);
 
-    // add aliases sizes
-    buf.extend_from_slice(buf_aliases_size.as_slice());
+    // add aliases sizes, as native endian encoded 32 bits integers
+    for alias in hostent.aliases.iter() {
+        out.extend_from_slice(&(alias.as_bytes_with_nul().len() as i32).to_ne_bytes());
+    }
 
-    // add serialized addresses from buf_addrs
-    buf.extend_from_slice(buf_addrs.as_slice());
+    // add addresses
+    for address in hostent.addr_list.iter() {
+        match address {
+            IpAddr::V4(ip4) => out.extend_from_slice(&ip4.octets()),
+            IpAddr::V6(ip6) => out.extend_from_slice(&ip6.octets()),
+        }
+    }
 
     // add aliases
-    buf.extend_from_slice(buf_aliases.as_slice());
+    for alias in hostent.aliases.iter() {
+        out.extend_from_slice(alias.as_bytes_with_nul());
+    }
+
+    Ok(())
+}
+
+/// Send a service entry (getservbyname/getservbyport response) back to the
+/// client.
+///
+/// The header is followed by the NUL-terminated name and protocol, then the
+/// length of each alias as a native endian `u32`, then the NUL-terminated
+/// aliases, like in `nscd/servicescache.c`.
+fn write_servent(out: &mut Vec<u8>, servent: Option<Servent>) -> Result<()> {
+    let servent = match servent {
+        Some(servent) => servent,
+        None => {
+            out.extend_from_slice(protocol::SERV_RESPONSE_HEADER_NOT_FOUND.as_slice());
+            return Ok(());
+        }
+    };
+    let name = servent.name.as_bytes_with_nul();
+    let proto = servent.proto.as_bytes_with_nul();
+    let header = protocol::ServResponseHeader {
+        version: protocol::VERSION,
+        found: 1,
+        s_name_len: name.len().try_into()?,
+        s_proto_len: proto.len().try_into()?,
+        s_aliases_cnt: servent.aliases.len().try_into()?,
+        s_port: servent.port,
+    };
+
+    let aliases_len: usize = servent
+        .aliases
+        .iter()
+        .map(|alias| size_of::<u32>() + alias.as_bytes_with_nul().len())
+        .sum();
+    out.reserve_exact(
+        size_of::<protocol::ServResponseHeader>() + name.len() + proto.len() + aliases_len,
+    );
+    out.extend_from_slice(header.as_slice());
+    out.extend_from_slice(name);
+    out.extend_from_slice(proto);
+    for alias in servent.aliases.iter() {
+        let len: u32 = alias.as_bytes_with_nul().len().try_into()?;
+        out.extend_from_slice(&len.to_ne_bytes());
+    }
+    for alias in servent.aliases.iter() {
+        out.extend_from_slice(alias.as_bytes_with_nul());
+    }
+    Ok(())
+}
 
-    debug_assert_eq!(buf.len(), total_len);
+/// Send the members of a netgroup (setnetgrent response) back to the client.
+///
+/// Each member is sent as its NUL-terminated host, user and domain, with
+/// wildcards as empty strings, like in `nscd/netgroupcache.c`.
+fn write_netgroup(out: &mut Vec<u8>, triples: Option<Vec<NetgroupTriple>>) -> Result<()> {
+    let triples = match triples {
+        Some(triples) => triples,
+        None => {
+            let header = protocol::NetgroupResponseHeader {
+                version: protocol::VERSION,
+                ..Default::default()
+            };
+            out.extend_from_slice(header.as_slice());
+            return Ok(());
+        }
+    };
+    let members_len: usize = triples
+        .iter()
+        .flat_map(|triple| [&triple.host, &triple.user, &triple.domain])
+        .map(|field| field.as_ref().map_or(0, |field| field.as_bytes().len()) + 1)
+        .sum();
+    let header = protocol::NetgroupResponseHeader {
+        version: protocol::VERSION,
+        found: 1,
+        nresults: triples.len().try_into()?,
+        result_len: members_len.try_into()?,
+    };
+
+    out.reserve_exact(size_of::<protocol::NetgroupResponseHeader>() + members_len);
+    out.extend_from_slice(header.as_slice());
+    for triple in triples.iter() {
+        for field in [&triple.host, &triple.user, &triple.domain].iter() {
+            if let Some(field) = field {
+                out.extend_from_slice(field.as_bytes());
+            }
+            out.push(0);
+        }
+    }
+    Ok(())
+}
+
+/// Build the reply to a GETSTAT request out of the config and its stats.
+///
+/// Lookups answered from the response cache are hits, and every other one is
+/// a miss, with or without a result. The timeouts, sizes and occupancy are
+/// the response cache's; the fields about nscd's own internals are left at
+/// zero.
+fn serialize_stats(config: &Config) -> protocol::StatResponse {
+    let snapshot = config.stats.snapshot();
+    let mut stats = protocol::StatResponse {
+        runtime: config
+            .stats
+            .uptime()
+            .as_secs()
+            .try_into()
+            .unwrap_or(i64::MAX),
+        nthreads: config.total_worker_count().try_into().unwrap_or(i32::MAX),
+        max_nthreads: config.total_worker_count().try_into().unwrap_or(i32::MAX),
+        ndbs: protocol::DATABASES.len() as i32,
+        ..Default::default()
+    };
+    if let Some(version) = &config.stat_version {
+        stats.version[..version.len()].copy_from_slice(version.as_bytes());
+    }
+    for (database, db) in stats.dbs.iter_mut().enumerate() {
+        db.check_file = config
+            .watch_files
+            .contains(&protocol::DATABASES[database])
+            .into();
+        db.persistent = config.cache_file.is_some().into();
+        let occupancy = config.cache.occupancy(database);
+        db.nentries = occupancy.entries;
+        db.maxnentries = occupancy.peak_entries;
+        db.datasize = config.cache.database_policy(database).max_bytes;
+        db.dataused = occupancy.bytes;
+    }
+    for ty in RequestType::all() {
+        let db = match ty.stat_database() {
+            Some(db) => &mut stats.dbs[db],
+            None => continue,
+        };
+        if !config.should_ignore(&ty) {
+            db.enabled = 1;
+        }
+        db.postimeout = config.cache.ttl(ty).as_secs() as c_ulong;
+        db.negtimeout = config.cache.negative_ttl(ty).as_secs() as c_ulong;
+        // cache hits are counted as answers too.
+        db.poshit += snapshot.hits_of(ty);
+        db.posmiss += snapshot.found_of(ty).saturating_sub(snapshot.hits_of(ty));
+        db.neghit += snapshot.negative_hits_of(ty);
+        db.negmiss += snapshot
+            .not_found_of(ty)
+            .saturating_sub(snapshot.negative_hits_of(ty));
+    }
+    stats
+}
 
-    Ok(buf)
+/// Log the response cache's statistics for each cached database: what
+/// `nscd -g` shows, and the evictions it can't.
+pub fn log_cache_stats(log: &Logger, config: &Config) {
+    let stats = serialize_stats(config);
+    for (database, db) in stats.dbs.iter().enumerate() {
+        if db.postimeout == 0 && db.negtimeout == 0 {
+            continue;
+        }
+        let occupancy = config.cache.occupancy(database);
+        info!(log, "cache statistics";
+            "database" => protocol::DATABASES[database],
+            "entries" => db.nentries,
+            "bytes" => db.dataused,
+            "hits" => db.poshit,
+            "negative_hits" => db.neghit,
+            "misses" => db.posmiss,
+            "negative_misses" => db.negmiss,
+            "evictions" => occupancy.evictions,
+            "invalidated" => occupancy.invalidated);
+    }
 }
 
 /// Serialize a [RequestType::GETAI] response to the wire.
@@ -505,7 +1586,7 @@ fn serialize_hostent(hostent: Hostent) -> Result<Vec<u8>> {
 /// 5. canonlen: int32. Total length of the null-terminated canonical
 ///    name string.
 /// 6. error: int32. Error code. Always 0 in the current nscd
-///           implementation.
+///    implementation.
 /// 7. addrs: \[BE-encoded IPv4/IPv6\]. We sequentially write the
 ///    IPv4 and IPv6 bytes using a big endian encoding. There's no
 ///    padding, an IPv4 will be 4 bytes wide, an IPv6 16 bytes wide.
@@ -514,65 +1595,106 @@ fn serialize_hostent(hostent: Hostent) -> Result<Vec<u8>> {
 ///    the associated IP addr family number. AF_INET for an IPv4,
 ///    AF_INET6 for a v6.
 /// 9. canon_name: Canonical name of the host. Null-terminated string.
-fn serialize_address_info(resp: AiResponse) -> Result<Vec<u8>> {
-    let mut b_families: Vec<u8> = Vec::with_capacity(2);
-    let mut b_addrs: Vec<u8> = Vec::with_capacity(2);
+fn write_address_info(out: &mut Vec<u8>, resp: AiResponse) -> Result<()> {
+    if resp.addrs.is_empty() {
+        out.extend_from_slice(protocol::AI_RESPONSE_HEADER_NOT_FOUND.as_slice());
+        return Ok(());
+    }
+    let addrslen: usize = resp
+        .addrs
+        .iter()
+        .map(|addr| if addr.is_ipv4() { 4 } else { 16 })
+        .sum();
+    let b_canon_name = CString::new(resp.canon_name)?.into_bytes_with_nul();
+    let ai_response_header = AiResponseHeader {
+        version: protocol::VERSION,
+        found: 1,
+        naddrs: resp.addrs.len() as i32,
+        addrslen: addrslen as i32,
+        canonlen: b_canon_name.len() as i32,
+        error: protocol::H_ERRNO_NETDB_SUCCESS,
+    };
+
+    out.reserve_exact(
+        size_of::<AiResponseHeader>() + addrslen + resp.addrs.len() + b_canon_name.len(),
+    );
+    out.extend_from_slice(ai_response_header.as_slice());
     for addr in &resp.addrs {
         match addr {
-            IpAddr::V4(ip) => {
-                b_families.push(AddressFamily::Inet as u8);
-                b_addrs.extend(ip.octets())
-            }
-            IpAddr::V6(ip) => {
-                b_families.push(AddressFamily::Inet6 as u8);
-                b_addrs.extend(ip.octets())
-            }
+            IpAddr::V4(ip) => out.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => out.extend_from_slice(&ip.octets()),
         }
     }
-    let addrslen = b_addrs.len();
-    if addrslen > 0 {
-        let b_canon_name = CString::new(resp.canon_name)?.into_bytes_with_nul();
-        let ai_response_header = AiResponseHeader {
-            version: protocol::VERSION,
-            found: 1,
-            naddrs: resp.addrs.len() as i32,
-            addrslen: addrslen as i32,
-            canonlen: b_canon_name.len() as i32,
-            error: protocol::H_ERRNO_NETDB_SUCCESS,
-        };
-
-        let total_len = size_of::<AiResponseHeader>() + b_addrs.len() + b_families.len();
-        let mut buffer = Vec::with_capacity(total_len);
-        buffer.extend_from_slice(ai_response_header.as_slice());
-        buffer.extend_from_slice(&b_addrs);
-        buffer.extend_from_slice(&b_families);
-        buffer.extend_from_slice(&b_canon_name);
-        Ok(buffer)
-    } else {
-        let mut buffer = Vec::with_capacity(size_of::<AiResponseHeader>());
-        buffer.extend_from_slice(protocol::AI_RESPONSE_HEADER_NOT_FOUND.as_slice());
-        Ok(buffer)
+    for addr in &resp.addrs {
+        out.push(if addr.is_ipv4() {
+            AddressFamily::Inet as u8
+        } else {
+            AddressFamily::Inet6 as u8
+        });
     }
+    out.extend_from_slice(&b_canon_name);
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
 
-    use nix::libc::{AF_INET, AF_INET6};
+    use nix::libc::{c_long, AF_INET, AF_INET6};
+    use nix::unistd::getgrouplist;
 
     use super::*;
+    use crate::backend::Backend;
+    use crate::cache::{Cached, Policy, ResponseCache};
+    use crate::test_util::{capture_logger, Captured, CapturedRecord};
 
     fn test_logger() -> slog::Logger {
         Logger::root(slog::Discard, slog::o!())
     }
 
+    // what the writers write into an empty buffer, i.e. the whole reply.
+    fn collect(write: impl FnOnce(&mut Vec<u8>) -> Result<()>) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        write(&mut out)?;
+        Ok(out)
+    }
+
+    fn serialize_user(user: Option<User>) -> Result<Vec<u8>> {
+        collect(|out| write_user(out, user))
+    }
+
+    fn serialize_shadow(spwd: Option<Spwd>) -> Result<Vec<u8>> {
+        collect(|out| write_shadow(out, spwd))
+    }
+
+    fn serialize_group(group: Option<Group>) -> Result<Vec<u8>> {
+        collect(|out| write_group(out, group))
+    }
+
+    fn serialize_initgroups(groups: Vec<Gid>) -> Result<Vec<u8>> {
+        collect(|out| write_initgroups(out, groups))
+    }
+
+    fn serialize_hostent(hostent: Hostent) -> Result<Vec<u8>> {
+        collect(|out| write_hostent(out, hostent))
+    }
+
+    fn serialize_servent(servent: Option<Servent>) -> Result<Vec<u8>> {
+        collect(|out| write_servent(out, servent))
+    }
+
+    fn serialize_netgroup(triples: Option<Vec<NetgroupTriple>>) -> Result<Vec<u8>> {
+        collect(|out| write_netgroup(out, triples))
+    }
+
+    fn serialize_address_info(resp: AiResponse) -> Result<Vec<u8>> {
+        collect(|out| write_address_info(out, resp))
+    }
+
     #[test]
     fn test_handle_request_empty_key() {
-        let request = protocol::Request {
-            ty: protocol::RequestType::GETPWBYNAME,
-            key: &[],
-        };
+        let request = protocol::Request::new(protocol::RequestType::GETPWBYNAME, &[]);
 
         let result = handle_request(&test_logger(), &Config::default(), &request);
         assert!(result.is_err(), "should error on empty input");
@@ -580,10 +1702,8 @@ mod test {
 
     #[test]
     fn test_handle_request_nul_data() {
-        let request = protocol::Request {
-            ty: protocol::RequestType::GETPWBYNAME,
-            key: &[0x7F, 0x0, 0x0, 0x01],
-        };
+        let request =
+            protocol::Request::new(protocol::RequestType::GETPWBYNAME, &[0x7F, 0x0, 0x0, 0x01]);
 
         let result = handle_request(&test_logger(), &Config::default(), &request);
         assert!(result.is_err(), "should error on garbage input");
@@ -593,12 +1713,10 @@ mod test {
     fn test_handle_request_current_user() {
         let current_user = User::from_uid(nix::unistd::geteuid()).unwrap().unwrap();
 
-        let request = protocol::Request {
-            ty: protocol::RequestType::GETPWBYNAME,
-            key: &CString::new(current_user.name.clone())
-                .unwrap()
-                .into_bytes_with_nul(),
-        };
+        let key = CString::new(current_user.name.clone())
+            .unwrap()
+            .into_bytes_with_nul();
+        let request = protocol::Request::new(protocol::RequestType::GETPWBYNAME, &key);
 
         let expected = serialize_user(Some(current_user))
             .expect("send_user should serialize current user data");
@@ -608,142 +1726,1830 @@ mod test {
     }
 
     #[test]
-    fn test_handle_request_getai() {
-        let request = protocol::Request {
-            ty: protocol::RequestType::GETAI,
-            key: &CString::new("localhost".to_string())
-                .unwrap()
-                .into_bytes_with_nul(),
+    fn test_lookup() {
+        let log = test_logger();
+        let config = Config::default();
+        let current_user = User::from_uid(nix::unistd::geteuid()).unwrap().unwrap();
+
+        let key = format!("{}\0", current_user.uid);
+        let request = protocol::Request::new(RequestType::GETPWBYUID, key.as_bytes());
+        match lookup(&log, &config, &request).unwrap() {
+            Response::Pw(Some(user)) => assert_eq!(user, current_user),
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        let request = protocol::Request::new(RequestType::INVALIDATE, b"shadow\0");
+        assert!(matches!(
+            lookup(&log, &config, &request).unwrap(),
+            Response::Errno(nix::libc::EINVAL)
+        ));
+
+        let request = protocol::Request::new(RequestType::GETFDPW, b"\0");
+        assert!(matches!(
+            lookup(&log, &config, &request).unwrap(),
+            Response::None
+        ));
+    }
+
+    #[test]
+    fn test_write_ai_error() {
+        let mut out = vec![];
+        Response::AiError(protocol::H_ERRNO_TRY_AGAIN)
+            .write(&mut out)
+            .unwrap();
+        let header = AiResponseHeader {
+            error: protocol::H_ERRNO_TRY_AGAIN,
+            ..protocol::AI_RESPONSE_HEADER_NOT_FOUND
         };
+        assert_eq!(out, header.as_slice());
 
-        // The getaddrinfo call can actually return different ordering, or in the case of a
-        // IPv4-only host, only return an IPv4 response.
-        // Be happy with any of these permutations.
-        let gen_ai_resp = |addrs| protocol::AiResponse {
-            addrs,
-            canon_name: "localhost".to_string(),
+        let mut out = vec![];
+        Response::None.write(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_handle_request_overrides() {
+        let config = Config {
+            overrides: std::sync::Arc::new(crate::files::Table::parse(
+                b"nsncd-pinned:x:4242:4243::/srv:/bin/false\n",
+                b"nsncd-pinned:x:4243:nsncd-pinned\n",
+            )),
+            ..Config::default()
         };
-        let ai_resp_1 = gen_ai_resp(vec![
-            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-        ]);
-        let ai_resp_2 = gen_ai_resp(vec![
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
-        ]);
-        let ai_resp_3 = gen_ai_resp(vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]);
-        let expected_1: Vec<u8> = serialize_address_info(ai_resp_1)
-            .expect("serialize_address_info should serialize correctly");
-        let expected_2: Vec<u8> = serialize_address_info(ai_resp_2)
-            .expect("serialize_address_info should serialize correctly");
-        let expected_3: Vec<u8> = serialize_address_info(ai_resp_3)
-            .expect("serialize_address_info should serialize correctly");
+        let pinned_user = config.overrides.users[0].clone();
+        let pinned_group = config.overrides.groups[0].clone();
 
-        let output = handle_request(&test_logger(), &Config::default(), &request)
-            .expect("should handle request with no error");
+        let request = protocol::Request::new(RequestType::GETPWBYNAME, b"nsncd-pinned\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(serialize_user(Some(pinned_user.clone())).unwrap(), output);
 
+        let request = protocol::Request::new(RequestType::GETPWBYUID, b"4242\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(serialize_user(Some(pinned_user)).unwrap(), output);
+
+        let request = protocol::Request::new(RequestType::GETGRBYGID, b"4243\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(serialize_group(Some(pinned_group)).unwrap(), output);
+
+        // everything else still goes to NSS.
+        let current_user = User::from_uid(nix::unistd::geteuid()).unwrap().unwrap();
+        let key = CString::new(current_user.name.clone())
+            .unwrap()
+            .into_bytes_with_nul();
+        let request = protocol::Request::new(RequestType::GETPWBYNAME, &key);
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(serialize_user(Some(current_user)).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_local_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let passwd = dir.path().join("passwd");
+        let group = dir.path().join("group");
+        std::fs::write(&passwd, "nsncd-local:x:4242:4243::/srv:/bin/false\n").unwrap();
+        std::fs::write(&group, "nsncd-local:x:4243:\n").unwrap();
+        let local = std::sync::Arc::new(crate::files::LocalFiles::load(&passwd, &group).unwrap());
+        let config = Config {
+            local_files: Some(local.clone()),
+            ..Config::default()
+        };
+
+        // hit.
+        let request = protocol::Request::new(RequestType::GETPWBYNAME, b"nsncd-local\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        let local_user = local.table().users[0].clone();
+        assert_eq!(serialize_user(Some(local_user)).unwrap(), output);
+
+        // miss, which goes to NSS.
+        let current_user = User::from_uid(nix::unistd::geteuid()).unwrap().unwrap();
+        let key = CString::new(current_user.name.clone())
+            .unwrap()
+            .into_bytes_with_nul();
+        let request = protocol::Request::new(RequestType::GETPWBYNAME, &key);
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(serialize_user(Some(current_user)).unwrap(), output);
+
+        // an edit is picked up on refresh.
+        std::fs::write(&group, "nsncd-local:x:4243:\nnsncd-new:x:4244:\n").unwrap();
+        local.refresh().unwrap();
+        let request = protocol::Request::new(RequestType::GETGRBYGID, b"4244\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        let new_group = local
+            .table()
+            .group_by_gid(Gid::from_raw(4244))
+            .unwrap()
+            .clone();
+        assert_eq!(serialize_group(Some(new_group)).unwrap(), output);
+    }
+
+    #[test]
+    fn test_handle_request_name_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let passwd = dir.path().join("passwd");
+        let group = dir.path().join("group");
+        std::fs::write(&passwd, "migrated:x:1001:1001::/home/migrated:/bin/sh\n").unwrap();
+        std::fs::write(&group, "migrated:x:1001:\n").unwrap();
+        // the overrides stand in for the remote source NSS picked; the local
+        // files are what conflict detection compares it with.
+        let config = Config {
+            overrides: std::sync::Arc::new(crate::files::Table::parse(
+                b"migrated:x:50001:50001::/home/migrated:/bin/sh\n",
+                b"migrated:x:50001:\n",
+            )),
+            local_files: Some(std::sync::Arc::new(
+                crate::files::LocalFiles::load(&passwd, &group).unwrap(),
+            )),
+            detect_name_conflicts: true,
+            ..Config::default()
+        };
+        let (log, records) = capture_logger();
+
+        let request = protocol::Request::new(RequestType::GETPWBYNAME, b"migrated\0");
+        let output = handle_request(&log, &config, &request).unwrap();
+        let remote_user = config.overrides.users[0].clone();
+        assert_eq!(serialize_user(Some(remote_user)).unwrap(), output);
+
+        let request = protocol::Request::new(RequestType::GETGRBYNAME, b"migrated\0");
+        let output = handle_request(&log, &config, &request).unwrap();
+        let remote_group = config.overrides.groups[0].clone();
+        assert_eq!(serialize_group(Some(remote_group)).unwrap(), output);
+
+        let warnings: Vec<String> = records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| record.level == slog::Level::Warning)
+            .map(|record| record.msg.clone())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "passwd sources disagree, returning the NSS answer",
+                "group sources disagree, returning the NSS answer",
+            ]
+        );
+
+        // no warning when they agree, or when detection is off.
+        records.lock().unwrap().clear();
+        let quiet = Config {
+            detect_name_conflicts: false,
+            ..config.clone()
+        };
+        let request = protocol::Request::new(RequestType::GETPWBYNAME, b"migrated\0");
+        handle_request(&log, &quiet, &request).unwrap();
+        let agreeing = Config {
+            overrides: Default::default(),
+            ..config
+        };
+        handle_request(&log, &agreeing, &request).unwrap();
+        assert!(records
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|record| record.level != slog::Level::Warning));
+    }
+
+    #[test]
+    fn test_logged_key_is_escaped() {
+        let (log, records) = capture_logger();
+        let request = protocol::Request::new(RequestType::GETPWBYNAME, b"x\nERRO forged\x1b[0m\0");
+        let _ = handle_request(&log, &Config::default(), &request);
+
+        let records = records.lock().unwrap();
+        let logged = records
+            .iter()
+            .find(|record| record.msg == "handling request")
+            .and_then(|record| record.value("request"))
+            .expect("request should be logged");
         assert!(
-            expected_1 == output || expected_2 == output || expected_3 == output,
-            "\nExpecting \n{:?}\nTo be equal to\n{:?}\nor\n{:?}\nor\n{:?}\n",
-            output,
-            expected_1,
-            expected_2,
-            expected_3
+            logged.contains(r#"key: "x\x0aERRO forged\x1b[0m\x00""#),
+            "{}",
+            logged
         );
+        assert!(!logged.contains('\n') && !logged.contains('\x1b'));
     }
 
     #[test]
-    fn test_handle_gethostbyaddr() {
-        let request = protocol::Request {
-            ty: protocol::RequestType::GETHOSTBYADDR,
-            key: &[127, 0, 0, 1],
+    fn test_handle_request_merge_group_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let passwd = dir.path().join("passwd");
+        let group = dir.path().join("group");
+        std::fs::write(&passwd, "").unwrap();
+        std::fs::write(&group, "hybrid:x:60001:bob,carol\n").unwrap();
+        // the overrides and the local files stand in for two NSS sources
+        // (e.g. files and LDAP), each knowing some of the members.
+        let config = Config {
+            overrides: Arc::new(files::Table::parse(b"", b"hybrid:*:60001:alice,bob\n")),
+            local_files: Some(Arc::new(files::LocalFiles::load(&passwd, &group).unwrap())),
+            merge_group_sources: true,
+            ..Config::default()
         };
+        let mut expected = config.overrides.groups[0].clone();
+        expected.mem = vec!["alice".into(), "bob".into(), "carol".into()];
+        let expected = serialize_group(Some(expected)).unwrap();
 
-        let expected = serialize_hostent(Hostent {
-            addr_list: vec![IpAddr::from(Ipv4Addr::new(127, 0, 0, 1))],
-            name: CString::new(b"localhost".to_vec()).unwrap(),
-            addr_type: AF_INET,
-            aliases: Vec::new(),
-            herrno: 0,
-        })
-        .expect("must serialize");
+        let request = protocol::Request::new(RequestType::GETGRBYNAME, b"hybrid\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(expected, output);
+        let request = protocol::Request::new(RequestType::GETGRBYGID, b"60001\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(expected, output);
 
-        let output = handle_request(&test_logger(), &Config::default(), &request)
-            .expect("should handle request with no error");
+        // without merging, the first source wins.
+        let config = Config {
+            merge_group_sources: false,
+            ..config
+        };
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        let first = serialize_group(Some(config.overrides.groups[0].clone())).unwrap();
+        assert_eq!(first, output);
+    }
 
-        assert_eq!(expected, output)
+    #[test]
+    fn test_merge_group() {
+        let mut merged = None;
+        let group = |name: &str, gid, mem: &[&str]| Group {
+            name: name.into(),
+            passwd: CString::new("x").unwrap(),
+            gid: Gid::from_raw(gid),
+            mem: mem.iter().map(|m| m.to_string()).collect(),
+        };
+        merge_group(&mut merged, group("staff", 50, &["alice"]));
+        merge_group(&mut merged, group("staff", 50, &["alice", "bob"]));
+        // a different group that happens to share the name isn't merged.
+        merge_group(&mut merged, group("staff", 51, &["mallory"]));
+        assert_eq!(merged.unwrap().mem, vec!["alice", "bob"]);
     }
 
     #[test]
-    fn test_handle_gethostbyaddr_invalid_len() {
-        let request = protocol::Request {
-            ty: protocol::RequestType::GETHOSTBYADDR,
-            key: &[127, 0, 0],
+    fn test_handle_request_duplicate_uid() {
+        let dir = tempfile::tempdir().unwrap();
+        let passwd = dir.path().join("passwd");
+        let group = dir.path().join("group");
+        std::fs::write(
+            &passwd,
+            "admin:x:60100:60100::/root:/bin/sh\n\
+             toor:x:60100:60100::/root:/bin/csh\n\
+             other:x:60101:60101::/home/other:/bin/sh\n",
+        )
+        .unwrap();
+        std::fs::write(&group, "").unwrap();
+        let config = Config {
+            local_files: Some(Arc::new(files::LocalFiles::load(&passwd, &group).unwrap())),
+            detect_duplicate_uids: true,
+            ..Config::default()
         };
+        let (log, records) = capture_logger();
+
+        // the first entry in the file wins, every time.
+        let expected = serialize_user(Some(
+            config.local_files.as_ref().unwrap().table().users[0].clone(),
+        ))
+        .unwrap();
+        let request = protocol::Request::new(RequestType::GETPWBYUID, b"60100\0");
+        for _ in 0..2 {
+            let output = handle_request(&log, &config, &request).unwrap();
+            assert_eq!(expected, output);
+        }
+        let request = protocol::Request::new(RequestType::GETPWBYUID, b"60101\0");
+        handle_request(&log, &config, &request).unwrap();
 
-        let result = handle_request(&test_logger(), &Config::default(), &request);
+        let records = records.lock().unwrap();
+        let warnings: Vec<_> = records
+            .iter()
+            .filter(|record| record.level == slog::Level::Warning)
+            .collect();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].value("name"), Some("admin"));
+        assert_eq!(warnings[0].value("names"), Some("admin,toor"));
+        assert_eq!(config.stats.snapshot().duplicate_uids, 2);
+    }
 
-        assert!(result.is_err(), "should error on invalid length");
+    #[test]
+    fn test_reconcile_members() {
+        // what a paginating backend might return by gid and by name.
+        let by_gid = crate::files::Table::parse(b"", b"staff:x:50:alice,bob\n").groups;
+        let by_name = crate::files::Table::parse(b"", b"staff:x:50:bob,carol\n").groups;
+        let (log, records) = capture_logger();
+
+        let mut group = by_gid[0].clone();
+        reconcile_members(&log, &mut group, Some(&by_name[0]));
+        assert_eq!(group.mem, vec!["alice", "bob", "carol"]);
+        let mut group = by_name[0].clone();
+        reconcile_members(&log, &mut group, Some(&by_gid[0]));
+        assert_eq!(group.mem, vec!["bob", "carol", "alice"]);
+        assert_eq!(records.lock().unwrap().len(), 2);
+        let warning = records.lock().unwrap()[0].clone();
+        assert_eq!(warning.level, slog::Level::Warning);
+        assert_eq!(warning.value("group"), Some("staff"));
+        assert_eq!(warning.value("added"), Some("1"));
+
+        // consistent answers, or a different group, are left alone.
+        records.lock().unwrap().clear();
+        let mut group = by_gid[0].clone();
+        reconcile_members(&log, &mut group, Some(&by_gid[0]));
+        let other = crate::files::Table::parse(b"", b"staff:x:51:dave\n").groups;
+        reconcile_members(&log, &mut group, Some(&other[0]));
+        reconcile_members(&log, &mut group, None);
+        assert_eq!(group.mem, vec!["alice", "bob"]);
+        assert!(records.lock().unwrap().is_empty());
     }
 
     #[test]
-    // Fails on CI: depending on the host setup, we might get
-    // different or less aliases for localhost.
-    #[ignore]
-    fn test_handle_gethostbyaddrv6() {
-        let request = protocol::Request {
-            ty: protocol::RequestType::GETHOSTBYADDRv6,
-            key: &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+    fn test_handle_request_fold_name_case() {
+        let mut config = Config {
+            overrides: std::sync::Arc::new(crate::files::Table::parse(
+                b"nsncd-alice:x:4242:4242:::\n",
+                b"",
+            )),
+            ..Config::default()
         };
+        let lower = protocol::Request::new(RequestType::GETPWBYNAME, b"nsncd-alice\0");
+        let upper = protocol::Request::new(RequestType::GETPWBYNAME, b"NSNCD-Alice\0");
+        let found = serialize_user(Some(config.overrides.users[0].clone())).unwrap();
+
+        // case-sensitive by default.
+        let output = handle_request(&test_logger(), &config, &upper).unwrap();
+        assert_eq!(serialize_user(None).unwrap(), output);
+
+        config.fold_name_case = true;
+        let output = handle_request(&test_logger(), &config, &upper).unwrap();
+        assert_eq!(found, output);
+        let output = handle_request(&test_logger(), &config, &lower).unwrap();
+        assert_eq!(found, output);
+
+        // both are the same cached reply.
+        let mut policies = [Policy::default(); protocol::DATABASES.len()];
+        policies[0].ttl = Duration::from_secs(60);
+        config.cache = Arc::new(ResponseCache::new(policies));
+        let output = handle_request(&test_logger(), &config, &upper).unwrap();
+        assert_eq!(found, output);
+        let cached = config
+            .cache
+            .get(RequestType::GETPWBYNAME, b"nsncd-alice\0")
+            .expect("should be cached under the folded name");
+        assert_eq!(&cached.reply()[..], &found[..]);
+        assert!(config
+            .cache
+            .get(RequestType::GETPWBYNAME, b"NSNCD-Alice\0")
+            .is_none());
+    }
 
-        let expected = serialize_hostent(Hostent {
-            addr_list: vec![IpAddr::from(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))],
-            name: CString::new(b"localhost".to_vec()).unwrap(),
-            addr_type: AF_INET6,
-            aliases: Vec::new(),
-            herrno: 0,
-        })
-        .expect("must serialize");
+    #[test]
+    fn test_handle_request_user_with_nul() {
+        let config = Config {
+            overrides: std::sync::Arc::new(crate::files::Table::parse(
+                b"bad\0name:x:4244:4244:::\n",
+                b"",
+            )),
+            ..Config::default()
+        };
+        assert!(serialize_user(Some(config.overrides.users[0].clone())).is_err());
 
-        let output = handle_request(&test_logger(), &Config::default(), &request)
+        let request = protocol::Request::new(RequestType::GETPWBYUID, b"4244\0");
+        let output = handle_request(&test_logger(), &config, &request)
             .expect("should handle request with no error");
+        assert_eq!(serialize_user(None).unwrap(), output);
 
-        assert_eq!(expected, output)
+        let request = protocol::Request::new(RequestType::BATCHGETPWBYUID, b"4244\x000\0");
+        let output = handle_request(&test_logger(), &config, &request)
+            .expect("should handle request with no error");
+        let mut expected = protocol::BatchResponseHeader {
+            version: protocol::VERSION,
+            nentries: 2,
+        }
+        .as_slice()
+        .to_vec();
+        expected.extend(serialize_user(None).unwrap());
+        expected.extend(serialize_user(User::from_uid(Uid::from_raw(0)).unwrap()).unwrap());
+        assert_eq!(expected, output);
     }
 
     #[test]
-    fn test_handle_gethostbyaddrv6_invalid_len() {
-        let request = protocol::Request {
-            ty: protocol::RequestType::GETHOSTBYADDRv6,
-            key: &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    fn test_handle_request_group_with_nul() {
+        let config = Config {
+            overrides: std::sync::Arc::new(crate::files::Table::parse(
+                b"",
+                b"bad\0name:x:4245:\nstaff:x:4246:alice,b\0b\n",
+            )),
+            ..Config::default()
         };
+        for group in &config.overrides.groups {
+            assert!(serialize_group(Some(group.clone())).is_err());
+        }
 
-        let result = handle_request(&test_logger(), &Config::default(), &request);
+        for (ty, key) in [
+            (RequestType::GETGRBYGID, &b"4245\0"[..]),
+            (RequestType::GETGRBYGID, b"4246\0"),
+            (RequestType::GETGRBYNAME, b"staff\0"),
+        ] {
+            let request = protocol::Request::new(ty, key);
+            let output = handle_request(&test_logger(), &config, &request)
+                .expect("should handle request with no error");
+            assert_eq!(serialize_group(None).unwrap(), output);
+        }
+    }
 
-        assert!(result.is_err(), "should error on invalid length");
+    #[test]
+    fn test_handle_request_initgroups() {
+        let current_user = User::from_uid(nix::unistd::geteuid()).unwrap().unwrap();
+        let name = CString::new(current_user.name.clone()).unwrap();
+        let request = protocol::Request::new(RequestType::INITGROUPS, name.as_bytes_with_nul());
+
+        let groups = getgrouplist(&name, current_user.gid).unwrap();
+        assert!(groups.contains(&current_user.gid));
+        let expected = serialize_initgroups(groups).unwrap();
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+        assert_eq!(expected, output);
     }
 
     #[test]
-    fn test_hostent_serialization() {
-        let hostent = serialize_hostent(Hostent {
-            name: CString::new(b"trantor.alternativebit.fr".to_vec()).unwrap(),
-            aliases: vec![CString::new(b"trantor".to_vec()).unwrap()],
-            addr_type: AF_INET6,
-            addr_list: vec![IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))],
-            herrno: 0,
-        })
-        .expect("should serialize");
+    fn test_handle_request_initgroups_unknown_user() {
+        let request = protocol::Request::new(RequestType::INITGROUPS, b"nsncd-no-such-user\0");
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+        // found, with no groups: glibc would take a refusal to mean we don't
+        // do group lookups at all.
+        let header = protocol::InitgroupsResponseHeader {
+            version: protocol::VERSION,
+            found: 1,
+            ngrps: 0,
+        };
+        assert_eq!(header.as_slice(), output);
+    }
 
-        // Captured through a mismatched sockburp run
-        let expected_bytes: Vec<u8> = vec![
-            0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x1a, 0x00, 0x00, 0x00, 0x01, 0x00,
-            0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x74, 0x72, 0x61, 0x6e, 0x74, 0x6f, 0x72, 0x2e, 0x61, 0x6c,
-            0x74, 0x65, 0x72, 0x6e, 0x61, 0x74, 0x69, 0x76, 0x65, 0x62, 0x69, 0x74, 0x2e, 0x66,
-            0x72, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x74, 0x72, 0x61, 0x6e, 0x74, 0x6f,
-            0x72, 0x00,
-        ];
-        assert_eq!(hostent, expected_bytes)
+    #[test]
+    fn test_handle_request_initgroups_key_is_username() {
+        // glibc sends nothing after the username, so a key with more is
+        // malformed.
+        let mut key = b"root\0".to_vec();
+        key.extend_from_slice(&4321u32.to_ne_bytes());
+        let request = protocol::Request::new(RequestType::INITGROUPS, &key);
+        assert!(handle_request(&test_logger(), &Config::default(), &request).is_err());
+    }
+
+    /// A directory with one user and one group, whose lookups of anything
+    /// else fail.
+    struct FakeBackend;
+
+    impl Backend for FakeBackend {
+        fn user_by_uid(&self, uid: Uid) -> nix::Result<Option<User>> {
+            match uid.as_raw() {
+                4321 => self.user_by_name("alice"),
+                _ => Err(Errno::EIO),
+            }
+        }
+
+        fn user_by_name(&self, name: &str) -> nix::Result<Option<User>> {
+            match name {
+                "alice" => Ok(Some(User {
+                    name: "alice".to_string(),
+                    passwd: CString::new("x").unwrap(),
+                    uid: Uid::from_raw(4321),
+                    gid: Gid::from_raw(4321),
+                    gecos: CString::new("").unwrap(),
+                    dir: "/home/alice".into(),
+                    shell: "/bin/sh".into(),
+                })),
+                "ghost" => Ok(None),
+                _ => Err(Errno::EIO),
+            }
+        }
+
+        fn group_by_gid(&self, gid: Gid) -> nix::Result<Option<Group>> {
+            match gid.as_raw() {
+                4300 => self.group_by_name("staff"),
+                _ => Err(Errno::EIO),
+            }
+        }
+
+        fn group_by_name(&self, name: &str) -> nix::Result<Option<Group>> {
+            match name {
+                "staff" => Ok(Some(Group {
+                    name: "staff".to_string(),
+                    passwd: CString::new("x").unwrap(),
+                    gid: Gid::from_raw(4300),
+                    mem: vec!["alice".into(), "bob".into(), "carol".into()],
+                })),
+                _ => Err(Errno::EIO),
+            }
+        }
+
+        fn group_list(&self, _user: &CStr, group: Gid) -> nix::Result<Vec<Gid>> {
+            Ok(vec![group, Gid::from_raw(100)])
+        }
+    }
+
+    #[test]
+    fn test_lookup_backends() {
+        let log = test_logger();
+        let mut config = Config::default();
+        config.backends.passwd = Arc::new(FakeBackend);
+
+        let request = protocol::Request::new(RequestType::GETPWBYUID, b"4321\0");
+        match lookup(&log, &config, &request).unwrap() {
+            Response::Pw(Some(user)) => assert_eq!(user.name, "alice"),
+            other => panic!("unexpected response {:?}", other),
+        }
+        // backend failures are errors, not "not found".
+        let request = protocol::Request::new(RequestType::GETPWBYNAME, b"bob\0");
+        let err = lookup(&log, &config, &request).unwrap_err();
+        assert!(err.chain().any(|cause| cause.is::<nix::Error>()));
+        // the group database still goes to NSS.
+        let request = protocol::Request::new(RequestType::GETGRBYGID, b"0\0");
+        assert!(matches!(
+            lookup(&log, &config, &request).unwrap(),
+            Response::Gr(Some(_))
+        ));
+
+        config.backends.group = Arc::new(FakeBackend);
+        let request = protocol::Request::new(RequestType::INITGROUPS, b"alice\0");
+        match lookup(&log, &config, &request).unwrap() {
+            Response::Initgroups(groups) => {
+                assert_eq!(groups, vec![Gid::from_raw(4321), Gid::from_raw(100)])
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    /// Only answers group lists, like a directory too big to enumerate.
+    struct GroupListOnly;
+
+    impl Backend for GroupListOnly {
+        fn user_by_uid(&self, _uid: Uid) -> nix::Result<Option<User>> {
+            unreachable!()
+        }
+
+        fn user_by_name(&self, _name: &str) -> nix::Result<Option<User>> {
+            unreachable!()
+        }
+
+        fn group_by_gid(&self, _gid: Gid) -> nix::Result<Option<Group>> {
+            panic!("INITGROUPS looked groups up one by one")
+        }
+
+        fn group_by_name(&self, _name: &str) -> nix::Result<Option<Group>> {
+            panic!("INITGROUPS looked groups up one by one")
+        }
+
+        fn group_list(&self, _user: &CStr, group: Gid) -> nix::Result<Vec<Gid>> {
+            Ok(vec![group])
+        }
+    }
+
+    #[test]
+    fn test_initgroups_uses_group_list() {
+        let mut config = Config::default();
+        config.backends.passwd = Arc::new(FakeBackend);
+        config.backends.group = Arc::new(GroupListOnly);
+        let request = protocol::Request::new(RequestType::INITGROUPS, b"alice\0");
+        match lookup(&test_logger(), &config, &request).unwrap() {
+            Response::Initgroups(groups) => assert_eq!(groups, vec![Gid::from_raw(4321)]),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    /// A group backend counting its group list lookups, which take a while.
+    struct SlowGroupList(std::sync::atomic::AtomicUsize);
+
+    impl Backend for SlowGroupList {
+        fn user_by_uid(&self, _uid: Uid) -> nix::Result<Option<User>> {
+            unreachable!()
+        }
+
+        fn user_by_name(&self, _name: &str) -> nix::Result<Option<User>> {
+            unreachable!()
+        }
+
+        fn group_by_gid(&self, _gid: Gid) -> nix::Result<Option<Group>> {
+            unreachable!()
+        }
+
+        fn group_by_name(&self, _name: &str) -> nix::Result<Option<Group>> {
+            unreachable!()
+        }
+
+        fn group_list(&self, _user: &CStr, group: Gid) -> nix::Result<Vec<Gid>> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(vec![group])
+        }
+    }
+
+    #[test]
+    fn test_initgroups_shared() {
+        let mut config = Config::default();
+        config.backends.passwd = Arc::new(FakeBackend);
+        let backend = Arc::new(SlowGroupList(Default::default()));
+        config.backends.group = backend.clone();
+        let request = protocol::Request::new(RequestType::INITGROUPS, b"alice\0");
+        let barrier = std::sync::Barrier::new(8);
+        let replies: Vec<_> = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        barrier.wait();
+                        handle_request(&test_logger(), &config, &request).unwrap()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        for reply in replies {
+            let groups = protocol::deserialize::initgroups(&reply).unwrap();
+            assert_eq!(groups, vec![Gid::from_raw(4321)]);
+        }
+        // concurrent requests for the same user share one lookup.
+        assert_eq!(backend.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let mut config = Config::default();
+        config.backends.passwd = Arc::new(FakeBackend);
+        let mut policies = [Policy::default(); protocol::DATABASES.len()];
+        policies[0].ttl = Duration::from_secs(60);
+        config.cache = Arc::new(ResponseCache::new(policies));
+        let log = test_logger();
+        let alice = protocol::Request::new(RequestType::GETPWBYNAME, b"alice\0");
+
+        let first = handle_request(&log, &config, &alice).unwrap();
+        // answered from the cache, without asking the backend.
+        config.backends.passwd = Arc::new(GroupListOnly);
+        assert_eq!(handle_request(&log, &config, &alice).unwrap(), first);
+        let snapshot = config.stats.snapshot();
+        assert_eq!((snapshot.cache_hits, snapshot.cache_misses), (1, 1));
+        assert_eq!(snapshot.hits_of(RequestType::GETPWBYNAME), 1);
+        let passwd = &serialize_stats(&config).dbs[0];
+        assert_eq!((passwd.nentries, passwd.maxnentries), (1, 1));
+        assert_eq!(passwd.dataused, first.len() + b"alice\0".len());
+        assert_eq!((passwd.check_file, passwd.persistent), (0, 0));
+
+        let (capture, records) = capture_logger();
+        log_cache_stats(&capture, &config);
+        let logged: Vec<_> = records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| {
+                (
+                    record.value("database").unwrap().to_string(),
+                    record.value("hits").unwrap().to_string(),
+                    record.value("evictions").unwrap().to_string(),
+                )
+            })
+            .collect();
+        // only cached databases are logged.
+        let expected = ("passwd".to_string(), "1".to_string(), "0".to_string());
+        assert_eq!(logged, vec![expected]);
+
+        // failures aren't cached.
+        config.backends.passwd = Arc::new(FakeBackend);
+        let bob = protocol::Request::new(RequestType::GETPWBYNAME, b"bob\0");
+        assert!(handle_request(&log, &config, &bob).is_err());
+        assert!(config
+            .cache
+            .get(RequestType::GETPWBYNAME, b"bob\0")
+            .is_none());
+
+        let (capture, records) = capture_logger();
+        let invalidate = protocol::Request::new(RequestType::INVALIDATE, b"passwd\0");
+        handle_request(&capture, &config, &invalidate).unwrap();
+        let records = records.lock().unwrap();
+        let record = records
+            .iter()
+            .find(|record| record.msg == "invalidating database")
+            .unwrap();
+        assert_eq!(record.value("cached_entries"), Some("1"));
+        assert_eq!(config.cache.occupancy(0).invalidated, 1);
+        assert!(config
+            .cache
+            .get(RequestType::GETPWBYNAME, b"alice\0")
+            .is_none());
+
+        // nor are the answers to clients that bypass the cache.
+        config.cache_bypass_uids = vec![Uid::from_raw(4242)];
+        let mut agent = protocol::Request::new(RequestType::GETPWBYNAME, b"alice\0");
+        agent.peer_uid = Some(Uid::from_raw(4242));
+        handle_request(&log, &config, &agent).unwrap();
+        assert!(config
+            .cache
+            .get(RequestType::GETPWBYNAME, b"alice\0")
+            .is_none());
+
+        config.cache_bypass_types.insert(&RequestType::GETPWBYNAME);
+        handle_request(&log, &config, &alice).unwrap();
+        assert!(config
+            .cache
+            .get(RequestType::GETPWBYNAME, b"alice\0")
+            .is_none());
+    }
+
+    #[test]
+    fn test_stale_cache() {
+        let mut config = Config::default();
+        config.backends.passwd = Arc::new(FakeBackend);
+        let mut policies = [Policy::default(); protocol::DATABASES.len()];
+        policies[0].ttl = Duration::from_millis(50);
+        policies[0].max_stale = Duration::from_secs(60);
+        config.cache = Arc::new(ResponseCache::new(policies).with_refresher(1));
+        let log = test_logger();
+        let alice = protocol::Request::new(RequestType::GETPWBYNAME, b"alice\0");
+        let ty = alice.ty;
+        // wait for the background refresh to be done, one way or another.
+        let refreshed = |config: &Config| {
+            for _ in 0..100 {
+                match config.cache.get(ty, b"alice\0") {
+                    Some(Cached::Stale { refresh: false, .. }) => {
+                        std::thread::sleep(Duration::from_millis(10))
+                    }
+                    cached => return cached,
+                }
+            }
+            panic!("refresh never finished");
+        };
+
+        let first = handle_request(&log, &config, &alice).unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        // served stale, and refreshed.
+        assert_eq!(handle_request(&log, &config, &alice).unwrap(), first);
+        assert!(matches!(refreshed(&config), Some(Cached::Fresh(_))));
+
+        // a failed refresh keeps the stale reply, for the next request to
+        // try again.
+        std::thread::sleep(Duration::from_millis(60));
+        config.backends.passwd = Arc::new(GroupListOnly);
+        assert_eq!(handle_request(&log, &config, &alice).unwrap(), first);
+        assert!(matches!(
+            refreshed(&config),
+            Some(Cached::Stale { refresh: true, .. })
+        ));
+        assert_eq!(config.stats.snapshot().hits_of(ty), 2);
+    }
+
+    #[test]
+    fn test_reload_cache() {
+        let mut config = Config::default();
+        config.backends.passwd = Arc::new(FakeBackend);
+        let mut policies = [Policy::default(); protocol::DATABASES.len()];
+        policies[0].ttl = Duration::from_millis(200);
+        policies[0].reload_count = 1;
+        config.cache = Arc::new(ResponseCache::new(policies).with_refresher(1));
+        let log = test_logger();
+        let alice = protocol::Request::new(RequestType::GETPWBYNAME, b"alice\0");
+
+        let first = handle_request(&log, &config, &alice).unwrap();
+        std::thread::sleep(Duration::from_millis(160));
+        // answered right away, and refreshed before it expires.
+        assert_eq!(handle_request(&log, &config, &alice).unwrap(), first);
+        std::thread::sleep(Duration::from_millis(100));
+        let cached = config.cache.get(alice.ty, b"alice\0");
+        assert!(matches!(cached, Some(Cached::Fresh(_))));
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let mut request = protocol::Request::new(RequestType::GETPWBYNAME, b"alice\0");
+        assert_eq!(cache_key(&request).unwrap(), &b"alice\0"[..]);
+        request.peer_uid = Some(Uid::from_raw(1000));
+        assert_eq!(cache_key(&request).unwrap(), &b"alice\0"[..]);
+
+        // shadow entries fetched for root are kept apart from everyone else's.
+        let mut request = protocol::Request::new(RequestType::GETSPBYNAME, b"alice\0");
+        assert!(cache_key(&request).is_none());
+        request.peer_uid = Some(Uid::from_raw(0));
+        let root = cache_key(&request).unwrap().into_owned();
+        request.peer_uid = Some(Uid::from_raw(1000));
+        let user = cache_key(&request).unwrap().into_owned();
+        assert_ne!(root, user);
+        assert!(root.ends_with(b"alice\0") && user.ends_with(b"alice\0"));
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let mut config = Config::default();
+        config.backends.passwd = Arc::new(FakeBackend);
+        let mut policies = [Policy::default(); protocol::DATABASES.len()];
+        policies[0].ttl = Duration::from_secs(60);
+        config.cache = Arc::new(ResponseCache::new(policies));
+        config.warm.users = vec!["alice".to_string(), "bob".to_string()];
+        config.warm.hosts = vec!["localhost".to_string()];
+        let log = test_logger();
+        let cached = |config: &Config| {
+            for _ in 0..100 {
+                if let Some(alice) = config.cache.get(RequestType::GETPWBYNAME, b"alice\0") {
+                    return alice;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            panic!("alice was never cached");
+        };
+
+        warm_up(&log, &config, &protocol::DATABASES);
+        cached(&config);
+        // bob doesn't exist, and hosts aren't cached.
+        assert!(config
+            .cache
+            .get(RequestType::GETAI, b"localhost\0")
+            .is_none());
+
+        // looked up again once invalidated.
+        invalidate(&log, &config, "passwd");
+        cached(&config);
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let mut config = Config::default();
+        config.backends.passwd = Arc::new(FakeBackend);
+        let mut policies = [Policy::default(); protocol::DATABASES.len()];
+        policies[0].ttl = Duration::from_secs(60);
+        policies[0].negative_ttl = Duration::from_secs(5);
+        config.cache = Arc::new(ResponseCache::new(policies));
+        let log = test_logger();
+        let ghost = protocol::Request::new(RequestType::GETPWBYNAME, b"ghost\0");
+
+        let first = handle_request(&log, &config, &ghost).unwrap();
+        assert_eq!(protocol::reply_found(ghost.ty, &first), Some(false));
+        config.backends.passwd = Arc::new(GroupListOnly);
+        assert_eq!(handle_request(&log, &config, &ghost).unwrap(), first);
+        let snapshot = config.stats.snapshot();
+        assert_eq!(snapshot.negative_hits_of(RequestType::GETPWBYNAME), 1);
+        assert_eq!(snapshot.hits_of(RequestType::GETPWBYNAME), 0);
+
+        // a DNS failure may be gone on the next try.
+        let try_again = protocol::H_ERRNO_TRY_AGAIN;
+        assert!(Response::AiError(try_again).is_transient());
+        assert!(Response::Hst(Hostent::error_value(try_again)).is_transient());
+        let not_found = Hostent::error_value(protocol::H_ERRNO_HOST_NOT_FOUND);
+        assert!(!Response::Hst(not_found).is_transient());
+        assert!(!Response::Pw(None).is_transient());
+    }
+
+    #[test]
+    fn test_max_group_members() {
+        let (log, records) = capture_logger();
+        let mut config = Config::default();
+        config.backends.group = Arc::new(FakeBackend);
+        let by_gid = protocol::Request::new(RequestType::GETGRBYGID, b"4300\0");
+        let by_name = protocol::Request::new(RequestType::GETGRBYNAME, b"staff\0");
+
+        config.max_group_members = Some(3);
+        match lookup(&log, &config, &by_gid).unwrap() {
+            Response::Gr(Some(group)) => assert_eq!(group.mem.len(), 3),
+            other => panic!("unexpected response {:?}", other),
+        }
+        let warnings = |records: &Captured| -> Vec<CapturedRecord> {
+            records
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|record| record.level == slog::Level::Warning)
+                .cloned()
+                .collect()
+        };
+        assert!(warnings(&records).is_empty());
+
+        config.max_group_members = Some(2);
+        for request in [&by_gid, &by_name] {
+            match lookup(&log, &config, request).unwrap() {
+                Response::Gr(Some(group)) => assert_eq!(group.mem, vec!["alice", "bob"]),
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+
+        config.member_overflow = MemberOverflow::Refuse;
+        assert!(matches!(
+            lookup(&log, &config, &by_name).unwrap(),
+            Response::None
+        ));
+
+        assert_eq!(config.stats.snapshot().oversized_groups, 3);
+        let records = warnings(&records);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].value("group"), Some("staff"));
+        assert_eq!(records[0].value("members"), Some("3"));
+        assert_eq!(records[2].msg, "group has too many members, not serving it");
+    }
+
+    #[test]
+    fn test_handle_request_invalidate_hooks() {
+        struct Recorder(std::sync::Mutex<Vec<String>>);
+        impl crate::invalidate::InvalidationHook for Recorder {
+            fn invalidate(&self, _log: &Logger, database: &str) {
+                self.0.lock().unwrap().push(database.to_string());
+            }
+        }
+        let recorder = Arc::new(Recorder(Default::default()));
+        let mut config = Config::default();
+        config.invalidation_hooks.push(recorder.clone());
+
+        let request = protocol::Request::new(RequestType::INVALIDATE, b"passwd\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(output, 0i32.to_ne_bytes());
+
+        let request = protocol::Request::new(RequestType::INVALIDATE, b"shadow\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(output, nix::libc::EINVAL.to_ne_bytes());
+
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["passwd"]);
+    }
+
+    #[test]
+    fn test_handle_request_getfd() {
+        // the key isn't even looked at, so garbage doesn't matter.
+        for ty in [
+            RequestType::GETFDPW,
+            RequestType::GETFDGR,
+            RequestType::GETFDHST,
+            RequestType::GETFDSERV,
+            RequestType::GETFDNETGR,
+        ] {
+            let request = protocol::Request::new(ty, &[0xff, 0xff]);
+            let output = handle_request(&test_logger(), &Config::default(), &request)
+                .expect("should handle request with no error");
+            assert!(output.is_empty());
+        }
+
+        // not even when caching.
+        let mut policies = [Policy::default(); protocol::DATABASES.len()];
+        for policy in policies.iter_mut() {
+            policy.ttl = Duration::from_secs(60);
+        }
+        let config = Config {
+            cache: Arc::new(ResponseCache::new(policies)),
+            ..Config::default()
+        };
+        let request = protocol::Request::new(RequestType::GETFDPW, b"passwd\0");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_serialize_user_empty_fields() {
+        let user = User {
+            name: "nobody".to_string(),
+            passwd: CString::new("x").unwrap(),
+            uid: Uid::from_raw(65534),
+            gid: Gid::from_raw(65534),
+            gecos: CString::new("").unwrap(),
+            dir: "/nonexistent".into(),
+            shell: "".into(),
+        };
+
+        let mut expected = vec![];
+        expected.extend_from_slice(
+            protocol::PwResponseHeader {
+                version: protocol::VERSION,
+                found: 1,
+                pw_name_len: 7,
+                pw_passwd_len: 2,
+                pw_uid: 65534,
+                pw_gid: 65534,
+                pw_gecos_len: 1,
+                pw_dir_len: 13,
+                pw_shell_len: 1,
+            }
+            .as_slice(),
+        );
+        expected.extend_from_slice(b"nobody\0x\0\0/nonexistent\0\0");
+
+        let output = serialize_user(Some(user)).expect("should serialize empty fields");
+        assert_eq!(expected, output);
+        assert_ne!(output, serialize_user(None).unwrap());
+    }
+
+    #[test]
+    fn test_serialize_user_empty_dir() {
+        let user = User {
+            name: "daemon".to_string(),
+            passwd: CString::new("*").unwrap(),
+            uid: Uid::from_raw(1),
+            gid: Gid::from_raw(1),
+            gecos: CString::new("daemon").unwrap(),
+            dir: "".into(),
+            shell: "".into(),
+        };
+
+        let output = serialize_user(Some(user)).expect("should serialize empty dir");
+        let header_len = std::mem::size_of::<protocol::PwResponseHeader>();
+        let header = protocol::PwResponseHeader {
+            version: protocol::VERSION,
+            found: 1,
+            pw_name_len: 7,
+            pw_passwd_len: 2,
+            pw_uid: 1,
+            pw_gid: 1,
+            pw_gecos_len: 7,
+            pw_dir_len: 1,
+            pw_shell_len: 1,
+        };
+        assert_eq!(&output[..header_len], header.as_slice());
+        assert_eq!(&output[header_len..], b"daemon\0*\0daemon\0\0\0");
+    }
+
+    #[test]
+    fn test_serialize_user_not_found() {
+        let output = serialize_user(None).unwrap();
+        // only the header, all zeros: found = 0 and every length 0, where an
+        // entry always has found = 1 and lengths of at least 1 for the NULs.
+        assert_eq!(
+            output.len(),
+            std::mem::size_of::<protocol::PwResponseHeader>()
+        );
+        assert!(output.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_serialize_large_group() {
+        let group = Group {
+            name: "everyone".to_string(),
+            passwd: CString::new("x").unwrap(),
+            gid: Gid::from_raw(4300),
+            mem: (0..50_000).map(|i| format!("user{}", i)).collect(),
+        };
+
+        // lay the reply out by hand: header, member lengths, name, passwd,
+        // members.
+        let mut expected = protocol::GrResponseHeader {
+            version: protocol::VERSION,
+            found: 1,
+            gr_name_len: 9,
+            gr_passwd_len: 2,
+            gr_gid: 4300,
+            gr_mem_cnt: 50_000,
+        }
+        .as_slice()
+        .to_vec();
+        for member in group.mem.iter() {
+            expected.extend_from_slice(&(member.len() as i32 + 1).to_ne_bytes());
+        }
+        expected.extend_from_slice(b"everyone\0x\0");
+        for member in group.mem.iter() {
+            expected.extend_from_slice(member.as_bytes());
+            expected.push(0);
+        }
+
+        let output = serialize_group(Some(group)).unwrap();
+        assert_eq!(output.len(), expected.len());
+        assert_eq!(output, expected);
+        assert_eq!(
+            output.capacity(),
+            output.len(),
+            "should allocate exactly once"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        use protocol::deserialize;
+
+        let current_user = User::from_uid(nix::unistd::geteuid()).unwrap().unwrap();
+        let output = serialize_user(Some(current_user.clone())).unwrap();
+        assert_eq!(deserialize::user(&output).unwrap(), Some(current_user));
+        let output = serialize_user(None).unwrap();
+        assert_eq!(deserialize::user(&output).unwrap(), None);
+
+        let group = Group {
+            name: "staff".to_string(),
+            passwd: CString::new("x").unwrap(),
+            gid: Gid::from_raw(4300),
+            mem: vec!["alice".to_string(), "bob".to_string()],
+        };
+        let output = serialize_group(Some(group.clone())).unwrap();
+        assert_eq!(deserialize::group(&output).unwrap(), Some(group));
+
+        let groups = vec![Gid::from_raw(100), Gid::from_raw(4300)];
+        let output = serialize_initgroups(groups.clone()).unwrap();
+        assert_eq!(deserialize::initgroups(&output).unwrap(), groups);
+    }
+
+    #[test]
+    fn test_serialize_group_member_with_nul() {
+        let group = Group {
+            name: "g".to_string(),
+            passwd: CString::new("x").unwrap(),
+            gid: Gid::from_raw(4300),
+            mem: vec!["a".to_string(), "b\0c".to_string()],
+        };
+        assert!(serialize_group(Some(group)).is_err());
+    }
+
+    #[test]
+    fn test_serialize_group_passwd() {
+        for passwd in ["", "!", "x", "$6$salt$hash"] {
+            let group = Group {
+                name: "g".to_string(),
+                passwd: CString::new(passwd).unwrap(),
+                gid: Gid::from_raw(4300),
+                mem: vec![],
+            };
+            let output = serialize_group(Some(group)).unwrap();
+            let group = protocol::deserialize::group(&output).unwrap().unwrap();
+            assert_eq!(group.passwd.to_bytes(), passwd.as_bytes());
+        }
+
+        // same for NSS's answer.
+        let nss = Group::from_gid(Gid::from_raw(0)).unwrap().unwrap();
+        let request = protocol::Request::new(RequestType::GETGRBYGID, b"0\0");
+        let output = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        let served = protocol::deserialize::group(&output).unwrap().unwrap();
+        assert_eq!(served.passwd, nss.passwd);
+    }
+
+    #[test]
+    fn test_handle_request_batch_getpwbyuid() {
+        let current_user = User::from_uid(nix::unistd::geteuid()).unwrap().unwrap();
+        let root = User::from_uid(Uid::from_raw(0)).unwrap();
+        let nobody = User::from_uid(Uid::from_raw(4294967294)).unwrap();
+        let key = format!("{}\x000\x004294967294\x00", current_user.uid);
+
+        let request =
+            protocol::Request::new(protocol::RequestType::BATCHGETPWBYUID, key.as_bytes());
+
+        let mut expected = vec![];
+        expected.extend_from_slice(
+            protocol::BatchResponseHeader {
+                version: protocol::VERSION,
+                nentries: 3,
+            }
+            .as_slice(),
+        );
+        expected.extend(serialize_user(Some(current_user)).unwrap());
+        expected.extend(serialize_user(root).unwrap());
+        expected.extend(serialize_user(nobody).unwrap());
+
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn test_handle_request_batch_getpwbyuid_invalid() {
+        let request = protocol::Request::new(protocol::RequestType::BATCHGETPWBYUID, b"0\x001");
+
+        let result = handle_request(&test_logger(), &Config::default(), &request);
+        assert!(result.is_err(), "should error on missing trailing NUL");
+    }
+
+    #[test]
+    fn test_handle_request_batch_getpwbyname() {
+        let root = User::from_uid(Uid::from_raw(0)).unwrap().unwrap();
+        let key = format!("{}\0nsncd-no-such-user\0{}\0", root.name, root.name);
+        let request =
+            protocol::Request::new(protocol::RequestType::BATCHGETPWBYNAME, key.as_bytes());
+
+        let mut expected = protocol::BatchResponseHeader {
+            version: protocol::VERSION,
+            nentries: 3,
+        }
+        .as_slice()
+        .to_vec();
+        expected.extend(serialize_user(Some(root.clone())).unwrap());
+        expected.extend(serialize_user(None).unwrap());
+        expected.extend(serialize_user(Some(root)).unwrap());
+
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+        assert_eq!(expected, output);
+
+        // every name has to be terminated, the last one included.
+        let request = protocol::Request::new(protocol::RequestType::BATCHGETPWBYNAME, b"root\0bin");
+        assert!(handle_request(&test_logger(), &Config::default(), &request).is_err());
+    }
+
+    #[test]
+    fn test_serialize_address_info() {
+        let output = serialize_address_info(protocol::AiResponse {
+            addrs: vec![
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ],
+            canon_name: "www.example.com".to_string(),
+        })
+        .unwrap();
+
+        // all the addresses, packed, then one family byte per address, then
+        // the canonical name.
+        let mut expected = vec![];
+        for field in [protocol::VERSION, 1, 2, 4 + 16, 16, 0].iter() {
+            expected.extend_from_slice(&field.to_ne_bytes());
+        }
+        expected.extend_from_slice(&[192, 0, 2, 1]);
+        expected.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        expected.extend_from_slice(&[AF_INET as u8, AF_INET6 as u8]);
+        expected.extend_from_slice(b"www.example.com\0");
+        assert_eq!(output, expected);
+        assert_eq!(output.capacity(), output.len());
+    }
+
+    #[test]
+    fn test_handle_request_getai() {
+        let key = CString::new("localhost".to_string())
+            .unwrap()
+            .into_bytes_with_nul();
+        let request = protocol::Request::new(protocol::RequestType::GETAI, &key);
+
+        // The getaddrinfo call can actually return different ordering, or in the case of a
+        // IPv4-only host, only return an IPv4 response.
+        // Be happy with any of these permutations.
+        let gen_ai_resp = |addrs| protocol::AiResponse {
+            addrs,
+            canon_name: "localhost".to_string(),
+        };
+        let ai_resp_1 = gen_ai_resp(vec![
+            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        ]);
+        let ai_resp_2 = gen_ai_resp(vec![
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+        ]);
+        let ai_resp_3 = gen_ai_resp(vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]);
+        let expected_1: Vec<u8> = serialize_address_info(ai_resp_1)
+            .expect("serialize_address_info should serialize correctly");
+        let expected_2: Vec<u8> = serialize_address_info(ai_resp_2)
+            .expect("serialize_address_info should serialize correctly");
+        let expected_3: Vec<u8> = serialize_address_info(ai_resp_3)
+            .expect("serialize_address_info should serialize correctly");
+
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+
+        assert!(
+            expected_1 == output || expected_2 == output || expected_3 == output,
+            "\nExpecting \n{:?}\nTo be equal to\n{:?}\nor\n{:?}\nor\n{:?}\n",
+            output,
+            expected_1,
+            expected_2,
+            expected_3
+        );
+    }
+
+    #[test]
+    fn test_lookup_getai_canon_name() {
+        let request = protocol::Request::new(RequestType::GETAI, b"localhost\0");
+        match lookup(&test_logger(), &Config::default(), &request).unwrap() {
+            Response::Ai(resp) => {
+                assert_eq!(resp.canon_name, "localhost");
+                assert!(!resp.addrs.is_empty());
+                let output = serialize_address_info(resp).unwrap();
+                assert!(output.ends_with(b"localhost\0"));
+                // canonlen, the fifth header field, counts the NUL.
+                assert_eq!(output[16..20], 10i32.to_ne_bytes());
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_drop_link_local() {
+        let link_local = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        let global = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        assert!(is_link_local_v6(&link_local));
+        assert!(is_link_local_v6(&IpAddr::V6(Ipv6Addr::new(
+            0xfebf, 0, 0, 0, 0, 0, 0, 1
+        ))));
+        assert!(!is_link_local_v6(&global));
+        // not scoped in the same way, so left alone.
+        assert!(!is_link_local_v6(&IpAddr::V4(Ipv4Addr::new(
+            169, 254, 0, 1
+        ))));
+
+        let hostent = Hostent {
+            name: CString::new("host").unwrap(),
+            aliases: vec![],
+            addr_list: vec![link_local, global],
+            herrno: 0,
+        };
+        let mut config = Config::default();
+        let kept = drop_link_local_hostent(&config, hostent.clone());
+        assert_eq!(kept.addr_list, vec![link_local, global]);
+
+        config.exclude_link_local = true;
+        let kept = drop_link_local_hostent(&config, hostent.clone());
+        assert_eq!(kept.addr_list, vec![global]);
+        let only_link_local = Hostent {
+            addr_list: vec![link_local],
+            ..hostent
+        };
+        let kept = drop_link_local_hostent(&config, only_link_local);
+        assert!(kept.addr_list.is_empty());
+        assert_eq!(kept.herrno, protocol::H_ERRNO_NO_DATA);
+    }
+
+    #[test]
+    fn test_usable_families_filter() {
+        let all = vec![
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+        ];
+
+        // an IPv4-only host.
+        let mut addrs = all.clone();
+        UsableFamilies {
+            v4: true,
+            v6: false,
+        }
+        .filter(&mut addrs);
+        assert_eq!(addrs, vec![all[0], all[2]]);
+
+        let mut addrs = all.clone();
+        UsableFamilies {
+            v4: false,
+            v6: true,
+        }
+        .filter(&mut addrs);
+        assert_eq!(addrs, vec![all[1]]);
+
+        // we can't be sure of anything here, so nothing is dropped.
+        for families in &[
+            UsableFamilies { v4: true, v6: true },
+            UsableFamilies {
+                v4: false,
+                v6: false,
+            },
+        ] {
+            let mut addrs = all.clone();
+            families.filter(&mut addrs);
+            assert_eq!(addrs, all);
+        }
+    }
+
+    /// The `found`, `h_addrtype` and `h_length` fields of a serialized
+    /// [protocol::HstResponseHeader].
+    fn hostent_reply_fields(output: &[u8]) -> (i32, i32, i32) {
+        assert!(output.len() >= size_of::<protocol::HstResponseHeader>());
+        let field = |i: usize| i32::from_ne_bytes(output[i * 4..i * 4 + 4].try_into().unwrap());
+        assert_eq!(field(0), protocol::VERSION);
+        (field(1), field(4), field(5))
+    }
+
+    #[test]
+    fn test_handle_gethostbyname() {
+        let request = protocol::Request::new(RequestType::GETHOSTBYNAME, b"localhost\0");
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+        // whether localhost resolves depends on the host, but the reply is
+        // always a full hosts reply, never the empty one that sends glibc
+        // back to doing the lookup itself.
+        let (found, addr_type, len) = hostent_reply_fields(&output);
+        if found == 1 {
+            assert_eq!((addr_type, len), (AF_INET, 4));
+        }
+    }
+
+    #[test]
+    fn test_handle_gethostbynamev6() {
+        let request = protocol::Request::new(RequestType::GETHOSTBYNAMEv6, b"localhost\0");
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+        let (found, addr_type, len) = hostent_reply_fields(&output);
+        if found == 1 {
+            assert_eq!((addr_type, len), (AF_INET6, 16));
+        }
+    }
+
+    #[test]
+    fn test_handle_long_hostname() {
+        let key = CString::new("a".repeat(300)).unwrap().into_bytes_with_nul();
+
+        for ty in [RequestType::GETHOSTBYNAME, RequestType::GETHOSTBYNAMEv6] {
+            let request = protocol::Request::new(ty, &key);
+            let output = handle_request(&test_logger(), &Config::default(), &request)
+                .expect("should handle request with no error");
+            let expected =
+                serialize_hostent(Hostent::error_value(protocol::H_ERRNO_HOST_NOT_FOUND)).unwrap();
+            assert_eq!(expected, output);
+        }
+
+        let request = protocol::Request::new(RequestType::GETAI, &key);
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+        assert_eq!(protocol::AI_RESPONSE_HEADER_NOT_FOUND.as_slice(), output);
+    }
+
+    #[test]
+    fn test_handle_gethostbyaddr() {
+        let request = protocol::Request::new(protocol::RequestType::GETHOSTBYADDR, &[127, 0, 0, 1]);
+
+        let expected = serialize_hostent(Hostent {
+            addr_list: vec![IpAddr::from(Ipv4Addr::new(127, 0, 0, 1))],
+            name: CString::new(b"localhost".to_vec()).unwrap(),
+            aliases: Vec::new(),
+            herrno: 0,
+        })
+        .expect("must serialize");
+
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+
+        assert_eq!(expected, output)
+    }
+
+    #[test]
+    fn test_parse_address_key() {
+        match parse_address_key(RequestType::GETHOSTBYADDR, &[192, 0, 2, 1]) {
+            Ok(LibcIp::V4(address)) => assert_eq!(address, [192, 0, 2, 1]),
+            _ => panic!("should parse a 4-byte address"),
+        }
+        let v6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets();
+        match parse_address_key(RequestType::GETHOSTBYADDRv6, &v6) {
+            Ok(LibcIp::V6(address)) => assert_eq!(address, v6),
+            _ => panic!("should parse a 16-byte address"),
+        }
+
+        // mismatched family and length.
+        assert!(parse_address_key(RequestType::GETHOSTBYADDR, &v6).is_err());
+        assert!(parse_address_key(RequestType::GETHOSTBYADDRv6, &[192, 0, 2, 1]).is_err());
+        assert!(parse_address_key(RequestType::GETHOSTBYNAME, &[192, 0, 2, 1]).is_err());
+    }
+
+    #[test]
+    fn test_handle_gethostbyaddr_invalid_len() {
+        let request = protocol::Request::new(protocol::RequestType::GETHOSTBYADDR, &[127, 0, 0]);
+
+        let result = handle_request(&test_logger(), &Config::default(), &request);
+
+        assert!(result.is_err(), "should error on invalid length");
+    }
+
+    #[test]
+    // Fails on CI: depending on the host setup, we might get
+    // different or less aliases for localhost.
+    #[ignore]
+    fn test_handle_gethostbyaddrv6() {
+        let request = protocol::Request::new(
+            protocol::RequestType::GETHOSTBYADDRv6,
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        );
+
+        let expected = serialize_hostent(Hostent {
+            addr_list: vec![IpAddr::from(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))],
+            name: CString::new(b"localhost".to_vec()).unwrap(),
+            aliases: Vec::new(),
+            herrno: 0,
+        })
+        .expect("must serialize");
+
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+
+        assert_eq!(expected, output)
+    }
+
+    #[test]
+    fn test_handle_gethostbyaddrv6_reply() {
+        // unlike test_handle_gethostbyaddrv6, doesn't depend on the name and
+        // aliases the host has for ::1, or on it having any.
+        let key = Ipv6Addr::LOCALHOST.octets();
+        let request = protocol::Request::new(protocol::RequestType::GETHOSTBYADDRv6, &key);
+        let output = handle_request(&test_logger(), &Config::default(), &request)
+            .expect("should handle request with no error");
+        let (found, addr_type, len) = hostent_reply_fields(&output);
+        if found == 1 {
+            assert_eq!((addr_type, len), (AF_INET6, 16));
+        }
+    }
+
+    #[test]
+    fn test_handle_gethostbyaddrv6_invalid_len() {
+        let request = protocol::Request::new(
+            protocol::RequestType::GETHOSTBYADDRv6,
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        );
+
+        let result = handle_request(&test_logger(), &Config::default(), &request);
+
+        assert!(result.is_err(), "should error on invalid length");
+    }
+
+    #[test]
+    fn test_hostent_serialization() {
+        let hostent = serialize_hostent(Hostent {
+            name: CString::new(b"trantor.alternativebit.fr".to_vec()).unwrap(),
+            aliases: vec![CString::new(b"trantor".to_vec()).unwrap()],
+            addr_list: vec![IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))],
+            herrno: 0,
+        })
+        .expect("should serialize");
+
+        // Captured through a mismatched sockburp run
+        let expected_bytes: Vec<u8> = vec![
+            0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x1a, 0x00, 0x00, 0x00, 0x01, 0x00,
+            0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x74, 0x72, 0x61, 0x6e, 0x74, 0x6f, 0x72, 0x2e, 0x61, 0x6c,
+            0x74, 0x65, 0x72, 0x6e, 0x61, 0x74, 0x69, 0x76, 0x65, 0x62, 0x69, 0x74, 0x2e, 0x66,
+            0x72, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x74, 0x72, 0x61, 0x6e, 0x74, 0x6f,
+            0x72, 0x00,
+        ];
+        assert_eq!(hostent, expected_bytes)
+    }
+
+    #[test]
+    fn test_parse_service_key() {
+        assert_eq!(
+            parse_service_key(b"http/tcp\0").unwrap(),
+            (&b"http"[..], Some(&b"tcp"[..]))
+        );
+        // any protocol.
+        assert_eq!(parse_service_key(b"http/\0").unwrap(), (&b"http"[..], None));
+        assert_eq!(parse_service_key(b"http\0").unwrap(), (&b"http"[..], None));
+        // split at the last '/', like nscd.
+        assert_eq!(
+            parse_service_key(b"a/b/udp\0").unwrap(),
+            (&b"a/b"[..], Some(&b"udp"[..]))
+        );
+        assert!(parse_service_key(b"http/tcp").is_err());
+    }
+
+    #[test]
+    fn test_servent_serialization() {
+        let servent = serialize_servent(Some(Servent {
+            name: CString::new("http").unwrap(),
+            proto: CString::new("tcp").unwrap(),
+            aliases: vec![CString::new("www").unwrap()],
+            port: i32::from(80u16.to_be()),
+        }))
+        .expect("should serialize");
+
+        let mut expected = vec![];
+        for field in [protocol::VERSION, 1, 5, 4, 1, i32::from(80u16.to_be())].iter() {
+            expected.extend_from_slice(&field.to_ne_bytes());
+        }
+        expected.extend_from_slice(b"http\0tcp\0");
+        expected.extend_from_slice(&4u32.to_ne_bytes());
+        expected.extend_from_slice(b"www\0");
+        assert_eq!(servent, expected);
+        assert_eq!(servent.capacity(), expected.len());
+
+        assert_eq!(
+            serialize_servent(None).unwrap(),
+            protocol::SERV_RESPONSE_HEADER_NOT_FOUND.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_handle_getservbyname() {
+        let name = CString::new("http").unwrap();
+        let proto = CString::new("tcp").unwrap();
+        let expected = serialize_servent(getservbyname_r(&name, Some(&proto)).unwrap()).unwrap();
+        let request = protocol::Request::new(protocol::RequestType::GETSERVBYNAME, b"http/tcp\0");
+        let output = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        assert_eq!(output, expected);
+
+        let request = protocol::Request::new(
+            protocol::RequestType::GETSERVBYNAME,
+            b"nsncd-no-such-service/tcp\0",
+        );
+        let output = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        assert_eq!(output, protocol::SERV_RESPONSE_HEADER_NOT_FOUND.as_slice());
+    }
+
+    #[test]
+    fn test_handle_getservbyport() {
+        let proto = CString::new("tcp").unwrap();
+        let port = i32::from(22u16.to_be());
+        let expected = serialize_servent(getservbyport_r(port, Some(&proto)).unwrap()).unwrap();
+        let key = CString::new(format!("{}/tcp", port)).unwrap();
+        let request = protocol::Request::new(
+            protocol::RequestType::GETSERVBYPORT,
+            key.as_bytes_with_nul(),
+        );
+        let output = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        assert_eq!(output, expected);
+
+        let request = protocol::Request::new(protocol::RequestType::GETSERVBYPORT, b"0/tcp\0");
+        let output = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        assert_eq!(output, protocol::SERV_RESPONSE_HEADER_NOT_FOUND.as_slice());
+
+        let request = protocol::Request::new(protocol::RequestType::GETSERVBYPORT, b"ssh/tcp\0");
+        assert!(handle_request(&test_logger(), &Config::default(), &request).is_err());
+    }
+
+    #[test]
+    fn test_netgroup_serialization() {
+        let field = |s: &str| Some(CString::new(s).unwrap());
+        let netgroup = serialize_netgroup(Some(vec![
+            NetgroupTriple {
+                host: field("bastion"),
+                user: field("alice"),
+                domain: field("example.com"),
+            },
+            // a wildcard user and domain.
+            NetgroupTriple {
+                host: field("build"),
+                ..Default::default()
+            },
+        ]))
+        .expect("should serialize");
+
+        let members: &[u8] = b"bastion\0alice\0example.com\0build\0\0\0";
+        let mut expected = vec![];
+        for field in [protocol::VERSION, 1, 2, members.len() as i32].iter() {
+            expected.extend_from_slice(&field.to_ne_bytes());
+        }
+        expected.extend_from_slice(members);
+        assert_eq!(netgroup, expected);
+
+        let mut expected = vec![];
+        for field in [protocol::VERSION, 0, 0, 0].iter() {
+            expected.extend_from_slice(&field.to_ne_bytes());
+        }
+        assert_eq!(serialize_netgroup(None).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_handle_getnetgrent_unknown() {
+        let request = protocol::Request::new(
+            protocol::RequestType::GETNETGRENT,
+            b"nsncd-no-such-netgroup\0",
+        );
+        let output = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        assert_eq!(output, serialize_netgroup(None).unwrap());
+    }
+
+    #[test]
+    fn test_parse_innetgr_field() {
+        let cstr = |bytes: &'static [u8]| CStr::from_bytes_with_nul(bytes).unwrap();
+        assert_eq!(parse_innetgr_field(cstr(b"\0")).unwrap(), None);
+        assert_eq!(
+            parse_innetgr_field(cstr(b"\x01alice\0")).unwrap(),
+            Some(cstr(b"alice\0"))
+        );
+        // an empty string, not a wildcard.
+        assert_eq!(
+            parse_innetgr_field(cstr(b"\x01\0")).unwrap(),
+            Some(cstr(b"\0"))
+        );
+        assert!(parse_innetgr_field(cstr(b"alice\0")).is_err());
+    }
+
+    #[test]
+    fn test_handle_innetgr_unknown() {
+        let request = protocol::Request::new(
+            protocol::RequestType::INNETGR,
+            b"nsncd-no-such-netgroup\0\x01bastion\0\0\0",
+        );
+        let output = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        let mut expected = vec![];
+        for field in [protocol::VERSION, 1, 0].iter() {
+            expected.extend_from_slice(&field.to_ne_bytes());
+        }
+        assert_eq!(output, expected);
+
+        // too few fields.
+        let request = protocol::Request::new(protocol::RequestType::INNETGR, b"admins\0\0\0");
+        assert!(handle_request(&test_logger(), &Config::default(), &request).is_err());
+    }
+
+    #[test]
+    fn test_serialize_stats() {
+        let mut config = Config {
+            worker_count: 4,
+            stat_version: Some("Jan  1 2024 12:00:00".to_string()),
+            ..Config::default()
+        };
+        config
+            .ignored_request_types
+            .insert(&RequestType::GETNETGRENT);
+        config.ignored_request_types.insert(&RequestType::INNETGR);
+        config.stats.record_answer(RequestType::GETPWBYNAME, true);
+        config.stats.record_answer(RequestType::GETPWBYUID, true);
+        config.stats.record_answer(RequestType::INITGROUPS, false);
+        config.stats.record_cache_hit(RequestType::GETPWBYUID, true);
+
+        let stats = serialize_stats(&config);
+        assert_eq!(&stats.version, b"Jan  1 2024 12:00:00\0");
+        assert_eq!((stats.nthreads, stats.max_nthreads, stats.ndbs), (4, 4, 5));
+        let (passwd, group) = (&stats.dbs[0], &stats.dbs[1]);
+        assert_eq!((passwd.poshit, passwd.posmiss, passwd.negmiss), (1, 1, 0));
+        assert_eq!((group.posmiss, group.negmiss), (0, 1));
+        let enabled: Vec<_> = stats.dbs.iter().map(|db| db.enabled).collect();
+        assert_eq!(enabled, vec![1, 1, 1, 1, 0]);
+
+        let request = protocol::Request::new(RequestType::GETSTAT, b"");
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(output.len(), size_of::<protocol::StatResponse>());
+        assert_eq!(&output[..21], b"Jan  1 2024 12:00:00\0");
+    }
+
+    #[test]
+    fn test_strict_keys() {
+        // atoi stops at the first non-digit, so this reads as uid 0...
+        let request = protocol::Request::new(RequestType::GETPWBYUID, b"0x\0");
+        let lenient = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        assert_eq!(
+            lenient,
+            serialize_user(User::from_uid(Uid::from_raw(0)).unwrap()).unwrap()
+        );
+
+        // ...unless keys are checked first.
+        let config = Config {
+            strict_keys: true,
+            ..Config::default()
+        };
+        let err = handle_request(&test_logger(), &config, &request).unwrap_err();
+        assert_eq!(err.to_string(), "malformed key");
+        let request = protocol::Request::new(RequestType::GETPWBYUID, b"0\0");
+        assert_eq!(
+            handle_request(&test_logger(), &config, &request).unwrap(),
+            lenient
+        );
+    }
+
+    #[test]
+    fn test_handle_shutdown() {
+        let config = Config::default();
+        let mut request = protocol::Request::new(RequestType::SHUTDOWN, b"");
+        // unknown client.
+        handle_request(&test_logger(), &config, &request).unwrap();
+        assert!(!config.shutdown.is_requested());
+        request.peer_uid = Some(Uid::from_raw(1000));
+        handle_request(&test_logger(), &config, &request).unwrap();
+        assert!(!config.shutdown.is_requested());
+
+        request.peer_uid = Some(Uid::from_raw(0));
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert!(output.is_empty());
+        assert!(config.shutdown.is_requested());
+    }
+
+    #[test]
+    fn test_shadow_serialization() {
+        let spwd = serialize_shadow(Some(Spwd {
+            name: CString::new("alice").unwrap(),
+            passwd: CString::new("$6$salt$hash").unwrap(),
+            last_change: 19000,
+            min: 0,
+            max: 99999,
+            warn: 7,
+            inactive: -1,
+            expire: -1,
+            flag: !0,
+        }))
+        .unwrap();
+
+        let mut expected = vec![];
+        for field in [protocol::VERSION, 1, 6, 13].iter() {
+            expected.extend_from_slice(&field.to_ne_bytes());
+        }
+        for field in [19000 as c_long, 0, 99999, 7, -1, -1].iter() {
+            expected.extend_from_slice(&field.to_ne_bytes());
+        }
+        expected.extend_from_slice(&(!0 as c_ulong).to_ne_bytes());
+        expected.extend_from_slice(b"alice\0$6$salt$hash\0");
+        assert_eq!(spwd, expected);
+
+        assert_eq!(
+            serialize_shadow(None).unwrap(),
+            protocol::SpResponseHeader::default().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_handle_getspbyname() {
+        let mut request = protocol::Request::new(RequestType::GETSPBYNAME, b"root\0");
+        request.peer_uid = Some(Uid::from_raw(0));
+        // off by default.
+        let output = handle_request(&test_logger(), &Config::default(), &request).unwrap();
+        assert!(output.is_empty());
+
+        let config = Config {
+            serve_shadow: true,
+            ..Config::default()
+        };
+        let root = CString::new("root").unwrap();
+        let expected = serialize_shadow(getspnam_r(&root).unwrap()).unwrap();
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert_eq!(output, expected);
+
+        // only root gets an answer.
+        request.peer_uid = Some(Uid::from_raw(1000));
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert!(output.is_empty());
+        request.peer_uid = None;
+        let output = handle_request(&test_logger(), &config, &request).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_nss_lookup() {
+        let what = || "looking up uid 1234".to_string();
+        assert_eq!(nss_lookup(Ok(Some(1)), what).unwrap(), Some(1));
+        assert_eq!(nss_lookup::<i32>(Ok(None), what).unwrap(), None);
+        // the errnos getpwnam_r(3) documents for "not found".
+        for errno in [Errno::ENOENT, Errno::ESRCH, Errno::EBADF, Errno::EPERM] {
+            assert_eq!(nss_lookup::<i32>(Err(errno), what).unwrap(), None);
+        }
+
+        // a backend failure stays an error, and says what failed.
+        let err = nss_lookup::<i32>(Err(Errno::ETIMEDOUT), what).unwrap_err();
+        assert_eq!(err.to_string(), "looking up uid 1234");
+        assert_eq!(err.root_cause().downcast_ref(), Some(&Errno::ETIMEDOUT));
     }
 }
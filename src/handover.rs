@@ -0,0 +1,234 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Handing our listening socket over to a new nsncd, so that it can be
+//! upgraded or restarted without the socket ever going away.
+//!
+//! On `SIGUSR2`, we start nsncd again, the way we were started, with our
+//! listening socket's fd in [LISTEN_FD_ENV] and one end of a socket pair in
+//! [READY_FD_ENV]. The new nsncd listens on the socket it was given instead
+//! of binding its own, and says so on the socket pair once it's ready. Then
+//! we stop accepting connections, answer the requests we have, and exit,
+//! leaving the socket in place. Connections made in between wait in the
+//! listen backlog for whichever of us accepts them first.
+
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use nix::libc;
+use nix::sys::signal::{self, SigHandler, Signal};
+
+/// The fd of the listening socket a new nsncd is handed.
+pub const LISTEN_FD_ENV: &str = "NSNCD_LISTEN_FD";
+
+/// The fd a new nsncd says it's ready on.
+pub const READY_FD_ENV: &str = "NSNCD_READY_FD";
+
+/// Set from the `SIGUSR2` handler, and cleared when the handover starts.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigusr2(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install a `SIGUSR2` handler that asks for a handover.
+pub fn install_handler() -> Result<()> {
+    unsafe { signal::signal(Signal::SIGUSR2, SigHandler::Handler(handle_sigusr2)) }
+        .context("installing SIGUSR2 handler")?;
+    Ok(())
+}
+
+/// Whether a handover was asked for since the last call.
+pub fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// nsncd, started again from the path we were started from, which an
+/// upgrade may have put a new version at, and with the same arguments.
+pub fn nsncd() -> Command {
+    let mut args = env::args_os();
+    let mut command = Command::new(args.next().unwrap_or_else(|| "/proc/self/exe".into()));
+    command.args(args);
+    command
+}
+
+/// The listening socket the nsncd we're taking over from handed us, if
+/// any. It has to be listening on `path`.
+pub fn inherited_listener(path: &Path) -> io::Result<Option<UnixListener>> {
+    let listener = match inherited_fd(LISTEN_FD_ENV)? {
+        Some(fd) => UnixListener::from(fd),
+        None => return Ok(None),
+    };
+    let addr = listener.local_addr()?;
+    if addr.as_pathname() != Some(path) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("inherited socket is bound to {:?}", addr),
+        ));
+    }
+    Ok(Some(listener))
+}
+
+/// Tell the nsncd we're taking over from, if any, that we're ready to
+/// accept connections.
+pub fn ready() -> io::Result<()> {
+    match inherited_fd(READY_FD_ENV)? {
+        Some(fd) => UnixStream::from(fd).write_all(b"1"),
+        None => Ok(()),
+    }
+}
+
+/// The fd in the environment variable `var`, which we inherited. It's
+/// closed on exec, so the children we start don't inherit it in turn.
+fn inherited_fd(var: &str) -> io::Result<Option<OwnedFd>> {
+    let value = match env::var(var) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    let fd: RawFd = value.parse().map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not an fd: {:?}", var, value),
+        )
+    })?;
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: it's open, and whoever started us passed it for us to own.
+    Ok(Some(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// A new nsncd we're handing our socket over to.
+pub struct Successor {
+    child: Child,
+    ready: UnixStream,
+}
+
+/// Start `command`, handing it `listener`.
+pub fn start(mut command: Command, listener: &UnixListener) -> Result<Successor> {
+    let (ready, theirs) = UnixStream::pair().context("creating socket pair")?;
+    let fds = [listener.as_raw_fd(), theirs.as_raw_fd()];
+    command
+        .env(LISTEN_FD_ENV, fds[0].to_string())
+        .env(READY_FD_ENV, fds[1].to_string());
+    // SAFETY: fcntl is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            for fd in fds {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().context("starting nsncd")?;
+    Ok(Successor { child, ready })
+}
+
+impl Successor {
+    /// Wait up to `timeout` for it to be ready, and return its pid. If it
+    /// isn't, it's killed.
+    pub fn wait_ready(mut self, timeout: Duration) -> Result<u32> {
+        let ready = self
+            .ready
+            .set_read_timeout(Some(timeout))
+            .and_then(|()| self.ready.read(&mut [0]));
+        match ready {
+            Ok(1) => return Ok(self.child.id()),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e).context("waiting for nsncd to be ready"),
+        }
+        let _ = self.child.kill();
+        let status = self.child.wait().context("waiting for nsncd to exit")?;
+        anyhow::bail!("nsncd didn't get ready: {}", status)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::process::Stdio;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Run by the tests below as the new nsncd: answers one connection on the
+    /// socket it was handed.
+    #[test]
+    fn test_successor() {
+        let path = match env::var_os("TEST_HANDOVER_PATH") {
+            Some(path) => path,
+            None => return,
+        };
+        let listener = inherited_listener(Path::new(&path)).unwrap().unwrap();
+        ready().unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"new").unwrap();
+    }
+
+    fn successor(path: &Path) -> Command {
+        let mut command = Command::new(env::current_exe().unwrap());
+        command
+            .args(["--exact", "handover::test::test_successor"])
+            .env("TEST_HANDOVER_PATH", path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        command
+    }
+
+    #[test]
+    fn test_handover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket");
+        let listener = UnixListener::bind(&path).unwrap();
+        // a client that connected before the handover...
+        let mut early = UnixStream::connect(&path).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let successor = start(successor(&path), &listener).unwrap();
+        successor.wait_ready(TIMEOUT).unwrap();
+        // ...is still ours to answer,
+        stream.write_all(b"old").unwrap();
+        drop((stream, listener));
+        let mut response = String::new();
+        early.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "old");
+
+        // but once we're gone, the socket's still there.
+        let mut late = UnixStream::connect(&path).unwrap();
+        let mut response = String::new();
+        late.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "new");
+    }
+
+    #[test]
+    fn test_handover_wrong_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = UnixListener::bind(dir.path().join("socket")).unwrap();
+        let successor = start(successor(&dir.path().join("other")), &listener).unwrap();
+        assert!(successor.wait_ready(TIMEOUT).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Group list lookups for INITGROUPS requests.
+//!
+//! `getgrouplist()` for a user in thousands of groups is expensive, and a
+//! burst of logins sends many of them at once. So [GroupLists] bounds how many
+//! run at the same time. Identical concurrent requests are already answered by
+//! a single lookup, and may be cached, like any other request (see
+//! [crate::handlers::handle_request]).
+
+use std::sync::{Condvar, Mutex};
+
+use nix::unistd::Gid;
+
+pub struct GroupLists {
+    limit: usize,
+    /// How many lookups are running.
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl GroupLists {
+    /// Run at most `limit` lookups at a time.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// How many lookups may run at the same time.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Call `lookup` once fewer than `limit` other lookups are running.
+    pub fn get<F>(&self, lookup: F) -> Vec<Gid>
+    where
+        F: FnOnce() -> Vec<Gid>,
+    {
+        {
+            let mut running = self.running.lock().unwrap();
+            while *running >= self.limit {
+                running = self.finished.wait(running).unwrap();
+            }
+            *running += 1;
+        }
+        // decrement even if lookup panics, or every worker would end up
+        // waiting here.
+        struct Release<'a>(&'a GroupLists);
+        impl Drop for Release<'_> {
+            fn drop(&mut self) {
+                *self.0.running.lock().unwrap() -= 1;
+                self.0.finished.notify_one();
+            }
+        }
+        let _release = Release(self);
+        lookup()
+    }
+}
+
+impl std::fmt::Debug for GroupLists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GroupLists")
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_limit() {
+        let lists = Arc::new(GroupLists::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..6)
+            .map(|_| {
+                let (lists, running, most) = (lists.clone(), running.clone(), most.clone());
+                thread::spawn(move || {
+                    lists.get(|| {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        vec![]
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_panicking_lookup() {
+        let lists = Arc::new(GroupLists::new(1));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            lists.get(|| panic!("lookup failed"));
+        }));
+        assert!(result.is_err());
+        // the panicking lookup gave its place back.
+        let (done, finished) = crossbeam_channel::bounded(1);
+        thread::spawn(move || done.send(lists.get(|| vec![Gid::from_raw(100)])));
+        let groups = finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(groups, vec![Gid::from_raw(100)]);
+    }
+}
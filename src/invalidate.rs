@@ -0,0 +1,92 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Hooks run on INVALIDATE requests.
+//!
+//! `nscd -i <database>` asks the daemon to forget what it knows about a
+//! database, e.g. after editing `/etc/group`. Anything that keeps state
+//! derived from a database (a cache, counters, a mirror elsewhere) can
+//! register an [InvalidationHook] to hear about it.
+
+use std::sync::Arc;
+
+use slog::Logger;
+
+pub trait InvalidationHook: Send + Sync {
+    /// Called when a client asks for `database` (one of
+    /// [crate::protocol::DATABASES]) to be invalidated.
+    fn invalidate(&self, log: &Logger, database: &str);
+}
+
+/// An ordered list of hooks, run one after the other.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Vec<Arc<dyn InvalidationHook>>,
+}
+
+impl Hooks {
+    /// Add a hook, to be run after the ones already there.
+    #[cfg(test)]
+    pub fn push(&mut self, hook: Arc<dyn InvalidationHook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Run every hook for `database`.
+    pub fn run(&self, log: &Logger, database: &str) {
+        for hook in self.hooks.iter() {
+            hook.invalidate(log, database);
+        }
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    /// Records the databases it's called for.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl InvalidationHook for Recorder {
+        fn invalidate(&self, _log: &Logger, database: &str) {
+            self.0.lock().unwrap().push(database.to_string());
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let log = Logger::root(slog::Discard, slog::o!());
+        let (first, second) = (Arc::new(Recorder::default()), Arc::new(Recorder::default()));
+        let mut hooks = Hooks::default();
+        hooks.run(&log, "passwd");
+        hooks.push(first.clone());
+        hooks.push(second.clone());
+        hooks.run(&log, "group");
+
+        assert_eq!(*first.0.lock().unwrap(), vec!["group"]);
+        assert_eq!(*second.0.lock().unwrap(), vec!["group"]);
+    }
+}
@@ -14,7 +14,8 @@
  * limitations under the License.
  */
 
-//! `nsncd` is a nscd-compatible daemon that proxies lookups, without caching.
+//! `nsncd` is a nscd-compatible daemon that proxies lookups, caching them
+//! only if asked to.
 //!
 //! `nsncd` stands for "Name Service Non-Caching Daemon."
 //!
@@ -25,16 +26,20 @@
 //! attempt to use the libc that nsncd is running with (and any nss plugins
 //! available to it), regardless of the libc used by a particular application.
 //!
-//! `nsncd` currently does all its lookups directly in its own process, handling
-//! each request on a thread. If you have `nss` plugins that behave badly (leak
-//! resources, are not thread safe, etc.), this may cause problems.
+//! By default, `nsncd` does all its lookups directly in its own process, on a
+//! fixed pool of worker threads; connections are read and written
+//! asynchronously, so clients that are idle or slow to read don't need a
+//! thread each. If you have `nss` plugins that behave badly (leak resources,
+//! are not thread safe, etc.), this may cause problems.
 //!
-//! The `unscd` project attempts to solve this by handling lookup requests in
-//! child processes instead of directly in the long-lived daemon. This isolates
-//! the daemon from problems in the children doing the lookups, but the extra
-//! overhead of forking additional child processes seems large. `unscd` can get
-//! away with that because it's also caching, but we're not caching right now.
-//! We might try forking child processes to handle requests at some point later.
+//! Like the `unscd` project, `nsncd` can instead make passwd and group
+//! lookups in child processes, when `NSNCD_LOOKUP_PROCESSES` is set (see
+//! [forked]). This isolates the daemon from problems in the children doing
+//! the lookups, at the cost of a round trip to a child for each one.
+//!
+//! Answers aren't cached unless a database is given a TTL with
+//! `NSNCD_CACHE_TTL_<DATABASE>` (see [cache]), so by default every lookup
+//! reaches the backend and sees its changes right away.
 
 // TODO:
 // - implement other pw and group methods?
@@ -44,55 +49,263 @@
 // - test errors in underlying calls
 // - daemon/pidfile stuff
 
-use std::io::prelude::*;
+use std::any::Any;
 use std::io::ErrorKind;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::Path;
-use std::time::Duration;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use crossbeam_channel as channel;
+use nix::libc;
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials, UnixCredentials};
+use nix::unistd::Uid;
 use sd_notify::NotifyState;
 use slog::{debug, error, o, Drain};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net;
+use tokio::runtime::{self, Runtime};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinSet;
+use tokio::time;
 
+mod audit;
+mod backend;
+mod bench;
+mod cache;
+mod coalesce;
 mod config;
+mod failover;
 mod ffi;
+mod files;
+mod forked;
 mod handlers;
+mod handover;
+mod initgroups;
+mod invalidate;
+mod middleware;
+mod nscd_conf;
+mod pool;
 mod protocol;
+mod queue;
+mod stats;
+#[cfg(test)]
+mod test_util;
+mod warm;
+mod watch;
+mod watchdog;
 mod work_group;
 
+use audit::AuditLog;
 use config::Config;
+use files::LocalFiles;
+use pool::BufferPool;
+use queue::{Class, Pool, PriorityQueue, Streak};
+use stats::Stats;
+use watch::Watcher;
+use watchdog::Watchdog;
 use work_group::WorkGroup;
 
-const SOCKET_PATH: &str = "/var/run/nscd/socket";
+/// How long to wait for a client to read some of a response before giving up
+/// on it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const BIND_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How much of a request we read at a time. glibc's requests fit: the
+/// longest key it sends is a hostname.
+const REQUEST_BUFFER_LEN: usize = 4096;
+
+/// The largest request we read, leaving room for batch lookups of many
+/// thousands of users.
+const MAX_REQUEST_LEN: usize = 1 << 20;
+
+/// How often the acceptor checks for a shutdown while no one's connecting.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the file watcher checks for a shutdown while no file changes.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the cache statistics logger checks for a shutdown.
+const STATS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the watchdog checks on the workers.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often to check whether a handover was asked for.
+const HANDOVER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a new nsncd we hand our socket over to has to get ready.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to check whether our threads are done while shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Set from the `SIGTERM` and `SIGINT` handlers, and checked by the
+/// acceptors, which stop accepting connections when they see it.
+static TERMINATE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_terminate(_: libc::c_int) {
+    TERMINATE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install `SIGTERM` and `SIGINT` handlers that shut us down gracefully.
+fn install_terminate_handler() -> Result<()> {
+    for sig in [Signal::SIGTERM, Signal::SIGINT] {
+        unsafe { signal::signal(sig, SigHandler::Handler(handle_terminate)) }
+            .with_context(|| format!("installing {} handler", sig))?;
+    }
+    Ok(())
+}
 
 fn main() -> Result<()> {
     ffi::disable_internal_nscd();
 
+    if std::env::var_os(forked::CHILD_ENV).is_some() {
+        // we're a child of another nsncd, making its lookups.
+        return Ok(forked::serve_stdin(&backend::Nss)?);
+    }
+    let mut args = std::env::args_os().skip(1);
+    if args.next().is_some_and(|arg| arg == "bench") {
+        return bench::main(args);
+    }
+
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let (drain, log_guard) = slog_async::Async::new(drain).build_with_guard();
+    let drain = drain.fuse();
 
     let logger = slog::Logger::root(drain, slog::o!());
 
     let config = Config::from_env()?;
-    let path = Path::new(SOCKET_PATH);
+    install_terminate_handler()?;
+    handover::install_handler()?;
+    match run(&logger, config) {
+        Ok(reason) => {
+            slog::info!(logger, "stopped"; "reason" => ?reason);
+            Ok(())
+        }
+        Err(e) => match e.downcast_ref::<BindError>() {
+            Some(bind_error) => {
+                error!(logger, "could not listen on socket"; "err" => %bind_error);
+                // flush the log before exiting.
+                drop(log_guard);
+                std::process::exit(bind_error.exit_code());
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Why [run] returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ShutdownReason {
+    /// Shutdown was requested through the config's [config::Shutdown].
+    Requested,
+    /// We got `SIGTERM` or `SIGINT`.
+    Signal,
+    /// We handed our socket over to a new nsncd.
+    HandedOver,
+    /// One of our threads stopped on its own, e.g. the acceptor gave up after
+    /// timing out waiting for a worker.
+    ThreadExited,
+}
 
+/// Serve requests on `config.socket_path` until we're asked to stop, or
+/// something goes wrong.
+///
+/// Failing to bind the socket returns a [BindError].
+fn run(logger: &slog::Logger, config: Config) -> Result<ShutdownReason> {
+    config.validate()?;
     slog::info!(logger, "started";
-        "path" => ?path,
+        "path" => ?config.socket_path,
         "config" => ?config,
     );
-    let mut wg = WorkGroup::new();
-    let tx = spawn_workers(&mut wg, &logger, config);
+    if let Some(local) = &config.local_files {
+        warn_skipped_lines(logger, &local.table());
+    }
+    if config.hosts_dns_only {
+        ffi::restrict_hosts_to_dns()?;
+    }
+    let audit = match &config.audit_log {
+        Some(path) => {
+            audit::install_reopen_handler()?;
+            Some(Arc::new(AuditLog::open(path)?))
+        }
+        None => None,
+    };
 
-    std::fs::create_dir_all(path.parent().expect("socket path has no parent"))?;
-    std::fs::remove_file(path).ok();
-    let listener = UnixListener::bind(path).context("could not bind to socket")?;
-    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o777))?;
-    spawn_acceptor(&mut wg, &logger, listener, tx, config.handoff_timeout);
+    if let Some(path) = &config.cache_file {
+        load_cache(logger, &config, path);
+    }
+    handlers::warm_up(logger, &config, &protocol::DATABASES);
 
-    let _ = sd_notify::notify(true, &[NotifyState::Ready]);
+    let mut wg = WorkGroup::new();
+    if let Some(local) = &config.local_files {
+        spawn_local_files_refresher(&mut wg, logger, local.clone(), config.local_files_refresh);
+    }
+    if !config.watch_files.is_empty() {
+        let watcher = Watcher::new(
+            Path::new("/etc"),
+            &config.watch_files,
+            config.watch_nsswitch,
+        )?;
+        spawn_file_watcher(&mut wg, logger, watcher, config.clone());
+    }
+    if !config.cache_stats_interval.is_zero() {
+        spawn_cache_stats_logger(&mut wg, logger, config.clone());
+    }
+    let watchdog = Arc::new(Watchdog::new(
+        config.total_worker_count(),
+        config.stuck_threshold,
+    ));
+    if !config.stuck_threshold.is_zero() {
+        spawn_watchdog(&mut wg, logger, config.clone(), watchdog.clone());
+    }
+    let workers = spawn_workers(&mut wg, logger, &config, &watchdog);
+
+    let listener = start_listening(logger, &config.socket_path, config.startup_timeout)?;
+    let socket = std::fs::metadata(&config.socket_path)
+        .map(|meta| (meta.dev(), meta.ino()))
+        .ok();
+    let handed_over = Arc::new(AtomicBool::new(false));
+    let handover_listener = listener.try_clone().context("cloning listener")?;
+    spawn_handover(
+        &mut wg,
+        logger,
+        handover_listener,
+        config.shutdown.clone(),
+        handed_over.clone(),
+    );
+    let server = Arc::new(Server {
+        config: config.clone(),
+        audit,
+        workers,
+        connections: AtomicUsize::new(0),
+    });
+    for acceptor_id in 0..config.accept_threads {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("starting runtime")?;
+        let listener = listener.try_clone().context("cloning listener")?;
+        spawn_acceptor(
+            &mut wg,
+            logger,
+            acceptor_id,
+            listener,
+            runtime,
+            server.clone(),
+        );
+    }
+    // the acceptors have their own copies, and stop listening when they
+    // stop accepting.
+    drop((listener, server));
 
     let (result, handles) = wg.run();
     if let Err(e) = result {
@@ -104,123 +317,2037 @@ fn main() -> Result<()> {
         // something else happened that made a process exit, so try to exit
         // gracefully.
         slog::info!(logger, "shutting down");
+        // a worker stuck in a lookup would keep us from ever exiting.
+        let deadline = Instant::now() + config.shutdown_timeout;
+        let mut busy = 0;
         for handle in handles {
-            let _ = handle.join();
+            while !handle.is_finished() && Instant::now() < deadline {
+                std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+            if handle.is_finished() {
+                let _ = handle.join();
+            } else {
+                busy += 1;
+            }
+        }
+        if busy > 0 {
+            slog::warn!(logger, "exiting with threads still busy"; "count" => busy);
+        }
+        // the new nsncd is listening on it now.
+        let handed_over = handed_over.load(Ordering::SeqCst);
+        if let (Some(socket), false) = (socket, handed_over) {
+            remove_socket(logger, &config.socket_path, socket);
+        }
+        if let Some(path) = &config.cache_file {
+            match config.cache.save(path) {
+                Ok(saved) => slog::info!(logger, "saved cache";
+                    "path" => ?path, "entries" => saved),
+                Err(e) => error!(logger, "saving cache"; "err" => %e),
+            }
+        }
+        if handed_over {
+            Ok(ShutdownReason::HandedOver)
+        } else if TERMINATE_REQUESTED.load(Ordering::SeqCst) {
+            Ok(ShutdownReason::Signal)
+        } else if config.shutdown.is_requested() {
+            Ok(ShutdownReason::Requested)
+        } else {
+            Ok(ShutdownReason::ThreadExited)
         }
-        Ok(())
     }
 }
 
+/// Fill the response cache with what was saved to `path` on the last
+/// shutdown, if anything.
+fn load_cache(logger: &slog::Logger, config: &Config, path: &Path) {
+    let changed = |since| watch::changed_since(Path::new("/etc"), since);
+    match config.cache.load(path, changed) {
+        Ok(loaded) => slog::info!(logger, "loaded cache"; "path" => ?path, "entries" => loaded),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            // nothing was saved yet.
+            Some(e) if e.kind() == ErrorKind::NotFound => {}
+            _ => slog::warn!(logger, "not loading cache"; "err" => %e),
+        },
+    }
+}
+
+/// Bind the listening socket at `path` and tell the service manager that we're
+/// ready.
+///
+/// If the nsncd we're taking over from handed us its socket, we listen on
+/// that instead, and tell it that we're ready too.
+///
+/// Binding is retried until `timeout` elapses, so that a socket directory that
+/// shows up late (e.g. a tmpfs being mounted) doesn't fail startup outright.
+/// Readiness is only signalled once the socket is bound and listening, so
+/// supervisors never race against the socket appearing: connections made
+/// after `READY=1` wait in the listen backlog until they're accepted.
+fn start_listening(
+    log: &slog::Logger,
+    path: &Path,
+    timeout: Duration,
+) -> Result<UnixListener, BindError> {
+    if let Some(listener) =
+        handover::inherited_listener(path).map_err(|e| BindError::from_io(path, e))?
+    {
+        slog::info!(log, "took over socket");
+        notify_ready(log);
+        return Ok(listener);
+    }
+
+    let deadline = Instant::now() + timeout;
+    let listener = loop {
+        match bind_socket(path) {
+            Ok(listener) => break listener,
+            Err(e) if Instant::now() < deadline => {
+                debug!(log, "retrying socket bind"; "err" => %e);
+                std::thread::sleep(BIND_RETRY_INTERVAL);
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    notify_ready(log);
+    Ok(listener)
+}
+
+fn notify_ready(log: &slog::Logger) {
+    // NOTIFY_SOCKET is left set, so that we can tell the service manager
+    // about the nsncd we hand our socket over to, and it can tell it that
+    // it's ready.
+    let _ = sd_notify::notify(false, &[NotifyState::Ready]);
+    if let Err(e) = handover::ready() {
+        slog::warn!(log, "telling the previous nsncd we're ready"; "err" => %e);
+    }
+}
+
+fn bind_socket(path: &Path) -> Result<UnixListener, BindError> {
+    let dir = path.parent().expect("socket path has no parent");
+    std::fs::create_dir_all(dir).map_err(|e| BindError::from_io(dir, e))?;
+
+    // if something is answering on the socket, it belongs to a running nsncd
+    // (or nscd), and we shouldn't pull it out from under it.
+    if UnixStream::connect(path).is_ok() {
+        return Err(BindError::AddressInUse(path.to_owned()));
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(match BindError::from_io(dir, e) {
+                BindError::Other(_, e) => BindError::StaleSocket(path.to_owned(), e),
+                e => e,
+            })
+        }
+    }
+
+    let listener = UnixListener::bind(path).map_err(|e| BindError::from_io(path, e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o777))
+        .map_err(|e| BindError::from_io(path, e))?;
+    Ok(listener)
+}
+
+/// Remove the socket we bound at `path`, so that clients fall back to their
+/// own lookups without trying to connect, unless it's been replaced since:
+/// another nsncd may have taken over while we were finishing up.
+fn remove_socket(log: &slog::Logger, path: &Path, (dev, ino): (u64, u64)) {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.dev() == dev && meta.ino() == ino => {
+            if let Err(e) = std::fs::remove_file(path) {
+                slog::warn!(log, "removing socket"; "path" => ?path, "err" => %e);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The ways binding the socket can fail that an operator can do something
+/// about, each with its own message and exit code (from `sysexits.h`).
+#[derive(Debug)]
+enum BindError {
+    /// We can't write to the socket directory.
+    PermissionDenied(PathBuf),
+    /// The socket directory is on a read-only filesystem.
+    ReadOnlyFilesystem(PathBuf),
+    /// The socket directory's filesystem is full, or we're out of quota.
+    NoSpace(PathBuf),
+    /// There's something at the socket path that isn't a live socket, and it
+    /// can't be removed.
+    StaleSocket(PathBuf, std::io::Error),
+    /// Another daemon is listening on the socket.
+    AddressInUse(PathBuf),
+    Other(PathBuf, std::io::Error),
+}
+
+impl BindError {
+    fn from_io(path: &Path, err: std::io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) => BindError::PermissionDenied(path.to_owned()),
+            Some(libc::EROFS) => BindError::ReadOnlyFilesystem(path.to_owned()),
+            Some(libc::ENOSPC) | Some(libc::EDQUOT) => BindError::NoSpace(path.to_owned()),
+            Some(libc::EADDRINUSE) => BindError::AddressInUse(path.to_owned()),
+            _ => BindError::Other(path.to_owned(), err),
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            BindError::PermissionDenied(_) => 77,   // EX_NOPERM
+            BindError::ReadOnlyFilesystem(_) => 73, // EX_CANTCREAT
+            BindError::NoSpace(_) => 72,            // EX_OSFILE
+            BindError::StaleSocket(..) => 74,       // EX_IOERR
+            BindError::AddressInUse(_) => 75,       // EX_TEMPFAIL
+            BindError::Other(..) => 71,             // EX_OSERR
+        }
+    }
+}
+
+impl std::fmt::Display for BindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindError::PermissionDenied(path) => write!(
+                f,
+                "permission denied on {}: nsncd needs to be able to create its socket there",
+                path.display()
+            ),
+            BindError::ReadOnlyFilesystem(path) => write!(
+                f,
+                "{} is on a read-only filesystem: the socket directory must be writable",
+                path.display()
+            ),
+            BindError::NoSpace(path) => write!(
+                f,
+                "no space left for {}: free some up (or raise the quota) on its filesystem",
+                path.display()
+            ),
+            BindError::StaleSocket(path, err) => write!(
+                f,
+                "could not remove stale socket {}: {}",
+                path.display(),
+                err
+            ),
+            BindError::AddressInUse(path) => write!(
+                f,
+                "{} is in use: is another nsncd (or nscd) already running?",
+                path.display()
+            ),
+            BindError::Other(path, err) => {
+                write!(f, "could not bind to {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BindError {}
+
+fn spawn_local_files_refresher(
+    wg: &mut WorkGroup,
+    log: &slog::Logger,
+    local: Arc<LocalFiles>,
+    interval: Duration,
+) {
+    let log = log.new(o!("thread" => "local_files"));
+
+    wg.add(move |ctx| {
+        while !ctx.is_shutdown() {
+            std::thread::sleep(interval);
+            match local.refresh() {
+                Ok(true) => {
+                    slog::info!(log, "reloaded local files"; "table" => ?local.table());
+                    warn_skipped_lines(&log, &local.table());
+                }
+                Ok(false) => {}
+                // keep serving the old entries, and try again next time.
+                Err(e) => error!(log, "reloading local files"; "err" => %e),
+            }
+        }
+    });
+}
+
+/// Log the lines of the local files that weren't loaded into `table`.
+fn warn_skipped_lines(log: &slog::Logger, table: &files::Table) {
+    for line in &table.skipped {
+        slog::warn!(log, "skipped a line of the local files"; "line" => line);
+    }
+}
+
+fn spawn_cache_stats_logger(wg: &mut WorkGroup, log: &slog::Logger, config: Config) {
+    let log = log.new(o!("thread" => "cache_stats"));
+
+    wg.add(move |ctx| {
+        let mut next = Instant::now() + config.cache_stats_interval;
+        while !ctx.is_shutdown() {
+            std::thread::sleep(STATS_POLL_INTERVAL);
+            if Instant::now() >= next {
+                handlers::log_cache_stats(&log, &config);
+                next += config.cache_stats_interval;
+            }
+        }
+    });
+}
+
+fn spawn_file_watcher(wg: &mut WorkGroup, log: &slog::Logger, watcher: Watcher, config: Config) {
+    let log = log.new(o!("thread" => "watch"));
+
+    wg.add(move |ctx| {
+        while !ctx.is_shutdown() {
+            let databases = match watcher.changed(WATCH_POLL_INTERVAL) {
+                Ok(databases) => databases,
+                Err(e) => {
+                    error!(log, "watching files"; "err" => %e);
+                    std::thread::sleep(WATCH_POLL_INTERVAL);
+                    continue;
+                }
+            };
+            for database in databases {
+                // reload the local files first, or answers from the old ones
+                // could be cached again right away.
+                match &config.local_files {
+                    Some(local) if database == "passwd" || database == "group" => {
+                        if let Err(e) = local.refresh() {
+                            error!(log, "reloading local files"; "err" => %e);
+                        }
+                    }
+                    _ => {}
+                }
+                handlers::invalidate(&log, &config, database);
+            }
+        }
+    });
+}
+
+/// Accept connections on `listener`, and serve them on `runtime`. Each
+/// acceptor has a runtime of its own, so connections are spread over as
+/// many threads as there are acceptors.
 fn spawn_acceptor(
     wg: &mut WorkGroup,
     log: &slog::Logger,
+    acceptor_id: usize,
     listener: UnixListener,
-    tx: channel::Sender<UnixStream>,
-    handoff_timeout: Duration,
+    runtime: Runtime,
+    server: Arc<Server>,
 ) {
-    let log = log.new(o!("thread" => "accept"));
+    let log = log.new(o!("thread" => format!("accept_{}", acceptor_id)));
+    let shutdown = server.config.shutdown.clone();
 
     wg.add(move |ctx| {
-        for stream in listener.incoming() {
-            if ctx.is_shutdown() {
-                break;
-            }
-
-            match stream {
-                // if something goes wrong and it's multiple seconds until we
-                // get a response, kill the process.
-                //
-                // the timeout here is set such that nss will fall back to system
-                // libc before this timeout is hit - clients will already be
-                // giving up and going elsewhere so crashing the process should
-                // not make a bad situation worse.
-                Ok(stream) => match tx.send_timeout(stream, handoff_timeout) {
-                    Err(channel::SendTimeoutError::Timeout(_)) => {
-                        error!(log, "timed out waiting for an available worker");
-                        break;
-                    }
-                    Err(channel::SendTimeoutError::Disconnected(_)) => {
-                        error!(log, "worker channel is disconnected");
-                        break;
-                    }
-                    Ok(()) => { /*ok!*/ }
-                },
+        runtime.block_on(async {
+            let listener = match listener
+                .set_nonblocking(true)
+                .and_then(|()| net::UnixListener::from_std(listener))
+            {
+                Ok(listener) => listener,
                 Err(err) => {
-                    error!(log, "error accepting connection"; "err" => %err);
+                    error!(log, "registering listener"; "err" => %err);
+                    return;
+                }
+            };
+            let mut connections = JoinSet::new();
+            let (closing, closed) = tokio::sync::watch::channel(false);
+            loop {
+                if ctx.is_shutdown() || shutdown.is_requested() {
+                    break;
+                }
+                if TERMINATE_REQUESTED.load(Ordering::SeqCst) {
+                    slog::info!(log, "terminating on signal");
                     break;
                 }
+
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            let open = match server.open_connection() {
+                                Some(open) => open,
+                                None => {
+                                    if let Some(count) = server.config.stats.record_refused(Instant::now()) {
+                                        slog::warn!(log, "too many connections, closing new ones";
+                                            "limit" => server.config.max_connections, "count" => count);
+                                    }
+                                    // closed right away, so the client falls
+                                    // back to its own lookup.
+                                    drop(stream);
+                                    continue;
+                                }
+                            };
+                            let (log, server) = (log.clone(), server.clone());
+                            let closed = closed.clone();
+                            connections.spawn(async move {
+                                let _open = open;
+                                let Server { config, audit, workers, .. } = &*server;
+                                let stats = &config.stats;
+                                serve(&log, config, audit.as_deref(), stats, workers, stream, closed)
+                                    .await
+                            });
+                        }
+                        Err(err) => {
+                            error!(log, "error accepting connection"; "err" => %err);
+                            break;
+                        }
+                    },
+                    // if something goes wrong and it's multiple seconds until
+                    // we get a response, kill the process.
+                    //
+                    // the timeout here is set such that nss will fall back to
+                    // system libc before this timeout is hit - clients will
+                    // already be giving up and going elsewhere so crashing the
+                    // process should not make a bad situation worse.
+                    () = server.workers.stuck.notified() => break,
+                    Some(done) = connections.join_next() => {
+                        if let Err(e) = done {
+                            if e.is_panic() {
+                                panic::resume_unwind(e.into_panic());
+                            }
+                        }
+                    }
+                    // don't wait for a connection for so long that we'd miss
+                    // a shutdown while nobody's connecting.
+                    () = time::sleep(ACCEPT_POLL_INTERVAL) => {}
+                }
             }
+
+            // stop accepting, so new clients go elsewhere, and let the
+            // requests we already have be answered, but don't wait on clients
+            // that never send one.
+            drop(listener);
+            let _ = closing.send(true);
+            let drained = async { while connections.join_next().await.is_some() {} };
+            let timeout = server.config.shutdown_timeout;
+            if time::timeout(timeout, drained).await.is_err() {
+                debug!(log, "closing connections still open"; "count" => connections.len());
+            }
+        });
+    });
+}
+
+/// Hand `listener` over to a new nsncd when we get `SIGUSR2`, and exit once
+/// it's ready, setting `handed_over`.
+fn spawn_handover(
+    wg: &mut WorkGroup,
+    log: &slog::Logger,
+    listener: UnixListener,
+    shutdown: config::Shutdown,
+    handed_over: Arc<AtomicBool>,
+) {
+    let log = log.new(o!("thread" => "handover"));
+    wg.add(move |ctx| {
+        // the acceptors drop their copies of the listener when they stop
+        // accepting, so this one mustn't outlive them by much.
+        while !ctx.is_shutdown()
+            && !shutdown.is_requested()
+            && !TERMINATE_REQUESTED.load(Ordering::SeqCst)
+        {
+            if handover::take_request() {
+                slog::info!(log, "handing socket over to new nsncd");
+                match handover::start(handover::nsncd(), &listener)
+                    .and_then(|successor| successor.wait_ready(HANDOVER_TIMEOUT))
+                {
+                    Ok(pid) => {
+                        slog::info!(log, "new nsncd is ready"; "pid" => pid);
+                        let _ = sd_notify::notify(false, &[NotifyState::MainPid(pid)]);
+                        handed_over.store(true, Ordering::SeqCst);
+                        return;
+                    }
+                    Err(e) => error!(log, "handing socket over"; "err" => %e),
+                }
+            }
+            std::thread::sleep(HANDOVER_POLL_INTERVAL);
         }
+    });
+}
 
-        // at the end of the listener loop, drop tx so that any working threads
-        // still waiting for a connection have a chance to finish.
-        //
-        // this is unnecessary but explicit
-        std::mem::drop(tx);
+/// Log and count the requests workers are stuck on, and kill the lookup
+/// processes stuck on them.
+fn spawn_watchdog(wg: &mut WorkGroup, log: &slog::Logger, config: Config, watchdog: Arc<Watchdog>) {
+    let log = log.new(o!("thread" => "watchdog"));
+    wg.add(move |ctx| {
+        while !ctx.is_shutdown() {
+            std::thread::sleep(WATCHDOG_INTERVAL);
+            for stuck in watchdog.newly_stuck(Instant::now()) {
+                config.stats.record_stuck_worker();
+                slog::warn!(log, "worker stuck on request";
+                    "worker" => stuck.worker,
+                    "request_type" => ?stuck.ty,
+                    "running" => ?stuck.running);
+            }
+            let killed = config.backends.kill_stuck(watchdog.threshold());
+            if killed > 0 {
+                slog::warn!(log, "killed stuck lookup processes"; "count" => killed);
+            }
+        }
     });
 }
 
+/// What the task serving each connection needs.
+struct Server {
+    config: Config,
+    audit: Option<Arc<AuditLog>>,
+    workers: Workers,
+    /// How many connections are open.
+    connections: AtomicUsize,
+}
+
+impl Server {
+    /// Count a connection as open until the returned guard is dropped, or
+    /// return `None` if `config.max_connections` already are.
+    fn open_connection(self: &Arc<Self>) -> Option<OpenConnection> {
+        let open = self.connections.fetch_add(1, Ordering::SeqCst);
+        let guard = OpenConnection(self.clone());
+        let limit = self.config.max_connections;
+        if limit > 0 && open >= limit {
+            return None;
+        }
+        Some(guard)
+    }
+}
+
+/// An open connection, counted in [Server::connections].
+struct OpenConnection(Arc<Server>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 fn spawn_workers(
     wg: &mut WorkGroup,
     log: &slog::Logger,
-    config: Config,
-) -> channel::Sender<UnixStream> {
-    let (tx, rx) = channel::bounded(0);
+    config: &Config,
+    watchdog: &Arc<Watchdog>,
+) -> Workers {
+    let (mut workers, done) = Workers::new(config.handoff_timeout, config.queue_capacity);
+    let pools = [
+        (Pool::Default, "worker", config.worker_count),
+        (Pool::Hosts, "hosts_worker", config.hosts_worker_count),
+        (
+            Pool::Netgroup,
+            "netgroup_worker",
+            config.netgroup_worker_count,
+        ),
+    ];
+
+    // the workers of every pool, numbered in order.
+    let mut worker = 0;
+    for (pool, name, count) in pools {
+        // without workers of its own, a pool's requests go to the default
+        // pool's workers.
+        if count == 0 {
+            continue;
+        }
+        let queue = match pool {
+            Pool::Default => workers.queue(pool).clone(),
+            _ => workers.separate(pool),
+        };
+        for worker_id in 0..count {
+            let queue = queue.clone();
+            let done = done.clone();
+            let log = log.new(o!("thread" => format!("{}_{}", name, worker_id)));
+            let config = config.clone();
+            let watchdog = watchdog.clone();
+
+            // ctx is ignored - the acceptor thread drops the Workers once
+            // it's done, and that's when it's time to exit.
+            wg.add(move |_ctx| {
+                let stats = &config.stats;
+                work(&log, &config, stats, &queue, &done, &watchdog, worker)
+            });
+            worker += 1;
+        }
+    }
+
+    workers
+}
+
+/// Answer requests from `queue`, fast ones first, until
+/// `done` is disconnected and there are none left. `worker` is our number in
+/// the `watchdog`.
+fn work(
+    log: &slog::Logger,
+    config: &Config,
+    stats: &Stats,
+    queue: &PriorityQueue<Job>,
+    done: &channel::Receiver<()>,
+    watchdog: &Watchdog,
+    worker: usize,
+) {
+    let mut streak = Streak::default();
+    loop {
+        if let Some(job) = queue.try_pop(&mut streak) {
+            answer(log, config, stats, job, watchdog, worker);
+            continue;
+        }
+        channel::select! {
+            recv(done) -> _ => break,
+            recv(queue.receiver(Class::Fast)) -> job => {
+                if let Ok(job) = job {
+                    answer(log, config, stats, job, watchdog, worker);
+                }
+            },
+            recv(queue.receiver(Class::Slow)) -> job => {
+                if let Ok(job) = job {
+                    answer(log, config, stats, job, watchdog, worker);
+                }
+            },
+        }
+    }
+    // no more connections: answer whatever was queued before exiting.
+    while let Some(job) = queue.try_pop(&mut streak) {
+        answer(log, config, stats, job, watchdog, worker);
+    }
+}
 
-    for worker_id in 0..config.worker_count {
-        let rx = rx.clone();
-        let log = log.new(o!("thread" => format!("worker_{}", worker_id)));
+/// The threads answering requests. NSS calls block, so they're made there,
+/// a bounded number at a time, rather than on the runtime serving the
+/// connections.
+struct Workers {
+    /// The queue of each [Pool], by its index. Pools without workers of their
+    /// own share the default pool's.
+    queues: [PriorityQueue<Job>; 3],
+    /// How many requests of each class can wait in a queue.
+    capacity: usize,
+    handoff_timeout: Duration,
+    /// Notified when a request timed out waiting for a worker.
+    stuck: Notify,
+    /// Dropped along with the Workers, which tells the threads to exit once
+    /// they've answered what's queued.
+    _running: channel::Sender<()>,
+}
+
+impl Workers {
+    /// Returns the receiver the threads should stop on.
+    fn new(handoff_timeout: Duration, capacity: usize) -> (Self, channel::Receiver<()>) {
+        let (running, done) = channel::bounded(0);
+        let queue = PriorityQueue::new(capacity);
+        let workers = Self {
+            queues: [queue.clone(), queue.clone(), queue],
+            capacity,
+            handoff_timeout,
+            stuck: Notify::new(),
+            _running: running,
+        };
+        (workers, done)
+    }
+
+    fn queue(&self, pool: Pool) -> &PriorityQueue<Job> {
+        &self.queues[pool as usize]
+    }
+
+    /// Give `pool` a queue of its own, and return it for its workers to
+    /// answer.
+    fn separate(&mut self, pool: Pool) -> PriorityQueue<Job> {
+        let queue = PriorityQueue::new(self.capacity);
+        self.queues[pool as usize] = queue.clone();
+        queue
+    }
 
-        // ctx is ignored - the acceptor thread will close the rx channel if
-        // the wg is shutdown and it's time to exit.
-        wg.add(move |_ctx| {
-            while let Ok(stream) = rx.recv() {
-                handle_stream(&log, &config, stream);
+    /// Have a worker answer the request in `buf`. Returns `None` if there's
+    /// no response to send, or no worker to get it from.
+    async fn dispatch(
+        &self,
+        log: &slog::Logger,
+        stats: &Stats,
+        ty: protocol::RequestType,
+        buf: Vec<u8>,
+        peer_uid: Option<Uid>,
+    ) -> Option<Vec<u8>> {
+        let (reply, response) = oneshot::channel();
+        let (taken, picked_up) = oneshot::channel();
+        let job = Job {
+            ty,
+            buf,
+            peer_uid,
+            reply,
+            taken,
+        };
+        if self
+            .queue(Pool::of(ty))
+            .try_push(Class::of(ty), job)
+            .is_err()
+        {
+            // the client gets no answer, and looks it up itself right away,
+            // rather than waiting behind more requests than we can answer
+            // in time. letting them pile up would only end with us out of
+            // file descriptors.
+            if let Some(count) = stats.record_shed(Instant::now()) {
+                slog::warn!(log, "queue full, turning requests away"; "count" => count);
             }
+            return None;
+        }
+        if time::timeout(self.handoff_timeout, picked_up)
+            .await
+            .is_err()
+        {
+            error!(log, "timed out waiting for an available worker");
+            self.stuck.notify_one();
+            return None;
+        }
+        response.await.ok().flatten()
+    }
+}
+
+/// A request waiting for a worker.
+struct Job {
+    ty: protocol::RequestType,
+    buf: Vec<u8>,
+    peer_uid: Option<Uid>,
+    /// Where to send the response.
+    reply: oneshot::Sender<Option<Vec<u8>>>,
+    /// Dropped once a worker takes the job, which tells
+    /// [Workers::dispatch] that it didn't wait too long.
+    taken: oneshot::Sender<()>,
+}
+
+/// Read requests from a connection and answer them, with a worker of our
+/// own.
+#[cfg(test)]
+fn handle_stream(
+    log: &slog::Logger,
+    config: &Config,
+    audit: Option<&AuditLog>,
+    stats: &Stats,
+    stream: UnixStream,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (workers, done) = Workers::new(config.handoff_timeout, config.queue_capacity);
+    let queue = workers.queue(Pool::Default).clone();
+    let watchdog = Watchdog::new(1, config.stuck_threshold);
+    std::thread::scope(|s| {
+        s.spawn(|| work(log, config, stats, &queue, &done, &watchdog, 0));
+        runtime.block_on(async {
+            stream.set_nonblocking(true).unwrap();
+            let stream = net::UnixStream::from_std(stream).unwrap();
+            let (_closing, closed) = tokio::sync::watch::channel(false);
+            serve(log, config, audit, stats, &workers, stream, closed).await;
         });
+        drop(workers);
+    });
+}
+
+/// Answer the requests a client sends on a connection, until it hangs up,
+/// stays quiet for the configured idle timeout, or `closed` says we're shutting down.
+///
+/// glibc connects for every lookup, but other clients may send several
+/// requests on one connection. They're answered in order, one at a time, so
+/// a chatty client doesn't pay for a new connection each time, and can't get
+/// its replies out of order.
+///
+/// Only answering takes a worker: waiting for a client to send a request, or
+/// to read its response, is done on the runtime, so idle and slow clients
+/// don't tie up threads.
+async fn serve(
+    log: &slog::Logger,
+    config: &Config,
+    audit: Option<&AuditLog>,
+    stats: &Stats,
+    workers: &Workers,
+    mut stream: net::UnixStream,
+    mut closed: tokio::sync::watch::Receiver<bool>,
+) {
+    let peer = getsockopt(&stream, PeerCredentials).ok();
+    let peer_uid = peer.as_ref().map(|cred| Uid::from_raw(cred.uid()));
+    let mut pending = vec![];
+    loop {
+        let read = read_request(
+            log,
+            &config.buffers,
+            audit,
+            stats,
+            &mut stream,
+            peer,
+            pending,
+        );
+        // a request that's already here is still answered when we're
+        // shutting down, but we don't wait for another one.
+        let request = tokio::select! {
+            biased;
+            request = time::timeout(config.idle_timeout, read) => match request {
+                Ok(request) => request,
+                Err(_) => {
+                    debug!(log, "closing idle connection");
+                    None
+                }
+            },
+            () = shutting_down(&mut closed) => None,
+        };
+        let (ty, buf, next) = match request {
+            Some(request) => request,
+            None => break,
+        };
+        let response = match workers.dispatch(log, stats, ty, buf, peer_uid).await {
+            Some(response) => response,
+            None => break,
+        };
+        let written = write_response(&mut stream, response.as_slice(), WRITE_TIMEOUT).await;
+        // glibc waits for the connection to close to tell that there's no
+        // answer, so there's no more after an empty response.
+        let keep_open = written.is_ok() && !response.is_empty();
+        if let Err(e) = written {
+            match e.kind() {
+                // If we send a response that's too big for the client's
+                // buffer, the client will disconnect and not read the rest of
+                // our response, and then come back with a new connection after
+                // increasing its buffer. There's no need to log that, and
+                // generally, clients can disappear at any point.
+                ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => (),
+                _ => debug!(log, "sending response"; "response_len" => response.len(), "err" => %e),
+            };
+        }
+        config.buffers.give_back(response);
+        if !keep_open {
+            break;
+        }
+        pending = next;
+        if pending.is_empty() {
+            let readable = tokio::select! {
+                biased;
+                readable = time::timeout(config.idle_timeout, stream.readable()) => readable,
+                () = shutting_down(&mut closed) => break,
+            };
+            match readable {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    debug!(log, "waiting for next request"; "err" => %e);
+                    break;
+                }
+                Err(_) => {
+                    debug!(log, "closing idle connection");
+                    break;
+                }
+            }
+        }
+    }
+    if let Err(e) = stream.shutdown().await {
+        debug!(log, "shutting down stream"; "err" => %e);
     }
+}
 
-    tx
+/// Wait until `closed` says we're shutting down.
+async fn shutting_down(closed: &mut tokio::sync::watch::Receiver<bool>) {
+    if closed.wait_for(|closed| *closed).await.is_err() {
+        // nobody's left to tell us, so it won't happen.
+        std::future::pending().await
+    }
 }
 
-fn handle_stream(log: &slog::Logger, config: &Config, mut stream: UnixStream) {
-    debug!(log, "accepted connection"; "stream" => ?stream);
-    let mut buf = [0; 4096];
-    let size_read = match stream.read(&mut buf) {
-        Ok(x) => x,
-        Err(e) => {
-            debug!(log, "reading from connection"; "err" => %e);
-            return;
+/// Read a request from a connection, starting with the `pending` bytes the
+/// client already sent. Returns its type, the buffer it's at the start of,
+/// and what was sent after it, or `None` if there's nothing to answer.
+async fn read_request(
+    log: &slog::Logger,
+    buffers: &BufferPool,
+    audit: Option<&AuditLog>,
+    stats: &Stats,
+    stream: &mut net::UnixStream,
+    peer: Option<UnixCredentials>,
+    pending: Vec<u8>,
+) -> Option<(protocol::RequestType, Vec<u8>, Vec<u8>)> {
+    debug!(log, "reading request"; "stream" => ?stream);
+    let mut buf = buffers.checkout();
+    buf.extend_from_slice(&pending);
+    // the request may take several reads to arrive, e.g. the rest of one
+    // sent right after the previous one.
+    loop {
+        match protocol::request_len(&buf) {
+            Some(len) if buf.len() >= len => break,
+            Some(len) if len > MAX_REQUEST_LEN => {
+                debug!(log, "request too long"; "len" => len);
+                stats.record_unparsed();
+                return None;
+            }
+            // a header with a negative key length, which fails to parse.
+            None if buf.len() >= 12 => break,
+            _ => {}
         }
-    };
-    let request = match protocol::Request::parse(&buf[0..size_read]) {
+        let start = buf.len();
+        buf.resize(start + REQUEST_BUFFER_LEN, 0);
+        let size_read = match stream.read(&mut buf[start..]).await {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(log, "reading from connection"; "err" => %e);
+                return None;
+            }
+        };
+        buf.truncate(start + size_read);
+        if size_read == 0 {
+            // the client hung up: whatever it sent is all we get.
+            break;
+        }
+    }
+    // a client that hangs up without asking anything (e.g. a health check,
+    // or one done with its requests) isn't a failed request; one that hangs
+    // up halfway through a header is, and fails to parse below.
+    if buf.is_empty() {
+        debug!(log, "connection closed before a request");
+        buffers.give_back(buf);
+        return None;
+    }
+    let request = match protocol::Request::parse(&buf) {
         Ok(x) => x,
         Err(e) => {
-            debug!(log, "parsing request"; "err" => %e);
-            return;
+            // like nscd, hang up without a reply: the client can't know the
+            // layout of ours, and falls back to looking it up itself.
+            if let Some(wrong) = e.downcast_ref::<protocol::WrongVersion>() {
+                slog::warn!(log, "rejecting request"; "err" => %wrong);
+            } else {
+                debug!(log, "parsing request"; "err" => %e);
+            }
+            stats.record_unparsed();
+            return None;
         }
     };
+    stats.record_request(request.ty);
+    if let Some(audit) = audit {
+        if let Err(e) = audit.record(&audit::format_record(peer.as_ref(), &request)) {
+            error!(log, "writing audit log"; "err" => %e);
+        }
+    }
+    let ty = request.ty;
+    let next = buf[request.wire_len()..].to_vec();
+    Some((ty, buf, next))
+}
+
+/// Answer a request queued by [Workers::dispatch], and send the response
+/// back to its connection, noting in `watchdog` that `worker` is busy
+/// meanwhile.
+fn answer(
+    log: &slog::Logger,
+    config: &Config,
+    stats: &Stats,
+    job: Job,
+    watchdog: &Watchdog,
+    worker: usize,
+) {
+    let Job {
+        ty,
+        buf,
+        peer_uid,
+        reply,
+        taken,
+    } = job;
+    drop(taken);
+    watchdog.start(worker, ty, Instant::now());
+    let response = respond(log, config, stats, &buf, peer_uid);
+    if let Some(took) = watchdog.finish(worker, Instant::now()) {
+        slog::info!(log, "stuck worker is done"; "worker" => worker, "took" => ?took);
+    }
+    config.buffers.give_back(buf);
+    // the client may have hung up while it waited.
+    if let Err(Some(response)) = reply.send(response) {
+        config.buffers.give_back(response);
+    }
+}
+
+/// Handle a request read by [read_request], and return the response to
+/// send, or `None` if there's none.
+fn respond(
+    log: &slog::Logger,
+    config: &Config,
+    stats: &Stats,
+    buf: &[u8],
+    peer_uid: Option<Uid>,
+) -> Option<Vec<u8>> {
+    let mut request = protocol::Request::parse(buf).expect("request was already parsed");
+    request.peer_uid = peer_uid;
     let type_str = format!("{:?}", request.ty);
     let log = log.new(o!("request_type" => type_str));
-    let response = match handlers::handle_request(&log, config, &request) {
+    // a bug hit by one request, in our code or an NSS module's, fails that
+    // request only, rather than name resolution for the whole machine.
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+        config.middleware.handle(&log, config, &request)
+    }));
+    let handled = match handled {
+        Ok(x) => x,
+        Err(payload) => {
+            error!(log, "panicked handling request"; "panic" => panic_message(&*payload));
+            stats.record_panic();
+            stats.record_error(request.ty);
+            return None;
+        }
+    };
+    let response = match handled {
         Ok(x) => x,
+        Err(e) if failover::should_fail_over(config, &request, &e) => {
+            let secondary = config.secondary_socket.as_deref().unwrap();
+            match failover::forward(&log, secondary, &request) {
+                Ok(x) => x,
+                Err(secondary_err) => {
+                    error!(log, "error handling request"; "err" => format!("{:#}", e),
+                        "secondary_err" => format!("{:#}", secondary_err));
+                    stats.record_error(request.ty);
+                    return None;
+                }
+            }
+        }
         Err(e) => {
-            error!(log, "error handling request"; "err" => %e);
-            return;
+            // with its causes, e.g. the errno behind "looking up uid 1234".
+            error!(log, "error handling request"; "err" => format!("{:#}", e));
+            stats.record_error(request.ty);
+            return None;
         }
     };
-    if let Err(e) = stream.write_all(response.as_slice()) {
-        match e.kind() {
-            // If we send a response that's too big for the client's buffer,
-            // the client will disconnect and not read the rest of our
-            // response, and then come back with a new connection after
-            // increasing its buffer. There's no need to log that, and
-            // generally, clients can disappear at any point.
-            ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => (),
-            _ => debug!(log, "sending response"; "response_len" => response.len(), "err" => %e),
-        };
-    }
-    if let Err(e) = stream.shutdown(std::net::Shutdown::Both) {
-        debug!(log, "shutting down stream"; "err" => %e);
+    if let Some(found) = protocol::reply_found(request.ty, &response) {
+        stats.record_answer(request.ty, found);
+    }
+    Some(response)
+}
+
+/// The message a panic was started with.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("(not a string)")
+}
+
+/// Write all of `buf`, giving up if the client doesn't make room for more
+/// of it within `timeout`.
+///
+/// Unlike `write_all`, this tells a client that's slow to read a large
+/// response apart from one that stopped reading: only the latter times out.
+///
+/// The response is a single buffer, not the header and each field as
+/// separate slices for `writev`: the handlers already write them into one
+/// pooled buffer without intermediate copies, and the middlewares, failover
+/// and stats need the whole response before it's sent anyway. So a plain
+/// `write` sends it with no more copying or allocation than `writev` would.
+async fn write_response<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    buf: &[u8],
+    timeout: Duration,
+) -> std::io::Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        match time::timeout(timeout, w.write(&buf[written..])).await {
+            Err(_) => return Err(ErrorKind::TimedOut.into()),
+            Ok(Ok(0)) => return Err(ErrorKind::WriteZero.into()),
+            Ok(Ok(n)) => written += n,
+            Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => {}
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::prelude::*;
+    use std::os::unix::net::UnixDatagram;
+    use std::pin::Pin;
+    use std::task::{self, Poll};
+
+    use nix::errno::Errno;
+
+    fn test_logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    /// Send a raw request over a fresh connection and handle it.
+    fn send_request(stats: &Stats, ty: i32, key: &[u8]) {
+        send_request_logged(&test_logger(), stats, ty, key)
+    }
+
+    fn send_request_logged(log: &slog::Logger, stats: &Stats, ty: i32, key: &[u8]) {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&protocol::VERSION.to_ne_bytes());
+        buf.extend_from_slice(&ty.to_ne_bytes());
+        buf.extend_from_slice(&(key.len() as i32).to_ne_bytes());
+        buf.extend_from_slice(key);
+        client.write_all(&buf).unwrap();
+        // that's all we're sending, so the connection isn't kept open for
+        // more.
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        handle_stream(log, &Config::default(), None, stats, server);
+    }
+
+    /// A writer taking at most 3 bytes at a time, and making the caller wait
+    /// or failing with `EINTR` every other call.
+    struct SlowReader {
+        received: Vec<u8>,
+        calls: usize,
+    }
+
+    impl AsyncWrite for SlowReader {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.calls += 1;
+            match self.calls % 4 {
+                1 => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                3 => Poll::Ready(Err(ErrorKind::Interrupted.into())),
+                _ => {
+                    let n = buf.len().min(3);
+                    self.received.extend_from_slice(&buf[..n]);
+                    Poll::Ready(Ok(n))
+                }
+            }
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _: &mut task::Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _: &mut task::Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_write_response_resumes() {
+        let response: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut writer = SlowReader {
+            received: vec![],
+            calls: 0,
+        };
+        block_on(write_response(&mut writer, &response, WRITE_TIMEOUT)).unwrap();
+        assert_eq!(writer.received, response);
+        assert_eq!(writer.calls, 668);
+    }
+
+    #[test]
+    fn test_write_response_gives_up() {
+        // a client that never reads: its socket buffer fills up, and stays
+        // full.
+        let (client, server) = UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let response = vec![0; 1 << 20];
+        let err = block_on(async {
+            let mut server = net::UnixStream::from_std(server).unwrap();
+            write_response(&mut server, &response, Duration::from_millis(50)).await
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        drop(client);
+    }
+
+    /// Connect to a server that's starting up.
+    fn connect(socket_path: &Path) -> UnixStream {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match UnixStream::connect(socket_path) {
+                Ok(client) => return client,
+                Err(_) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("server never started listening: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_and_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            socket_path: dir.path().join("socket"),
+            worker_count: 2,
+            ..Config::default()
+        };
+        let shutdown = config.shutdown.clone();
+        let socket_path = config.socket_path.clone();
+
+        // keep a concurrent test's NOTIFY_SOCKET away from our READY=1.
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            let mut client = connect(&socket_path);
+
+            let uid = nix::unistd::getuid().to_string();
+            let key = [uid.as_bytes(), b"\0"].concat();
+            let request = protocol::Request::new(protocol::RequestType::GETPWBYUID, &key);
+            let mut buf = Vec::new();
+            buf.extend_from_slice(&request.version.to_ne_bytes());
+            buf.extend_from_slice(&(request.ty as i32).to_ne_bytes());
+            buf.extend_from_slice(&request.key_len.to_ne_bytes());
+            buf.extend_from_slice(request.key);
+            client.write_all(&buf).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            let expected =
+                handlers::handle_request(&test_logger(), &Config::default(), &request).unwrap();
+            assert_eq!(response, expected);
+
+            shutdown.request();
+            let reason = server.join().unwrap().unwrap();
+            assert_eq!(reason, ShutdownReason::Requested);
+        });
+    }
+
+    #[test]
+    fn test_several_acceptors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            socket_path: dir.path().join("socket"),
+            accept_threads: 3,
+            ..Config::default()
+        };
+        let shutdown = config.shutdown.clone();
+        let socket_path = config.socket_path.clone();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            connect(&socket_path);
+            let clients: Vec<_> = (0..20)
+                .map(|_| {
+                    let socket_path = socket_path.clone();
+                    std::thread::spawn(move || {
+                        let mut client = connect(&socket_path);
+                        let request =
+                            protocol::Request::new(protocol::RequestType::GETGRBYGID, b"0\0");
+                        client.write_all(&request.to_bytes()).unwrap();
+                        let mut response = Vec::new();
+                        client.read_to_end(&mut response).unwrap();
+                        response
+                    })
+                })
+                .collect();
+            for client in clients {
+                assert!(!client.join().unwrap().is_empty());
+            }
+
+            shutdown.request();
+            assert_eq!(server.join().unwrap().unwrap(), ShutdownReason::Requested);
+        });
+    }
+
+    #[test]
+    fn test_idle_clients_hold_no_worker() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            socket_path: dir.path().join("socket"),
+            worker_count: 1,
+            ..Config::default()
+        };
+        let shutdown = config.shutdown.clone();
+        let socket_path = config.socket_path.clone();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            // connected, but not asking anything yet...
+            let idle: Vec<_> = (0..100).map(|_| connect(&socket_path)).collect();
+
+            // ...which doesn't keep the only worker from answering.
+            let mut client = connect(&socket_path);
+            let request = protocol::Request::new(protocol::RequestType::GETGRBYGID, b"0\0");
+            client.write_all(&request.to_bytes()).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            assert!(!response.is_empty());
+
+            drop(idle);
+            shutdown.request();
+            assert_eq!(server.join().unwrap().unwrap(), ShutdownReason::Requested);
+        });
+    }
+
+    #[test]
+    fn test_stalled_client_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            socket_path: dir.path().join("socket"),
+            idle_timeout: Duration::from_millis(200),
+            ..Config::default()
+        };
+        let shutdown = config.shutdown.clone();
+        let socket_path = config.socket_path.clone();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            let mut stalled = connect(&socket_path);
+            let start = Instant::now();
+            let mut response = Vec::new();
+            stalled.read_to_end(&mut response).unwrap();
+            assert!(response.is_empty());
+            assert!(start.elapsed() >= Duration::from_millis(200));
+
+            shutdown.request();
+            assert_eq!(server.join().unwrap().unwrap(), ShutdownReason::Requested);
+        });
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            socket_path: dir.path().join("socket"),
+            max_connections: 2,
+            idle_timeout: Duration::from_secs(10),
+            ..Config::default()
+        };
+        let shutdown = config.shutdown.clone();
+        let stats = config.stats.clone();
+        let socket_path = config.socket_path.clone();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            let mut open: Vec<_> = (0..2).map(|_| connect(&socket_path)).collect();
+
+            // one too many is closed right away.
+            let mut refused = connect(&socket_path);
+            let mut response = Vec::new();
+            let _ = refused.read_to_end(&mut response);
+            assert!(response.is_empty());
+            assert_eq!(stats.snapshot().refused, 1);
+
+            // once one of the others is closed (and the server has noticed),
+            // there's room again.
+            open.pop();
+            let request = protocol::Request::new(protocol::RequestType::GETGRBYGID, b"0\0");
+            let answered = (0..100).any(|_| {
+                let mut client = connect(&socket_path);
+                let mut response = vec![0; 4];
+                let answered = client.write_all(&request.to_bytes()).is_ok()
+                    && client.read_exact(&mut response).is_ok();
+                if !answered {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                answered
+            });
+            assert!(answered);
+
+            drop(open);
+            shutdown.request();
+            assert_eq!(server.join().unwrap().unwrap(), ShutdownReason::Requested);
+        });
+    }
+
+    #[test]
+    fn test_hosts_pool_isolated() {
+        /// Holds host lookups until `release` is dropped.
+        struct HungDns {
+            release: channel::Receiver<()>,
+        }
+
+        impl middleware::RequestMiddleware for HungDns {
+            fn before(
+                &self,
+                _log: &slog::Logger,
+                request: &protocol::Request,
+            ) -> Result<middleware::Action> {
+                if Pool::of(request.ty) == Pool::Hosts {
+                    let _ = self.release.recv();
+                }
+                Ok(middleware::Action::Continue)
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (release, released) = channel::bounded(0);
+        let mut config = Config {
+            socket_path: dir.path().join("socket"),
+            worker_count: 1,
+            hosts_worker_count: 1,
+            ..Config::default()
+        };
+        config
+            .middleware
+            .push(Arc::new(HungDns { release: released }));
+        let shutdown = config.shutdown.clone();
+        let socket_path = config.socket_path.clone();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            let mut hosts = connect(&socket_path);
+            let request = protocol::Request::new(protocol::RequestType::GETAI, b"localhost\0");
+            hosts.write_all(&request.to_bytes()).unwrap();
+
+            // the hosts worker is stuck, the other one isn't.
+            let mut client = connect(&socket_path);
+            let request = protocol::Request::new(protocol::RequestType::GETGRBYGID, b"0\0");
+            client.write_all(&request.to_bytes()).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            assert!(!response.is_empty());
+
+            drop(release);
+            let mut response = Vec::new();
+            hosts.read_to_end(&mut response).unwrap();
+            assert!(!response.is_empty());
+            shutdown.request();
+            assert_eq!(server.join().unwrap().unwrap(), ShutdownReason::Requested);
+        });
+    }
+
+    #[test]
+    fn test_shutdown_drains() {
+        /// Holds group lookups until `release` is dropped, saying when one
+        /// starts.
+        struct Held {
+            started: channel::Sender<()>,
+            release: channel::Receiver<()>,
+        }
+
+        impl middleware::RequestMiddleware for Held {
+            fn before(
+                &self,
+                _log: &slog::Logger,
+                _request: &protocol::Request,
+            ) -> Result<middleware::Action> {
+                let _ = self.started.send(());
+                let _ = self.release.recv();
+                Ok(middleware::Action::Continue)
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (started, starts) = channel::unbounded();
+        let (release, released) = channel::bounded(0);
+        let mut config = Config {
+            socket_path: dir.path().join("socket"),
+            ..Config::default()
+        };
+        config.middleware.push(Arc::new(Held {
+            started,
+            release: released,
+        }));
+        let shutdown = config.shutdown.clone();
+        let socket_path = config.socket_path.clone();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            let mut idle = connect(&socket_path);
+            let mut client = connect(&socket_path);
+            let request = protocol::Request::new(protocol::RequestType::GETGRBYGID, b"0\0");
+            client.write_all(&request.to_bytes()).unwrap();
+            starts.recv().unwrap();
+            shutdown.request();
+
+            // the idle connection is closed without waiting for a request...
+            let mut response = Vec::new();
+            idle.read_to_end(&mut response).unwrap();
+            assert!(response.is_empty());
+            assert!(UnixStream::connect(&socket_path).is_err());
+
+            // ...while the one being answered gets its answer.
+            drop(release);
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+            assert!(!response.is_empty());
+            assert_eq!(server.join().unwrap().unwrap(), ShutdownReason::Requested);
+            assert!(!socket_path.exists());
+        });
+    }
+
+    #[test]
+    fn test_stuck_worker_reported() {
+        /// Holds lookups until `release` is dropped.
+        struct Hung {
+            release: channel::Receiver<()>,
+        }
+
+        impl middleware::RequestMiddleware for Hung {
+            fn before(
+                &self,
+                _log: &slog::Logger,
+                _request: &protocol::Request,
+            ) -> Result<middleware::Action> {
+                let _ = self.release.recv();
+                Ok(middleware::Action::Continue)
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (release, released) = channel::bounded(0);
+        let mut config = Config {
+            socket_path: dir.path().join("socket"),
+            stuck_threshold: Duration::from_millis(100),
+            ..Config::default()
+        };
+        config.middleware.push(Arc::new(Hung { release: released }));
+        let shutdown = config.shutdown.clone();
+        let stats = config.stats.clone();
+        let socket_path = config.socket_path.clone();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            let mut client = connect(&socket_path);
+            let request = protocol::Request::new(protocol::RequestType::GETGRBYGID, b"0\0");
+            client.write_all(&request.to_bytes()).unwrap();
+
+            let reported = (0..100).any(|_| {
+                std::thread::sleep(Duration::from_millis(50));
+                stats.snapshot().stuck_workers == 1
+            });
+            assert!(reported);
+
+            // it's only reported once, and still answers once it's done.
+            std::thread::sleep(2 * WATCHDOG_INTERVAL);
+            assert_eq!(stats.snapshot().stuck_workers, 1);
+            drop(release);
+            let mut response = vec![0; 4];
+            client.read_exact(&mut response).unwrap();
+
+            shutdown.request();
+            assert_eq!(server.join().unwrap().unwrap(), ShutdownReason::Requested);
+        });
+    }
+
+    #[test]
+    fn test_shed_when_queue_full() {
+        /// Holds lookups until `release` is dropped, saying when it starts
+        /// one.
+        struct Blocked {
+            started: channel::Sender<()>,
+            release: channel::Receiver<()>,
+        }
+
+        impl middleware::RequestMiddleware for Blocked {
+            fn before(
+                &self,
+                _log: &slog::Logger,
+                _request: &protocol::Request,
+            ) -> Result<middleware::Action> {
+                let _ = self.started.send(());
+                let _ = self.release.recv();
+                Ok(middleware::Action::Continue)
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (started, starts) = channel::unbounded();
+        let (release, released) = channel::bounded(0);
+        let mut config = Config {
+            socket_path: dir.path().join("socket"),
+            worker_count: 1,
+            queue_capacity: 1,
+            ..Config::default()
+        };
+        config.middleware.push(Arc::new(Blocked {
+            started,
+            release: released,
+        }));
+        let stats = config.stats.clone();
+        let shutdown = config.shutdown.clone();
+        let socket_path = config.socket_path.clone();
+
+        temp_env::with_var_unset("NOTIFY_SOCKET", || {
+            let server = std::thread::spawn(move || run(&test_logger(), config));
+            let request = |socket_path: &Path| {
+                let mut client = connect(socket_path);
+                let request = protocol::Request::new(protocol::RequestType::GETGRBYGID, b"0\0");
+                client.write_all(&request.to_bytes()).unwrap();
+                client
+            };
+            let read = |mut client: UnixStream| {
+                let mut response = Vec::new();
+                client.read_to_end(&mut response).unwrap();
+                response
+            };
+
+            // one being answered, and one waiting...
+            let answered = request(&socket_path);
+            starts.recv().unwrap();
+            let queued = request(&socket_path);
+            std::thread::sleep(Duration::from_millis(100));
+            // ...leave no room for another one.
+            assert!(read(request(&socket_path)).is_empty());
+            assert_eq!(stats.snapshot().shed, 1);
+
+            drop(release);
+            assert!(!read(answered).is_empty());
+            assert!(!read(queued).is_empty());
+            shutdown.request();
+            assert_eq!(server.join().unwrap().unwrap(), ShutdownReason::Requested);
+        });
+    }
+
+    #[test]
+    fn test_run_invalid_config() {
+        let config = Config {
+            socket_path: PathBuf::from("relative/socket"),
+            ..Config::default()
+        };
+        assert!(run(&test_logger(), config).is_err());
+    }
+
+    /// Fails every request the way a backend in trouble would.
+    struct BrokenBackend;
+
+    impl middleware::RequestMiddleware for BrokenBackend {
+        fn before(
+            &self,
+            _log: &slog::Logger,
+            _request: &protocol::Request,
+        ) -> Result<middleware::Action> {
+            Err(anyhow::Error::from(Errno::ETIMEDOUT).context("looking up user"))
+        }
+    }
+
+    #[test]
+    fn test_failover_to_secondary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secondary");
+        let listener = UnixListener::bind(&path).unwrap();
+        let secondary = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // the 12-byte header and "alice\0".
+            let mut buf = [0; 18];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(b"secondary answer").unwrap();
+        });
+
+        let mut config = Config {
+            secondary_socket: Some(path),
+            ..Config::default()
+        };
+        config.middleware.push(Arc::new(BrokenBackend));
+        let stats = Stats::new();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client
+            .write_all(
+                &protocol::Request::new(protocol::RequestType::GETPWBYNAME, b"alice\0").to_bytes(),
+            )
+            .unwrap();
+        handle_stream(&test_logger(), &config, None, &stats, server);
+        secondary.join().unwrap();
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"secondary answer");
+        assert_eq!(stats.snapshot().errors, 0);
+    }
+
+    #[test]
+    fn test_close_without_request() {
+        let stats = Stats::new();
+        let (log, records) = test_util::capture_logger();
+        let (client, server) = UnixStream::pair().unwrap();
+        drop(client);
+        handle_stream(&log, &Config::default(), None, &stats, server);
+
+        assert_eq!(stats.snapshot(), stats::StatsSnapshot::default());
+        assert!(records
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|record| !record.level.is_at_least(slog::Level::Warning)));
+
+        // a truncated header is still an error.
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(&protocol::VERSION.to_ne_bytes()).unwrap();
+        drop(client);
+        handle_stream(&log, &Config::default(), None, &stats, server);
+        assert_eq!(stats.snapshot().errors, 1);
+    }
+
+    #[test]
+    fn test_lastreq_rejected() {
+        let stats = Stats::new();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&protocol::VERSION.to_ne_bytes());
+        buf.extend_from_slice(&(protocol::RequestType::LASTREQ as i32).to_ne_bytes());
+        buf.extend_from_slice(&0i32.to_ne_bytes());
+        client.write_all(&buf).unwrap();
+        handle_stream(&test_logger(), &Config::default(), None, &stats, server);
+
+        // the connection is closed without a reply, like for any request we
+        // can't parse, and it counts as an error rather than a LASTREQ.
+        let mut response = vec![];
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.errors, 1);
+        assert!(snapshot.by_type.is_empty());
+    }
+
+    #[test]
+    fn test_wrong_version_rejected() {
+        let stats = Stats::new();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut request = protocol::Request::new(protocol::RequestType::GETPWBYNAME, b"alice\0");
+        request.version = protocol::VERSION - 1;
+        client.write_all(&request.to_bytes()).unwrap();
+        handle_stream(&test_logger(), &Config::default(), None, &stats, server);
+
+        // the key isn't looked up: the client gets no reply, and falls back
+        // to its own lookup.
+        let mut response = vec![];
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.errors, 1);
+        assert!(snapshot.by_type.is_empty());
+    }
+
+    #[test]
+    fn test_several_requests_per_connection() {
+        let invalidate = |database: &[u8]| {
+            protocol::Request::new(protocol::RequestType::INVALIDATE, database).to_bytes()
+        };
+        let stats = Stats::new();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let client = std::thread::spawn(move || {
+            // two requests sent at once...
+            client
+                .write_all(&[invalidate(b"passwd\0"), invalidate(b"group\0")].concat())
+                .unwrap();
+            let mut replies = [0; 8];
+            client.read_exact(&mut replies).unwrap();
+            assert_eq!(replies, [0; 8]);
+            // ...and one sent after reading their replies.
+            client.write_all(&invalidate(b"nosuchdb\0")).unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(i32::from_ne_bytes(reply), libc::EINVAL);
+
+            // then the connection is closed once we stop sending requests.
+            let mut rest = vec![];
+            client.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty());
+        });
+        handle_stream(&test_logger(), &Config::default(), None, &stats, server);
+        client.join().unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests_of(protocol::RequestType::INVALIDATE), 3);
+        assert_eq!(snapshot.errors, 0);
+    }
+
+    #[test]
+    fn test_large_batch_request() {
+        let uids = b"0\0".repeat(3000);
+        let request =
+            protocol::Request::new(protocol::RequestType::BATCHGETPWBYUID, &uids).to_bytes();
+        assert!(request.len() > REQUEST_BUFFER_LEN);
+        let stats = Stats::new();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let client = std::thread::spawn(move || {
+            client.write_all(&request).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = vec![];
+            client.read_to_end(&mut response).unwrap();
+            protocol::deserialize::user_batch(&response).unwrap()
+        });
+        handle_stream(&test_logger(), &Config::default(), None, &stats, server);
+        let users = client.join().unwrap();
+        assert_eq!(users.len(), 3000);
+        assert!(users
+            .iter()
+            .all(|user| user.as_ref().unwrap().name == "root"));
+        assert_eq!(stats.snapshot().errors, 0);
+
+        // but there's a limit.
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut header =
+            protocol::Request::new(protocol::RequestType::BATCHGETPWBYUID, b"").to_bytes();
+        header[8..12].copy_from_slice(&(MAX_REQUEST_LEN as i32).to_ne_bytes());
+        client.write_all(&header).unwrap();
+        handle_stream(&test_logger(), &Config::default(), None, &stats, server);
+        let mut response = vec![];
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+        assert_eq!(stats.snapshot().errors, 1);
+    }
+
+    #[test]
+    fn test_request_split_across_reads() {
+        let request =
+            protocol::Request::new(protocol::RequestType::INVALIDATE, b"passwd\0").to_bytes();
+        let stats = Stats::new();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let client = std::thread::spawn(move || {
+            // a request, with the start of the next one behind it...
+            client
+                .write_all(&[&request[..], &request[..5]].concat())
+                .unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).unwrap();
+            // ...the rest of which comes in bits.
+            for part in [&request[5..10], &request[10..]] {
+                std::thread::sleep(Duration::from_millis(50));
+                client.write_all(part).unwrap();
+            }
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(reply, [0; 4]);
+        });
+        handle_stream(&test_logger(), &Config::default(), None, &stats, server);
+        client.join().unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests_of(protocol::RequestType::INVALIDATE), 2);
+        assert_eq!(snapshot.errors, 0);
+    }
+
+    #[test]
+    fn test_panic_isolated() {
+        /// Panics on GETPWBYNAME requests for "boom".
+        struct Boom;
+
+        impl middleware::RequestMiddleware for Boom {
+            fn before(
+                &self,
+                _log: &slog::Logger,
+                request: &protocol::Request,
+            ) -> anyhow::Result<middleware::Action> {
+                if request.key == b"boom\0" {
+                    panic!("boom {}", 1);
+                }
+                Ok(middleware::Action::Continue)
+            }
+        }
+
+        let (log, records) = test_util::capture_logger();
+        let mut config = Config::default();
+        config.middleware.push(Arc::new(Boom));
+        let stats = Stats::new();
+        let request = |key: &[u8]| {
+            let (mut client, server) = UnixStream::pair().unwrap();
+            let request = protocol::Request::new(protocol::RequestType::GETPWBYNAME, key);
+            client.write_all(&request.to_bytes()).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            handle_stream(&log, &config, None, &stats, server);
+            let mut response = vec![];
+            client.read_to_end(&mut response).unwrap();
+            response
+        };
+
+        // the client gets no reply, and falls back to its own lookup...
+        assert!(request(b"boom\0").is_empty());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.panics, 1);
+        assert_eq!(snapshot.failed_of(protocol::RequestType::GETPWBYNAME), 1);
+        let panic = records
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|record| record.value("panic").map(str::to_string));
+        assert_eq!(panic.as_deref(), Some("boom 1"));
+
+        // ...and the next request is answered as usual.
+        assert!(!request(b"root\0").is_empty());
+    }
+
+    #[test]
+    fn test_getfd_closes_connection() {
+        let stats = Stats::new();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&protocol::VERSION.to_ne_bytes());
+        buf.extend_from_slice(&(protocol::RequestType::GETFDPW as i32).to_ne_bytes());
+        buf.extend_from_slice(&7i32.to_ne_bytes());
+        buf.extend_from_slice(b"passwd\0");
+        client.write_all(&buf).unwrap();
+        handle_stream(&test_logger(), &Config::default(), None, &stats, server);
+
+        // the client sees the connection closed with nothing on it, which
+        // glibc takes as "no mapping", and it isn't an error on our side.
+        let mut response = vec![];
+        client.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.errors, 0);
+        assert_eq!(snapshot.requests_of(protocol::RequestType::GETFDPW), 1);
+    }
+
+    #[test]
+    fn test_answered_without_warnings() {
+        let stats = Stats::new();
+        let (log, records) = test_util::capture_logger();
+        for _ in 0..3 {
+            send_request_logged(&log, &stats, protocol::RequestType::GETSTAT as i32, b"");
+        }
+        send_request_logged(
+            &log,
+            &stats,
+            protocol::RequestType::INNETGR as i32,
+            b"admins\0\x01host\0\0\0",
+        );
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.errors, 0);
+        // innetgr replies are "found", whether or not the triple is in the
+        // netgroup.
+        assert_eq!(snapshot.found_of(protocol::RequestType::INNETGR), 1);
+        // GETSTAT isn't a lookup.
+        assert_eq!(snapshot.found_of(protocol::RequestType::GETSTAT), 0);
+
+        let warnings = records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| record.level == slog::Level::Warning)
+            .count();
+        assert_eq!(warnings, 0);
+    }
+
+    #[test]
+    fn test_stats_snapshot() {
+        let stats = Stats::new();
+        let uid = nix::unistd::getuid().to_string();
+
+        send_request(
+            &stats,
+            protocol::RequestType::GETPWBYUID as i32,
+            &[uid.as_bytes(), b"\0"].concat(),
+        );
+        send_request(
+            &stats,
+            protocol::RequestType::GETPWBYUID as i32,
+            &[uid.as_bytes(), b"\0"].concat(),
+        );
+        send_request(&stats, protocol::RequestType::GETGRBYGID as i32, b"0\0");
+        // not NUL-terminated: parses, but fails in the handler.
+        send_request(&stats, protocol::RequestType::GETPWBYNAME as i32, b"root");
+        // not a request type at all.
+        send_request(&stats, 1_000_000, b"root\0");
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests, 5);
+        assert_eq!(snapshot.errors, 2);
+        assert_eq!(
+            snapshot.by_type,
+            vec![
+                (protocol::RequestType::GETPWBYNAME, 1),
+                (protocol::RequestType::GETPWBYUID, 2),
+                (protocol::RequestType::GETGRBYGID, 1),
+            ]
+        );
+        assert_eq!(snapshot.cache_hits, 0);
+        assert_eq!(snapshot.cache_misses, 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_notify_after_listening() {
+        let dir = tempfile::tempdir().unwrap();
+        let notify_path = dir.path().join("notify");
+        let socket_path = dir.path().join("nscd").join("socket");
+
+        let notify = UnixDatagram::bind(&notify_path).unwrap();
+        notify.set_nonblocking(true).unwrap();
+
+        temp_env::with_var("NOTIFY_SOCKET", Some(&notify_path), || {
+            let mut buf = [0; 64];
+            assert!(notify.recv(&mut buf).is_err(), "notified before listening");
+            assert!(UnixStream::connect(&socket_path).is_err());
+
+            let _listener =
+                start_listening(&test_logger(), &socket_path, Duration::from_secs(1)).unwrap();
+
+            let len = notify.recv(&mut buf).expect("should have notified");
+            assert_eq!(&buf[..len], b"READY=1\n");
+            UnixStream::connect(&socket_path).expect("socket should be listening");
+        });
+    }
+
+    #[test]
+    fn test_bind_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("socket");
+
+        // a socket nobody is listening on anymore is replaced.
+        drop(UnixListener::bind(&socket_path).unwrap());
+        let _listener = bind_socket(&socket_path).expect("should replace stale socket");
+        UnixStream::connect(&socket_path).expect("socket should be listening");
+    }
+
+    #[test]
+    fn test_bind_address_in_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("socket");
+
+        let _other = UnixListener::bind(&socket_path).unwrap();
+        let err = bind_socket(&socket_path).unwrap_err();
+        assert!(matches!(err, BindError::AddressInUse(_)), "{:?}", err);
+        assert_eq!(err.exit_code(), 75);
+        // and the other daemon's socket is left alone.
+        UnixStream::connect(&socket_path).expect("socket should still be listening");
+    }
+
+    #[test]
+    fn test_bind_unremovable_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("socket");
+
+        // a non-empty directory can't be removed with remove_file.
+        std::fs::create_dir(&socket_path).unwrap();
+        std::fs::write(socket_path.join("file"), b"").unwrap();
+        let err = bind_socket(&socket_path).unwrap_err();
+        assert!(matches!(err, BindError::StaleSocket(..)), "{:?}", err);
+        assert_eq!(err.exit_code(), 74);
+    }
+
+    #[test]
+    fn test_bind_permission_denied() {
+        if nix::unistd::geteuid().is_root() {
+            // root can write anywhere, so there's nothing to test.
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o500)).unwrap();
+        let err = bind_socket(&dir.path().join("socket")).unwrap_err();
+        assert!(matches!(err, BindError::PermissionDenied(_)), "{:?}", err);
+        assert_eq!(err.exit_code(), 77);
+    }
+
+    #[test]
+    fn test_bind_error_classification() {
+        let path = Path::new("/run/nscd");
+        let from_errno = |errno| BindError::from_io(path, std::io::Error::from_raw_os_error(errno));
+
+        assert!(matches!(
+            from_errno(libc::EACCES),
+            BindError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            from_errno(libc::EPERM),
+            BindError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            from_errno(libc::EROFS),
+            BindError::ReadOnlyFilesystem(_)
+        ));
+        assert!(matches!(
+            from_errno(libc::EADDRINUSE),
+            BindError::AddressInUse(_)
+        ));
+        assert!(matches!(from_errno(libc::ENOSPC), BindError::NoSpace(_)));
+        assert!(matches!(from_errno(libc::EDQUOT), BindError::NoSpace(_)));
+        assert!(matches!(from_errno(libc::EIO), BindError::Other(..)));
+        assert_eq!(from_errno(libc::EROFS).exit_code(), 73);
+        assert_eq!(from_errno(libc::ENOSPC).exit_code(), 72);
+        assert_eq!(from_errno(libc::EIO).exit_code(), 71);
+        assert!(from_errno(libc::ENOSPC)
+            .to_string()
+            .starts_with("no space left for /run/nscd"));
+    }
+
+    #[test]
+    fn test_start_listening_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        // the parent of the socket is a regular file, so binding can never
+        // succeed.
+        let parent = dir.path().join("file");
+        std::fs::write(&parent, b"").unwrap();
+        let socket_path = parent.join("socket");
+
+        let start = Instant::now();
+        let result = start_listening(&test_logger(), &socket_path, Duration::from_millis(300));
+        assert!(result.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(300));
     }
 }
@@ -0,0 +1,203 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Hooks run around request handling.
+//!
+//! A [Chain] of [RequestMiddleware] sees every request on its way to
+//! [handlers::handle_request], in the order they were added, and every
+//! response on its way back, in the reverse order. Each one can let the
+//! request through, rewrite its key, or answer it itself.
+//!
+//! nsncd is only a binary, so nothing but its own code can add to the chain,
+//! and for now only the tests do.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use slog::Logger;
+
+use super::config::Config;
+use super::handlers;
+use super::protocol::Request;
+
+/// What a middleware wants done with a request.
+pub enum Action {
+    /// Pass the request on unchanged.
+    Continue,
+    /// Pass the request on with this key instead. The key has to be in the
+    /// format the request type expects, e.g. NUL-terminated for names.
+    #[cfg(test)]
+    RewriteKey(Vec<u8>),
+    /// Don't handle the request: reply with this (serialized) response.
+    /// The middlewares that already saw the request still see the response.
+    #[cfg(test)]
+    Respond(Vec<u8>),
+}
+
+pub trait RequestMiddleware: Send + Sync {
+    /// Called before the request is handled.
+    fn before(&self, _log: &Logger, _request: &Request) -> Result<Action> {
+        Ok(Action::Continue)
+    }
+
+    /// Called with the response to the request (as it was passed to
+    /// `before`), which it may replace.
+    fn after(&self, _log: &Logger, _request: &Request, response: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(response)
+    }
+}
+
+/// An ordered list of middlewares. The empty chain just calls
+/// [handlers::handle_request].
+#[derive(Clone, Default)]
+pub struct Chain {
+    middlewares: Vec<Arc<dyn RequestMiddleware>>,
+}
+
+impl Chain {
+    /// Add a middleware at the end of the chain, closest to the handlers.
+    #[cfg(test)]
+    pub fn push(&mut self, middleware: Arc<dyn RequestMiddleware>) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Handle a request, running it through every middleware.
+    pub fn handle(&self, log: &Logger, config: &Config, request: &Request) -> Result<Vec<u8>> {
+        self.handle_from(0, log, config, request)
+    }
+
+    fn handle_from(
+        &self,
+        i: usize,
+        log: &Logger,
+        config: &Config,
+        request: &Request,
+    ) -> Result<Vec<u8>> {
+        let middleware = match self.middlewares.get(i) {
+            Some(middleware) => middleware,
+            None => return handlers::handle_request(log, config, request),
+        };
+        let response = match middleware.before(log, request)? {
+            Action::Continue => self.handle_from(i + 1, log, config, request)?,
+            #[cfg(test)]
+            Action::RewriteKey(key) => {
+                let rewritten = Request {
+                    key_len: key.len() as i32,
+                    key: &key,
+                    ..*request
+                };
+                self.handle_from(i + 1, log, config, &rewritten)?
+            }
+            #[cfg(test)]
+            Action::Respond(response) => response,
+        };
+        middleware.after(log, request, response)
+    }
+}
+
+impl std::fmt::Debug for Chain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Chain")
+            .field("middlewares", &self.middlewares.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::ffi::CString;
+    use std::sync::Mutex;
+
+    use nix::unistd::{getuid, User};
+
+    use crate::protocol::RequestType;
+
+    fn test_logger() -> Logger {
+        Logger::root(slog::Discard, slog::o!())
+    }
+
+    /// Maps an alias to the current user's name, and records what it saw.
+    struct Alias {
+        name: Vec<u8>,
+        seen: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl RequestMiddleware for Alias {
+        fn before(&self, _log: &Logger, request: &Request) -> Result<Action> {
+            self.seen.lock().unwrap().push(request.key.to_vec());
+            if request.ty == RequestType::GETPWBYNAME && request.key == b"me\0" {
+                Ok(Action::RewriteKey(self.name.clone()))
+            } else {
+                Ok(Action::Continue)
+            }
+        }
+    }
+
+    /// Answers everything with an empty response.
+    struct Deny;
+
+    impl RequestMiddleware for Deny {
+        fn before(&self, _log: &Logger, _request: &Request) -> Result<Action> {
+            Ok(Action::Respond(vec![]))
+        }
+    }
+
+    #[test]
+    fn test_rewrite_key() {
+        let current_user = User::from_uid(getuid()).unwrap().unwrap();
+        let name = CString::new(current_user.name)
+            .unwrap()
+            .into_bytes_with_nul();
+        let alias = Arc::new(Alias {
+            name: name.clone(),
+            seen: Mutex::new(vec![]),
+        });
+        let mut chain = Chain::default();
+        chain.push(alias.clone());
+
+        let config = Config::default();
+        let request = Request::new(RequestType::GETPWBYNAME, b"me\0");
+        let output = chain.handle(&test_logger(), &config, &request).unwrap();
+
+        let direct = Request::new(RequestType::GETPWBYNAME, &name);
+        let expected = handlers::handle_request(&test_logger(), &config, &direct).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(*alias.seen.lock().unwrap(), vec![b"me\0".to_vec()]);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let alias = Arc::new(Alias {
+            name: b"root\0".to_vec(),
+            seen: Mutex::new(vec![]),
+        });
+        let mut chain = Chain::default();
+        chain.push(Arc::new(Deny));
+        chain.push(alias.clone());
+
+        let request = Request::new(RequestType::GETPWBYNAME, b"me\0");
+        let output = chain
+            .handle(&test_logger(), &Config::default(), &request)
+            .unwrap();
+        assert!(output.is_empty());
+        assert!(
+            alias.seen.lock().unwrap().is_empty(),
+            "should not reach alias"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reading the cache tuning out of an `nscd.conf`.
+//!
+//! Hosts moving from nscd to nsncd usually have an `nscd.conf` tuned over
+//! the years. Rather than have operators translate it, nsncd can read the
+//! per-database settings it has an equivalent for: `enable-cache`,
+//! `positive-time-to-live`, `negative-time-to-live`, `check-files` and
+//! `max-db-size`, and the global `reload-count`. Everything else in the file
+//! is ignored.
+
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use super::cache::Policy;
+use super::protocol::DATABASES;
+
+/// nscd's TTLs for a cached database that doesn't set its own.
+const DEFAULT_POSITIVE_TTL: Duration = Duration::from_secs(3600);
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(20);
+/// nscd's `reload-count` if it isn't set.
+const DEFAULT_RELOAD_COUNT: u32 = 5;
+
+/// What an `nscd.conf` says about one database.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tuning {
+    pub enable_cache: Option<bool>,
+    pub positive_ttl: Option<Duration>,
+    pub negative_ttl: Option<Duration>,
+    pub check_files: Option<bool>,
+    pub max_db_size: Option<usize>,
+    /// The global `reload-count`, which applies to every database.
+    pub reload_count: Option<u32>,
+}
+
+impl Tuning {
+    /// Cache like nscd would: only if `enable-cache` is on, with nscd's
+    /// default TTLs unless they're set.
+    pub fn apply(&self, policy: &mut Policy) {
+        if self.enable_cache != Some(true) {
+            return;
+        }
+        policy.ttl = self.positive_ttl.unwrap_or(DEFAULT_POSITIVE_TTL);
+        policy.negative_ttl = self.negative_ttl.unwrap_or(DEFAULT_NEGATIVE_TTL);
+        policy.reload_count = self.reload_count.unwrap_or(DEFAULT_RELOAD_COUNT);
+        if let Some(max_db_size) = self.max_db_size {
+            policy.max_bytes = max_db_size;
+        }
+    }
+}
+
+/// The tuning of each of [DATABASES], in that order, from the `nscd.conf` at
+/// `path`.
+pub fn load(path: &Path) -> Result<[Tuning; DATABASES.len()]> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    parse(&text).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn parse(text: &str) -> Result<[Tuning; DATABASES.len()]> {
+    let mut tunings = [Tuning::default(); DATABASES.len()];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let line = number + 1;
+        let (directive, database, value) = match fields[..] {
+            [directive, database, value] => (directive, database, value),
+            ["reload-count", value] => {
+                let reload_count = match value {
+                    "unlimited" => u32::MAX,
+                    _ => parse_number(value, line)?.try_into().unwrap_or(u32::MAX),
+                };
+                for tuning in tunings.iter_mut() {
+                    tuning.reload_count = Some(reload_count);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        let tuning = match DATABASES.iter().position(|db| *db == database) {
+            Some(index) => &mut tunings[index],
+            // nscd ignores databases it doesn't know too.
+            None => continue,
+        };
+        match directive {
+            "enable-cache" => tuning.enable_cache = Some(parse_bool(value, line)?),
+            "positive-time-to-live" => tuning.positive_ttl = Some(parse_secs(value, line)?),
+            "negative-time-to-live" => tuning.negative_ttl = Some(parse_secs(value, line)?),
+            "check-files" => tuning.check_files = Some(parse_bool(value, line)?),
+            "max-db-size" => tuning.max_db_size = Some(parse_number(value, line)?),
+            _ => {}
+        }
+    }
+    Ok(tunings)
+}
+
+fn parse_bool(value: &str, line: usize) -> Result<bool> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => bail!("line {}: expected yes or no, got {:?}", line, value),
+    }
+}
+
+fn parse_number(value: &str, line: usize) -> Result<usize> {
+    value
+        .parse()
+        .with_context(|| format!("line {}: expected a number, got {:?}", line, value))
+}
+
+fn parse_secs(value: &str, line: usize) -> Result<Duration> {
+    Ok(Duration::from_secs(parse_number(value, line)? as u64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NSCD_CONF: &str = "
+# logfile /var/log/nscd.log
+	server-user		nscd
+	reload-count		2
+	enable-cache		passwd		yes
+	positive-time-to-live	passwd		600
+	negative-time-to-live	passwd		20
+	check-files		passwd		yes
+	persistent		passwd		yes
+	max-db-size		passwd		33554432
+	enable-cache		group		yes   # with nscd's TTLs
+	enable-cache		hosts		no
+	positive-time-to-live	hosts		3600
+	enable-cache		services	yes
+	positive-time-to-live	netgroup	28800
+";
+
+    #[test]
+    fn test_parse() {
+        let tunings = parse(NSCD_CONF).unwrap();
+        assert_eq!(
+            tunings[0],
+            Tuning {
+                enable_cache: Some(true),
+                positive_ttl: Some(Duration::from_secs(600)),
+                negative_ttl: Some(Duration::from_secs(20)),
+                check_files: Some(true),
+                max_db_size: Some(33554432),
+                reload_count: Some(2),
+            }
+        );
+        assert_eq!(tunings[2].enable_cache, Some(false));
+        assert_eq!(tunings[4].positive_ttl, Some(Duration::from_secs(28800)));
+
+        assert_eq!(
+            parse("reload-count unlimited").unwrap()[3].reload_count,
+            Some(u32::MAX)
+        );
+        assert!(parse("check-files passwd maybe").is_err());
+        assert!(parse("positive-time-to-live passwd 10m").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let tunings = parse(NSCD_CONF).unwrap();
+        let policy = |index: usize| {
+            let mut policy = Policy::default();
+            tunings[index].apply(&mut policy);
+            policy
+        };
+        let passwd = policy(0);
+        assert_eq!(passwd.ttl, Duration::from_secs(600));
+        assert_eq!(passwd.max_bytes, 33554432);
+        assert_eq!(passwd.reload_count, 2);
+        let group = policy(1);
+        assert_eq!(
+            (group.ttl, group.negative_ttl),
+            (DEFAULT_POSITIVE_TTL, DEFAULT_NEGATIVE_TTL)
+        );
+        assert_eq!(group.max_bytes, Policy::default().max_bytes);
+        // disabled, explicitly or not.
+        assert_eq!(policy(2), Policy::default());
+        assert_eq!(policy(4), Policy::default());
+    }
+}
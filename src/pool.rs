@@ -0,0 +1,175 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A pool of buffers shared by all the workers.
+//!
+//! Workers read requests into buffers checked out from the pool, and return
+//! them, along with the responses they sent, once a request is answered. The
+//! pool keeps a bounded number of buffers, and drops the ones that grew past
+//! a size cap, so one huge response doesn't stay allocated forever.
+//!
+//! A worker only needs a couple of buffers at a time (a request and its
+//! response), so each thread keeps the last few it gave back to itself, and
+//! only goes to the shared list, behind a lock, when it has none left.
+//!
+//! Buffers aren't sorted into size classes: any buffer does for any request,
+//! since it grows as needed, and the size of a response isn't known before
+//! it's written anyway.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// How many buffers each thread keeps for itself, in addition to the shared
+/// ones.
+const LOCAL_BUFFERS: usize = 2;
+
+/// Gives every pool a distinct id, so a thread using several pools never
+/// hands the buffers of one out from another.
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The buffers this thread keeps, tagged with the id of their pool.
+    static LOCAL: RefCell<Vec<(usize, Vec<u8>)>> = const { RefCell::new(Vec::new()) };
+}
+
+pub struct BufferPool {
+    id: usize,
+    max_buffers: usize,
+    max_capacity: usize,
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// Make a pool sharing at most `max_buffers` buffers of at most
+    /// `max_capacity` bytes each. Each thread using it may also keep a few
+    /// of its own.
+    pub fn new(max_buffers: usize, max_capacity: usize) -> Self {
+        Self {
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            max_buffers,
+            max_capacity,
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+        }
+    }
+
+    /// Take an empty buffer from the pool, this thread's first, or a new one
+    /// if it has none.
+    pub fn checkout(&self) -> Vec<u8> {
+        let local = LOCAL.with(|local| {
+            let mut local = local.borrow_mut();
+            let i = local.iter().rposition(|(id, _)| *id == self.id)?;
+            Some(local.swap_remove(i).1)
+        });
+        local.unwrap_or_else(|| self.buffers.lock().unwrap().pop().unwrap_or_default())
+    }
+
+    /// Return a buffer to the pool, keeping it in this thread if it has room.
+    /// It's dropped instead if it's larger than the cap, or if the pool is
+    /// full.
+    pub fn give_back(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > self.max_capacity {
+            return;
+        }
+        buf.clear();
+        // what this thread has no room for goes to the shared list.
+        let buf = LOCAL.with(|local| {
+            let mut local = local.borrow_mut();
+            if local.len() < LOCAL_BUFFERS {
+                local.push((self.id, buf));
+                return None;
+            }
+            Some(buf)
+        });
+        let buf = match buf {
+            Some(buf) => buf,
+            None => return,
+        };
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+}
+
+impl std::fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("max_buffers", &self.max_buffers)
+            .field("max_capacity", &self.max_capacity)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_checkout_and_give_back() {
+        let pool = BufferPool::new(2, 1024);
+        let mut buf = pool.checkout();
+        assert_eq!(buf.capacity(), 0);
+        buf.extend_from_slice(b"response");
+        let ptr = buf.as_ptr();
+        pool.give_back(buf);
+
+        // the same allocation comes back, emptied.
+        let buf = pool.checkout();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(pool.checkout().capacity() == 0);
+    }
+
+    #[test]
+    fn test_oversized_buffers_not_kept() {
+        let pool = BufferPool::new(2, 1024);
+        pool.give_back(Vec::with_capacity(1 << 20));
+        assert_eq!(pool.checkout().capacity(), 0);
+
+        pool.give_back(Vec::with_capacity(1024));
+        assert_eq!(pool.checkout().capacity(), 1024);
+    }
+
+    #[test]
+    fn test_pool_size_capped() {
+        let pool = BufferPool::new(2, 1024);
+        for _ in 0..LOCAL_BUFFERS + 3 {
+            pool.give_back(Vec::with_capacity(16));
+        }
+        assert_eq!(pool.buffers.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_local_buffers() {
+        let pool = Arc::new(BufferPool::new(2, 1024));
+        pool.give_back(Vec::with_capacity(16));
+        // kept by this thread, so the shared list is still empty...
+        assert!(pool.buffers.lock().unwrap().is_empty());
+        // ...and other threads don't get it.
+        let other = pool.clone();
+        let capacity = std::thread::spawn(move || other.checkout().capacity());
+        assert_eq!(capacity.join().unwrap(), 0);
+        assert_eq!(pool.checkout().capacity(), 16);
+
+        // nor do other pools.
+        pool.give_back(Vec::with_capacity(16));
+        assert_eq!(BufferPool::new(2, 1024).checkout().capacity(), 0);
+        assert_eq!(pool.checkout().capacity(), 16);
+    }
+}
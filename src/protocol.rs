@@ -19,31 +19,68 @@
 //! The response structs here only describe the format of the header of the
 //! response. For each such response, if the lookup succeeded, there are
 //! additional strings we need to send after the header. Those are dealt with in
-//! `handlers::send_{user,group}`. For a full picture of the protocol, you will
-//! need to read both.
+//! `handlers::write_{user,group}` and the other `write_*` functions, which
+//! `handlers::Response::write` calls to serialize each kind of answer into a
+//! pooled buffer. For a full picture of the protocol, you will need to read
+//! both. [deserialize] parses responses back, as a client would.
 
+use std::convert::{TryFrom, TryInto};
+use std::ffi::CStr;
 use std::mem::size_of;
-use std::{convert::TryInto, net::IpAddr};
+use std::net::IpAddr;
 
 use anyhow::{ensure, Context, Result};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::FromPrimitive;
+use static_assertions::const_assert_eq;
 
-use nix::libc::{c_int, gid_t, uid_t};
+use nix::libc::{c_int, c_long, c_uint, c_ulong, gid_t, time_t, uid_t};
+use nix::unistd::Uid;
+
+pub mod deserialize;
 
 /// This is version 2 of the glibc nscd protocol. The version is passed as part
 /// of each message header.
 pub const VERSION: i32 = 2;
 
+/// The error [Request::parse] returns for a request of another protocol
+/// version than [VERSION]. Nothing after the version can be trusted to mean
+/// what it does in ours, so the request isn't parsed any further.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WrongVersion(pub i32);
+
+impl std::fmt::Display for WrongVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "wrong protocol version {} (expected {})",
+            self.0, VERSION
+        )
+    }
+}
+
+impl std::error::Error for WrongVersion {}
+
 /// Errors used in {Ai,Hst}ResponseHeader structs.
 /// See NSCD's resolv/netdb.h for the complete list.
 pub const H_ERRNO_NETDB_SUCCESS: i32 = 0;
-#[allow(dead_code)]
+pub const H_ERRNO_HOST_NOT_FOUND: i32 = 1; // Authoritative Answer Host not found
 pub const H_ERRNO_TRY_AGAIN: i32 = 2; // Non-Authoritative Host not found
+pub const H_ERRNO_NO_DATA: i32 = 4; // Valid name, no data record of requested type
+
+/// Request type codes at or above this value are nsncd extensions, which glibc
+/// never sends. The value spells out "ns" in its upper half so it can't be
+/// mistaken for (or collide with) a standard nscd request code.
+pub const EXTENSION_BASE: i32 = 0x6e73_0000;
 
 /// Available services. This enum describes all service types the nscd protocol
-/// knows about, though we only implement `GETPW*`, `GETGR*`, and `INITGROUPS`.
-#[derive(Clone, Copy, Debug, FromPrimitive, ToPrimitive)]
+/// knows about. We handle all of them, but turn down the `GETFD*` requests,
+/// which ask for a shared memory database we don't keep, with no reply: the
+/// client then sends the lookups themselves instead.
+///
+/// Variants after `LASTREQ` are nsncd extensions, numbered from
+/// [EXTENSION_BASE].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum RequestType {
     GETPWBYNAME,
@@ -72,41 +109,301 @@ pub enum RequestType {
     INNETGR,
     GETFDNETGR,
     LASTREQ,
+    /// nsncd extension: look up several uids at once. The key is a sequence
+    /// of NUL-terminated uid strings, and the reply is a
+    /// [BatchResponseHeader] followed by one `GETPWBYUID` reply per uid.
+    BATCHGETPWBYUID = EXTENSION_BASE as isize,
+    /// nsncd extension: look up a user in the shadow database. The key is the
+    /// NUL-terminated user name, and the reply is a [SpResponseHeader]
+    /// followed by the name and the password hash. Only answered for root,
+    /// and only if enabled.
+    GETSPBYNAME,
+    /// nsncd extension: look up several user names at once, like
+    /// `BATCHGETPWBYUID`. The key is a sequence of NUL-terminated names, and
+    /// the reply is a [BatchResponseHeader] followed by one `GETPWBYNAME`
+    /// reply per name.
+    BATCHGETPWBYNAME,
+}
+
+/// All the nsncd extension request types.
+pub const EXTENSIONS: &[RequestType] = &[
+    RequestType::BATCHGETPWBYUID,
+    RequestType::GETSPBYNAME,
+    RequestType::BATCHGETPWBYNAME,
+];
+
+/// The number of distinct values [RequestType::index] can return.
+pub const INDEX_COUNT: usize = RequestType::LASTREQ as usize + 1 + EXTENSIONS.len();
+
+impl RequestType {
+    /// Whether this is an nsncd extension rather than a standard nscd request.
+    pub fn is_extension(&self) -> bool {
+        *self as i32 >= EXTENSION_BASE
+    }
+
+    /// A dense index for this request type, for per-type tables. Extensions
+    /// are numbered from `EXTENSION_BASE`, so they're packed in right after
+    /// `LASTREQ`.
+    pub fn index(&self) -> usize {
+        if self.is_extension() {
+            RequestType::LASTREQ as usize + 1 + (*self as i32 - EXTENSION_BASE) as usize
+        } else {
+            *self as usize
+        }
+    }
+
+    /// How many NUL-terminated strings the key of this request type is made
+    /// of, or `None` if it's not made of strings alone (e.g. the binary
+    /// address of `GETHOSTBYADDR`) or has a variable number of them.
+    pub fn key_field_count(&self) -> Option<usize> {
+        use RequestType::*;
+        match self {
+            SHUTDOWN | GETSTAT => Some(0),
+            GETPWBYNAME | GETPWBYUID | GETGRBYNAME | GETGRBYGID | GETHOSTBYNAME
+            | GETHOSTBYNAMEv6 | INVALIDATE | GETFDPW | GETFDGR | GETFDHST | GETAI | GETFDSERV
+            | GETNETGRENT | GETFDNETGR | GETSPBYNAME | INITGROUPS => Some(1),
+            // name (or port) and protocol, in a single "name/proto" string.
+            GETSERVBYNAME | GETSERVBYPORT => Some(1),
+            // netgroup, host, user and domain. the last three start with a
+            // '\x01' byte, unless they're wildcards, which are empty.
+            INNETGR => Some(4),
+            GETHOSTBYADDR | GETHOSTBYADDRv6 | LASTREQ | BATCHGETPWBYUID | BATCHGETPWBYNAME => None,
+        }
+    }
+
+    /// Whether the reply to a request of this type depends on who sent it,
+    /// e.g. because only root may see shadow entries. Such replies must
+    /// never be served to another client.
+    pub fn depends_on_peer(&self) -> bool {
+        matches!(self, RequestType::GETSPBYNAME)
+    }
+
+    /// The index in [DATABASES] of the database nscd counts requests of
+    /// this type under, if they're lookups nscd counts at all.
+    pub fn stat_database(&self) -> Option<usize> {
+        use RequestType::*;
+        match self {
+            GETPWBYNAME | GETPWBYUID => Some(0),
+            GETGRBYNAME | GETGRBYGID | INITGROUPS => Some(1),
+            GETHOSTBYNAME | GETHOSTBYNAMEv6 | GETHOSTBYADDR | GETHOSTBYADDRv6 | GETAI => Some(2),
+            GETSERVBYNAME | GETSERVBYPORT => Some(3),
+            GETNETGRENT | INNETGR => Some(4),
+            // not lookups, or (BATCHGETPWBY*) several of them at once, or
+            // (GETSPBYNAME) of a database nscd doesn't have.
+            SHUTDOWN | GETSTAT | INVALIDATE | GETFDPW | GETFDGR | GETFDHST | GETFDSERV
+            | GETFDNETGR | LASTREQ | BATCHGETPWBYUID | GETSPBYNAME | BATCHGETPWBYNAME => None,
+        }
+    }
+
+    /// All the request types we know about, standard ones first.
+    pub fn all() -> impl Iterator<Item = RequestType> {
+        (0..RequestType::LASTREQ as i32)
+            .map(|i| FromPrimitive::from_i32(i).unwrap())
+            .chain(EXTENSIONS.iter().copied())
+    }
 }
 
 /// An incoming request. All requests have a version, a type, and a string key.
-/// This struct keeps the type and key, because that's what we need to reply to
-/// it, we only handle one version and we validate, but don't retain it.
+///
+/// The request header (glibc's `request_header`) is just the version, the
+/// type and the key length; there are no flags. In particular, a client that
+/// only wants a file descriptor for the shared database says so with one of
+/// the `GETFD*` request types, which we answer without doing any lookup.
 ///
 /// The parsed Request object is valid as long as the buffer it is parsed from
 /// (that is, the key is a reference to the bytes in the buffer).
-#[derive(Debug)]
 pub struct Request<'a> {
+    /// The protocol version the client speaks.
+    pub version: i32,
     pub ty: RequestType,
+    /// The key length, as sent in the request header. This is the length of
+    /// `key`, including its terminating NUL if it has one.
+    pub key_len: i32,
     pub key: &'a [u8],
+    /// The uid of the client, from the peer credentials of its connection.
+    /// It's not part of what the client sends, so it's `None` until whoever
+    /// has the connection fills it in.
+    pub peer_uid: Option<Uid>,
 }
 
 impl<'a> Request<'a> {
+    /// Build a request of the current protocol version.
+    pub fn new(ty: RequestType, key: &'a [u8]) -> Self {
+        Request {
+            version: VERSION,
+            ty,
+            key_len: key.len() as i32,
+            key,
+            peer_uid: None,
+        }
+    }
+
+    /// Serialize the request, as a client would send it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12 + self.key.len());
+        buf.extend_from_slice(&self.version.to_ne_bytes());
+        buf.extend_from_slice(&(self.ty as i32).to_ne_bytes());
+        buf.extend_from_slice(&self.key_len.to_ne_bytes());
+        buf.extend_from_slice(self.key);
+        buf
+    }
+
     /// Parse a Request from a buffer.
     pub fn parse(buf: &'a [u8]) -> Result<Self> {
         ensure!(buf.len() >= 12, "request body too small: {}", buf.len());
 
         let version = buf[0..4].try_into().map(i32::from_ne_bytes)?;
-        ensure!(version == VERSION, "wrong protocol version {}", version);
+        ensure!(version == VERSION, WrongVersion(version));
 
         let type_val = buf[4..8].try_into().map(i32::from_ne_bytes)?;
         let ty = FromPrimitive::from_i32(type_val)
             .with_context(|| format!("invalid enum value {}", type_val))?;
+        // LASTREQ only marks the end of the standard request types, and codes
+        // above it (short of the extensions) don't parse at all.
+        ensure!(
+            ty != RequestType::LASTREQ,
+            "invalid request type {}",
+            type_val
+        );
 
         let key_len = buf[8..12].try_into().map(i32::from_ne_bytes)?;
         let key_end = (12 + key_len).try_into()?;
         ensure!(buf.len() >= key_end, "request body too small");
 
         Ok(Request {
+            version,
             ty,
+            key_len,
             key: &buf[12..key_end],
+            peer_uid: None,
         })
     }
+
+    /// Check that the key has exactly the shape glibc's client gives keys of
+    /// this request type. The handlers are more lenient (e.g. a uid is read
+    /// up to the first non-digit), so this is only enforced in strict mode.
+    pub fn check_key(&self) -> Result<()> {
+        use RequestType::*;
+        match self.ty {
+            GETPWBYUID | GETGRBYGID => check_id_field(self.key),
+            BATCHGETPWBYUID => {
+                let fields = split_key(self.key)?;
+                ensure!(!fields.is_empty(), "empty batch");
+                fields
+                    .iter()
+                    .try_for_each(|field| check_id_field(field.to_bytes_with_nul()))
+            }
+            BATCHGETPWBYNAME => {
+                ensure!(!split_key(self.key)?.is_empty(), "empty batch");
+                Ok(())
+            }
+            GETHOSTBYADDR | GETHOSTBYADDRv6 => {
+                let expected = if self.ty == GETHOSTBYADDR { 4 } else { 16 };
+                ensure!(
+                    self.key.len() == expected,
+                    "address is {} bytes long, expected {}",
+                    self.key.len(),
+                    expected
+                );
+                Ok(())
+            }
+            _ => self.key_fields().map(|_| ()),
+        }
+    }
+
+    /// How many bytes of the buffer it was parsed from the request takes up.
+    /// Anything after that is the start of the client's next request.
+    pub fn wire_len(&self) -> usize {
+        12 + self.key.len()
+    }
+
+    /// Split the key into its NUL-terminated fields, checking that there are
+    /// as many as the request type calls for.
+    pub fn key_fields(&self) -> Result<Vec<&'a CStr>> {
+        let expected = self
+            .ty
+            .key_field_count()
+            .with_context(|| format!("{:?} keys aren't made of strings", self.ty))?;
+        let fields = split_key(self.key)?;
+        ensure!(
+            fields.len() == expected,
+            "expected {} key fields for {:?}, got {}",
+            expected,
+            self.ty,
+            fields.len()
+        );
+        Ok(fields)
+    }
+}
+
+/// How long the request at the start of `buf` is, header and key, if `buf`
+/// holds enough of it to tell. It may be longer than `buf`.
+pub fn request_len(buf: &[u8]) -> Option<usize> {
+    let key_len = i32::from_ne_bytes(buf.get(8..12)?.try_into().ok()?);
+    Some(12 + usize::try_from(key_len).ok()?)
+}
+
+// The key comes from an untrusted client, so it's escaped: a key with
+// newlines or terminal escapes in it must not be able to forge log lines.
+impl std::fmt::Debug for Request<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Request")
+            .field("version", &self.version)
+            .field("ty", &self.ty)
+            .field("key_len", &self.key_len)
+            .field("key", &format_args!("\"{}\"", EscapedKey(self.key)))
+            .field("peer_uid", &self.peer_uid)
+            .finish()
+    }
+}
+
+/// Displays a key (or any bytes from a client) so that it's safe to log:
+/// printable ASCII is written as is, except for `\` and `"`, which are
+/// backslash-escaped, and every other byte is written as `\xNN`.
+pub struct EscapedKey<'a>(pub &'a [u8]);
+
+impl std::fmt::Display for EscapedKey<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for &b in self.0 {
+            match b {
+                b'\\' | b'"' => write!(f, "\\{}", b as char)?,
+                b' '..=b'~' => write!(f, "{}", b as char)?,
+                _ => write!(f, "\\x{:02x}", b)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that a NUL-terminated key field is a uid or gid: decimal digits
+/// only, and small enough to be one.
+fn check_id_field(field: &[u8]) -> Result<()> {
+    let digits = field
+        .strip_suffix(&[0])
+        .context("id is not NUL-terminated")?;
+    ensure!(
+        !digits.is_empty() && digits.iter().all(u8::is_ascii_digit),
+        "id \"{}\" is not a decimal number",
+        EscapedKey(digits)
+    );
+    std::str::from_utf8(digits)?
+        .parse::<uid_t>()
+        .with_context(|| format!("id {} is out of range", EscapedKey(digits)))?;
+    Ok(())
+}
+
+/// Split a key made of NUL-terminated strings into those strings.
+///
+/// Every field has to be terminated, the last one included: a key that
+/// doesn't end with a NUL was cut short, and its last field can't be trusted.
+pub fn split_key(key: &[u8]) -> Result<Vec<&CStr>> {
+    ensure!(
+        key.is_empty() || key.ends_with(&[0]),
+        "key is not NUL-terminated"
+    );
+    key.split_inclusive(|b| *b == 0)
+        .map(|field| CStr::from_bytes_with_nul(field).map_err(Into::into))
+        .collect()
 }
 
 // the nscd protocol just puts structs onto a socket and hopes they come out
@@ -115,6 +412,25 @@ impl<'a> Request<'a> {
 //
 // this is pretty sketchy, but we have to match it, so all of the structs
 // below use repr(C) and not repr(padded).
+//
+// there's a single layout to match: glibc bumps the protocol VERSION (which
+// clients check) rather than change a header, and these haven't changed
+// since version 2. the sizes are pinned below so they can't drift either.
+// if a glibc ever changes one, that will come with a new version number, and
+// the place to handle it is where we check the request's version.
+
+const_assert_eq!(size_of::<PwResponseHeader>(), 9 * 4);
+const_assert_eq!(size_of::<GrResponseHeader>(), 6 * 4);
+const_assert_eq!(size_of::<InitgroupsResponseHeader>(), 3 * 4);
+const_assert_eq!(size_of::<AiResponseHeader>(), 6 * 4);
+const_assert_eq!(size_of::<HstResponseHeader>(), 8 * 4);
+const_assert_eq!(size_of::<ServResponseHeader>(), 6 * 4);
+const_assert_eq!(size_of::<NetgroupResponseHeader>(), 4 * 4);
+const_assert_eq!(size_of::<InnetgroupResponseHeader>(), 3 * 4);
+#[cfg(target_pointer_width = "64")]
+const_assert_eq!(size_of::<StatResponse>(), 792);
+#[cfg(target_pointer_width = "64")]
+const_assert_eq!(size_of::<SpResponseHeader>(), 4 * 4 + 7 * 8);
 
 /// Structure sent in reply to password query.  Note that this struct is
 /// sent also if the service is disabled or there is no record found.
@@ -143,6 +459,37 @@ impl PwResponseHeader {
     }
 }
 
+/// Structure sent in reply to a [RequestType::GETSPBYNAME] query, an nsncd
+/// extension, laid out like `PwResponseHeader` with the fields of
+/// `struct spwd`. Note that this struct is sent also if there is no record
+/// found.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SpResponseHeader {
+    pub version: c_int,
+    pub found: c_int,
+    pub sp_namp_len: c_int,
+    pub sp_pwdp_len: c_int,
+    pub sp_lstchg: c_long,
+    pub sp_min: c_long,
+    pub sp_max: c_long,
+    pub sp_warn: c_long,
+    pub sp_inact: c_long,
+    pub sp_expire: c_long,
+    pub sp_flag: c_ulong,
+}
+
+impl SpResponseHeader {
+    /// Serialize the header to bytes.
+    ///
+    /// The C implementations of nscd just take the address of the struct, so
+    /// we will too, to make it easy to convince ourselves it's correct.
+    pub fn as_slice(&self) -> &[u8] {
+        let p = self as *const _ as *const u8;
+        unsafe { std::slice::from_raw_parts(p, size_of::<Self>()) }
+    }
+}
+
 /// Structure sent in reply to group query.  Note that this struct is
 /// sent also if the service is disabled or there is no record found.
 #[repr(C)]
@@ -237,6 +584,164 @@ pub const AI_RESPONSE_HEADER_NOT_FOUND: AiResponseHeader = AiResponseHeader {
     error: 0,
 };
 
+/// Reply header of a getservbyname/getservbyport request. Maps to the
+/// serv_response_header struct in nscd.
+///
+/// It's followed by the NUL-terminated name and protocol, the length of each
+/// alias (as `u32`s), and the NUL-terminated aliases.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ServResponseHeader {
+    pub version: c_int,
+    pub found: c_int,
+    pub s_name_len: c_int,
+    pub s_proto_len: c_int,
+    pub s_aliases_cnt: c_int,
+    /// The port, in network byte order, like in `struct servent`.
+    pub s_port: c_int,
+}
+
+impl ServResponseHeader {
+    /// Serialize the header to bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        let p = self as *const _ as *const u8;
+        unsafe { std::slice::from_raw_parts(p, size_of::<Self>()) }
+    }
+}
+
+/// Services header returned to the client when there's no such service. See
+/// glibc's `nscd/servicescache.c` file for the original definition.
+pub const SERV_RESPONSE_HEADER_NOT_FOUND: ServResponseHeader = ServResponseHeader {
+    version: VERSION,
+    found: 0,
+    s_name_len: 0,
+    s_proto_len: 0,
+    s_aliases_cnt: 0,
+    s_port: -1,
+};
+
+/// Reply header of a getnetgrent request (really a setnetgrent: the client
+/// gets every member at once). Maps to the netgroup_response_header struct in
+/// nscd.
+///
+/// It's followed by `result_len` bytes: the host, user and domain of each
+/// member, as NUL-terminated strings, empty if the field is a wildcard.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct NetgroupResponseHeader {
+    pub version: c_int,
+    pub found: c_int,
+    pub nresults: c_int,
+    pub result_len: c_int,
+}
+
+impl NetgroupResponseHeader {
+    /// Serialize the header to bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        let p = self as *const _ as *const u8;
+        unsafe { std::slice::from_raw_parts(p, size_of::<Self>()) }
+    }
+}
+
+/// Reply to an innetgr request. Maps to the innetgroup_response_header
+/// struct in nscd.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct InnetgroupResponseHeader {
+    pub version: c_int,
+    pub found: c_int,
+    /// 1 if the triple is in the netgroup, 0 otherwise.
+    pub result: c_int,
+}
+
+impl InnetgroupResponseHeader {
+    /// Serialize the header to bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        let p = self as *const _ as *const u8;
+        unsafe { std::slice::from_raw_parts(p, size_of::<Self>()) }
+    }
+}
+
+/// Whether a reply to a lookup of type `ty` carries an entry: every reply
+/// header starts with the version and a `found` field. `None` if `ty` isn't a
+/// lookup nscd counts, or if the reply says the database is disabled.
+pub fn reply_found(ty: RequestType, reply: &[u8]) -> Option<bool> {
+    ty.stat_database()?;
+    match i32::from_ne_bytes(reply.get(4..8)?.try_into().ok()?) {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+/// The databases nscd serves, by the names `nscd -i` takes, in the order of
+/// a GETSTAT reply.
+pub const DATABASES: [&str; 5] = ["passwd", "group", "hosts", "services", "netgroup"];
+
+/// Reply to a GETSTAT request, as read by `nscd -g`. Maps to the statdata
+/// struct in nscd's `nscd_stat.c`, as laid out on 64-bit Linux with nscd built
+/// with SELinux support (as distributions do).
+///
+/// `nscd -g` only accepts the reply if `version` is the build date and time
+/// of its own binary. Padding is spelled out, so every byte is initialized.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StatResponse {
+    /// `__DATE__ " " __TIME__` of the nscd build, NUL-terminated.
+    pub version: [u8; 21],
+    pub _pad0: [u8; 3],
+    pub debug_level: c_int,
+    pub _pad1: [u8; 4],
+    /// Seconds since the daemon started.
+    pub runtime: time_t,
+    pub client_queued: c_ulong,
+    pub nthreads: c_int,
+    pub max_nthreads: c_int,
+    pub paranoia: c_int,
+    pub _pad2: [u8; 4],
+    pub restart_interval: time_t,
+    pub reload_count: c_uint,
+    pub ndbs: c_int,
+    pub dbs: [DbStat; DATABASES.len()],
+    /// SELinux access vector cache statistics (avc_cache_stats).
+    pub avc_cache_stats: [c_uint; 8],
+}
+
+impl StatResponse {
+    /// Serialize the response to bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        let p = self as *const _ as *const u8;
+        unsafe { std::slice::from_raw_parts(p, size_of::<Self>()) }
+    }
+}
+
+/// The statistics of one database in a [StatResponse]. Maps to the dbstat
+/// struct in nscd. The sizes are `size_t`s in C.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DbStat {
+    pub enabled: c_int,
+    pub check_file: c_int,
+    pub shared: c_int,
+    pub persistent: c_int,
+    pub module: usize,
+    pub postimeout: c_ulong,
+    pub negtimeout: c_ulong,
+    pub nentries: usize,
+    pub maxnentries: usize,
+    pub maxnsearched: usize,
+    pub datasize: usize,
+    pub dataused: usize,
+    /// Cache hits and misses, for lookups with and without a result.
+    pub poshit: u64,
+    pub neghit: u64,
+    pub posmiss: u64,
+    pub negmiss: u64,
+    pub rdlockdelayed: u64,
+    pub wrlockdelayed: u64,
+    pub addfailed: u64,
+}
+
 /// Structure used to hold the reply header of a
 /// gethostbyaddr[v6]/gethostbyname[v6] request.
 /// Maps to the hst_response_header struct in nscd.
@@ -264,10 +769,128 @@ impl HstResponseHeader {
     }
 }
 
+/// Structure sent in reply to an nsncd batch query (e.g.
+/// [RequestType::BATCHGETPWBYUID]). It is followed by `nentries` replies, each
+/// formatted exactly like the reply to the equivalent single-key request.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct BatchResponseHeader {
+    pub version: c_int,
+    pub nentries: c_int,
+}
+
+impl BatchResponseHeader {
+    /// Serialize the header to bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        let p = self as *const _ as *const u8;
+        unsafe { std::slice::from_raw_parts(p, size_of::<Self>()) }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_split_key() {
+        assert!(split_key(b"").unwrap().is_empty());
+        assert_eq!(split_key(b"alice\0").unwrap(), vec![cstr(b"alice\0")]);
+        assert_eq!(
+            split_key(b"\0host\0").unwrap(),
+            vec![cstr(b"\0"), cstr(b"host\0")]
+        );
+        assert!(split_key(b"alice").is_err());
+        assert!(split_key(b"http\0tcp").is_err());
+    }
+
+    #[test]
+    fn test_escaped_key() {
+        let escaped = EscapedKey(b"al\x1b[31mice\n\\\"\xff\0").to_string();
+        assert_eq!(escaped, r#"al\x1b[31mice\x0a\\\"\xff\x00"#);
+        let request = Request::new(RequestType::GETPWBYNAME, b"root\nfake\0");
+        let debug = format!("{:?}", request);
+        assert!(debug.contains(r#"key: "root\x0afake\x00""#), "{}", debug);
+    }
+
+    #[test]
+    fn test_key_fields() {
+        let request = Request::new(RequestType::GETSERVBYNAME, b"http/tcp\0");
+        assert_eq!(request.key_fields().unwrap(), vec![cstr(b"http/tcp\0")]);
+
+        let request = Request::new(
+            RequestType::INNETGR,
+            b"admins\0\x01host\0\0\x01example.com\0",
+        );
+        assert_eq!(
+            request.key_fields().unwrap(),
+            vec![
+                cstr(b"admins\0"),
+                cstr(b"\x01host\0"),
+                cstr(b"\0"),
+                cstr(b"\x01example.com\0")
+            ]
+        );
+
+        // missing the trailing NUL.
+        let request = Request::new(RequestType::INNETGR, b"admins\0host\0\0example.com");
+        assert!(request.key_fields().is_err());
+        // wrong number of fields.
+        let request = Request::new(RequestType::GETSERVBYNAME, b"http\0tcp\0");
+        assert!(request.key_fields().is_err());
+        // not strings at all.
+        let request = Request::new(RequestType::GETHOSTBYADDR, &[127, 0, 0, 1]);
+        assert!(request.key_fields().is_err());
+    }
+
+    #[test]
+    fn test_check_key() {
+        let ok = |ty, key: &[u8]| Request::new(ty, key).check_key().is_ok();
+        assert!(ok(RequestType::GETPWBYUID, b"1000\0"));
+        assert!(!ok(RequestType::GETPWBYUID, b"1000"));
+        assert!(!ok(RequestType::GETPWBYUID, b"\0"));
+        assert!(!ok(RequestType::GETGRBYGID, b"10x\0"));
+        assert!(!ok(RequestType::GETGRBYGID, b"-1\0"));
+        assert!(!ok(RequestType::GETGRBYGID, b"4294967296\0"));
+        assert!(ok(RequestType::BATCHGETPWBYUID, b"0\x001000\0"));
+        assert!(!ok(RequestType::BATCHGETPWBYUID, b"0\0\0"));
+        assert!(!ok(RequestType::BATCHGETPWBYUID, b""));
+        assert!(ok(RequestType::BATCHGETPWBYNAME, b"alice\0bob\0"));
+        assert!(!ok(RequestType::BATCHGETPWBYNAME, b"alice\0bob"));
+        assert!(!ok(RequestType::BATCHGETPWBYNAME, b""));
+
+        assert!(ok(RequestType::GETHOSTBYADDR, &[127, 0, 0, 1]));
+        assert!(!ok(RequestType::GETHOSTBYADDR, &[127, 0, 0, 1, 0]));
+        assert!(ok(RequestType::GETHOSTBYADDRv6, &[0; 16]));
+        assert!(!ok(RequestType::GETHOSTBYADDRv6, &[0; 4]));
+
+        assert!(ok(RequestType::INITGROUPS, b"alice\0"));
+        assert!(!ok(RequestType::INITGROUPS, b"alice\0\x64\0\0\0"));
+        assert!(!ok(RequestType::INITGROUPS, b"alice"));
+
+        assert!(ok(RequestType::GETPWBYNAME, b"alice\0"));
+        assert!(!ok(RequestType::GETPWBYNAME, b"alice"));
+        assert!(!ok(RequestType::GETPWBYNAME, b"alice\0bob\0"));
+        assert!(ok(RequestType::GETSTAT, b""));
+        assert!(!ok(RequestType::GETSTAT, b"junk\0"));
+    }
+
+    #[test]
+    fn test_reply_found() {
+        let reply = |found: i32| [VERSION.to_ne_bytes(), found.to_ne_bytes()].concat();
+        assert_eq!(reply_found(RequestType::GETPWBYUID, &reply(1)), Some(true));
+        assert_eq!(reply_found(RequestType::GETAI, &reply(0)), Some(false));
+        // disabled.
+        assert_eq!(reply_found(RequestType::GETPWBYUID, &reply(-1)), None);
+        // not a lookup.
+        assert_eq!(reply_found(RequestType::GETSTAT, &reply(1)), None);
+        // no header at all, e.g. an ignored request.
+        assert_eq!(reply_found(RequestType::GETPWBYUID, &[]), None);
+    }
+
+    fn cstr(bytes: &[u8]) -> &CStr {
+        CStr::from_bytes_with_nul(bytes).unwrap()
+    }
+
     #[test]
     fn test_pw_response_header_as_slice() {
         let header = PwResponseHeader {
@@ -339,4 +962,88 @@ mod test {
 
         assert_eq!(header.as_slice(), expected);
     }
+
+    #[test]
+    fn batch_response_header_as_slice() {
+        let header = BatchResponseHeader {
+            version: VERSION,
+            nentries: 3,
+        };
+
+        let mut expected = Vec::with_capacity(4 * 2);
+        {
+            expected.extend_from_slice(&VERSION.to_ne_bytes());
+            expected.extend_from_slice(&3i32.to_ne_bytes());
+        }
+
+        assert_eq!(header.as_slice(), expected);
+    }
+
+    #[test]
+    fn test_parse_lastreq() {
+        for ty in [RequestType::LASTREQ as i32, RequestType::LASTREQ as i32 + 1].iter() {
+            let mut buf = vec![];
+            buf.extend_from_slice(&VERSION.to_ne_bytes());
+            buf.extend_from_slice(&ty.to_ne_bytes());
+            buf.extend_from_slice(&0i32.to_ne_bytes());
+            assert!(Request::parse(&buf).is_err(), "type {}", ty);
+        }
+    }
+
+    #[test]
+    fn test_parse_extension_request() {
+        let mut buf = vec![];
+        buf.extend_from_slice(&VERSION.to_ne_bytes());
+        buf.extend_from_slice(&EXTENSION_BASE.to_ne_bytes());
+        buf.extend_from_slice(&4i32.to_ne_bytes());
+        buf.extend_from_slice(b"0\x001\x00");
+
+        let request = Request::parse(&buf).unwrap();
+        assert_eq!(request.version, VERSION);
+        assert_eq!(request.key_len, 4);
+        assert!(matches!(request.ty, RequestType::BATCHGETPWBYUID));
+        assert!(request.ty.is_extension());
+        assert!(!RequestType::GETPWBYUID.is_extension());
+        assert_eq!(request.key, b"0\x001\x00");
+        assert_eq!(request.to_bytes(), buf);
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let mut buf = vec![];
+        buf.extend_from_slice(&VERSION.to_ne_bytes());
+        buf.extend_from_slice(&(RequestType::GETPWBYNAME as i32).to_ne_bytes());
+        buf.extend_from_slice(&6i32.to_ne_bytes());
+        buf.extend_from_slice(b"alice\0");
+
+        let request = Request::parse(&buf).unwrap();
+        assert_eq!(request.version, VERSION);
+        assert!(matches!(request.ty, RequestType::GETPWBYNAME));
+        assert_eq!(request.key_len, 6);
+        assert_eq!(request.key, b"alice\0");
+
+        // trailing bytes beyond the key are not part of it.
+        buf.extend_from_slice(b"junk");
+        let request = Request::parse(&buf).unwrap();
+        assert_eq!(request.key_len, 6);
+        assert_eq!(request.key, b"alice\0");
+
+        assert_eq!(request.wire_len(), 18);
+        assert_eq!(request_len(&buf), Some(18));
+        assert_eq!(request_len(&buf[..11]), None);
+
+        // a key that's longer than the buffer is an error.
+        assert_eq!(request_len(&buf[..14]), Some(18));
+        assert!(Request::parse(&buf[..14]).is_err());
+        assert!(Request::parse(&buf[..8]).is_err());
+    }
+
+    #[test]
+    fn test_parse_wrong_version() {
+        let mut request = Request::new(RequestType::GETPWBYNAME, b"alice\0");
+        request.version = VERSION + 1;
+        let err = Request::parse(&request.to_bytes()).err().unwrap();
+        assert_eq!(err.downcast_ref(), Some(&WrongVersion(VERSION + 1)));
+        assert_eq!(err.to_string(), "wrong protocol version 3 (expected 2)");
+    }
 }
@@ -0,0 +1,305 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Parsing responses back into structured types: the client's side of the
+//! protocol.
+//!
+//! Each function takes a whole response, as read from the socket, and fails
+//! if it's truncated, inconsistent, or followed by more bytes.
+
+use std::convert::TryInto;
+use std::ffi::{CStr, OsStr};
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use anyhow::{bail, ensure, Context, Result};
+use nix::unistd::{Gid, Group, Uid, User};
+
+use super::{
+    BatchResponseHeader, GrResponseHeader, InitgroupsResponseHeader, PwResponseHeader, VERSION,
+};
+
+/// A response header that can be read straight out of the bytes of a
+/// response, like the C clients do.
+///
+/// # Safety
+///
+/// Only for `repr(C)` structs of integers, which any bytes are a valid value
+/// of.
+unsafe trait Header: Copy {}
+
+unsafe impl Header for PwResponseHeader {}
+unsafe impl Header for GrResponseHeader {}
+unsafe impl Header for InitgroupsResponseHeader {}
+unsafe impl Header for BatchResponseHeader {}
+
+/// The bytes of a response not parsed yet.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(
+            len <= self.0.len(),
+            "response truncated: expected {} more bytes, got {}",
+            len,
+            self.0.len()
+        );
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn header<T: Header>(&mut self) -> Result<T> {
+        let bytes = self.bytes(size_of::<T>())?;
+        // Safe thanks to the `Header` contract; the bytes needn't be aligned.
+        Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+    }
+
+    fn int(&mut self) -> Result<i32> {
+        Ok(i32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// A NUL-terminated string, `len` bytes long with its NUL.
+    fn string(&mut self, len: i32) -> Result<&'a CStr> {
+        let len = len.try_into().context("negative string length")?;
+        Ok(CStr::from_bytes_with_nul(self.bytes(len)?)?)
+    }
+
+    fn finish(self) -> Result<()> {
+        ensure!(
+            self.0.is_empty(),
+            "{} trailing bytes after response",
+            self.0.len()
+        );
+        Ok(())
+    }
+}
+
+/// Check the header fields every response starts with. A "not found" reply
+/// may have a zero version, so it's only checked when `found` is 1.
+fn check_found(version: i32, found: i32) -> Result<bool> {
+    match found {
+        1 => {
+            ensure!(version == VERSION, "wrong protocol version {}", version);
+            Ok(true)
+        }
+        0 | -1 => Ok(false),
+        _ => bail!("invalid found value {}", found),
+    }
+}
+
+fn read_user(reader: &mut Reader) -> Result<Option<User>> {
+    let header: PwResponseHeader = reader.header()?;
+    if !check_found(header.version, header.found)? {
+        return Ok(None);
+    }
+    let name = reader.string(header.pw_name_len)?;
+    let passwd = reader.string(header.pw_passwd_len)?;
+    let gecos = reader.string(header.pw_gecos_len)?;
+    let dir = reader.string(header.pw_dir_len)?;
+    let shell = reader.string(header.pw_shell_len)?;
+    Ok(Some(User {
+        name: name.to_str()?.to_string(),
+        passwd: passwd.to_owned(),
+        uid: Uid::from_raw(header.pw_uid),
+        gid: Gid::from_raw(header.pw_gid),
+        gecos: gecos.to_owned(),
+        dir: PathBuf::from(OsStr::from_bytes(dir.to_bytes())),
+        shell: PathBuf::from(OsStr::from_bytes(shell.to_bytes())),
+    }))
+}
+
+/// Parse the reply to a GETPWBYNAME or GETPWBYUID request.
+pub fn user(response: &[u8]) -> Result<Option<User>> {
+    let mut reader = Reader(response);
+    let user = read_user(&mut reader)?;
+    reader.finish()?;
+    Ok(user)
+}
+
+/// Parse the reply to a BATCHGETPWBYUID or BATCHGETPWBYNAME request.
+pub fn user_batch(response: &[u8]) -> Result<Vec<Option<User>>> {
+    let mut reader = Reader(response);
+    let header: BatchResponseHeader = reader.header()?;
+    ensure!(
+        header.version == VERSION,
+        "wrong protocol version {}",
+        header.version
+    );
+    let users = (0..header.nentries)
+        .map(|_| read_user(&mut reader))
+        .collect::<Result<Vec<_>>>()?;
+    reader.finish()?;
+    Ok(users)
+}
+
+/// Parse the reply to a GETGRBYNAME or GETGRBYGID request.
+pub fn group(response: &[u8]) -> Result<Option<Group>> {
+    let mut reader = Reader(response);
+    let header: GrResponseHeader = reader.header()?;
+    if !check_found(header.version, header.found)? {
+        reader.finish()?;
+        return Ok(None);
+    }
+    // The member lengths come first, then the strings.
+    let lens = (0..header.gr_mem_cnt)
+        .map(|_| reader.int())
+        .collect::<Result<Vec<_>>>()?;
+    let name = reader.string(header.gr_name_len)?;
+    let passwd = reader.string(header.gr_passwd_len)?;
+    let mem = lens
+        .into_iter()
+        .map(|len| Ok(reader.string(len)?.to_str()?.to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    reader.finish()?;
+    Ok(Some(Group {
+        name: name.to_str()?.to_string(),
+        passwd: passwd.to_owned(),
+        gid: Gid::from_raw(header.gr_gid),
+        mem,
+    }))
+}
+
+/// Parse the reply to an INITGROUPS request.
+pub fn initgroups(response: &[u8]) -> Result<Vec<Gid>> {
+    let mut reader = Reader(response);
+    let header: InitgroupsResponseHeader = reader.header()?;
+    check_found(header.version, header.found)?;
+    let groups = (0..header.ngrps)
+        .map(|_| Ok(Gid::from_raw(reader.int()? as u32)))
+        .collect::<Result<Vec<_>>>()?;
+    reader.finish()?;
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pw_response(name: &[u8]) -> Vec<u8> {
+        let header = PwResponseHeader {
+            version: VERSION,
+            found: 1,
+            pw_name_len: name.len() as i32,
+            pw_passwd_len: 2,
+            pw_uid: 1000,
+            pw_gid: 100,
+            pw_gecos_len: 1,
+            pw_dir_len: 12,
+            pw_shell_len: 8,
+        };
+        let mut response = header.as_slice().to_vec();
+        response.extend_from_slice(name);
+        response.extend_from_slice(b"x\0\0/home/alice\0/bin/sh\0");
+        response
+    }
+
+    #[test]
+    fn test_user() {
+        let user = user(&pw_response(b"alice\0")).unwrap().unwrap();
+        assert_eq!(user.name, "alice");
+        assert_eq!(user.passwd.as_bytes(), b"x");
+        assert_eq!(user.uid, Uid::from_raw(1000));
+        assert_eq!(user.gid, Gid::from_raw(100));
+        assert!(user.gecos.as_bytes().is_empty());
+        assert_eq!(user.dir, PathBuf::from("/home/alice"));
+        assert_eq!(user.shell, PathBuf::from("/bin/sh"));
+
+        assert_eq!(
+            super::user(PwResponseHeader::default().as_slice()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_user_malformed() {
+        let response = pw_response(b"alice\0");
+        assert!(user(&response[..response.len() - 1]).is_err());
+        let mut trailing = response.clone();
+        trailing.push(0);
+        assert!(user(&trailing).is_err());
+        // the name's NUL isn't where its length says.
+        assert!(user(&pw_response(b"ali\0ce")).is_err());
+
+        let mut header = PwResponseHeader {
+            version: 1,
+            found: 1,
+            ..Default::default()
+        };
+        assert!(user(header.as_slice()).is_err());
+        header.version = VERSION;
+        header.pw_name_len = -1;
+        assert!(user(header.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_user_batch() {
+        let header = BatchResponseHeader {
+            version: VERSION,
+            nentries: 2,
+        };
+        let mut response = header.as_slice().to_vec();
+        response.extend_from_slice(PwResponseHeader::default().as_slice());
+        response.extend_from_slice(&pw_response(b"alice\0"));
+        let users = user_batch(&response).unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users[0].is_none());
+        assert_eq!(users[1].as_ref().unwrap().name, "alice");
+    }
+
+    #[test]
+    fn test_group() {
+        let header = GrResponseHeader {
+            version: VERSION,
+            found: 1,
+            gr_name_len: 6,
+            gr_passwd_len: 2,
+            gr_gid: 100,
+            gr_mem_cnt: 2,
+        };
+        let mut response = header.as_slice().to_vec();
+        response.extend_from_slice(&6i32.to_ne_bytes());
+        response.extend_from_slice(&4i32.to_ne_bytes());
+        response.extend_from_slice(b"staff\0x\0alice\0bob\0");
+        let group = group(&response).unwrap().unwrap();
+        assert_eq!(group.name, "staff");
+        assert_eq!(group.gid, Gid::from_raw(100));
+        assert_eq!(group.mem, vec!["alice", "bob"]);
+
+        assert_eq!(
+            super::group(GrResponseHeader::default().as_slice()).unwrap(),
+            None
+        );
+        assert!(super::group(&response[..response.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_initgroups() {
+        let header = InitgroupsResponseHeader {
+            version: VERSION,
+            found: 1,
+            ngrps: 2,
+        };
+        let mut response = header.as_slice().to_vec();
+        response.extend_from_slice(&100i32.to_ne_bytes());
+        response.extend_from_slice(&4321i32.to_ne_bytes());
+        assert_eq!(
+            initgroups(&response).unwrap(),
+            vec![Gid::from_raw(100), Gid::from_raw(4321)]
+        );
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The queue of requests waiting for a worker.
+//!
+//! Host lookups can take seconds (DNS timeouts), while passwd and group
+//! lookups usually take microseconds. With a single FIFO, a burst of slow
+//! host lookups makes every passwd lookup behind it wait. So requests are
+//! queued by [Class], and workers serve the fast ones first: `ps`, `ls` and
+//! sudo shouldn't wait on DNS. They still take a slow one every
+//! [FAST_STREAK] fast ones, so that a steady stream of fast requests can't
+//! hold host lookups back until they time out waiting for a worker.
+//!
+//! Serving some first doesn't help once every worker is stuck on a hung DNS or
+//! LDAP server, though. Each [Pool] of requests can have workers of its own
+//! instead, so an outage of one backend only ties up its own.
+
+use crossbeam_channel as channel;
+
+use super::protocol::RequestType;
+
+/// How many fast items are taken in a row, while slow ones are waiting,
+/// before a slow one is.
+pub const FAST_STREAK: u32 = 8;
+
+/// How expensive a request is to answer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// passwd, group and everything else answered from local state or a
+    /// (usually) nearby directory.
+    Fast,
+    /// Host lookups, which may wait on DNS.
+    Slow,
+}
+
+impl Class {
+    pub fn of(ty: RequestType) -> Self {
+        match ty {
+            RequestType::GETHOSTBYNAME
+            | RequestType::GETHOSTBYNAMEv6
+            | RequestType::GETHOSTBYADDR
+            | RequestType::GETHOSTBYADDRv6
+            | RequestType::GETAI => Class::Slow,
+            _ => Class::Fast,
+        }
+    }
+}
+
+/// Which workers answer a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pool {
+    /// passwd, group, and everything that isn't in another pool.
+    Default,
+    /// Host lookups, which depend on DNS.
+    Hosts,
+    /// Netgroup lookups, which usually depend on LDAP or NIS.
+    Netgroup,
+}
+
+impl Pool {
+    pub fn of(ty: RequestType) -> Self {
+        match ty {
+            RequestType::GETHOSTBYNAME
+            | RequestType::GETHOSTBYNAMEv6
+            | RequestType::GETHOSTBYADDR
+            | RequestType::GETHOSTBYADDRv6
+            | RequestType::GETAI => Pool::Hosts,
+            RequestType::GETNETGRENT | RequestType::INNETGR => Pool::Netgroup,
+            _ => Pool::Default,
+        }
+    }
+}
+
+/// How many fast items a worker took in a row while slow ones were waiting.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Streak(u32);
+
+/// A bounded queue per [Class]. Clones share the same queues.
+pub struct PriorityQueue<T> {
+    fast: (channel::Sender<T>, channel::Receiver<T>),
+    slow: (channel::Sender<T>, channel::Receiver<T>),
+}
+
+impl<T> PriorityQueue<T> {
+    /// Make a queue holding up to `capacity` items of each class.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            fast: channel::bounded(capacity),
+            slow: channel::bounded(capacity),
+        }
+    }
+
+    /// Queue an item, or hand it back if its class's queue is full.
+    pub fn try_push(&self, class: Class, item: T) -> Result<(), T> {
+        let (tx, _) = self.queue(class);
+        tx.try_send(item).map_err(|e| e.into_inner())
+    }
+
+    /// Take an item without waiting: a fast one if there is one, unless
+    /// `streak` says it's a slow one's turn.
+    pub fn try_pop(&self, streak: &mut Streak) -> Option<T> {
+        let order = if streak.0 >= FAST_STREAK {
+            [Class::Slow, Class::Fast]
+        } else {
+            [Class::Fast, Class::Slow]
+        };
+        for class in order {
+            if let Ok(item) = self.receiver(class).try_recv() {
+                streak.0 = match class {
+                    Class::Fast if !self.slow.1.is_empty() => streak.0 + 1,
+                    _ => 0,
+                };
+                return Some(item);
+            }
+        }
+        None
+    }
+
+    /// The receiving end of a class's queue, to wait on with `select!`.
+    pub fn receiver(&self, class: Class) -> &channel::Receiver<T> {
+        &self.queue(class).1
+    }
+
+    fn queue(&self, class: Class) -> &(channel::Sender<T>, channel::Receiver<T>) {
+        match class {
+            Class::Fast => &self.fast,
+            Class::Slow => &self.slow,
+        }
+    }
+}
+
+// not derived: that would require `T: Clone`.
+impl<T> Clone for PriorityQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            fast: self.fast.clone(),
+            slow: self.slow.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_class_of() {
+        assert_eq!(Class::of(RequestType::GETPWBYNAME), Class::Fast);
+        assert_eq!(Class::of(RequestType::INITGROUPS), Class::Fast);
+        assert_eq!(Class::of(RequestType::GETAI), Class::Slow);
+        assert_eq!(Class::of(RequestType::GETHOSTBYADDRv6), Class::Slow);
+    }
+
+    #[test]
+    fn test_pool_of() {
+        assert_eq!(Pool::of(RequestType::GETPWBYUID), Pool::Default);
+        assert_eq!(Pool::of(RequestType::INITGROUPS), Pool::Default);
+        assert_eq!(Pool::of(RequestType::GETSTAT), Pool::Default);
+        assert_eq!(Pool::of(RequestType::GETAI), Pool::Hosts);
+        assert_eq!(Pool::of(RequestType::INNETGR), Pool::Netgroup);
+    }
+
+    #[test]
+    fn test_fast_requests_not_stuck_behind_slow_ones() {
+        let queue = PriorityQueue::new(100);
+        for i in 0..50 {
+            queue.try_push(Class::Slow, format!("slow{}", i)).unwrap();
+        }
+        for i in 0..3 {
+            queue.try_push(Class::Fast, format!("fast{}", i)).unwrap();
+        }
+
+        let mut streak = Streak::default();
+        let order: Vec<String> = std::iter::from_fn(|| queue.try_pop(&mut streak)).collect();
+        assert_eq!(order.len(), 53);
+        // the fast ones are served first, not after the 50 slow ones queued
+        // before them...
+        assert_eq!(&order[..4], &["fast0", "fast1", "fast2", "slow0"]);
+        // ...and each class is still served in order.
+        assert_eq!(order[52], "slow49");
+    }
+
+    #[test]
+    fn test_slow_requests_not_starved() {
+        let queue = PriorityQueue::new(100);
+        for i in 0..2 {
+            queue.try_push(Class::Slow, format!("slow{}", i)).unwrap();
+        }
+        for i in 0..20 {
+            queue.try_push(Class::Fast, format!("fast{}", i)).unwrap();
+        }
+
+        let mut streak = Streak::default();
+        let order: Vec<String> = std::iter::from_fn(|| queue.try_pop(&mut streak)).collect();
+        let slow: Vec<usize> = (0..order.len())
+            .filter(|&i| order[i].starts_with("slow"))
+            .collect();
+        let streak = FAST_STREAK as usize;
+        assert_eq!(slow, vec![streak, 2 * streak + 1]);
+    }
+
+    #[test]
+    fn test_try_push_full() {
+        let queue = PriorityQueue::new(1);
+        queue.try_push(Class::Slow, 1).unwrap();
+        assert_eq!(queue.try_push(Class::Slow, 2), Err(2));
+        // the other class has room of its own.
+        queue.try_push(Class::Fast, 3).unwrap();
+    }
+}
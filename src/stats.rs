@@ -0,0 +1,381 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Counters describing what nsncd has been doing.
+//!
+//! A single [Stats] is shared by all the workers. The counters are plain
+//! atomics, so bumping one never blocks a worker; a [StatsSnapshot] is a
+//! copy of all of them at one point in time. They're also sent, in nscd's
+//! format, in reply to GETSTAT requests (`nscd -g`).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::protocol::{self, RequestType};
+
+/// How often to warn about requests or connections turned away while we're
+/// overloaded.
+pub const SHED_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Stats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    by_type: Vec<AtomicU64>,
+    /// Answered requests, by type and by whether the entry was found.
+    found: Vec<AtomicU64>,
+    not_found: Vec<AtomicU64>,
+    /// Requests we failed to answer, by type.
+    failed: Vec<AtomicU64>,
+    /// Lookups answered from the response cache, by type and by whether
+    /// the entry was found.
+    hits: Vec<AtomicU64>,
+    negative_hits: Vec<AtomicU64>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    duplicate_uids: AtomicU64,
+    oversized_groups: AtomicU64,
+    lookup_timeouts: AtomicU64,
+    stuck_workers: AtomicU64,
+    panics: AtomicU64,
+    shed: AtomicU64,
+    /// When we last warned about turning requests away.
+    shed_warned: Mutex<Option<Instant>>,
+    refused: AtomicU64,
+    /// When we last warned about turning connections away.
+    refused_warned: Mutex<Option<Instant>>,
+    started: Instant,
+}
+
+/// A point-in-time copy of the counters in [Stats].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Requests received, including the ones we failed to parse.
+    pub requests: u64,
+    /// Requests we couldn't parse or answer.
+    pub errors: u64,
+    /// Parsed requests, by type. Types with no requests are left out.
+    pub by_type: Vec<(RequestType, u64)>,
+    /// Lookups answered with an entry, by type, like `by_type`.
+    pub found: Vec<(RequestType, u64)>,
+    /// Lookups answered with "not found", by type, like `by_type`.
+    pub not_found: Vec<(RequestType, u64)>,
+    /// Requests we failed to answer, by type, like `by_type`. Unlike
+    /// `errors`, this doesn't count the requests we couldn't parse.
+    pub failed: Vec<(RequestType, u64)>,
+    /// Lookups answered from the response cache with an entry, by type,
+    /// like `by_type`. They're also counted in `found`.
+    pub hits: Vec<(RequestType, u64)>,
+    /// Lookups answered from the response cache with "not found", by type,
+    /// like `by_type`. They're also counted in `not_found`.
+    pub negative_hits: Vec<(RequestType, u64)>,
+    /// Lookups answered from the response cache.
+    pub cache_hits: u64,
+    /// Lookups the response cache could have answered, but didn't.
+    pub cache_misses: u64,
+    /// uid lookups answered for a uid that several users share.
+    pub duplicate_uids: u64,
+    /// Group lookups answered for a group with more members than allowed.
+    pub oversized_groups: u64,
+    /// Backend lookups we stopped waiting for.
+    pub lookup_timeouts: u64,
+    /// Requests a worker was stuck on for longer than the configured
+    /// threshold.
+    pub stuck_workers: u64,
+    /// Requests whose handling panicked. They're also counted as failed.
+    pub panics: u64,
+    /// Requests turned away unanswered because too many were waiting for a
+    /// worker.
+    pub shed: u64,
+    /// Connections closed as soon as they were accepted because too many
+    /// were open.
+    pub refused: u64,
+}
+
+impl StatsSnapshot {
+    /// The number of requests of type `ty`.
+    #[cfg(test)]
+    pub fn requests_of(&self, ty: RequestType) -> u64 {
+        count_of(&self.by_type, ty)
+    }
+
+    /// The number of lookups of type `ty` answered with an entry.
+    pub fn found_of(&self, ty: RequestType) -> u64 {
+        count_of(&self.found, ty)
+    }
+
+    /// The number of lookups of type `ty` answered with "not found".
+    pub fn not_found_of(&self, ty: RequestType) -> u64 {
+        count_of(&self.not_found, ty)
+    }
+
+    /// The number of requests of type `ty` we failed to answer.
+    #[cfg(test)]
+    pub fn failed_of(&self, ty: RequestType) -> u64 {
+        count_of(&self.failed, ty)
+    }
+
+    /// The number of lookups of type `ty` answered from the response cache
+    /// with an entry.
+    pub fn hits_of(&self, ty: RequestType) -> u64 {
+        count_of(&self.hits, ty)
+    }
+
+    /// The number of lookups of type `ty` answered from the response cache
+    /// with "not found".
+    pub fn negative_hits_of(&self, ty: RequestType) -> u64 {
+        count_of(&self.negative_hits, ty)
+    }
+}
+
+fn count_of(counts: &[(RequestType, u64)], ty: RequestType) -> u64 {
+    counts.iter().find(|(t, _)| *t == ty).map_or(0, |(_, n)| *n)
+}
+
+/// Load every per-type counter that isn't zero.
+fn load_by_type(counters: &[AtomicU64]) -> Vec<(RequestType, u64)> {
+    RequestType::all()
+        .map(|ty| (ty, counters[ty.index()].load(Ordering::Relaxed)))
+        .filter(|(_, n)| *n > 0)
+        .collect()
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            by_type: (0..protocol::INDEX_COUNT)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            found: (0..protocol::INDEX_COUNT)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            not_found: (0..protocol::INDEX_COUNT)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            failed: (0..protocol::INDEX_COUNT)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            hits: (0..protocol::INDEX_COUNT)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            negative_hits: (0..protocol::INDEX_COUNT)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            duplicate_uids: AtomicU64::new(0),
+            oversized_groups: AtomicU64::new(0),
+            lookup_timeouts: AtomicU64::new(0),
+            stuck_workers: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+            shed_warned: Mutex::new(None),
+            refused: AtomicU64::new(0),
+            refused_warned: Mutex::new(None),
+            started: Instant::now(),
+        }
+    }
+
+    /// How long ago these counters started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Count a request we received but couldn't parse.
+    pub fn record_unparsed(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a parsed request.
+    pub fn record_request(&self, ty: RequestType) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.by_type[ty.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a lookup we answered, with an entry if `found`.
+    pub fn record_answer(&self, ty: RequestType, found: bool) {
+        let counters = if found { &self.found } else { &self.not_found };
+        counters[ty.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a parsed request we failed to answer.
+    pub fn record_error(&self, ty: RequestType) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.failed[ty.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a lookup answered from the response cache, with an entry if
+    /// `found`.
+    pub fn record_cache_hit(&self, ty: RequestType, found: bool) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        let counters = if found {
+            &self.hits
+        } else {
+            &self.negative_hits
+        };
+        counters[ty.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a lookup the response cache couldn't answer.
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a uid lookup for a uid that several users share.
+    pub fn record_duplicate_uid(&self) {
+        self.duplicate_uids.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a group lookup for a group with more members than allowed.
+    pub fn record_oversized_group(&self) {
+        self.oversized_groups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a backend lookup we stopped waiting for.
+    pub fn record_lookup_timeout(&self) {
+        self.lookup_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request a worker got stuck on.
+    pub fn record_stuck_worker(&self) {
+        self.stuck_workers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request whose handling panicked.
+    pub fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request turned away because the queue was full.
+    ///
+    /// Returns how many we've turned away if it's time to warn about it,
+    /// at most once every [SHED_WARNING_INTERVAL].
+    pub fn record_shed(&self, now: Instant) -> Option<u64> {
+        let count = self.shed.fetch_add(1, Ordering::Relaxed) + 1;
+        warning_due(&self.shed_warned, now).then_some(count)
+    }
+
+    /// Count a connection closed because too many were open, and return how
+    /// many we've closed if it's time to warn about it, like
+    /// [Stats::record_shed].
+    pub fn record_refused(&self, now: Instant) -> Option<u64> {
+        let count = self.refused.fetch_add(1, Ordering::Relaxed) + 1;
+        warning_due(&self.refused_warned, now).then_some(count)
+    }
+
+    /// Copy the current value of every counter.
+    ///
+    /// Each counter is read atomically, but they aren't read all at once, so
+    /// a snapshot taken while requests are in flight may e.g. count a request
+    /// in `requests` and not yet in `by_type`.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            by_type: load_by_type(&self.by_type),
+            found: load_by_type(&self.found),
+            not_found: load_by_type(&self.not_found),
+            failed: load_by_type(&self.failed),
+            hits: load_by_type(&self.hits),
+            negative_hits: load_by_type(&self.negative_hits),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            duplicate_uids: self.duplicate_uids.load(Ordering::Relaxed),
+            oversized_groups: self.oversized_groups.load(Ordering::Relaxed),
+            lookup_timeouts: self.lookup_timeouts.load(Ordering::Relaxed),
+            stuck_workers: self.stuck_workers.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+            shed: self.shed.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether it's been [SHED_WARNING_INTERVAL] since `warned`, and if so, note
+/// that we're warning `now`.
+fn warning_due(warned: &Mutex<Option<Instant>>, now: Instant) -> bool {
+    let mut warned = warned.lock().unwrap();
+    match *warned {
+        Some(at) if now.saturating_duration_since(at) < SHED_WARNING_INTERVAL => false,
+        _ => {
+            *warned = Some(now);
+            true
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let stats = Stats::new();
+        assert_eq!(stats.snapshot(), StatsSnapshot::default());
+
+        stats.record_request(RequestType::GETPWBYNAME);
+        stats.record_request(RequestType::GETPWBYNAME);
+        stats.record_request(RequestType::BATCHGETPWBYUID);
+        stats.record_error(RequestType::BATCHGETPWBYUID);
+        stats.record_unparsed();
+        stats.record_answer(RequestType::GETPWBYNAME, true);
+        stats.record_answer(RequestType::GETPWBYNAME, false);
+        stats.record_answer(RequestType::GETPWBYNAME, false);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests, 4);
+        assert_eq!(snapshot.errors, 2);
+        assert_eq!(snapshot.requests_of(RequestType::GETPWBYNAME), 2);
+        assert_eq!(snapshot.requests_of(RequestType::BATCHGETPWBYUID), 1);
+        assert_eq!(snapshot.requests_of(RequestType::GETGRBYGID), 0);
+        assert_eq!(snapshot.by_type.len(), 2);
+        assert_eq!(snapshot.found_of(RequestType::GETPWBYNAME), 1);
+        assert_eq!(snapshot.not_found_of(RequestType::GETPWBYNAME), 2);
+        assert_eq!(snapshot.failed_of(RequestType::BATCHGETPWBYUID), 1);
+        // parse failures have no type.
+        assert_eq!(snapshot.failed.len(), 1);
+    }
+
+    #[test]
+    fn test_record_shed() {
+        let stats = Stats::new();
+        let start = Instant::now();
+        assert_eq!(stats.record_shed(start), Some(1));
+        assert_eq!(stats.record_shed(start), None);
+        assert_eq!(stats.record_shed(start + SHED_WARNING_INTERVAL), Some(3));
+        assert_eq!(stats.snapshot().shed, 3);
+
+        // warned about on their own schedule.
+        assert_eq!(stats.record_refused(start), Some(1));
+        assert_eq!(stats.record_refused(start), None);
+        assert_eq!(stats.snapshot().refused, 2);
+    }
+}
@@ -0,0 +1,107 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Helpers shared by the tests of several modules.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use slog::{Drain, Key, Level, Logger, OwnedKVList, Record, Serializer, KV};
+
+/// A log record, as seen by [capture_logger].
+#[derive(Clone, Debug)]
+pub struct CapturedRecord {
+    pub level: Level,
+    pub msg: String,
+    /// The record's key-value pairs, followed by the logger's, formatted with
+    /// `Display`.
+    pub kvs: Vec<(String, String)>,
+}
+
+impl CapturedRecord {
+    /// The value of the first pair with this key.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.kvs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub type Captured = Arc<Mutex<Vec<CapturedRecord>>>;
+
+/// A logger that keeps every record it's given, synchronously and in order,
+/// so tests can check what was logged.
+pub fn capture_logger() -> (Logger, Captured) {
+    let records = Captured::default();
+    let logger = Logger::root(Capture(records.clone()), slog::o!());
+    (logger, records)
+}
+
+struct Capture(Captured);
+
+impl Drain for Capture {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), slog::Never> {
+        let mut kvs = KvCollector(vec![]);
+        // neither can fail: the collector's serializer never returns an error.
+        let _ = record.kv().serialize(record, &mut kvs);
+        let _ = values.serialize(record, &mut kvs);
+        self.0.lock().unwrap().push(CapturedRecord {
+            level: record.level(),
+            msg: record.msg().to_string(),
+            kvs: kvs.0,
+        });
+        Ok(())
+    }
+}
+
+struct KvCollector(Vec<(String, String)>);
+
+impl Serializer for KvCollector {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.push((key.to_string(), val.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use slog::{info, o, warn};
+
+    #[test]
+    fn test_capture_logger() {
+        let (log, records) = capture_logger();
+        let log = log.new(o!("thread" => "worker_0"));
+
+        warn!(log, "slow lookup"; "request_type" => "GETPWBYNAME", "ms" => 1500);
+        info!(log, "done");
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].level, Level::Warning);
+        assert_eq!(records[0].msg, "slow lookup");
+        assert_eq!(records[0].value("request_type"), Some("GETPWBYNAME"));
+        assert_eq!(records[0].value("ms"), Some("1500"));
+        assert_eq!(records[0].value("thread"), Some("worker_0"));
+        assert_eq!(records[1].level, Level::Info);
+        assert_eq!(records[1].value("ms"), None);
+    }
+}
@@ -0,0 +1,92 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Names looked up ahead of time.
+//!
+//! The first login as a service account, or to a bastion host, after a
+//! restart or an invalidation would otherwise pay for a trip to the
+//! directory. A [WarmList] names the entries to look up into the response
+//! cache beforehand instead.
+
+use super::protocol::RequestType;
+
+/// The users, groups and hosts to look up ahead of time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WarmList {
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+    pub hosts: Vec<String>,
+}
+
+impl WarmList {
+    /// Parse a comma-separated list of names, ignoring blanks around them.
+    pub fn parse_names(names: &str) -> Vec<String> {
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// The requests looking up the listed entries of `database`, one of
+    /// [crate::protocol::DATABASES]. Hosts are looked up the way
+    /// `getaddrinfo()` does.
+    pub fn requests(&self, database: &str) -> Vec<(RequestType, Vec<u8>)> {
+        let (ty, names) = match database {
+            "passwd" => (RequestType::GETPWBYNAME, &self.users),
+            "group" => (RequestType::GETGRBYNAME, &self.groups),
+            "hosts" => (RequestType::GETAI, &self.hosts),
+            _ => return vec![],
+        };
+        names
+            .iter()
+            .map(|name| {
+                let mut key = name.as_bytes().to_vec();
+                key.push(0);
+                (ty, key)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let list = WarmList {
+            users: WarmList::parse_names("root, svc-deploy,,"),
+            groups: vec![],
+            hosts: WarmList::parse_names("bastion.example.com"),
+        };
+        assert_eq!(list.users, vec!["root", "svc-deploy"]);
+        assert_eq!(
+            list.requests("passwd"),
+            vec![
+                (RequestType::GETPWBYNAME, b"root\0".to_vec()),
+                (RequestType::GETPWBYNAME, b"svc-deploy\0".to_vec()),
+            ]
+        );
+        assert!(list.requests("group").is_empty());
+        assert_eq!(
+            list.requests("hosts"),
+            vec![(RequestType::GETAI, b"bastion.example.com\0".to_vec())]
+        );
+        assert!(list.requests("services").is_empty());
+    }
+}
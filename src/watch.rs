@@ -0,0 +1,206 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Noticing changes to the files in `/etc` that databases come from, so
+//! their cached answers can be dropped, like nscd's `check-files`.
+
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+
+use super::protocol::DATABASES;
+
+/// The files watched, and the databases whose answers they can change.
+const FILES: &[(&str, &[&str])] = &[
+    ("passwd", &["passwd"]),
+    ("group", &["group"]),
+    ("hosts", &["hosts"]),
+    ("services", &["services"]),
+    ("netgroup", &["netgroup"]),
+];
+
+/// nsswitch.conf says where every database comes from.
+const NSSWITCH: (&str, &[&str]) = ("nsswitch.conf", &DATABASES);
+
+/// Watches a directory for changes to the files in [FILES].
+pub struct Watcher {
+    inotify: Inotify,
+    files: Vec<(&'static str, &'static [&'static str])>,
+}
+
+impl Watcher {
+    /// Watch the files of `databases` in `dir`, normally `/etc`, and
+    /// `nsswitch.conf` too if `nsswitch`.
+    pub fn new(dir: &Path, databases: &[&str], nsswitch: bool) -> Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .context("creating inotify instance")?;
+        // The directory, rather than the files: useradd, editors and
+        // configuration management replace them by renaming a new file over
+        // the old one, which a watch on the old one wouldn't see.
+        inotify
+            .add_watch(
+                dir,
+                AddWatchFlags::IN_CLOSE_WRITE
+                    | AddWatchFlags::IN_MOVED_TO
+                    | AddWatchFlags::IN_MOVED_FROM
+                    | AddWatchFlags::IN_CREATE
+                    | AddWatchFlags::IN_DELETE,
+            )
+            .with_context(|| format!("watching {}", dir.display()))?;
+        let mut files: Vec<_> = FILES
+            .iter()
+            .filter(|(_, file_databases)| file_databases.iter().any(|db| databases.contains(db)))
+            .copied()
+            .collect();
+        if nsswitch {
+            files.push(NSSWITCH);
+        }
+        Ok(Self { inotify, files })
+    }
+
+    /// Wait up to `timeout` for files to change, and return the databases
+    /// whose answers they may have changed. Empty if nothing changed.
+    pub fn changed(&self, timeout: Duration) -> Result<Vec<&'static str>> {
+        let mut fds = [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)];
+        let timeout = PollTimeout::try_from(timeout).expect("timeout out of range");
+        match poll(&mut fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(vec![]),
+            Ok(_) => {}
+            Err(e) => return Err(e).context("polling inotify"),
+        }
+        let mut databases = vec![];
+        loop {
+            let events = match self.inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EAGAIN) => break,
+                Err(e) => return Err(e).context("reading inotify events"),
+            };
+            for event in events {
+                // some events were lost, so any file may have changed.
+                if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                    databases.extend_from_slice(&DATABASES);
+                    continue;
+                }
+                let name = match &event.name {
+                    Some(name) => name,
+                    None => continue,
+                };
+                for (file, file_databases) in &self.files {
+                    if name.as_os_str() == OsStr::new(file) {
+                        databases.extend_from_slice(file_databases);
+                    }
+                }
+            }
+        }
+        // in DATABASES order, once each.
+        Ok(DATABASES
+            .iter()
+            .copied()
+            .filter(|database| databases.contains(database))
+            .collect())
+    }
+}
+
+/// The databases whose files in `dir`, normally `/etc`, were modified after
+/// `since`. Every database if `nsswitch.conf` was.
+pub fn changed_since(dir: &Path, since: SystemTime) -> Vec<&'static str> {
+    let mut databases = vec![];
+    for (file, file_databases) in FILES.iter().chain([NSSWITCH].iter()) {
+        // a file that's gone can't have new entries in it.
+        let modified = match std::fs::metadata(dir.join(file)).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if modified > since {
+            databases.extend_from_slice(file_databases);
+        }
+    }
+    DATABASES
+        .iter()
+        .copied()
+        .filter(|database| databases.contains(database))
+        .collect()
+}
+
+impl std::fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watcher")
+            .field("files", &self.files)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn test_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = Watcher::new(dir.path(), &DATABASES, false).unwrap();
+        assert!(watcher.changed(Duration::ZERO).unwrap().is_empty());
+
+        // edited in place.
+        std::fs::write(dir.path().join("passwd"), "alice:x:1000:1000::/:/bin/sh\n").unwrap();
+        assert_eq!(watcher.changed(TIMEOUT).unwrap(), vec!["passwd"]);
+
+        // replaced by a rename, along with another one.
+        std::fs::write(dir.path().join("group+"), "staff:x:100:alice\n").unwrap();
+        std::fs::rename(dir.path().join("group+"), dir.path().join("group")).unwrap();
+        std::fs::write(dir.path().join("hosts"), "127.0.0.1 localhost\n").unwrap();
+        assert_eq!(watcher.changed(TIMEOUT).unwrap(), vec!["group", "hosts"]);
+
+        // neither watched nor the source of a database.
+        std::fs::write(dir.path().join("motd"), "hello\n").unwrap();
+        std::fs::write(dir.path().join("nsswitch.conf"), "passwd: files\n").unwrap();
+        assert!(watcher.changed(TIMEOUT).unwrap().is_empty());
+
+        // not watched for its database.
+        let watcher = Watcher::new(dir.path(), &["group"], false).unwrap();
+        std::fs::write(dir.path().join("passwd"), "bob:x:1001:1001::/:/bin/sh\n").unwrap();
+        std::fs::write(dir.path().join("group"), "staff:x:100:bob\n").unwrap();
+        assert_eq!(watcher.changed(TIMEOUT).unwrap(), vec!["group"]);
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("passwd"), "alice:x:1000:1000::/:/bin/sh\n").unwrap();
+        let since = SystemTime::now() + Duration::from_secs(1);
+        assert!(changed_since(dir.path(), since).is_empty());
+        let since = SystemTime::now() - Duration::from_secs(60);
+        assert_eq!(changed_since(dir.path(), since), vec!["passwd"]);
+        std::fs::write(dir.path().join("nsswitch.conf"), "passwd: files\n").unwrap();
+        assert_eq!(changed_since(dir.path(), since), DATABASES.to_vec());
+    }
+
+    #[test]
+    fn test_watcher_nsswitch() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = Watcher::new(dir.path(), &[], true).unwrap();
+        std::fs::write(dir.path().join("nsswitch.conf"), "passwd: files\n").unwrap();
+        assert_eq!(watcher.changed(TIMEOUT).unwrap(), DATABASES.to_vec());
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ * Copyright 2024 Two Sigma Open Source, LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Noticing workers stuck on a request.
+//!
+//! NSS calls can't be interrupted, so a lookup hung on an unresponsive
+//! directory keeps its worker until it returns, which may be never. Each
+//! worker notes in the [Watchdog] when it starts and finishes answering a
+//! request, and the watchdog reports the ones that have been at it for too
+//! long, once each, so that a worker going out of service doesn't go
+//! unnoticed.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::protocol::RequestType;
+
+/// What each worker is doing.
+pub struct Watchdog {
+    threshold: Duration,
+    workers: Vec<Mutex<Worker>>,
+}
+
+#[derive(Default)]
+struct Worker {
+    /// The request it's answering, and since when.
+    running: Option<(RequestType, Instant)>,
+    /// Whether that request was reported stuck.
+    reported: bool,
+}
+
+/// A request a worker has been answering for longer than the threshold.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Stuck {
+    pub worker: usize,
+    pub ty: RequestType,
+    pub running: Duration,
+}
+
+impl Watchdog {
+    /// Watch `workers` workers, numbered from 0, for requests taking longer
+    /// than `threshold`.
+    pub fn new(workers: usize, threshold: Duration) -> Self {
+        Self {
+            threshold,
+            workers: (0..workers).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// Note that `worker` started answering a request of type `ty`.
+    pub fn start(&self, worker: usize, ty: RequestType, now: Instant) {
+        *self.workers[worker].lock().unwrap() = Worker {
+            running: Some((ty, now)),
+            reported: false,
+        };
+    }
+
+    /// Note that `worker` is done with its request. Returns how long it took
+    /// if it was reported stuck.
+    pub fn finish(&self, worker: usize, now: Instant) -> Option<Duration> {
+        let Worker { running, reported } =
+            std::mem::take(&mut *self.workers[worker].lock().unwrap());
+        match running {
+            Some((_, since)) if reported => Some(now.saturating_duration_since(since)),
+            _ => None,
+        }
+    }
+
+    /// The requests that have been running for longer than the threshold,
+    /// and weren't returned by an earlier call.
+    pub fn newly_stuck(&self, now: Instant) -> Vec<Stuck> {
+        let mut stuck = vec![];
+        for (index, worker) in self.workers.iter().enumerate() {
+            let mut worker = worker.lock().unwrap();
+            let (ty, since) = match worker.running {
+                Some(running) if !worker.reported => running,
+                _ => continue,
+            };
+            let running = now.saturating_duration_since(since);
+            if running > self.threshold {
+                worker.reported = true;
+                stuck.push(Stuck {
+                    worker: index,
+                    ty,
+                    running,
+                });
+            }
+        }
+        stuck
+    }
+}
+
+impl std::fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn test_newly_stuck() {
+        let watchdog = Watchdog::new(3, THRESHOLD);
+        let start = Instant::now();
+        watchdog.start(0, RequestType::GETPWBYUID, start);
+        watchdog.start(2, RequestType::GETAI, start + Duration::from_secs(10));
+        assert!(watchdog.newly_stuck(start + THRESHOLD).is_empty());
+
+        let later = start + THRESHOLD + Duration::from_secs(1);
+        assert_eq!(
+            watchdog.newly_stuck(later),
+            vec![Stuck {
+                worker: 0,
+                ty: RequestType::GETPWBYUID,
+                running: THRESHOLD + Duration::from_secs(1),
+            }]
+        );
+        // each is only reported once...
+        assert!(watchdog.newly_stuck(later).is_empty());
+        let later = start + Duration::from_secs(60);
+        assert_eq!(watchdog.newly_stuck(later).len(), 1);
+
+        // ...and says how long it took once it's done.
+        assert_eq!(watchdog.finish(0, later), Some(Duration::from_secs(60)));
+        assert_eq!(watchdog.finish(1, later), None);
+
+        // a new request starts over.
+        watchdog.start(2, RequestType::GETAI, later);
+        assert!(watchdog
+            .newly_stuck(later + Duration::from_secs(1))
+            .is_empty());
+        assert_eq!(watchdog.finish(2, later + Duration::from_secs(1)), None);
+    }
+}